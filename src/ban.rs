@@ -0,0 +1,100 @@
+use anyhow::Context;
+use std::collections::{BTreeMap, HashMap};
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Peers we neither connect to nor accept, by IP address, with the reason
+/// they were banned. Shared by the torrents of a session.
+#[derive(Debug, Default)]
+pub struct BanList {
+    banned: Mutex<HashMap<Ipv4Addr, String>>,
+}
+
+impl BanList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The bans saved at `path`, one address and its reason per line. No
+    /// file is no bans.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::new()),
+            Err(e) => return Err(e).with_context(|| format!("read {}", path.display())),
+        };
+        let mut banned = HashMap::new();
+        for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+            let (ip, reason) = line.split_once(' ').unwrap_or((line, ""));
+            let ip = ip
+                .parse()
+                .with_context(|| format!("{}: invalid address {ip}", path.display()))?;
+            banned.insert(ip, reason.to_string());
+        }
+        Ok(Self {
+            banned: Mutex::new(banned),
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let banned: BTreeMap<_, _> = self.banned().into_iter().collect();
+        let contents: String = banned
+            .iter()
+            .map(|(ip, reason)| format!("{ip} {}\n", reason.replace('\n', " ")))
+            .collect();
+        std::fs::write(path, contents).with_context(|| format!("write {}", path.display()))
+    }
+
+    /// Returns false if `ip` was already banned, keeping the first reason.
+    pub fn ban(&self, ip: Ipv4Addr, reason: impl Into<String>) -> bool {
+        let mut banned = self.banned.lock().unwrap();
+        if banned.contains_key(&ip) {
+            return false;
+        }
+        banned.insert(ip, reason.into());
+        true
+    }
+
+    pub fn unban(&self, ip: &Ipv4Addr) -> bool {
+        self.banned.lock().unwrap().remove(ip).is_some()
+    }
+
+    pub fn is_banned(&self, ip: &Ipv4Addr) -> bool {
+        self.banned.lock().unwrap().contains_key(ip)
+    }
+
+    pub fn reason(&self, ip: &Ipv4Addr) -> Option<String> {
+        self.banned.lock().unwrap().get(ip).cloned()
+    }
+
+    pub fn banned(&self) -> Vec<(Ipv4Addr, String)> {
+        let banned = self.banned.lock().unwrap();
+        banned
+            .iter()
+            .map(|(ip, reason)| (*ip, reason.clone()))
+            .collect()
+    }
+}
+
+#[test]
+fn bans_are_saved_with_their_reason() {
+    let path = std::env::temp_dir().join(format!("rustorrent-bans-{}", std::process::id()));
+    assert!(BanList::load(&path).unwrap().banned().is_empty());
+
+    let bans = BanList::new();
+    assert!(bans.ban(Ipv4Addr::new(10, 0, 0, 2), "sent corrupt piece 3"));
+    assert!(!bans.ban(Ipv4Addr::new(10, 0, 0, 2), "again"));
+    assert!(bans.ban(Ipv4Addr::new(10, 0, 0, 1), "bitfield after the handshake"));
+    bans.save(&path).unwrap();
+
+    let loaded = BanList::load(&path).unwrap();
+    assert!(loaded.is_banned(&Ipv4Addr::new(10, 0, 0, 1)));
+    assert_eq!(
+        loaded.reason(&Ipv4Addr::new(10, 0, 0, 2)).as_deref(),
+        Some("sent corrupt piece 3")
+    );
+    assert!(loaded.unban(&Ipv4Addr::new(10, 0, 0, 2)));
+    assert_eq!(loaded.banned().len(), 1);
+    std::fs::remove_file(&path).unwrap();
+}
@@ -1,96 +1,1150 @@
-#![allow(warnings)]
-
-use bendy::encoding::{Error, ToBencode};
-use bendy::{serde::from_bytes, serde::to_bytes, value::Value};
-use serde::Serialize;
+use serde::de::{self, Unexpected, Visitor};
+use serde::{forward_to_deserialize_any, ser, Deserialize, Serialize};
 use std::collections::BTreeMap;
-use std::fs::File;
-use std::io::{self, Read};
-
-#[derive(Debug, Clone, Serialize)]
-pub enum OwnedValue {
-    /// An owned byte string
-    Str(String),
-    /// A dictionary mapping byte strings to owned values
-    Dict(BTreeMap<String, OwnedValue>),
+use std::fmt;
+use std::io;
+use std::ops::Range;
+
+/// A bencoded dictionary, keyed by byte strings.
+pub type Dict<'a> = BTreeMap<&'a [u8], Value<'a>>;
+
+/// A bencoded value borrowing its byte strings from the decoded buffer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value<'a> {
+    /// A byte string
+    Bytes(&'a [u8]),
+    /// A dictionary mapping byte strings to values
+    Dict(Dict<'a>),
     /// A signed integer
     Integer(i64),
-    /// A list of owned values
-    List(Vec<OwnedValue>),
-}
-
-fn safe_utf8_string(bytes: &[u8]) -> String {
-    bytes
-        .iter()
-        .map(|&b| {
-            if (0x20..=0x7E).contains(&b) {
-                // Printable ASCII character
-                (b as char).to_string()
-            } else {
-                // Non-printable character, convert to hexadecimal representation
-                format!("\\u{:04x}", b)
+    /// A list of values
+    List(Vec<Value<'a>>),
+}
+
+impl<'a> Value<'a> {
+    pub fn as_bytes(&self) -> Option<&'a [u8]> {
+        match self {
+            Value::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    /// The byte string, if it is valid UTF-8.
+    pub fn as_str(&self) -> Option<&'a str> {
+        self.as_bytes()
+            .and_then(|bytes| std::str::from_utf8(bytes).ok())
+    }
+
+    pub fn as_integer(&self) -> Option<i64> {
+        match self {
+            Value::Integer(num) => Some(*num),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&[Value<'a>]> {
+        match self {
+            Value::List(list) => Some(list),
+            _ => None,
+        }
+    }
+
+    pub fn as_dict(&self) -> Option<&Dict<'a>> {
+        match self {
+            Value::Dict(dict) => Some(dict),
+            _ => None,
+        }
+    }
+
+    /// The value of `key`, if this is a dictionary holding it.
+    pub fn get(&self, key: &str) -> Option<&Value<'a>> {
+        self.as_dict()?.get(key.as_bytes())
+    }
+
+    /// Bencodes the value; dictionaries come out with sorted keys.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode_into(&mut out);
+        out
+    }
+
+    fn encode_into(&self, out: &mut Vec<u8>) {
+        match self {
+            Value::Bytes(bytes) => encode_bytes(bytes, out),
+            Value::Integer(num) => out.extend_from_slice(format!("i{num}e").as_bytes()),
+            Value::List(list) => {
+                out.push(b'l');
+                for value in list {
+                    value.encode_into(out);
+                }
+                out.push(b'e');
+            }
+            Value::Dict(dict) => {
+                out.push(b'd');
+                for (key, value) in dict {
+                    encode_bytes(key, out);
+                    value.encode_into(out);
+                }
+                out.push(b'e');
             }
+        }
+    }
+}
+
+fn encode_bytes(bytes: &[u8], out: &mut Vec<u8>) {
+    out.extend_from_slice(bytes.len().to_string().as_bytes());
+    out.push(b':');
+    out.extend_from_slice(bytes);
+}
+
+/// Bounds on what a single decode may allocate, against hostile input.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// Deepest nesting of lists and dictionaries.
+    pub max_depth: usize,
+    /// Most values, at any depth, in the input.
+    pub max_values: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_depth: 64,
+            max_values: 1 << 20,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeErrorKind {
+    UnexpectedEnd,
+    UnexpectedByte(u8),
+    /// Integers and string lengths are written without leading zeros.
+    LeadingZero,
+    NegativeZero,
+    IntegerOverflow,
+    UnsortedKeys,
+    DuplicateKey,
+    TooDeep,
+    TooManyValues,
+    TrailingData,
+    NotADictionary,
+    MissingKey(&'static str),
+}
+
+impl DecodeErrorKind {
+    /// Whether the input is valid bencode, only not in canonical form.
+    pub fn is_non_canonical(self) -> bool {
+        matches!(
+            self,
+            Self::LeadingZero | Self::NegativeZero | Self::UnsortedKeys | Self::DuplicateKey
+        )
+    }
+}
+
+/// Why decoding failed, and at which byte of the input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeError {
+    pub offset: usize,
+    pub kind: DecodeErrorKind,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            DecodeErrorKind::UnexpectedEnd => write!(f, "unexpected end of input")?,
+            DecodeErrorKind::UnexpectedByte(byte) => {
+                write!(f, "unexpected byte {:?}", byte as char)?
+            }
+            DecodeErrorKind::LeadingZero => write!(f, "number with a leading zero")?,
+            DecodeErrorKind::NegativeZero => write!(f, "negative zero")?,
+            DecodeErrorKind::IntegerOverflow => write!(f, "number too large")?,
+            DecodeErrorKind::UnsortedKeys => write!(f, "dictionary keys out of order")?,
+            DecodeErrorKind::DuplicateKey => write!(f, "duplicate dictionary key")?,
+            DecodeErrorKind::TooDeep => write!(f, "nesting too deep")?,
+            DecodeErrorKind::TooManyValues => write!(f, "too many values")?,
+            DecodeErrorKind::TrailingData => write!(f, "trailing data")?,
+            DecodeErrorKind::NotADictionary => write!(f, "not a dictionary")?,
+            DecodeErrorKind::MissingKey(key) => write!(f, "missing key {key:?}")?,
+        }
+        write!(f, " at byte {}", self.offset)
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl From<DecodeError> for io::Error {
+    fn from(e: DecodeError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+/// Decodes bencode in a single pass over a borrowed buffer. Like other
+/// clients it accepts keys out of order, keeping the first of duplicate
+/// keys, and numbers with leading zeros or `-0`, unless made `strict`.
+pub struct Decoder<'a> {
+    input: &'a [u8],
+    pos: usize,
+    depth: usize,
+    values: usize,
+    limits: Limits,
+    strict: bool,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(input: &'a [u8]) -> Self {
+        Self::with_limits(input, Limits::default())
+    }
+
+    pub fn with_limits(input: &'a [u8], limits: Limits) -> Self {
+        Self {
+            input,
+            pos: 0,
+            depth: 0,
+            values: 0,
+            limits,
+            strict: false,
+        }
+    }
+
+    /// Accepts only the canonical form: sorted unique keys and numbers
+    /// without leading zeros or `-0`.
+    pub fn strict(mut self) -> Self {
+        self.strict = true;
+        self
+    }
+
+    /// Decodes the whole input as one value.
+    pub fn decode(mut self) -> Result<Value<'a>, DecodeError> {
+        let value = self.value()?;
+        if self.pos != self.input.len() {
+            return Err(self.error(DecodeErrorKind::TrailingData));
+        }
+        Ok(value)
+    }
+
+    /// Decodes the value at the start of the input and returns it with the
+    /// number of bytes it took, for messages carrying raw data after it.
+    pub fn decode_prefix(mut self) -> Result<(Value<'a>, usize), DecodeError> {
+        let value = self.value()?;
+        Ok((value, self.pos))
+    }
+
+    fn error(&self, kind: DecodeErrorKind) -> DecodeError {
+        DecodeError {
+            offset: self.pos,
+            kind,
+        }
+    }
+
+    fn peek(&self) -> Result<u8, DecodeError> {
+        self.input
+            .get(self.pos)
+            .copied()
+            .ok_or_else(|| self.error(DecodeErrorKind::UnexpectedEnd))
+    }
+
+    fn value(&mut self) -> Result<Value<'a>, DecodeError> {
+        self.values += 1;
+        if self.values > self.limits.max_values {
+            return Err(self.error(DecodeErrorKind::TooManyValues));
+        }
+        match self.peek()? {
+            b'i' => {
+                self.pos += 1;
+                let num = self.integer(b'e')?;
+                Ok(Value::Integer(num))
+            }
+            b'0'..=b'9' => self.bytes().map(Value::Bytes),
+            b'l' => {
+                self.enter()?;
+                let mut list = Vec::new();
+                while self.peek()? != b'e' {
+                    list.push(self.value()?);
+                }
+                self.leave();
+                Ok(Value::List(list))
+            }
+            b'd' => {
+                self.enter()?;
+                let mut dict = Dict::new();
+                let mut previous: Option<&[u8]> = None;
+                while self.peek()? != b'e' {
+                    let key_pos = self.pos;
+                    let key = self.bytes()?;
+                    if self.strict {
+                        self.check_order(previous, key, key_pos)?;
+                    }
+                    previous = Some(key);
+                    let value = self.value()?;
+                    dict.entry(key).or_insert(value);
+                }
+                self.leave();
+                Ok(Value::Dict(dict))
+            }
+            b => Err(self.error(DecodeErrorKind::UnexpectedByte(b))),
+        }
+    }
+
+    fn enter(&mut self) -> Result<(), DecodeError> {
+        if self.depth == self.limits.max_depth {
+            return Err(self.error(DecodeErrorKind::TooDeep));
+        }
+        self.depth += 1;
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn leave(&mut self) {
+        self.depth -= 1;
+        self.pos += 1;
+    }
+
+    fn check_order(
+        &self,
+        previous: Option<&[u8]>,
+        key: &[u8],
+        key_pos: usize,
+    ) -> Result<(), DecodeError> {
+        let kind = match previous {
+            Some(previous) if previous == key => DecodeErrorKind::DuplicateKey,
+            Some(previous) if previous > key => DecodeErrorKind::UnsortedKeys,
+            _ => return Ok(()),
+        };
+        Err(DecodeError {
+            offset: key_pos,
+            kind,
         })
-        .collect::<String>()
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], DecodeError> {
+        match self.peek()? {
+            b'0'..=b'9' => {}
+            b => return Err(self.error(DecodeErrorKind::UnexpectedByte(b))),
+        }
+        let length = self.integer(b':')?;
+        let end = self
+            .pos
+            .checked_add(length as usize)
+            .filter(|&end| end <= self.input.len())
+            .ok_or_else(|| self.error(DecodeErrorKind::UnexpectedEnd))?;
+        let bytes = &self.input[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    /// Reads a decimal number up to `terminator`, which is consumed.
+    fn integer(&mut self, terminator: u8) -> Result<i64, DecodeError> {
+        let start = self.pos;
+        let negative = self.peek()? == b'-';
+        if negative {
+            self.pos += 1;
+        }
+        let digits_start = self.pos;
+        let mut num: i64 = 0;
+        loop {
+            match self.peek()? {
+                b @ b'0'..=b'9' => {
+                    let digit = (b - b'0') as i64;
+                    num = num
+                        .checked_mul(10)
+                        .and_then(|num| {
+                            if negative {
+                                num.checked_sub(digit)
+                            } else {
+                                num.checked_add(digit)
+                            }
+                        })
+                        .ok_or_else(|| self.error(DecodeErrorKind::IntegerOverflow))?;
+                    self.pos += 1;
+                }
+                b if b == terminator && self.pos > digits_start => break,
+                b => return Err(self.error(DecodeErrorKind::UnexpectedByte(b))),
+            }
+        }
+        let digits = &self.input[digits_start..self.pos];
+        if !self.strict {
+            self.pos += 1;
+            return Ok(num);
+        }
+        if digits.len() > 1 && digits[0] == b'0' {
+            return Err(DecodeError {
+                offset: start,
+                kind: DecodeErrorKind::LeadingZero,
+            });
+        }
+        if negative && num == 0 {
+            return Err(DecodeError {
+                offset: start,
+                kind: DecodeErrorKind::NegativeZero,
+            });
+        }
+        self.pos += 1;
+        Ok(num)
+    }
+}
+
+/// Decodes `contents` as one value with the default limits.
+pub fn decode(contents: &[u8]) -> Result<Value<'_>, DecodeError> {
+    Decoder::new(contents).decode()
+}
+
+/// Decodes `contents` like `decode`, refusing anything not in canonical form.
+pub fn decode_strict(contents: &[u8]) -> Result<Value<'_>, DecodeError> {
+    Decoder::new(contents).strict().decode()
+}
+
+pub fn decode_bencoded_string(contents: &[u8]) -> Result<Dict<'_>, DecodeError> {
+    match decode(contents)? {
+        Value::Dict(map) => Ok(map),
+        _ => Err(DecodeError {
+            offset: 0,
+            kind: DecodeErrorKind::NotADictionary,
+        }),
+    }
+}
+
+/// Failure to map bencode to or from a typed value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    Decode(DecodeError),
+    Message(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Decode(e) => e.fmt(f),
+            Error::Message(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<DecodeError> for Error {
+    fn from(e: DecodeError) -> Self {
+        Error::Decode(e)
+    }
+}
+
+impl de::Error for Error {
+    fn custom<T: fmt::Display>(message: T) -> Self {
+        Error::Message(message.to_string())
+    }
+}
+
+impl ser::Error for Error {
+    fn custom<T: fmt::Display>(message: T) -> Self {
+        Error::Message(message.to_string())
+    }
+}
+
+/// Deserializes `T` from bencode accepted by the decoder. Byte
+/// strings are borrowed from `bytes` where `T` allows it.
+pub fn from_bytes<'de, T: Deserialize<'de>>(bytes: &'de [u8]) -> Result<T, Error> {
+    T::deserialize(&decode(bytes)?)
+}
+
+/// Bencodes `value`. Struct fields and map entries come out sorted by key,
+/// and `None` fields are left out.
+pub fn to_bytes<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, Error> {
+    let mut encoder = Encoder { out: Vec::new() };
+    value.serialize(&mut encoder)?;
+    Ok(encoder.out)
 }
 
-fn to_owned_value(value: Value) -> OwnedValue {
-    match value {
-        Value::Bytes(bytes) => OwnedValue::Str(safe_utf8_string(&bytes)),
-        Value::Dict(dict) => {
-            let mut owned_dict = BTreeMap::new();
-            for (key, value) in dict {
-                owned_dict.insert(safe_utf8_string(&key), to_owned_value(value));
+impl<'de> de::Deserializer<'de> for &Value<'de> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self {
+            Value::Bytes(bytes) => visitor.visit_borrowed_bytes(bytes),
+            Value::Integer(num) => visitor.visit_i64(*num),
+            Value::List(list) => visitor.visit_seq(SeqDeserializer(list.iter())),
+            Value::Dict(dict) => visitor.visit_map(MapDeserializer {
+                entries: dict.iter(),
+                value: None,
+            }),
+        }
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self {
+            Value::Integer(0) => visitor.visit_bool(false),
+            Value::Integer(1) => visitor.visit_bool(true),
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self {
+            Value::Bytes(bytes) => match std::str::from_utf8(bytes) {
+                Ok(s) => visitor.visit_borrowed_str(s),
+                Err(_) => Err(de::Error::invalid_value(
+                    Unexpected::Bytes(bytes),
+                    &"a UTF-8 string",
+                )),
+            },
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_char<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        // absent keys are the `None`s; a present value is always `Some`
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        match self {
+            // unit variants are their name, the others a dict of one entry
+            Value::Bytes(_) => {
+                let variant = self
+                    .as_str()
+                    .ok_or_else(|| Error::Message(String::from("variant is not UTF-8")))?;
+                visitor.visit_enum(de::value::StrDeserializer::new(variant))
+            }
+            Value::Dict(dict) if dict.len() == 1 => {
+                visitor.visit_enum(de::value::MapAccessDeserializer::new(MapDeserializer {
+                    entries: dict.iter(),
+                    value: None,
+                }))
+            }
+            _ => Err(de::Error::invalid_type(self.unexpected(), &"an enum")),
+        }
+    }
+
+    forward_to_deserialize_any! {
+        i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 bytes byte_buf unit
+        unit_struct seq tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+impl Value<'_> {
+    fn unexpected(&self) -> Unexpected<'_> {
+        match self {
+            Value::Bytes(bytes) => Unexpected::Bytes(bytes),
+            Value::Integer(num) => Unexpected::Signed(*num),
+            Value::List(_) => Unexpected::Seq,
+            Value::Dict(_) => Unexpected::Map,
+        }
+    }
+}
+
+struct SeqDeserializer<'a, 'de>(std::slice::Iter<'a, Value<'de>>);
+
+impl<'a, 'de> de::SeqAccess<'de> for SeqDeserializer<'a, 'de> {
+    type Error = Error;
+
+    fn next_element_seed<T: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Error> {
+        self.0
+            .next()
+            .map(|value| seed.deserialize(value))
+            .transpose()
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.0.len())
+    }
+}
+
+struct MapDeserializer<'a, 'de> {
+    entries: std::collections::btree_map::Iter<'a, &'de [u8], Value<'de>>,
+    value: Option<&'a Value<'de>>,
+}
+
+impl<'a, 'de> de::MapAccess<'de> for MapDeserializer<'a, 'de> {
+    type Error = Error;
+
+    fn next_key_seed<K: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Error> {
+        match self.entries.next() {
+            Some((key, value)) => {
+                self.value = Some(value);
+                seed.deserialize(KeyDeserializer(key)).map(Some)
             }
-            OwnedValue::Dict(owned_dict)
+            None => Ok(None),
         }
-        Value::Integer(num) => OwnedValue::Integer(num),
-        Value::List(list) => OwnedValue::List(list.into_iter().map(to_owned_value).collect()),
     }
+
+    fn next_value_seed<V: de::DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        let value = self
+            .value
+            .take()
+            .ok_or_else(|| Error::Message(String::from("value asked before its key")))?;
+        seed.deserialize(value)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.entries.len())
+    }
+}
+
+impl<'a, 'de> de::EnumAccess<'de> for MapDeserializer<'a, 'de> {
+    type Error = Error;
+    type Variant = &'a Value<'de>;
+
+    fn variant_seed<V: de::DeserializeSeed<'de>>(
+        mut self,
+        seed: V,
+    ) -> Result<(V::Value, Self::Variant), Error> {
+        let (key, value) = self
+            .entries
+            .next()
+            .ok_or_else(|| Error::Message(String::from("empty enum dictionary")))?;
+        Ok((seed.deserialize(KeyDeserializer(key))?, value))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for &Value<'de> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Error> {
+        de::Deserialize::deserialize(self)
+    }
+
+    fn newtype_variant_seed<T: de::DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, Error> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_seq(self, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_map(self, visitor)
+    }
+}
+
+/// Dictionary keys: strings when they are UTF-8, so they match field names
+/// and `String` map keys, raw bytes otherwise.
+struct KeyDeserializer<'de>(&'de [u8]);
+
+impl<'de> de::Deserializer<'de> for KeyDeserializer<'de> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match std::str::from_utf8(self.0) {
+            Ok(s) => visitor.visit_borrowed_str(s),
+            Err(_) => visitor.visit_borrowed_bytes(self.0),
+        }
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_borrowed_bytes(self.0)
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_borrowed_bytes(self.0)
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        option unit unit_struct newtype_struct seq tuple tuple_struct map
+        struct enum identifier ignored_any
+    }
+}
+
+/// Writes bencode. Values that have no bencode form (`None`, `()`) write
+/// nothing, which is how dictionaries know to leave their entry out.
+struct Encoder {
+    out: Vec<u8>,
+}
+
+impl Encoder {
+    fn integer(&mut self, num: impl fmt::Display) -> Result<(), Error> {
+        self.out.extend_from_slice(format!("i{num}e").as_bytes());
+        Ok(())
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        encode_bytes(bytes, &mut self.out);
+        Ok(())
+    }
+
+    fn dict(&mut self) -> DictEncoder<'_> {
+        DictEncoder {
+            encoder: self,
+            entries: BTreeMap::new(),
+            key: None,
+        }
+    }
+}
+
+impl<'a> ser::Serializer for &'a mut Encoder {
+    type Ok = ();
+    type Error = Error;
+    type SerializeSeq = ListEncoder<'a>;
+    type SerializeTuple = ListEncoder<'a>;
+    type SerializeTupleStruct = ListEncoder<'a>;
+    type SerializeTupleVariant = VariantEncoder<ListEncoder<'a>>;
+    type SerializeMap = DictEncoder<'a>;
+    type SerializeStruct = DictEncoder<'a>;
+    type SerializeStructVariant = VariantEncoder<DictEncoder<'a>>;
+
+    fn serialize_bool(self, v: bool) -> Result<(), Error> {
+        self.integer(v as u8)
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<(), Error> {
+        self.integer(v)
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<(), Error> {
+        self.integer(v)
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<(), Error> {
+        self.integer(v)
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<(), Error> {
+        self.integer(v)
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<(), Error> {
+        self.integer(v)
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<(), Error> {
+        self.integer(v)
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<(), Error> {
+        self.integer(v)
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<(), Error> {
+        self.integer(v)
+    }
+
+    fn serialize_f32(self, _v: f32) -> Result<(), Error> {
+        Err(Error::Message(String::from(
+            "bencode has no floating point numbers",
+        )))
+    }
+
+    fn serialize_f64(self, _v: f64) -> Result<(), Error> {
+        Err(Error::Message(String::from(
+            "bencode has no floating point numbers",
+        )))
+    }
+
+    fn serialize_char(self, v: char) -> Result<(), Error> {
+        self.bytes(v.encode_utf8(&mut [0; 4]).as_bytes())
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), Error> {
+        self.bytes(v.as_bytes())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), Error> {
+        self.bytes(v)
+    }
+
+    fn serialize_none(self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+    ) -> Result<(), Error> {
+        self.bytes(variant.as_bytes())
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        let mut dict = self.dict();
+        ser::SerializeStruct::serialize_field(&mut dict, variant, value)?;
+        ser::SerializeStruct::end(dict)
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<ListEncoder<'a>, Error> {
+        self.out.push(b'l');
+        Ok(ListEncoder { encoder: self })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<ListEncoder<'a>, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<ListEncoder<'a>, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<VariantEncoder<ListEncoder<'a>>, Error> {
+        self.out.push(b'd');
+        encode_bytes(variant.as_bytes(), &mut self.out);
+        Ok(VariantEncoder(self.serialize_seq(None)?))
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<DictEncoder<'a>, Error> {
+        Ok(self.dict())
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<DictEncoder<'a>, Error> {
+        Ok(self.dict())
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<VariantEncoder<DictEncoder<'a>>, Error> {
+        self.out.push(b'd');
+        encode_bytes(variant.as_bytes(), &mut self.out);
+        Ok(VariantEncoder(self.dict()))
+    }
+}
+
+struct ListEncoder<'a> {
+    encoder: &'a mut Encoder,
 }
 
-pub fn decode_bencoded_string(contents: Vec<u8>) -> io::Result<BTreeMap<String, OwnedValue>> {
-    let decoded: Value =
-        from_bytes(&contents).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+impl ser::SerializeSeq for ListEncoder<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        value.serialize(&mut *self.encoder)
+    }
 
-    match decoded {
-        Value::Dict(map) => {
-            let mut result_map = BTreeMap::new();
-            for (key, value) in map {
-                let key_string = safe_utf8_string(&key);
-                result_map.insert(key_string, to_owned_value(value));
+    fn end(self) -> Result<(), Error> {
+        self.encoder.out.push(b'e');
+        Ok(())
+    }
+}
+
+impl ser::SerializeTuple for ListEncoder<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+impl ser::SerializeTupleStruct for ListEncoder<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+/// Dictionary entries are encoded on their own and written out sorted by
+/// key once all of them are known.
+struct DictEncoder<'a> {
+    encoder: &'a mut Encoder,
+    entries: BTreeMap<Vec<u8>, Vec<u8>>,
+    key: Option<Vec<u8>>,
+}
+
+impl DictEncoder<'_> {
+    fn entry(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Error> {
+        if value.is_empty() {
+            return Ok(());
+        }
+        if self.entries.insert(key, value).is_some() {
+            return Err(Error::Message(String::from("duplicate dictionary key")));
+        }
+        Ok(())
+    }
+}
+
+impl ser::SerializeMap for DictEncoder<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+        let key = to_bytes(key)?;
+        match decode(&key) {
+            Ok(Value::Bytes(bytes)) => self.key = Some(bytes.to_vec()),
+            _ => {
+                return Err(Error::Message(String::from(
+                    "dictionary keys must be byte strings",
+                )))
             }
-            Ok(result_map)
         }
-        _ => Err(io::Error::new(io::ErrorKind::Other, "Not a dictionary")),
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        let key = self
+            .key
+            .take()
+            .ok_or_else(|| Error::Message(String::from("value given before its key")))?;
+        self.entry(key, to_bytes(value)?)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        let out = &mut self.encoder.out;
+        out.push(b'd');
+        for (key, value) in self.entries {
+            encode_bytes(&key, out);
+            out.extend_from_slice(&value);
+        }
+        out.push(b'e');
+        Ok(())
+    }
+}
+
+impl ser::SerializeStruct for DictEncoder<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.entry(key.as_bytes().to_vec(), to_bytes(value)?)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        ser::SerializeMap::end(self)
+    }
+}
+
+/// The value of an enum variant, closed by the dictionary around it.
+struct VariantEncoder<T>(T);
+
+impl ser::SerializeTupleVariant for VariantEncoder<ListEncoder<'_>> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        ser::SerializeSeq::serialize_element(&mut self.0, value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        let encoder = self.0.encoder;
+        encoder.out.extend_from_slice(b"ee");
+        Ok(())
     }
 }
 
-pub fn read_content(file_path: &str) -> Result<Vec<u8>, io::Error> {
-    let mut file = File::open(file_path)?;
-    let mut contents = Vec::new();
-    file.read_to_end(&mut contents)?;
-    Ok(contents)
+impl ser::SerializeStructVariant for VariantEncoder<DictEncoder<'_>> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        ser::SerializeStruct::serialize_field(&mut self.0, key, value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        let DictEncoder {
+            encoder, entries, ..
+        } = self.0;
+        let out = &mut encoder.out;
+        out.push(b'd');
+        for (key, value) in entries {
+            encode_bytes(&key, out);
+            out.extend_from_slice(&value);
+        }
+        out.extend_from_slice(b"ee");
+        Ok(())
+    }
 }
 
-pub fn encode_info_field(file_path: &str) -> io::Result<String> {
-    let contents = read_content(file_path)?;
+/// Byte range of the top-level `info` value in `contents`, so the info
+/// hash can be taken over the bytes exactly as the torrent stores them.
+pub fn info_span(contents: &[u8]) -> Result<Range<usize>, DecodeError> {
+    let mut decoder = Decoder::new(contents);
+    if decoder.peek()? != b'd' {
+        return Err(decoder.error(DecodeErrorKind::NotADictionary));
+    }
+    decoder.enter()?;
+    while decoder.peek()? != b'e' {
+        let key = decoder.bytes()?;
+        let start = decoder.pos;
+        decoder.value()?;
+        if key == b"info" {
+            return Ok(start..decoder.pos);
+        }
+    }
+    Err(decoder.error(DecodeErrorKind::MissingKey("info")))
+}
 
-    let decoded: Value =
-        from_bytes(&contents).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+#[test]
+fn byte_strings_round_trip() {
+    let contents = b"d4:infod4:name5:\xc3\xa9t\xc3\xa96:pieces4:\x00\xff\x80\x01e3:numi-3ee";
+    let dict = decode_bencoded_string(contents).unwrap();
+    let info = &dict[&b"info"[..]];
+    assert_eq!(info.get("name").and_then(|v| v.as_str()), Some("été"));
+    assert_eq!(
+        info.get("pieces").and_then(|v| v.as_bytes()),
+        Some(&b"\x00\xff\x80\x01"[..])
+    );
+    assert_eq!(Value::Dict(dict).encode(), contents);
+}
 
-    if let Value::Dict(dict) = decoded {
-        let info_field = dict
-            .get("info".as_bytes())
-            .expect("Required field missing: info");
-        if let Value::Dict(info) = info_field {
-            let bytes = info.to_bencode().unwrap();
-            let bencode = unsafe { String::from_utf8_unchecked(bytes) };
-            Ok(bencode.to_string())
-        } else {
-            Ok(String::from(""))
+#[test]
+fn decoder_rejects_non_canonical_input() {
+    let error = |input: &[u8]| decode_strict(input).unwrap_err();
+    assert_eq!(error(b"i03e").kind, DecodeErrorKind::LeadingZero);
+    assert_eq!(error(b"i-0e").kind, DecodeErrorKind::NegativeZero);
+    assert_eq!(error(b"ie").kind, DecodeErrorKind::UnexpectedByte(b'e'));
+    assert_eq!(error(b"02:ab").kind, DecodeErrorKind::LeadingZero);
+    assert_eq!(
+        error(b"i99999999999999999999e").kind,
+        DecodeErrorKind::IntegerOverflow
+    );
+    assert_eq!(
+        error(b"d1:bi0e1:ai0ee"),
+        DecodeError {
+            offset: 7,
+            kind: DecodeErrorKind::UnsortedKeys
         }
-    } else {
-        Err(io::Error::new(io::ErrorKind::Other, "Not a dictionary"))
+    );
+    assert_eq!(error(b"d1:ai0e1:ai0ee").kind, DecodeErrorKind::DuplicateKey);
+    assert_eq!(error(b"5:abc").kind, DecodeErrorKind::UnexpectedEnd);
+    assert_eq!(error(b"i1ei2e").kind, DecodeErrorKind::TrailingData);
+    assert_eq!(error(&[b'l'; 100]).kind, DecodeErrorKind::TooDeep);
+    let limits = Limits {
+        max_values: 3,
+        ..Default::default()
+    };
+    let error = Decoder::with_limits(b"li1ei2ei3ee", limits).decode();
+    assert_eq!(error.unwrap_err().kind, DecodeErrorKind::TooManyValues);
+}
+
+#[test]
+fn decoder_accepts_non_canonical_input_unless_strict() {
+    assert_eq!(decode(b"i03e"), Ok(Value::Integer(3)));
+    assert_eq!(decode(b"i-0e"), Ok(Value::Integer(0)));
+    assert_eq!(decode(b"02:ab"), Ok(Value::Bytes(b"ab")));
+    let dict = decode_bencoded_string(b"d1:bi0e1:ai1e1:bi2ee").unwrap();
+    assert_eq!(dict.keys().collect::<Vec<_>>(), [b"a", b"b"]);
+    assert_eq!(dict[&b"b"[..]], Value::Integer(0));
+    assert_eq!(
+        decode(b"i1ei2e").unwrap_err().kind,
+        DecodeErrorKind::TrailingData
+    );
+}
+
+#[test]
+fn info_span_keeps_original_encoding() {
+    let contents = b"d8:announce3:url4:infod6:lengthi1e4:name1:xe5:otheri0ee";
+    let span = info_span(contents).unwrap();
+    assert_eq!(&contents[span], b"d6:lengthi1e4:name1:xe");
+    assert!(info_span(b"d4:infod4:name5:abce").is_err());
+}
+
+#[test]
+fn serde_round_trips_typed_structs() {
+    use serde_bytes::ByteBuf;
+    use std::collections::HashMap;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Message {
+        #[serde(rename = "piece length")]
+        piece_length: u32,
+        id: ByteBuf,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        port: Option<u16>,
+        name: Option<String>,
+        #[serde(flatten)]
+        extra: HashMap<String, i64>,
     }
+
+    let message = Message {
+        piece_length: 16384,
+        id: ByteBuf::from(vec![0, 0xff]),
+        port: None,
+        name: Some(String::from("x")),
+        extra: HashMap::from([(String::from("a"), 1)]),
+    };
+    let bytes = to_bytes(&message).unwrap();
+    assert_eq!(
+        &bytes[..],
+        b"d1:ai1e2:id2:\x00\xff4:name1:x12:piece lengthi16384ee"
+    );
+    assert_eq!(from_bytes::<Message>(&bytes).unwrap(), message);
+
+    let error = from_bytes::<Message>(b"d2:id0:12:piece length1:xe").unwrap_err();
+    assert!(error.to_string().contains("invalid type"), "{error}");
 }
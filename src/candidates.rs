@@ -0,0 +1,151 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddrV4;
+use std::time::{Duration, Instant};
+
+/// Failed connections after which we give up on an address.
+const MAX_FAILURES: u32 = 5;
+
+/// Wait before trying an address again after its first failure, doubled
+/// after each of the next ones.
+const RETRY_AFTER: Duration = Duration::from_secs(15);
+
+/// The longest we wait before trying an address again.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(10 * 60);
+
+/// Where we learned about a peer address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerSource {
+    Tracker,
+    Pex,
+    Dht,
+    Lsd,
+}
+
+#[derive(Debug, Clone)]
+struct Candidate {
+    source: PeerSource,
+    /// Connections to it that failed, or that it dropped.
+    failures: u32,
+    /// Not tried again before then.
+    retry_at: Instant,
+}
+
+/// Addresses we could connect to, in the order we learned about them,
+/// those that failed the fewest times first and not before their back-off.
+#[derive(Debug)]
+pub(crate) struct CandidatePool {
+    known: HashMap<SocketAddrV4, Candidate>,
+    queue: VecDeque<SocketAddrV4>,
+    capacity: usize,
+}
+
+impl CandidatePool {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            known: HashMap::new(),
+            queue: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    /// Adds an address to the pool. Returns false if it was already known or
+    /// the pool is full.
+    pub(crate) fn add(&mut self, addr: SocketAddrV4, source: PeerSource) -> bool {
+        if self.known.contains_key(&addr) || self.known.len() >= self.capacity {
+            return false;
+        }
+        self.known.insert(
+            addr,
+            Candidate {
+                source,
+                failures: 0,
+                retry_at: Instant::now(),
+            },
+        );
+        self.queue.push_back(addr);
+        true
+    }
+
+    pub(crate) fn extend(
+        &mut self,
+        addrs: impl IntoIterator<Item = SocketAddrV4>,
+        source: PeerSource,
+    ) -> usize {
+        addrs
+            .into_iter()
+            .filter(|&addr| self.add(addr, source))
+            .count()
+    }
+
+    /// Next address to try now. It is only tried again if it `failed`.
+    pub(crate) fn next(&mut self) -> Option<SocketAddrV4> {
+        let now = Instant::now();
+        let at = self
+            .queue
+            .iter()
+            .enumerate()
+            .filter(|(_, addr)| self.known[addr].retry_at <= now)
+            .min_by_key(|(_, addr)| self.known[addr].failures)
+            .map(|(at, _)| at)?;
+        self.queue.remove(at)
+    }
+
+    /// Counts a failed or dropped connection to `addr`, and queues it
+    /// again after its back-off unless it failed too many times.
+    pub(crate) fn failed(&mut self, addr: SocketAddrV4) {
+        let Some(candidate) = self.known.get_mut(&addr) else {
+            return;
+        };
+        candidate.failures += 1;
+        if candidate.failures >= MAX_FAILURES || self.queue.contains(&addr) {
+            return;
+        }
+        let backoff = RETRY_AFTER * 2u32.pow(candidate.failures - 1);
+        candidate.retry_at = Instant::now() + backoff.min(MAX_RETRY_AFTER);
+        self.queue.push_back(addr);
+    }
+
+    /// When the next address waiting for its back-off may be tried, if any
+    /// is left.
+    pub(crate) fn next_retry(&self) -> Option<Instant> {
+        self.queue
+            .iter()
+            .map(|addr| self.known[addr].retry_at)
+            .min()
+    }
+
+    pub(crate) fn source(&self, addr: &SocketAddrV4) -> Option<PeerSource> {
+        self.known.get(addr).map(|c| c.source)
+    }
+
+    /// Addresses that may be tried now.
+    pub(crate) fn pending(&self) -> usize {
+        let now = Instant::now();
+        self.queue
+            .iter()
+            .filter(|addr| self.known[addr].retry_at <= now)
+            .count()
+    }
+}
+
+#[test]
+fn failed_candidates_back_off() {
+    let addr = |port| SocketAddrV4::new(std::net::Ipv4Addr::LOCALHOST, port);
+    let mut pool = CandidatePool::new(10);
+    pool.extend([addr(1), addr(2)], PeerSource::Tracker);
+    assert!(!pool.add(addr(1), PeerSource::Pex));
+    assert_eq!(pool.next(), Some(addr(1)));
+
+    pool.failed(addr(1));
+    assert_eq!(pool.pending(), 1);
+    assert_eq!(pool.next(), Some(addr(2)));
+    assert_eq!(pool.next(), None);
+    let retry = pool.next_retry().unwrap();
+    assert!(retry > Instant::now() + RETRY_AFTER / 2);
+
+    for _ in 1..MAX_FAILURES {
+        pool.queue.clear();
+        pool.failed(addr(1));
+    }
+    assert_eq!(pool.next_retry(), None);
+}
@@ -0,0 +1,283 @@
+use crate::bdecoder::{Dict, Value};
+use anyhow::Context;
+use sha1::{Digest, Sha1};
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Piece lengths picked by `auto_piece_length`.
+const MIN_PIECE_LENGTH: usize = 1 << 14;
+const MAX_PIECE_LENGTH: usize = 1 << 24;
+
+/// Number of pieces `auto_piece_length` aims for.
+const TARGET_PIECES: usize = 1500;
+
+/// Optional fields of a torrent being created.
+#[derive(Debug, Clone, Default)]
+pub struct CreateOptions {
+    pub announce: Option<String>,
+    /// Tiers of trackers (BEP 12).
+    pub announce_list: Vec<Vec<String>>,
+    pub comment: Option<String>,
+    pub created_by: Option<String>,
+    pub creation_date: Option<i64>,
+    pub private: bool,
+    /// Web seeds (BEP 19).
+    pub url_list: Vec<String>,
+    /// Chosen from the content size when not set.
+    pub piece_length: Option<usize>,
+}
+
+#[derive(Debug, Clone)]
+pub struct CreatedTorrent {
+    /// The bencoded `.torrent` file.
+    pub bytes: Vec<u8>,
+    pub info_hash: [u8; 20],
+}
+
+/// A file of the torrent: where it is on disk and its path in the torrent.
+#[derive(Debug)]
+struct SourceFile {
+    disk_path: PathBuf,
+    path: Vec<String>,
+    length: usize,
+}
+
+/// Builds a torrent of the file or directory at `path`.
+pub fn create_torrent(path: &Path, options: &CreateOptions) -> anyhow::Result<CreatedTorrent> {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .context("path has no usable file name")?
+        .to_string();
+    let metadata = fs::metadata(path).with_context(|| format!("read {}", path.display()))?;
+    let single_file = metadata.is_file();
+    let files = if single_file {
+        vec![SourceFile {
+            disk_path: path.to_path_buf(),
+            path: Vec::new(),
+            length: metadata.len() as usize,
+        }]
+    } else {
+        let mut files = Vec::new();
+        walk(path, &mut Vec::new(), &mut files)?;
+        anyhow::ensure!(!files.is_empty(), "{} contains no files", path.display());
+        files
+    };
+
+    let total_length: usize = files.iter().map(|file| file.length).sum();
+    let piece_length = match options.piece_length {
+        Some(piece_length) => {
+            anyhow::ensure!(
+                piece_length >= MIN_PIECE_LENGTH && piece_length.is_power_of_two(),
+                "piece length must be a power of two of at least {MIN_PIECE_LENGTH} bytes"
+            );
+            piece_length
+        }
+        None => auto_piece_length(total_length),
+    };
+    let pieces = hash_pieces(&files, total_length, piece_length)?;
+
+    let mut info = Dict::new();
+    info.insert(&b"name"[..], Value::Bytes(name.as_bytes()));
+    info.insert(&b"piece length"[..], Value::Integer(piece_length as i64));
+    info.insert(&b"pieces"[..], Value::Bytes(&pieces));
+    if options.private {
+        info.insert(&b"private"[..], Value::Integer(1));
+    }
+    if single_file {
+        info.insert(&b"length"[..], Value::Integer(total_length as i64));
+    } else {
+        let files = files
+            .iter()
+            .map(|file| {
+                let mut entry = Dict::new();
+                entry.insert(&b"length"[..], Value::Integer(file.length as i64));
+                let path = file.path.iter().map(|c| bytes(c)).collect();
+                entry.insert(&b"path"[..], Value::List(path));
+                Value::Dict(entry)
+            })
+            .collect();
+        info.insert(&b"files"[..], Value::List(files));
+    }
+    let info = Value::Dict(info);
+    let info_hash: [u8; 20] = Sha1::digest(info.encode()).into();
+
+    let mut torrent = Dict::new();
+    torrent.insert(&b"info"[..], info);
+    if let Some(announce) = &options.announce {
+        torrent.insert(&b"announce"[..], bytes(announce));
+    }
+    if !options.announce_list.is_empty() {
+        let tiers = options
+            .announce_list
+            .iter()
+            .map(|tier| Value::List(tier.iter().map(|url| bytes(url)).collect()))
+            .collect();
+        torrent.insert(&b"announce-list"[..], Value::List(tiers));
+    }
+    if let Some(comment) = &options.comment {
+        torrent.insert(&b"comment"[..], bytes(comment));
+    }
+    if let Some(created_by) = &options.created_by {
+        torrent.insert(&b"created by"[..], bytes(created_by));
+    }
+    let creation_date = options.creation_date.unwrap_or_else(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or(0)
+    });
+    torrent.insert(&b"creation date"[..], Value::Integer(creation_date));
+    match options.url_list.as_slice() {
+        [] => {}
+        [url] => {
+            torrent.insert(&b"url-list"[..], bytes(url));
+        }
+        urls => {
+            let urls = urls.iter().map(|url| bytes(url)).collect();
+            torrent.insert(&b"url-list"[..], Value::List(urls));
+        }
+    }
+
+    Ok(CreatedTorrent {
+        bytes: Value::Dict(torrent).encode(),
+        info_hash,
+    })
+}
+
+fn bytes(s: &str) -> Value<'_> {
+    Value::Bytes(s.as_bytes())
+}
+
+/// Collects the regular files under `dir`, sorted by path so the same
+/// content always gives the same torrent.
+fn walk(dir: &Path, prefix: &mut Vec<String>, files: &mut Vec<SourceFile>) -> anyhow::Result<()> {
+    let mut entries = fs::read_dir(dir)
+        .with_context(|| format!("read directory {}", dir.display()))?
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let file_type = entry.file_type()?;
+        let name = entry
+            .file_name()
+            .into_string()
+            .map_err(|name| anyhow::anyhow!("file name {name:?} is not UTF-8"))?;
+        prefix.push(name);
+        if file_type.is_dir() {
+            walk(&entry.path(), prefix, files)?;
+        } else if file_type.is_file() {
+            files.push(SourceFile {
+                disk_path: entry.path(),
+                path: prefix.clone(),
+                length: entry.metadata()?.len() as usize,
+            });
+        }
+        prefix.pop();
+    }
+    Ok(())
+}
+
+/// Smallest power of two keeping the piece count around `TARGET_PIECES`.
+fn auto_piece_length(total_length: usize) -> usize {
+    (total_length / TARGET_PIECES)
+        .next_power_of_two()
+        .clamp(MIN_PIECE_LENGTH, MAX_PIECE_LENGTH)
+}
+
+/// SHA1 of every piece, concatenated. Pieces are split between threads,
+/// each reading its own span of the files.
+fn hash_pieces(
+    files: &[SourceFile],
+    total_length: usize,
+    piece_length: usize,
+) -> anyhow::Result<Vec<u8>> {
+    let num_pieces = total_length.div_ceil(piece_length);
+    let threads = std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+        .min(num_pieces.max(1));
+    let per_thread = (num_pieces + threads - 1) / threads.max(1);
+
+    let mut hashes = vec![0u8; num_pieces * 20];
+    std::thread::scope(|scope| {
+        let workers: Vec<_> = hashes
+            .chunks_mut(per_thread.max(1) * 20)
+            .enumerate()
+            .map(|(worker, hashes)| {
+                scope.spawn(move || -> anyhow::Result<()> {
+                    let mut piece = vec![0u8; piece_length];
+                    for (i, hash) in hashes.chunks_mut(20).enumerate() {
+                        let index = worker * per_thread + i;
+                        let offset = index * piece_length;
+                        let length = piece_length.min(total_length - offset);
+                        read_span(files, offset, &mut piece[..length])?;
+                        hash.copy_from_slice(&Sha1::digest(&piece[..length]));
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .try_for_each(|worker| worker.join().expect("hashing thread panicked"))
+    })?;
+    Ok(hashes)
+}
+
+/// Reads `buf.len()` bytes at `offset` of the files laid end to end.
+fn read_span(files: &[SourceFile], offset: usize, buf: &mut [u8]) -> anyhow::Result<()> {
+    let mut file_start = 0;
+    let mut filled = 0;
+    for file in files {
+        let file_end = file_start + file.length;
+        let position = offset + filled;
+        if filled < buf.len() && position < file_end && position >= file_start {
+            let n = (file_end - position).min(buf.len() - filled);
+            let mut handle = fs::File::open(&file.disk_path)
+                .with_context(|| format!("open {}", file.disk_path.display()))?;
+            handle.seek(SeekFrom::Start((position - file_start) as u64))?;
+            handle
+                .read_exact(&mut buf[filled..][..n])
+                .with_context(|| format!("read {}", file.disk_path.display()))?;
+            filled += n;
+        }
+        file_start = file_end;
+    }
+    anyhow::ensure!(filled == buf.len(), "files changed while hashing");
+    Ok(())
+}
+
+#[test]
+fn created_torrent_round_trips() {
+    use crate::parsing::parse_metainfo;
+
+    let dir = std::env::temp_dir().join(format!("rustorrent-create-{}", std::process::id()));
+    let content = dir.join("dataset");
+    fs::create_dir_all(content.join("sub")).unwrap();
+    fs::write(content.join("a.txt"), vec![b'a'; 40_000]).unwrap();
+    fs::write(content.join("sub").join("b.bin"), vec![b'b'; 10_000]).unwrap();
+
+    let options = CreateOptions {
+        announce: Some(String::from("http://tracker.example/announce")),
+        comment: Some(String::from("test")),
+        private: true,
+        url_list: vec![String::from("http://mirror.example/")],
+        ..Default::default()
+    };
+    let created = create_torrent(&content, &options).unwrap();
+    let torrent_file = dir.join("dataset.torrent");
+    fs::write(&torrent_file, &created.bytes).unwrap();
+
+    let meta_info = parse_metainfo(&fs::read(&torrent_file).unwrap()).unwrap();
+    assert_eq!(meta_info.info_hash.0, created.info_hash);
+    assert_eq!(meta_info.info.name, "dataset");
+    assert_eq!(meta_info.info.piece_length, MIN_PIECE_LENGTH);
+    assert_eq!(meta_info.url_list, vec!["http://mirror.example/"]);
+    let files = meta_info.info.files.unwrap();
+    assert_eq!(files.len(), 2);
+    assert_eq!(files[1].path, "sub/b.bin");
+    assert_eq!(files[1].length, 10_000);
+    fs::remove_dir_all(dir).unwrap();
+}
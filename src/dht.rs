@@ -0,0 +1,769 @@
+use crate::bdecoder;
+use crate::krpc::{self, KrpcArgs, KrpcMessage, KrpcReturn};
+use anyhow::Context;
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
+use std::collections::{HashMap, HashSet};
+use std::net::{SocketAddr, SocketAddrV4};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::net::UdpSocket;
+use tokio::sync::oneshot;
+use tokio::time::{self, Duration, Instant};
+
+/// Bucket size and number of nodes returned by lookups.
+pub const K: usize = 8;
+
+/// Number of queries a lookup keeps in flight.
+const ALPHA: usize = 3;
+
+/// How long an announced peer stays in our store.
+const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+
+/// How often the token secret rotates. Tokens from the previous secret are
+/// still accepted.
+const SECRET_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// Timeouts before a node is considered bad and can be replaced.
+const MAX_FAILURES: u32 = 2;
+
+pub const DEFAULT_BOOTSTRAP: &[&str] =
+    &["router.bittorrent.com:6881", "dht.transmissionbt.com:6881"];
+
+pub type NodeId = [u8; 20];
+
+fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    let mut d = [0u8; 20];
+    for i in 0..20 {
+        d[i] = a[i] ^ b[i];
+    }
+    d
+}
+
+/// Number of leading bits `a` and `b` share, i.e. the bucket `b` falls in
+/// when `a` is our own id.
+fn common_prefix(a: &NodeId, b: &NodeId) -> usize {
+    let d = distance(a, b);
+    for (i, byte) in d.iter().enumerate() {
+        if *byte != 0 {
+            return i * 8 + byte.leading_zeros() as usize;
+        }
+    }
+    160
+}
+
+#[derive(Debug, Clone)]
+pub struct Node {
+    pub id: NodeId,
+    pub addr: SocketAddrV4,
+    last_seen: Instant,
+    failures: u32,
+}
+
+/// Kademlia routing table: bucket `i` holds up to `K` nodes whose id shares
+/// exactly `i` leading bits with ours.
+#[derive(Debug)]
+pub struct RoutingTable {
+    id: NodeId,
+    buckets: Vec<Vec<Node>>,
+}
+
+impl RoutingTable {
+    pub fn new(id: NodeId) -> Self {
+        Self {
+            id,
+            buckets: vec![Vec::new(); 160],
+        }
+    }
+
+    /// Records that `id` answered from `addr`. Returns false if its bucket is
+    /// full of good nodes.
+    pub fn insert(&mut self, id: NodeId, addr: SocketAddrV4) -> bool {
+        let prefix = common_prefix(&self.id, &id);
+        if prefix == 160 {
+            return false;
+        }
+        let bucket = &mut self.buckets[prefix];
+        if let Some(node) = bucket.iter_mut().find(|node| node.id == id) {
+            node.addr = addr;
+            node.last_seen = Instant::now();
+            node.failures = 0;
+            return true;
+        }
+        let node = Node {
+            id,
+            addr,
+            last_seen: Instant::now(),
+            failures: 0,
+        };
+        if bucket.len() < K {
+            bucket.push(node);
+            return true;
+        }
+        if let Some(bad) = bucket.iter_mut().find(|node| node.failures >= MAX_FAILURES) {
+            *bad = node;
+            return true;
+        }
+        false
+    }
+
+    pub fn failed(&mut self, addr: SocketAddrV4) {
+        for node in self.buckets.iter_mut().flatten() {
+            if node.addr == addr {
+                node.failures += 1;
+            }
+        }
+    }
+
+    /// The `n` good nodes closest to `target`.
+    pub fn closest(&self, target: &NodeId, n: usize) -> Vec<Node> {
+        let mut nodes: Vec<_> = self
+            .buckets
+            .iter()
+            .flatten()
+            .filter(|node| node.failures < MAX_FAILURES)
+            .cloned()
+            .collect();
+        nodes.sort_by_key(|node| distance(&node.id, target));
+        nodes.truncate(n);
+        nodes
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+
+    fn nodes(&self) -> impl Iterator<Item = &Node> {
+        self.buckets.iter().flatten()
+    }
+}
+
+/// Routing table as written to disk between runs.
+#[derive(Debug, Serialize, Deserialize)]
+struct SavedState {
+    id: ByteBuf,
+    nodes: ByteBuf,
+}
+
+#[derive(Debug, Clone)]
+pub struct DhtConfig {
+    /// Any free port by default, as the uTP socket of a session takes the
+    /// peer port.
+    pub bind: SocketAddrV4,
+    /// `host:port` of nodes used to join the network.
+    pub bootstrap: Vec<String>,
+    /// Where the routing table is loaded from and saved to.
+    pub state_file: Option<PathBuf>,
+    pub query_timeout: Duration,
+}
+
+impl Default for DhtConfig {
+    fn default() -> Self {
+        Self {
+            bind: "0.0.0.0:0".parse().unwrap(),
+            bootstrap: DEFAULT_BOOTSTRAP.iter().map(|s| s.to_string()).collect(),
+            state_file: None,
+            query_timeout: Duration::from_secs(2),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct GetPeers {
+    pub token: Option<Vec<u8>>,
+    pub values: Vec<SocketAddrV4>,
+    pub nodes: Vec<(NodeId, SocketAddrV4)>,
+}
+
+struct State {
+    table: RoutingTable,
+    peers: HashMap<NodeId, Vec<(SocketAddrV4, Instant)>>,
+    pending: HashMap<[u8; 2], oneshot::Sender<KrpcMessage>>,
+    next_transaction: u16,
+    secret: [u8; 8],
+    previous_secret: [u8; 8],
+    secret_changed: Instant,
+}
+
+struct Inner {
+    id: NodeId,
+    socket: UdpSocket,
+    state: Mutex<State>,
+    config: DhtConfig,
+}
+
+/// A mainline DHT node (BEP 5).
+pub struct Dht {
+    inner: Arc<Inner>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl Drop for Dht {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// The IPv4 addresses of `host`, none if it does not resolve.
+async fn resolve(host: &str) -> Vec<SocketAddrV4> {
+    match tokio::net::lookup_host(host).await {
+        Ok(addrs) => addrs
+            .filter_map(|addr| match addr {
+                SocketAddr::V4(addr) => Some(addr),
+                SocketAddr::V6(_) => None,
+            })
+            .collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+impl Dht {
+    /// Binds the node and starts answering queries. The id and known nodes
+    /// are restored from `config.state_file` when it exists.
+    pub async fn start(config: DhtConfig) -> anyhow::Result<Self> {
+        let saved = match &config.state_file {
+            Some(path) if path.exists() => {
+                let bytes = tokio::fs::read(path).await.context("read dht state file")?;
+                Some(bdecoder::from_bytes::<SavedState>(&bytes).context("decode dht state")?)
+            }
+            _ => None,
+        };
+        let id = saved
+            .as_ref()
+            .and_then(|saved| krpc::id_from_bytes(&saved.id))
+            .unwrap_or_else(rand::random);
+        let mut table = RoutingTable::new(id);
+        if let Some(saved) = &saved {
+            for (node_id, addr) in krpc::decode_nodes(&saved.nodes) {
+                table.insert(node_id, addr);
+            }
+        }
+
+        let socket = UdpSocket::bind(config.bind)
+            .await
+            .context("bind dht socket")?;
+        let inner = Arc::new(Inner {
+            id,
+            socket,
+            state: Mutex::new(State {
+                table,
+                peers: HashMap::new(),
+                pending: HashMap::new(),
+                next_transaction: 0,
+                secret: rand::random(),
+                previous_secret: rand::random(),
+                secret_changed: Instant::now(),
+            }),
+            config,
+        });
+        let task = tokio::spawn(Arc::clone(&inner).run());
+        Ok(Self { inner, task })
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.inner.id
+    }
+
+    pub fn local_addr(&self) -> anyhow::Result<SocketAddrV4> {
+        match self.inner.socket.local_addr()? {
+            SocketAddr::V4(addr) => Ok(addr),
+            SocketAddr::V6(_) => anyhow::bail!("dht socket is not IPv4"),
+        }
+    }
+
+    pub fn routing_table_len(&self) -> usize {
+        self.inner.state.lock().unwrap().table.len()
+    }
+
+    /// Writes the node id and routing table to `config.state_file`.
+    pub async fn save(&self) -> anyhow::Result<()> {
+        let Some(path) = &self.inner.config.state_file else {
+            return Ok(());
+        };
+        let nodes: Vec<_> = {
+            let state = self.inner.state.lock().unwrap();
+            state
+                .table
+                .nodes()
+                .map(|node| (node.id, node.addr))
+                .collect()
+        };
+        let saved = SavedState {
+            id: ByteBuf::from(self.inner.id.to_vec()),
+            nodes: ByteBuf::from(krpc::encode_nodes(&nodes)),
+        };
+        let bytes = bdecoder::to_bytes(&saved).context("encode dht state")?;
+        tokio::fs::write(path, bytes)
+            .await
+            .context("write dht state file")
+    }
+
+    /// Joins the network through the saved nodes and the configured
+    /// bootstrap nodes, then looks up our own id to fill the table.
+    pub async fn bootstrap(&self) -> anyhow::Result<()> {
+        let mut contacts: Vec<SocketAddrV4> = {
+            let state = self.inner.state.lock().unwrap();
+            state.table.nodes().map(|node| node.addr).collect()
+        };
+        for host in &self.inner.config.bootstrap {
+            contacts.extend(resolve(host).await);
+        }
+
+        let mut pings: FuturesUnordered<_> =
+            contacts.into_iter().map(|addr| self.ping(addr)).collect();
+        while pings.next().await.is_some() {}
+        drop(pings);
+
+        anyhow::ensure!(self.routing_table_len() > 0, "no dht node answered");
+        self.lookup(self.inner.id, false).await;
+        Ok(())
+    }
+
+    /// Pings the nodes a torrent lists, as `host` and port, to add them to
+    /// the routing table.
+    pub async fn add_nodes(&self, nodes: &[(String, u16)]) {
+        let mut contacts = Vec::new();
+        for (host, port) in nodes {
+            contacts.extend(resolve(&format!("{host}:{port}")).await);
+        }
+        let mut pings: FuturesUnordered<_> =
+            contacts.into_iter().map(|addr| self.ping(addr)).collect();
+        while pings.next().await.is_some() {}
+    }
+
+    pub async fn ping(&self, addr: SocketAddrV4) -> anyhow::Result<NodeId> {
+        let r = self.inner.query(addr, "ping", self.args()).await?;
+        krpc::id_from_bytes(&r.id).context("ping response without id")
+    }
+
+    pub async fn find_node(
+        &self,
+        addr: SocketAddrV4,
+        target: NodeId,
+    ) -> anyhow::Result<Vec<(NodeId, SocketAddrV4)>> {
+        let args = KrpcArgs {
+            target: Some(ByteBuf::from(target.to_vec())),
+            ..self.args()
+        };
+        let r = self.inner.query(addr, "find_node", args).await?;
+        Ok(r.nodes.map(|n| krpc::decode_nodes(&n)).unwrap_or_default())
+    }
+
+    pub async fn get_peers(
+        &self,
+        addr: SocketAddrV4,
+        info_hash: [u8; 20],
+    ) -> anyhow::Result<GetPeers> {
+        let args = KrpcArgs {
+            info_hash: Some(ByteBuf::from(info_hash.to_vec())),
+            ..self.args()
+        };
+        let r = self.inner.query(addr, "get_peers", args).await?;
+        Ok(GetPeers {
+            token: r.token.map(ByteBuf::into_vec),
+            values: r
+                .values
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|v| krpc::decode_peer(v))
+                .collect(),
+            nodes: r.nodes.map(|n| krpc::decode_nodes(&n)).unwrap_or_default(),
+        })
+    }
+
+    pub async fn announce_peer(
+        &self,
+        addr: SocketAddrV4,
+        info_hash: [u8; 20],
+        port: u16,
+        token: Vec<u8>,
+    ) -> anyhow::Result<()> {
+        let args = KrpcArgs {
+            info_hash: Some(ByteBuf::from(info_hash.to_vec())),
+            port: Some(port),
+            token: Some(ByteBuf::from(token)),
+            ..self.args()
+        };
+        self.inner.query(addr, "announce_peer", args).await?;
+        Ok(())
+    }
+
+    /// Asks the network for peers of `info_hash`.
+    pub async fn lookup_peers(&self, info_hash: [u8; 20]) -> Vec<SocketAddrV4> {
+        self.lookup(info_hash, true).await.0
+    }
+
+    /// Finds the nodes closest to `info_hash` and announces that we serve it
+    /// on `port`. Returns the peers found along the way.
+    pub async fn announce(&self, info_hash: [u8; 20], port: u16) -> Vec<SocketAddrV4> {
+        let (peers, tokens) = self.lookup(info_hash, true).await;
+        let mut announces: FuturesUnordered<_> = tokens
+            .into_iter()
+            .map(|(addr, token)| self.announce_peer(addr, info_hash, port, token))
+            .collect();
+        while announces.next().await.is_some() {}
+        peers
+    }
+
+    fn args(&self) -> KrpcArgs {
+        KrpcArgs {
+            id: ByteBuf::from(self.inner.id.to_vec()),
+            ..Default::default()
+        }
+    }
+
+    /// Iterative Kademlia lookup towards `target`, using `get_peers` queries
+    /// when `want_peers` is set and `find_node` otherwise. Returns the peers
+    /// found and the tokens of the closest nodes that answered.
+    async fn lookup(
+        &self,
+        target: NodeId,
+        want_peers: bool,
+    ) -> (Vec<SocketAddrV4>, Vec<(SocketAddrV4, Vec<u8>)>) {
+        let mut shortlist: Vec<(NodeId, SocketAddrV4)> = {
+            let state = self.inner.state.lock().unwrap();
+            state
+                .table
+                .closest(&target, K)
+                .into_iter()
+                .map(|node| (node.id, node.addr))
+                .collect()
+        };
+        let mut queried = HashSet::new();
+        let mut peers = HashSet::new();
+        let mut tokens = Vec::new();
+
+        loop {
+            shortlist.sort_by_key(|(id, _)| distance(id, &target));
+            shortlist.dedup_by_key(|(id, _)| *id);
+            let batch: Vec<_> = shortlist
+                .iter()
+                .take(K)
+                .filter(|(_, addr)| !queried.contains(addr))
+                .take(ALPHA)
+                .copied()
+                .collect();
+            if batch.is_empty() {
+                break;
+            }
+
+            let mut queries = FuturesUnordered::new();
+            for (id, addr) in batch {
+                queried.insert(addr);
+                queries.push(async move {
+                    let result = if want_peers {
+                        self.get_peers(addr, target).await
+                    } else {
+                        self.find_node(addr, target).await.map(|nodes| GetPeers {
+                            nodes,
+                            ..Default::default()
+                        })
+                    };
+                    (id, addr, result)
+                });
+            }
+            while let Some((id, addr, result)) = queries.next().await {
+                match result {
+                    Ok(found) => {
+                        peers.extend(found.values);
+                        if let Some(token) = found.token {
+                            tokens.push((id, addr, token));
+                        }
+                        shortlist.extend(
+                            found
+                                .nodes
+                                .into_iter()
+                                .filter(|(node_id, _)| *node_id != self.inner.id),
+                        );
+                    }
+                    Err(_) => {
+                        shortlist.retain(|(_, a)| *a != addr);
+                    }
+                }
+            }
+        }
+
+        tokens.sort_by_key(|(id, _, _)| distance(id, &target));
+        tokens.truncate(K);
+        (
+            peers.into_iter().collect(),
+            tokens
+                .into_iter()
+                .map(|(_, addr, token)| (addr, token))
+                .collect(),
+        )
+    }
+}
+
+impl Inner {
+    async fn query(
+        &self,
+        addr: SocketAddrV4,
+        q: &str,
+        args: KrpcArgs,
+    ) -> anyhow::Result<KrpcReturn> {
+        let (sender, receiver) = oneshot::channel();
+        let transaction = {
+            let mut state = self.state.lock().unwrap();
+            state.next_transaction = state.next_transaction.wrapping_add(1);
+            let transaction = state.next_transaction.to_be_bytes();
+            state.pending.insert(transaction, sender);
+            transaction
+        };
+        let message = KrpcMessage::query(transaction, q, args);
+        let sent = self.socket.send_to(&message.to_bytes()?, addr).await;
+        let result = match sent {
+            Ok(_) => time::timeout(self.config.query_timeout, receiver).await,
+            Err(e) => {
+                self.state.lock().unwrap().pending.remove(&transaction);
+                return Err(e).context("send krpc query");
+            }
+        };
+
+        let mut state = self.state.lock().unwrap();
+        state.pending.remove(&transaction);
+        match result {
+            Ok(Ok(response)) => {
+                if let Some((code, message)) = response.e {
+                    anyhow::bail!("{addr} answered {q} with error {code}: {message}");
+                }
+                let r = response.r.context("krpc response without body")?;
+                if let Some(id) = krpc::id_from_bytes(&r.id) {
+                    state.table.insert(id, addr);
+                }
+                Ok(r)
+            }
+            Ok(Err(_)) => anyhow::bail!("dht stopped"),
+            Err(_) => {
+                state.table.failed(addr);
+                anyhow::bail!("{addr} did not answer {q}")
+            }
+        }
+    }
+
+    async fn run(self: Arc<Self>) {
+        let mut buf = vec![0u8; 2048];
+        loop {
+            let (n, from) = match self.socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(_) => continue,
+            };
+            let SocketAddr::V4(from) = from else {
+                continue;
+            };
+            let Ok(message) = KrpcMessage::from_bytes(&buf[..n]) else {
+                continue;
+            };
+            match message.y.as_str() {
+                "q" => {
+                    let reply = self.answer(message, from);
+                    if let Ok(bytes) = reply.to_bytes() {
+                        let _ = self.socket.send_to(&bytes, from).await;
+                    }
+                }
+                "r" | "e" => {
+                    let Ok(transaction) = <[u8; 2]>::try_from(message.t.as_ref()) else {
+                        continue;
+                    };
+                    let mut state = self.state.lock().unwrap();
+                    if let Some(sender) = state.pending.remove(&transaction) {
+                        let _ = sender.send(message);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Builds the reply to a query received from `from`.
+    fn answer(&self, message: KrpcMessage, from: SocketAddrV4) -> KrpcMessage {
+        let t = message.t;
+        let Some(args) = message.a else {
+            return KrpcMessage::error(t, krpc::ERROR_PROTOCOL, "missing arguments");
+        };
+        let Some(sender) = krpc::id_from_bytes(&args.id) else {
+            return KrpcMessage::error(t, krpc::ERROR_PROTOCOL, "invalid id");
+        };
+
+        let mut state = self.state.lock().unwrap();
+        state.rotate_secret();
+        state.table.insert(sender, from);
+        let mut r = KrpcReturn {
+            id: ByteBuf::from(self.id.to_vec()),
+            ..Default::default()
+        };
+        match message.q.as_deref() {
+            Some("ping") => {}
+            Some("find_node") => {
+                let Some(target) = args.target.as_ref().and_then(|b| krpc::id_from_bytes(b)) else {
+                    return KrpcMessage::error(t, krpc::ERROR_PROTOCOL, "invalid target");
+                };
+                r.nodes = Some(state.closest_compact(&target));
+            }
+            Some("get_peers") => {
+                let Some(info_hash) = args.info_hash.as_ref().and_then(|b| krpc::id_from_bytes(b))
+                else {
+                    return KrpcMessage::error(t, krpc::ERROR_PROTOCOL, "invalid info_hash");
+                };
+                r.token = Some(ByteBuf::from(state.token(from, &state.secret)));
+                let values: Vec<_> = state
+                    .peers
+                    .get_mut(&info_hash)
+                    .map(|peers| {
+                        peers.retain(|(_, seen)| seen.elapsed() < PEER_TTL);
+                        peers
+                            .iter()
+                            .map(|(addr, _)| ByteBuf::from(krpc::encode_peer(*addr).to_vec()))
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                if values.is_empty() {
+                    r.nodes = Some(state.closest_compact(&info_hash));
+                } else {
+                    r.values = Some(values);
+                }
+            }
+            Some("announce_peer") => {
+                let Some(info_hash) = args.info_hash.as_ref().and_then(|b| krpc::id_from_bytes(b))
+                else {
+                    return KrpcMessage::error(t, krpc::ERROR_PROTOCOL, "invalid info_hash");
+                };
+                let valid = args.token.as_ref().is_some_and(|token| {
+                    **token == state.token(from, &state.secret)
+                        || **token == state.token(from, &state.previous_secret)
+                });
+                if !valid {
+                    return KrpcMessage::error(t, krpc::ERROR_PROTOCOL, "bad token");
+                }
+                let port = match (args.implied_port, args.port) {
+                    (Some(1), _) => from.port(),
+                    (_, Some(port)) => port,
+                    _ => return KrpcMessage::error(t, krpc::ERROR_PROTOCOL, "missing port"),
+                };
+                let peer = SocketAddrV4::new(*from.ip(), port);
+                let peers = state.peers.entry(info_hash).or_default();
+                peers.retain(|(addr, _)| *addr != peer);
+                peers.push((peer, Instant::now()));
+            }
+            _ => return KrpcMessage::error(t, krpc::ERROR_METHOD_UNKNOWN, "method unknown"),
+        }
+        KrpcMessage::response(t, r)
+    }
+}
+
+impl State {
+    fn rotate_secret(&mut self) {
+        if self.secret_changed.elapsed() >= SECRET_TTL {
+            self.previous_secret = self.secret;
+            self.secret = rand::random();
+            self.secret_changed = Instant::now();
+        }
+    }
+
+    /// Token handed to `addr` in `get_peers` responses and checked on
+    /// `announce_peer`.
+    fn token(&self, addr: SocketAddrV4, secret: &[u8; 8]) -> Vec<u8> {
+        let mut hasher = Sha1::new();
+        hasher.update(secret);
+        hasher.update(addr.ip().octets());
+        hasher.finalize()[..8].to_vec()
+    }
+
+    fn closest_compact(&self, target: &NodeId) -> ByteBuf {
+        let nodes: Vec<_> = self
+            .table
+            .closest(target, K)
+            .into_iter()
+            .map(|node| (node.id, node.addr))
+            .collect();
+        ByteBuf::from(krpc::encode_nodes(&nodes))
+    }
+}
+
+#[cfg(test)]
+fn loopback_config(bootstrap: Vec<String>, state_file: Option<PathBuf>) -> DhtConfig {
+    DhtConfig {
+        bind: "127.0.0.1:0".parse().unwrap(),
+        bootstrap,
+        state_file,
+        query_timeout: Duration::from_millis(500),
+    }
+}
+
+#[test]
+fn routing_table_buckets() {
+    let mut table = RoutingTable::new([0u8; 20]);
+    let addr: SocketAddrV4 = "10.0.0.1:6881".parse().unwrap();
+    assert!(!table.insert([0u8; 20], addr), "never store ourselves");
+
+    let mut far = [0u8; 20];
+    far[0] = 0x80;
+    for i in 0..K as u8 {
+        far[19] = i;
+        assert!(table.insert(far, addr));
+    }
+    far[19] = 0xff;
+    assert!(!table.insert(far, addr), "bucket 0 is full");
+    table.failed(addr);
+    table.failed(addr);
+    assert!(table.insert(far, addr), "bad nodes get replaced");
+
+    let mut near = [0u8; 20];
+    near[19] = 1;
+    table.insert(near, "10.0.0.2:6881".parse().unwrap());
+    assert_eq!(table.closest(&[0u8; 20], 1)[0].id, near);
+}
+
+#[tokio::test]
+async fn dht_loopback_swarm() {
+    let seed = Dht::start(loopback_config(Vec::new(), None)).await.unwrap();
+    let seed_addr = seed.local_addr().unwrap().to_string();
+
+    let mut nodes = Vec::new();
+    for _ in 0..4 {
+        let node = Dht::start(loopback_config(vec![seed_addr.clone()], None))
+            .await
+            .unwrap();
+        node.bootstrap().await.unwrap();
+        nodes.push(node);
+    }
+    assert!(seed.routing_table_len() >= 4);
+
+    let info_hash = [7u8; 20];
+    let found = nodes[0].announce(info_hash, 51413).await;
+    assert!(found.is_empty());
+
+    let peers = nodes[3].lookup_peers(info_hash).await;
+    assert_eq!(peers, vec!["127.0.0.1:51413".parse().unwrap()]);
+
+    let bad = nodes[1]
+        .announce_peer(seed.local_addr().unwrap(), info_hash, 1, b"forged".to_vec())
+        .await;
+    assert!(bad.is_err());
+}
+
+#[tokio::test]
+async fn dht_state_persists() {
+    let path = std::env::temp_dir().join(format!("rustorrent-dht-{}.state", std::process::id()));
+    let seed = Dht::start(loopback_config(Vec::new(), None)).await.unwrap();
+    let node = Dht::start(loopback_config(
+        vec![seed.local_addr().unwrap().to_string()],
+        Some(path.clone()),
+    ))
+    .await
+    .unwrap();
+    node.bootstrap().await.unwrap();
+    node.save().await.unwrap();
+    let id = node.id();
+    drop(node);
+
+    let restored = Dht::start(loopback_config(Vec::new(), Some(path.clone())))
+        .await
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(restored.id(), id);
+    assert_eq!(restored.routing_table_len(), 1);
+    restored.bootstrap().await.unwrap();
+}
@@ -1,74 +1,238 @@
-use crate::bdecoder::OwnedValue;
-use crate::parsing::parse_metainfo;
-use crate::parsing::File;
+use crate::ban::BanList;
+use crate::candidates::{CandidatePool, PeerSource};
+use crate::dht::Dht;
+use crate::event::{Event, Events};
+use crate::lsd::Lsd;
 use crate::parsing::MetaInfo;
+use crate::peers::ConnectConfig;
 use crate::peers::Peer;
 use crate::peers::Piece;
-use crate::piece::PieceFile;
+use crate::peers::{violation, Violation};
+use crate::piece::{piece_priorities, PieceFile};
+use crate::session::{ConnectionSlots, FilePriority};
+use crate::smartban::SmartBan;
+use crate::stream::Progress;
 use crate::tracker::compute_length;
+use crate::tracker::dump_peers;
 use crate::tracker::send_request;
-use crate::tracker::TrackerResponse;
+use crate::webseed::WebSeed;
+use crate::LogLevel;
 use crate::BLOCK_MAX;
 use anyhow::Context;
 use futures_util::stream::StreamExt;
 use sha1::{Digest, Sha1};
-use std::collections::BTreeMap;
-use std::collections::BinaryHeap;
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::net::SocketAddrV4;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Number of peers we keep connections open with by default.
+const MAX_PEERS: usize = 5;
+
+/// Connections being opened at once.
+const MAX_CONNECTING: usize = 10;
+
+/// Time a peer has to accept our connection, handshake and send its
+/// bitfield.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Number of addresses remembered from the tracker, the DHT, LSD and PEX.
+const MAX_CANDIDATES: usize = 500;
+
+/// Which piece to download next, among those of the highest priority.
+/// Pieces a reader is waiting for always come first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PieceOrder {
+    /// The pieces the most peers have first.
+    #[default]
+    Available,
+    /// From the first piece to the last, to play or inspect the files
+    /// while they download.
+    Sequential,
+}
+
+/// How a torrent is downloaded.
+#[derive(Debug, Clone)]
+pub struct DownloadConfig {
+    pub connect: ConnectConfig,
+    /// Number of peers we keep connections open with.
+    pub max_peers: usize,
+    /// Connections shared with the other torrents of a session, which sets
+    /// them along with `events`.
+    pub slots: Option<Arc<ConnectionSlots>>,
+    pub events: Option<Events>,
+    /// Peers refused by every download sharing the list.
+    pub bans: Arc<BanList>,
+    /// How much each file is wanted, in the order of the torrent. Files
+    /// past the end, and all of them when it is empty, are normal.
+    pub file_priorities: Vec<FilePriority>,
+    pub order: PieceOrder,
+    /// Port we tell the tracker and the DHT we listen on.
+    pub port: u16,
+    pub log: LogLevel,
+}
+
+impl DownloadConfig {
+    /// Number of peers to keep now: `max_peers`, or our share of the
+    /// session's connections when that is smaller.
+    fn peer_limit(&self) -> usize {
+        match &self.slots {
+            Some(slots) => self.max_peers.min(slots.share()),
+            None => self.max_peers,
+        }
+    }
+}
+
+impl Default for DownloadConfig {
+    fn default() -> Self {
+        Self {
+            connect: ConnectConfig::default(),
+            max_peers: MAX_PEERS,
+            slots: None,
+            events: None,
+            bans: Arc::default(),
+            file_priorities: Vec::new(),
+            order: PieceOrder::default(),
+            port: 6881,
+            log: LogLevel::default(),
+        }
+    }
+}
 
 pub(crate) async fn all(
-    dict: BTreeMap<String, OwnedValue>,
+    meta_info: &MetaInfo,
     info_hash: [u8; 20],
-) -> anyhow::Result<Downloaded> {
-    let meta_info = parse_metainfo(dict.clone());
-    let peer_info = send_request(dict, info_hash).await;
-
-    let mut peer_list = Vec::new();
-    let mut peers = futures_util::stream::iter(peer_info.peers.0.iter())
-        .map(|&peer_addr| async move {
-            let peer = Peer::new(peer_addr, info_hash).await;
-            (peer_addr, peer)
-        })
-        .buffer_unordered(5);
-    while let Some((peer_addr, peer)) = peers.next().await {
-        match peer {
-            Ok(peer) => {
-                peer_list.push(peer);
-                if peer_list.len() >= 5
-                {
-                    break;
+    dht: Option<&Dht>,
+    lsd: Option<&Lsd>,
+    progress: &Progress,
+    config: &DownloadConfig,
+) -> anyhow::Result<()> {
+    let private = meta_info.info.is_private();
+    let (dht, lsd) = if private { (None, None) } else { (dht, lsd) };
+    let connect = &ConnectConfig {
+        private,
+        listen_port: Some(config.port),
+        ..config.connect.clone()
+    };
+    let length = compute_length(&meta_info.info);
+    let mut candidates = CandidatePool::new(MAX_CANDIDATES);
+    let mut tracker_error = None;
+    if let Some(announce) = &meta_info.announce {
+        // the other sources may still find peers
+        match send_request(announce, info_hash, connect.peer_id, config.port, length).await {
+            Ok(peer_info) => {
+                if config.log >= LogLevel::Debug {
+                    dump_peers(&peer_info);
                 }
+                candidates.extend(peer_info.peers.0.iter().copied(), PeerSource::Tracker);
             }
             Err(e) => {
-                println!("failed to connect to peer {peer_addr:?}: {e:?}");
+                emit(config, || Event::TrackerError {
+                    info_hash,
+                    message: format!("{e:#}"),
+                });
+                if config.log >= LogLevel::Warn {
+                    eprintln!("Warning: {}: {e:#}", meta_info.info.name);
+                }
+                tracker_error = Some(e);
             }
         }
     }
-    
-    drop(peers);
-    let mut peers = peer_list;
+    if let Some(dht) = dht {
+        candidates.extend(dht.announce(info_hash, config.port).await, PeerSource::Dht);
+    }
+    let mut lan_peers = match lsd.map(|lsd| lsd.join(info_hash)) {
+        Some(joined) => match joined.await {
+            Ok(lan_peers) => Some(lan_peers),
+            Err(e) => {
+                // the other sources may still find peers
+                if config.log >= LogLevel::Warn {
+                    eprintln!("Warning: {}: {e:#}", meta_info.info.name);
+                }
+                None
+            }
+        },
+        None => None,
+    };
+    if candidates.pending() == 0 && meta_info.url_list.is_empty() && lan_peers.is_none() {
+        return Err(tracker_error.unwrap_or_else(|| anyhow::anyhow!("no peers to download from")));
+    }
+    let num_pieces = meta_info.info.pieces.len();
+    let mut peers = Vec::new();
+    let mut smart_ban = SmartBan::default();
+    let mut web_seeds: Vec<_> = meta_info.url_list.iter().map(WebSeed::new).collect();
 
-    let mut need_pieces = BinaryHeap::new();
-    let mut no_peers = Vec::new();
-    for piece_i in 0..meta_info.info.pieces.len() {
-        let piece = PieceFile::new(piece_i, &meta_info, &peers);
-        if piece.peers().is_empty() {
-            no_peers.push(piece);
-        } else {
-            need_pieces.push(piece);
+    // pieces are picked among those a peer or a web seed has, the others
+    // wait for the peers to come
+    let priorities = piece_priorities(&meta_info.info, &config.file_priorities);
+    let mut need_pieces = Vec::new();
+    let mut no_peers: Vec<_> = priorities
+        .iter()
+        .enumerate()
+        .filter(|&(piece_i, &priority)| priority != FilePriority::Skip && !progress.has(piece_i))
+        .map(|(piece_i, &priority)| PieceFile::new(piece_i, meta_info, &peers, priority))
+        .collect();
+
+    loop {
+        if let Some(lan_peers) = &mut lan_peers {
+            while let Ok(addr) = lan_peers.try_recv() {
+                candidates.add(addr, PeerSource::Lsd);
+            }
         }
-    }
-    println!("len = {}", need_pieces.len());
+        exchange_peers(
+            &mut peers,
+            &mut candidates,
+            info_hash,
+            num_pieces,
+            connect,
+            config,
+        )
+        .await;
+        let (found, missing): (Vec<_>, Vec<_>) = no_peers
+            .drain(..)
+            .map(|piece| PieceFile::new(piece.index(), meta_info, &peers, piece.priority()))
+            .partition(|piece| !piece.peers().is_empty() || !web_seeds.is_empty());
+        need_pieces.extend(found);
+        no_peers = missing;
 
-    let length = compute_length(&meta_info.info);
-    let mut all_pieces = vec![0; length];
-    while let Some(piece) = need_pieces.pop() {
+        let suggested: HashSet<_> = peers
+            .iter()
+            .flat_map(|peer| peer.suggested_pieces())
+            .map(|&index| index as usize)
+            .collect();
+        let allowed_fast: HashSet<_> = peers.iter().flat_map(Peer::fast_pieces).collect();
+        let preferred = Preferred {
+            allowed_fast: &allowed_fast,
+            suggested: &suggested,
+        };
+        let Some(piece) = pick(&mut need_pieces, config.order, progress, preferred) else {
+            if no_peers.is_empty() {
+                break;
+            }
+            // wait for the peers we may try again, or for a LAN peer
+            let retry = candidates.next_retry();
+            let Some(lan) = lan_peers.as_mut().filter(|_| retry.is_none()) else {
+                let retry = retry
+                    .with_context(|| format!("no peers left to get {} pieces", no_peers.len()))?;
+                tokio::time::sleep_until(retry.into()).await;
+                continue;
+            };
+            let addr = lan.recv().await.context("lsd stopped")?;
+            candidates.add(addr, PeerSource::Lsd);
+            continue;
+        };
         let piece_size = piece.length();
-        let nblocks = (piece_size + (BLOCK_MAX - 1)) / BLOCK_MAX;
-        let peers: Vec<_> = peers
+        let nblocks = piece_size.div_ceil(BLOCK_MAX);
+        let mut piece_peers: Vec<_> = peers
             .iter_mut()
-            .enumerate()
-            .filter_map(|(peer_i, peer)| piece.peers().contains(&peer_i).then_some(peer))
+            .filter(|peer| peer.has_piece(piece.index()))
             .collect();
+        if let Some(suspects) = smart_ban.suspects(piece.index()) {
+            // from a single peer, a suspect first, so that a failure is its own
+            piece_peers.sort_by_key(|peer| !suspects.contains(&peer.addr));
+            piece_peers.truncate(1);
+        }
 
         let (submit, tasks) = kanal::bounded_async(nblocks);
         for block in 0..nblocks {
@@ -79,56 +243,54 @@ pub(crate) async fn all(
         }
         let (finish, mut done) = tokio::sync::mpsc::channel(nblocks);
         let mut participants = futures_util::stream::futures_unordered::FuturesUnordered::new();
-        for peer in peers {
-            participants.push(peer.participate(
+        for peer in piece_peers {
+            let addr = peer.addr;
+            let participation = peer.participate(
                 piece.index(),
                 piece_size,
                 nblocks,
                 submit.clone(),
                 tasks.clone(),
                 finish.clone(),
-            ));
+            );
+            participants.push(async move { (addr, participation.await) });
         }
         drop(submit);
         drop(finish);
         drop(tasks);
 
-        println!("start receive loop");
         let mut all_blocks = vec![0u8; piece_size];
+        let mut senders = vec![None; nblocks];
         let mut bytes_received = 0;
+        let mut failed = Vec::new();
         loop {
             tokio::select! {
                 joined = participants.next(), if !participants.is_empty() => {
                     // if a participant ends early, it's either slow or failed
-                    println!("participant finished");
                     match joined {
                         None => {
                             // there are no peers!
                             // this must mean we are about to get None from done.recv(),
                             // so we'll handle it there
-                            println!("No peer");
                         }
-                        Some(Ok(_)) => {
+                        Some((_, Ok(_))) => {
                             // the peer gave up because it timed out
                             // nothing to do, except maybe de-prioritize this peer for later
-                            println!("The peer is timed out");
                         }
-                        Some(Err(e)) => {
-                            // the peer failed and should be removed
-                            // it already isn't participating in this piece any more, so this is
-                            // more of an indicator that we shouldn't try this peer again, and
-                            // should remove it from the global peer list
-                            println!("A peer failed with an error: {:?}", e);
+                        Some((addr, Err(e))) => {
+                            // the peer failed and isn't participating in this piece any
+                            // more: it is dropped once the piece is done, and tried again
+                            // later unless it broke the protocol
+                            failed.push((addr, e));
                         }
                     }
                 }
                 piece = done.recv() => {
-                    if let Some(piece) = piece {
-                        print!("got piece ");
+                    if let Some((sender, piece)) = piece {
                         // keep track of the bytes in message
                         let piece = Piece::ref_from_bytes(&piece.payload[..])
                             .expect("always get all Piece response fields from peer");
-                        println!("{}", piece.index());
+                        senders[piece.begin() as usize / BLOCK_MAX] = Some(sender);
                         bytes_received += piece.block().len();
                         all_blocks[piece.begin() as usize..][..piece.block().len()].copy_from_slice(piece.block());
                         if bytes_received == piece_size {
@@ -139,7 +301,6 @@ pub(crate) async fn all(
                             break;
                         }
                     } else {
-                        println!("got pieces end");
                         // there are no peers left, so we can't progress!
                         break;
                     }
@@ -147,85 +308,253 @@ pub(crate) async fn all(
             }
         }
         drop(participants);
+        for (addr, e) in failed {
+            drop_peer(&mut peers, &mut candidates, addr, &e, info_hash, config);
+        }
 
-        if bytes_received == piece_size {
-            
+        let all_blocks = if bytes_received == piece_size {
+            if !piece_matches(meta_info, &piece, &all_blocks) {
+                let senders: Vec<_> = senders.into_iter().flatten().collect();
+                let mut contributors = senders.clone();
+                contributors.sort();
+                contributors.dedup();
+                emit(config, || Event::HashFailed {
+                    info_hash,
+                    piece: piece.index(),
+                    peers: contributors.clone(),
+                });
+                if let [addr] = contributors[..] {
+                    let e = violation(format!("sent corrupt piece {}", piece.index()));
+                    drop_peer(&mut peers, &mut candidates, addr, &e, info_hash, config);
+                } else {
+                    smart_ban.failed(piece.index(), &all_blocks, &senders);
+                }
+                no_peers.push(piece);
+                continue;
+            }
+            all_blocks
         } else {
-            anyhow::bail!("no peers left to get piece {}", piece.index());
-        }
+            let fetched =
+                fetch_from_web_seeds(&mut web_seeds, meta_info, piece.index(), config.log).await;
+            match fetched {
+                Ok(all_blocks) => all_blocks,
+                Err(e) => {
+                    if config.log >= LogLevel::Debug {
+                        println!("piece {}: {e:#}", piece.index());
+                    }
+                    no_peers.push(piece);
+                    continue;
+                }
+            }
+        };
 
-        let mut hasher = Sha1::new();
-        hasher.update(&all_blocks);
-        let hash: [u8; 20] = hasher
-            .finalize()
-            .try_into()
-            .expect("GenericArray<_, 20> == [_; 20]");
-
-        all_pieces[piece.index() * meta_info.info.piece_length..][..piece_size]
-            .copy_from_slice(&all_blocks);
-    }
-
-    Ok(Downloaded {
-        bytes: all_pieces,
-        files: match &meta_info.info.length {
-            length => vec![File {
-                length: *length,
-                md5sum: Some(String::from("")),
-                path: meta_info.info.name.clone(),
-            }],
-            0 => vec![File::default()],
-        },
-    })
-}
+        for addr in smart_ban.passed(piece.index(), &all_blocks) {
+            let e = violation(format!("sent corrupt data in piece {}", piece.index()));
+            drop_peer(&mut peers, &mut candidates, addr, &e, info_hash, config);
+        }
+        progress.finish(piece.index(), &all_blocks).await?;
+        emit(config, || Event::PieceFinished {
+            info_hash,
+            piece: piece.index(),
+        });
+    }
 
-pub struct Downloaded {
-    bytes: Vec<u8>,
-    files: Vec<File>,
+    Ok(())
 }
 
-impl<'a> IntoIterator for &'a Downloaded {
-    type Item = DownloadedFile<'a>;
-    type IntoIter = DownloadedIter<'a>;
-    fn into_iter(self) -> Self::IntoIter {
-        DownloadedIter::new(self)
+/// Whether the data of `piece` matches its v1 hash and, in a hybrid
+/// torrent, its v2 hashes.
+fn piece_matches(meta_info: &MetaInfo, piece: &PieceFile, data: &[u8]) -> bool {
+    let hash: [u8; 20] = Sha1::digest(data).into();
+    if hash != piece.hash() {
+        return false;
+    }
+    match &meta_info.v2 {
+        Some(v2) => v2.verify_piece(piece.index(), data),
+        None => true,
     }
 }
 
-pub struct DownloadedIter<'d> {
-    downloaded: &'d Downloaded,
-    file_iter: std::slice::Iter<'d, File>,
-    offset: usize,
+/// Closes the connection to a failed peer.
+fn drop_peer(
+    peers: &mut Vec<Peer>,
+    candidates: &mut CandidatePool,
+    addr: SocketAddrV4,
+    e: &anyhow::Error,
+    info_hash: [u8; 20],
+    config: &DownloadConfig,
+) {
+    peers.retain(|peer| peer.addr != addr);
+    peer_failed(candidates, addr, e, info_hash, config);
 }
 
-impl<'d> DownloadedIter<'d> {
-    fn new(d: &'d Downloaded) -> Self {
-        Self {
-            downloaded: d,
-            file_iter: d.files.iter(),
-            offset: 0,
+/// Bans a peer that broke the protocol or sent corrupt data, and tries the
+/// others again after their back-off.
+fn peer_failed(
+    candidates: &mut CandidatePool,
+    addr: SocketAddrV4,
+    e: &anyhow::Error,
+    info_hash: [u8; 20],
+    config: &DownloadConfig,
+) {
+    if config.log >= LogLevel::Debug {
+        println!("peers: {addr}: {e:#}");
+    }
+    match e.downcast_ref::<Violation>() {
+        Some(Violation(reason)) => {
+            if config.bans.ban(*addr.ip(), reason.clone()) {
+                emit(config, || Event::PeerBanned {
+                    info_hash,
+                    addr,
+                    reason: reason.clone(),
+                });
+            }
         }
+        None => candidates.failed(addr),
     }
 }
 
-impl<'d> Iterator for DownloadedIter<'d> {
-    type Item = DownloadedFile<'d>;
+/// Pieces to take before others as wanted, in availability order.
+#[derive(Clone, Copy)]
+struct Preferred<'p> {
+    /// Pieces a choking peer still lets us request.
+    allowed_fast: &'p HashSet<usize>,
+    /// Pieces a peer suggested.
+    suggested: &'p HashSet<usize>,
+}
+
+/// Takes the next piece to download out of `need_pieces`. In availability
+/// order, among those as wanted, the pieces a choking peer allows fast come
+/// first, then the pieces a peer suggested.
+fn pick(
+    need_pieces: &mut Vec<PieceFile>,
+    order: PieceOrder,
+    progress: &Progress,
+    preferred: Preferred,
+) -> Option<PieceFile> {
+    let urgent = progress.most_urgent(need_pieces.iter().map(PieceFile::index));
+    let at = match (urgent, order) {
+        (Some(index), _) => need_pieces.iter().position(|piece| piece.index() == index),
+        (None, PieceOrder::Available) => (0..need_pieces.len()).max_by_key(|&i| {
+            let piece = &need_pieces[i];
+            (
+                piece.priority(),
+                preferred.allowed_fast.contains(&piece.index()),
+                preferred.suggested.contains(&piece.index()),
+                piece,
+            )
+        }),
+        (None, PieceOrder::Sequential) => (0..need_pieces.len())
+            .max_by_key(|&i| (need_pieces[i].priority(), Reverse(need_pieces[i].index()))),
+    }?;
+    Some(need_pieces.swap_remove(at))
+}
+
+fn emit(config: &DownloadConfig, event: impl FnOnce() -> Event) {
+    if let Some(events) = &config.events {
+        events.emit(event());
+    }
+}
 
-    fn next(&mut self) -> Option<Self::Item> {
-        let file = self.file_iter.next()?;
-        let bytes = &self.downloaded.bytes[self.offset..][..file.length];
-        self.offset += file.length;
-        Some(DownloadedFile { file, bytes })
+/// Fetches a piece from the first web seed that serves it intact. Seeds
+/// that sent corrupt data are banned by `fetch_piece` and skipped.
+async fn fetch_from_web_seeds(
+    web_seeds: &mut [WebSeed],
+    meta_info: &MetaInfo,
+    index: usize,
+    log: LogLevel,
+) -> anyhow::Result<Vec<u8>> {
+    for seed in web_seeds.iter_mut().filter(|seed| !seed.is_banned()) {
+        match seed.fetch_piece(&meta_info.info, index).await {
+            Ok(piece) => return Ok(piece),
+            Err(e) => {
+                if log >= LogLevel::Debug {
+                    println!("web seed {} failed: {e:?}", seed.url());
+                }
+            }
+        }
     }
+    anyhow::bail!("no web seed could serve piece {index}")
 }
 
-pub struct DownloadedFile<'d> {
-    file: &'d File,
-    bytes: &'d [u8],
+/// Opens up to `want` connections to the candidates that are not banned.
+async fn connect_peers(
+    candidates: &mut CandidatePool,
+    info_hash: [u8; 20],
+    num_pieces: usize,
+    connect: &ConnectConfig,
+    want: usize,
+    config: &DownloadConfig,
+) -> Vec<Peer> {
+    let mut peer_list = Vec::new();
+    while peer_list.len() < want && candidates.pending() > 0 {
+        let batch: Vec<_> = std::iter::from_fn(|| candidates.next())
+            .filter(|addr| !config.bans.is_banned(addr.ip()))
+            .take(want - peer_list.len())
+            .collect();
+        let mut peers = futures_util::stream::iter(batch)
+            .map(|peer_addr| async move {
+                let peer = Peer::new(peer_addr, info_hash, num_pieces, connect);
+                let peer = tokio::time::timeout(CONNECT_TIMEOUT, peer)
+                    .await
+                    .unwrap_or_else(|_| Err(anyhow::anyhow!("connection timed out")));
+                (peer_addr, peer)
+            })
+            .buffer_unordered(MAX_CONNECTING);
+        while let Some((peer_addr, peer)) = peers.next().await {
+            match peer {
+                Ok(peer) => {
+                    if config.log >= LogLevel::Debug {
+                        if let Some(source) = candidates.source(&peer_addr) {
+                            println!("peers: connect: {peer_addr} from {source:?}");
+                        }
+                    }
+                    emit(config, || Event::PeerConnected {
+                        info_hash,
+                        addr: peer_addr,
+                    });
+                    peer_list.push(peer);
+                }
+                Err(e) => peer_failed(candidates, peer_addr, &e, info_hash, config),
+            }
+        }
+    }
+    peer_list
 }
 
-impl<'d> DownloadedFile<'d> {
+/// Feeds the addresses our peers sent through PEX into the candidate pool,
+/// tells our peers about the swarm, and tops up the connections if some
+/// were lost.
+async fn exchange_peers(
+    peers: &mut Vec<Peer>,
+    candidates: &mut CandidatePool,
+    info_hash: [u8; 20],
+    num_pieces: usize,
+    connect: &ConnectConfig,
+    config: &DownloadConfig,
+) {
+    for peer in peers.iter_mut() {
+        candidates.extend(peer.take_pex_peers(), PeerSource::Pex);
+    }
+
+    let connected: Vec<(SocketAddrV4, u8)> = peers
+        .iter()
+        .map(|peer| (peer.addr, peer.pex_flags()))
+        .collect();
+    let mut failed = Vec::new();
+    for peer in peers.iter_mut() {
+        if let Err(e) = peer.send_pex(&connected).await {
+            failed.push((peer.addr, e));
+        }
+    }
+    for (addr, e) in failed {
+        drop_peer(peers, candidates, addr, &e, info_hash, config);
+    }
 
-    pub fn bytes(&self) -> &'d [u8] {
-        self.bytes
+    let max_peers = config.peer_limit();
+    if peers.len() < max_peers {
+        let want = max_peers - peers.len();
+        let more = connect_peers(candidates, info_hash, num_pieces, connect, want, config).await;
+        peers.extend(more);
     }
 }
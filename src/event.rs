@@ -0,0 +1,66 @@
+use std::net::SocketAddrV4;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::broadcast;
+
+/// Events kept for a subscriber that falls behind; older ones are dropped.
+pub(crate) const CAPACITY: usize = 1024;
+
+/// Something that happened in a torrent of a session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    PieceFinished {
+        info_hash: [u8; 20],
+        piece: usize,
+    },
+    TrackerError {
+        info_hash: [u8; 20],
+        message: String,
+    },
+    PeerConnected {
+        info_hash: [u8; 20],
+        addr: SocketAddrV4,
+    },
+    /// A piece did not match its hash and is downloaded again. `peers`
+    /// sent its blocks.
+    HashFailed {
+        info_hash: [u8; 20],
+        piece: usize,
+        peers: Vec<SocketAddrV4>,
+    },
+    /// The session refuses the peer from now on, for `reason`.
+    PeerBanned {
+        info_hash: [u8; 20],
+        addr: SocketAddrV4,
+        reason: String,
+    },
+    /// The torrent was checked or downloaded and is now seeding.
+    TorrentComplete {
+        info_hash: [u8; 20],
+    },
+}
+
+/// Where a download reports its events: to the subscribers of the session,
+/// counting the pieces it finished on the way.
+#[derive(Debug, Clone)]
+pub struct Events {
+    sender: broadcast::Sender<Event>,
+    pieces_done: Arc<AtomicUsize>,
+}
+
+impl Events {
+    pub(crate) fn new(sender: broadcast::Sender<Event>, pieces_done: Arc<AtomicUsize>) -> Self {
+        Self {
+            sender,
+            pieces_done,
+        }
+    }
+
+    pub(crate) fn emit(&self, event: Event) {
+        if let Event::PieceFinished { .. } = event {
+            self.pieces_done.fetch_add(1, Ordering::Relaxed);
+        }
+        // nobody listening is fine
+        let _ = self.sender.send(event);
+    }
+}
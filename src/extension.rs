@@ -0,0 +1,92 @@
+use crate::bdecoder;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Bit advertising the extension protocol (BEP 10) in the handshake reserved bytes.
+pub const EXTENSION_BIT: (usize, u8) = (5, 0x10);
+
+/// Extended message id of the extended handshake itself.
+pub const HANDSHAKE_ID: u8 = 0;
+
+/// Extended message ids we assign to the extensions we support. The peer
+/// uses these ids when sending us extended messages.
+pub const UT_PEX_ID: u8 = 1;
+
+pub const UT_PEX: &str = "ut_pex";
+
+/// Only advertised when fetching or serving the info dictionary (BEP 9).
+pub const UT_METADATA_ID: u8 = 2;
+
+pub const UT_METADATA: &str = "ut_metadata";
+
+/// Payload of the extended handshake (extended message id 0).
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ExtendedHandshake {
+    #[serde(default)]
+    pub m: BTreeMap<String, i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub p: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reqq: Option<i64>,
+    /// Length of the info dictionary, sent with `ut_metadata`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<i64>,
+}
+
+impl ExtendedHandshake {
+    /// Handshake listing every extension this client understands, with the
+    /// port we accept peers on if we listen on one.
+    pub fn ours(listen_port: Option<u16>) -> Self {
+        let mut m = BTreeMap::new();
+        m.insert(String::from(UT_PEX), UT_PEX_ID as i64);
+        Self {
+            m,
+            p: listen_port,
+            v: Some(String::from("Rustorrent 0.1.0")),
+            reqq: None,
+            metadata_size: None,
+        }
+    }
+
+    /// Id the remote peer wants us to use for `name`, if it supports it.
+    /// An id of 0 means the extension has been disabled.
+    pub fn id_of(&self, name: &str) -> Option<u8> {
+        match self.m.get(name) {
+            Some(&id) if id > 0 && id <= u8::MAX as i64 => Some(id as u8),
+            _ => None,
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        bdecoder::from_bytes(bytes).context("decode extended handshake")
+    }
+
+    pub fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        bdecoder::to_bytes(self).context("encode extended handshake")
+    }
+}
+
+/// Builds the payload of an extended message: the extended id followed by
+/// the bencoded body.
+pub fn extended_payload(id: u8, body: &[u8]) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(1 + body.len());
+    payload.push(id);
+    payload.extend_from_slice(body);
+    payload
+}
+
+pub fn supports_extensions(reserved: &[u8; 8]) -> bool {
+    reserved[EXTENSION_BIT.0] & EXTENSION_BIT.1 != 0
+}
+
+#[test]
+fn handshake_port_is_ours_or_omitted() {
+    let with_port = ExtendedHandshake::ours(Some(51413)).to_bytes().unwrap();
+    let theirs = ExtendedHandshake::from_bytes(&with_port).unwrap();
+    assert_eq!(theirs.p, Some(51413));
+    let without = ExtendedHandshake::ours(None).to_bytes().unwrap();
+    assert!(!without.windows(3).any(|key| key == b"1:p"));
+}
@@ -0,0 +1,55 @@
+use sha1::{Digest, Sha1};
+use std::net::Ipv4Addr;
+
+/// Bit advertising the fast extension (BEP 6) in the handshake reserved bytes.
+pub const FAST_BIT: (usize, u8) = (7, 0x04);
+
+/// Number of pieces in the allowed fast set we hand out.
+pub const ALLOWED_FAST_COUNT: usize = 10;
+
+pub fn supports_fast(reserved: &[u8; 8]) -> bool {
+    reserved[FAST_BIT.0] & FAST_BIT.1 != 0
+}
+
+/// Canonical allowed fast set of a peer at `ip`: the pieces it may request
+/// from us while choked. Peers in the same /24 get the same set.
+pub fn allowed_fast_set(
+    ip: Ipv4Addr,
+    info_hash: [u8; 20],
+    num_pieces: usize,
+    k: usize,
+) -> Vec<u32> {
+    let k = k.min(num_pieces);
+    let mut set = Vec::with_capacity(k);
+    let mut x = Vec::with_capacity(24);
+    x.extend_from_slice(&(u32::from(ip) & 0xFFFFFF00).to_be_bytes());
+    x.extend_from_slice(&info_hash);
+    while set.len() < k {
+        x = Sha1::digest(&x).to_vec();
+        for chunk in x.chunks_exact(4) {
+            if set.len() >= k {
+                break;
+            }
+            let y = u32::from_be_bytes(chunk.try_into().expect("chunks of 4 bytes"));
+            let index = y % num_pieces as u32;
+            if !set.contains(&index) {
+                set.push(index);
+            }
+        }
+    }
+    set
+}
+
+#[test]
+fn allowed_fast_set_bep6_vectors() {
+    let ip = Ipv4Addr::new(80, 4, 4, 200);
+    assert_eq!(
+        allowed_fast_set(ip, [0xaa; 20], 1313, 7),
+        vec![1059, 431, 808, 1217, 287, 376, 1188]
+    );
+    assert_eq!(
+        allowed_fast_set(ip, [0xaa; 20], 1313, 9),
+        vec![1059, 431, 808, 1217, 287, 376, 1188, 353, 508]
+    );
+    assert_eq!(allowed_fast_set(ip, [0xaa; 20], 3, 10).len(), 3);
+}
@@ -0,0 +1,175 @@
+use crate::parsing::MetaInfo;
+use anyhow::Context;
+use serde_json::Value;
+use std::fmt::Write;
+
+/// How `--pretty-print-file` shows a torrent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    #[default]
+    Json,
+    /// Indented `key: value` lines, like YAML.
+    Text,
+    /// The fields in two columns, then the files in a table.
+    Table,
+}
+
+impl Format {
+    pub const NAMES: [&'static str; 3] = ["json", "text", "table"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "json" => Some(Self::Json),
+            "text" => Some(Self::Text),
+            "table" => Some(Self::Table),
+            _ => None,
+        }
+    }
+}
+
+pub fn render(meta_info: &MetaInfo, format: Format) -> anyhow::Result<String> {
+    let value = serde_json::to_value(meta_info).context("serialize torrent")?;
+    Ok(match format {
+        Format::Json => serde_json::to_string_pretty(&value).context("serialize torrent")?,
+        Format::Text => {
+            let mut out = String::new();
+            write_text(&value, 0, &mut out);
+            out
+        }
+        Format::Table => table(&value),
+    })
+}
+
+fn scalar(value: &Value) -> Option<String> {
+    match value {
+        Value::Null => Some(String::from("~")),
+        Value::Bool(b) => Some(b.to_string()),
+        Value::Number(num) => Some(num.to_string()),
+        Value::String(s) => Some(s.escape_debug().to_string()),
+        Value::Array(items) if items.is_empty() => Some(String::from("[]")),
+        Value::Object(fields) if fields.is_empty() => Some(String::from("{}")),
+        _ => None,
+    }
+}
+
+fn write_text(value: &Value, indent: usize, out: &mut String) {
+    let pad = " ".repeat(indent);
+    match value {
+        Value::Object(fields) => {
+            for (key, value) in fields {
+                match scalar(value) {
+                    Some(value) => writeln!(out, "{pad}{key}: {value}").unwrap(),
+                    None => {
+                        writeln!(out, "{pad}{key}:").unwrap();
+                        write_text(value, indent + 2, out);
+                    }
+                }
+            }
+        }
+        Value::Array(items) => {
+            for item in items {
+                match scalar(item) {
+                    Some(item) => writeln!(out, "{pad}- {item}").unwrap(),
+                    None => {
+                        // the first line of the item goes after the dash
+                        let start = out.len();
+                        write_text(item, indent + 2, out);
+                        out.replace_range(start..start + indent + 2, &format!("{pad}- "));
+                    }
+                }
+            }
+        }
+        value => writeln!(out, "{pad}{}", scalar(value).unwrap_or_default()).unwrap(),
+    }
+}
+
+/// Flattens `value` into `key value` rows. Lists of hashes are counted
+/// rather than listed, and files go to their own table.
+fn rows(value: &Value, prefix: &str, rows: &mut Vec<(String, String)>) {
+    let Value::Object(fields) = value else {
+        return;
+    };
+    for (key, value) in fields {
+        let key = if prefix.is_empty() {
+            key.clone()
+        } else {
+            format!("{prefix}.{key}")
+        };
+        match value {
+            Value::Object(_) => self::rows(value, &key, rows),
+            Value::Array(_) if key.ends_with("files") => {}
+            Value::Array(items) if key.ends_with("pieces") => {
+                rows.push((key, format!("{} hashes", items.len())));
+            }
+            Value::Array(items) => {
+                let items: Vec<_> = items
+                    .iter()
+                    .map(|item| scalar(item).unwrap_or_else(|| item.to_string()))
+                    .collect();
+                rows.push((key, items.join(", ")));
+            }
+            value => rows.push((key, scalar(value).unwrap_or_default())),
+        }
+    }
+}
+
+fn table(value: &Value) -> String {
+    let mut fields = Vec::new();
+    rows(value, "", &mut fields);
+    let width = fields.iter().map(|(key, _)| key.len()).max().unwrap_or(0);
+    let mut out = String::new();
+    for (key, value) in &fields {
+        writeln!(out, "{key:width$}  {value}").unwrap();
+    }
+
+    let files = value["info"]["files"]
+        .as_array()
+        .or(value["v2"]["files"].as_array());
+    if let Some(files) = files {
+        let lengths: Vec<_> = files
+            .iter()
+            .map(|file| file["length"].to_string())
+            .collect();
+        let width = lengths.iter().map(String::len).max().unwrap_or(0).max(6);
+        writeln!(out, "\n{:>width$}  path", "length").unwrap();
+        for (file, length) in files.iter().zip(&lengths) {
+            let path = match &file["path"] {
+                Value::Array(components) => components
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .collect::<Vec<_>>()
+                    .join("/"),
+                path => path.as_str().unwrap_or_default().to_string(),
+            };
+            writeln!(out, "{length:>width$}  {}", path.escape_debug()).unwrap();
+        }
+    }
+    out
+}
+
+#[test]
+fn formats_show_the_same_fields() {
+    let torrent = b"d8:announce3:url7:comment3:\"hi4:infod5:filesld6:lengthi3e4:pathl1:aee\
+        d6:lengthi1e4:pathl1:b1:ceee4:name1:x12:piece lengthi4e6:pieces20:bbbbbbbbbbbbbbbbbbbbee";
+    let meta_info = crate::parsing::parse_metainfo(torrent).unwrap();
+
+    let json: Value = serde_json::from_str(&render(&meta_info, Format::Json).unwrap()).unwrap();
+    assert_eq!(json["comment"], "\"hi");
+    assert_eq!(json["info"]["pieces"][0], hex::encode([b'b'; 20]));
+    assert_eq!(json["info"]["files"][1]["path"], "b/c");
+    assert!(json.get("creation date").is_none());
+
+    let text = render(&meta_info, Format::Text).unwrap();
+    assert!(text.contains("\n  piece length: 4\n"), "{text}");
+    assert!(
+        text.contains("\n    - length: 3\n      path: a\n"),
+        "{text}"
+    );
+
+    let table = render(&meta_info, Format::Table).unwrap();
+    assert!(table.contains("info.pieces        1 hashes\n"), "{table}");
+    assert!(
+        table.contains("\nlength  path\n     3  a\n     1  b/c\n"),
+        "{table}"
+    );
+}
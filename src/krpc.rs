@@ -0,0 +1,155 @@
+use crate::bdecoder;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+pub const ERROR_PROTOCOL: i64 = 203;
+pub const ERROR_METHOD_UNKNOWN: i64 = 204;
+
+/// A KRPC message (BEP 5): a query (`y` = "q"), a response ("r") or an
+/// error ("e"), all sharing the transaction id `t`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct KrpcMessage {
+    pub t: ByteBuf,
+    pub y: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub q: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub a: Option<KrpcArgs>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub r: Option<KrpcReturn>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub e: Option<(i64, String)>,
+}
+
+/// Arguments of a query. Which fields are present depends on the method.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct KrpcArgs {
+    pub id: ByteBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub info_hash: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub implied_port: Option<i64>,
+}
+
+/// Body of a response.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct KrpcReturn {
+    pub id: ByteBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nodes: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub values: Option<Vec<ByteBuf>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<ByteBuf>,
+}
+
+impl KrpcMessage {
+    pub fn query(t: [u8; 2], q: &str, a: KrpcArgs) -> Self {
+        Self {
+            t: ByteBuf::from(t.to_vec()),
+            y: String::from("q"),
+            q: Some(String::from(q)),
+            a: Some(a),
+            ..Default::default()
+        }
+    }
+
+    pub fn response(t: ByteBuf, r: KrpcReturn) -> Self {
+        Self {
+            t,
+            y: String::from("r"),
+            r: Some(r),
+            ..Default::default()
+        }
+    }
+
+    pub fn error(t: ByteBuf, code: i64, message: &str) -> Self {
+        Self {
+            t,
+            y: String::from("e"),
+            e: Some((code, String::from(message))),
+            ..Default::default()
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        bdecoder::from_bytes(bytes).context("decode krpc message")
+    }
+
+    pub fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        bdecoder::to_bytes(self).context("encode krpc message")
+    }
+}
+
+/// Reads a 20-byte id out of a byte string field.
+pub fn id_from_bytes(bytes: &[u8]) -> Option<[u8; 20]> {
+    bytes.try_into().ok()
+}
+
+/// Compact node info: 20-byte node id followed by the compact address.
+pub fn encode_nodes(nodes: &[([u8; 20], SocketAddrV4)]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(26 * nodes.len());
+    for (id, addr) in nodes {
+        out.extend_from_slice(id);
+        out.extend_from_slice(&encode_peer(*addr));
+    }
+    out
+}
+
+pub fn decode_nodes(bytes: &[u8]) -> Vec<([u8; 20], SocketAddrV4)> {
+    bytes
+        .chunks_exact(26)
+        .map(|chunk| {
+            let id = chunk[..20].try_into().expect("chunk is 26 bytes");
+            (id, decode_peer(&chunk[20..]).expect("chunk is 26 bytes"))
+        })
+        .collect()
+}
+
+/// Compact peer info: 4-byte IP and 2-byte port, both big endian.
+pub fn encode_peer(addr: SocketAddrV4) -> [u8; 6] {
+    let mut out = [0u8; 6];
+    out[..4].copy_from_slice(&addr.ip().octets());
+    out[4..].copy_from_slice(&addr.port().to_be_bytes());
+    out
+}
+
+pub fn decode_peer(bytes: &[u8]) -> Option<SocketAddrV4> {
+    if bytes.len() != 6 {
+        return None;
+    }
+    Some(SocketAddrV4::new(
+        Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]),
+        u16::from_be_bytes([bytes[4], bytes[5]]),
+    ))
+}
+
+#[test]
+fn krpc_round_trip() {
+    let query = KrpcMessage::query(
+        *b"aa",
+        "get_peers",
+        KrpcArgs {
+            id: ByteBuf::from(vec![1u8; 20]),
+            info_hash: Some(ByteBuf::from(vec![2u8; 20])),
+            ..Default::default()
+        },
+    );
+    let bytes = query.to_bytes().unwrap();
+    assert!(bytes.starts_with(b"d1:ad2:id20:"));
+    let decoded = KrpcMessage::from_bytes(&bytes).unwrap();
+    assert_eq!(decoded.q.as_deref(), Some("get_peers"));
+    assert_eq!(decoded.a.unwrap().info_hash.unwrap().as_ref(), &[2u8; 20]);
+
+    let error = KrpcMessage::error(ByteBuf::from(b"aa".to_vec()), ERROR_PROTOCOL, "oops");
+    let decoded = KrpcMessage::from_bytes(&error.to_bytes().unwrap()).unwrap();
+    assert_eq!(decoded.e, Some((ERROR_PROTOCOL, String::from("oops"))));
+}
@@ -0,0 +1,130 @@
+//! A BitTorrent client to embed: a [`Session`] runs many torrents at once,
+//! each followed and controlled through a [`TorrentHandle`], and reports
+//! what happens in them as [`Event`]s.
+//!
+//! ```no_run
+//! # async fn example() -> anyhow::Result<()> {
+//! let session = rustorrent::Session::start(Default::default()).await?;
+//! let torrent = session.add_torrent_file("debian.iso.torrent")?;
+//! let state = torrent.wait().await?;
+//! println!("{state:?} in {:?}", torrent.status()?.location);
+//! session.shutdown().await
+//! # }
+//! ```
+
+mod ban;
+mod bdecoder;
+mod candidates;
+pub mod create;
+mod dht;
+mod download;
+mod event;
+mod extension;
+mod fast;
+pub mod format;
+mod krpc;
+mod listener;
+mod lsd;
+pub mod magnet;
+mod metadata;
+mod mse;
+pub mod output;
+pub mod parsing;
+mod parts;
+mod peers;
+mod pex;
+mod piece;
+pub mod rate;
+mod seed;
+pub mod select;
+mod session;
+mod smartban;
+mod stream;
+pub mod tracker;
+mod transport;
+mod utp;
+pub mod v2;
+pub mod validate;
+mod webseed;
+
+pub use ban::BanList;
+pub use dht::DhtConfig;
+pub use download::{DownloadConfig, PieceOrder};
+pub use event::{Event, Events};
+pub use lsd::LsdConfig;
+pub use mse::EncryptionPolicy;
+pub use peers::ConnectConfig;
+pub use rate::{RateConfig, RateLimits};
+pub use session::{
+    ConnectionSlots, FilePriority, Session, SessionConfig, TorrentHandle, TorrentState,
+    TorrentStatus,
+};
+pub use stream::{FileReader, Progress};
+pub use transport::TransportPolicy;
+pub use utp::UtpSocket;
+
+pub(crate) const BLOCK_MAX: usize = 1 << 14;
+
+/// How much is printed, from errors only to every message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    pub const NAMES: [&'static str; 5] = ["error", "warn", "info", "debug", "trace"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "error" => Some(Self::Error),
+            "warn" => Some(Self::Warn),
+            "info" => Some(Self::Info),
+            "debug" => Some(Self::Debug),
+            "trace" => Some(Self::Trace),
+            _ => None,
+        }
+    }
+
+    /// Each step shows one more level of detail.
+    pub fn raised(self, steps: u8) -> Self {
+        let levels = [
+            Self::Error,
+            Self::Warn,
+            Self::Info,
+            Self::Debug,
+            Self::Trace,
+        ];
+        levels[(self as usize + steps as usize).min(levels.len() - 1)]
+    }
+}
+
+/// Classes of failure, numbered as the exit status of the command line
+/// tool. Usage errors exit with 2, as clap does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// A torrent file could not be read or is invalid.
+    Torrent = 3,
+    /// The trackers or the peers could not be reached.
+    Network = 4,
+    /// The data on disk could not be read or written, or does not match.
+    Disk = 5,
+}
+
+/// An error and its class of failure.
+#[derive(Debug)]
+pub struct Failed(pub Failure, pub anyhow::Error);
+
+pub trait FailAs<T> {
+    fn fail_as(self, failure: Failure) -> Result<T, Failed>;
+}
+
+impl<T, E: Into<anyhow::Error>> FailAs<T> for Result<T, E> {
+    fn fail_as(self, failure: Failure) -> Result<T, Failed> {
+        self.map_err(|e| Failed(failure, e.into()))
+    }
+}
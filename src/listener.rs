@@ -0,0 +1,253 @@
+use crate::mse::{self, EncryptionPolicy, MseStream};
+use crate::peers::{Handshake, PeerStream, PEER_ID};
+use crate::transport::Transport;
+use crate::utp::UtpSocket;
+use anyhow::Context;
+use std::net::{SocketAddr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+const PROTOCOL_PREFIX: &[u8; 20] = b"\x13BitTorrent protocol";
+
+/// A peer that connected to us and completed the handshake.
+pub(crate) struct IncomingPeer {
+    pub addr: SocketAddrV4,
+    pub stream: PeerStream,
+    pub handshake: Handshake,
+}
+
+/// Accepts connections from peers for the torrents we serve, over TCP and
+/// optionally uTP, applying the encryption policy to their handshakes.
+pub(crate) struct Listener {
+    listener: TcpListener,
+    utp: Option<Arc<UtpSocket>>,
+    encryption: EncryptionPolicy,
+    peer_id: [u8; 20],
+    info_hashes: Mutex<Vec<[u8; 20]>>,
+}
+
+impl Listener {
+    pub(crate) async fn bind(
+        addr: SocketAddrV4,
+        encryption: EncryptionPolicy,
+    ) -> anyhow::Result<Self> {
+        let listener = TcpListener::bind(addr)
+            .await
+            .context("bind peer listener")?;
+        Ok(Self {
+            listener,
+            utp: None,
+            encryption,
+            peer_id: PEER_ID,
+            info_hashes: Mutex::default(),
+        })
+    }
+
+    pub(crate) fn local_addr(&self) -> anyhow::Result<SocketAddrV4> {
+        match self.listener.local_addr()? {
+            SocketAddr::V4(addr) => Ok(addr),
+            SocketAddr::V6(_) => anyhow::bail!("listener is not IPv4"),
+        }
+    }
+
+    /// Also accepts the uTP connections arriving on `utp`.
+    pub(crate) fn accept_utp(&mut self, utp: Arc<UtpSocket>) {
+        self.utp = Some(utp);
+    }
+
+    /// The peer id we answer handshakes with.
+    pub(crate) fn set_peer_id(&mut self, peer_id: [u8; 20]) {
+        self.peer_id = peer_id;
+    }
+
+    pub(crate) fn add_torrent(&self, info_hash: [u8; 20]) {
+        let mut info_hashes = self.info_hashes.lock().unwrap();
+        if !info_hashes.contains(&info_hash) {
+            info_hashes.push(info_hash);
+        }
+    }
+
+    pub(crate) fn remove_torrent(&self, info_hash: &[u8; 20]) {
+        self.info_hashes
+            .lock()
+            .unwrap()
+            .retain(|served| served != info_hash);
+    }
+
+    /// Waits for the next connection, over TCP or uTP.
+    pub(crate) async fn accept(&self) -> anyhow::Result<(Transport, SocketAddrV4)> {
+        let (stream, addr) = match &self.utp {
+            Some(utp) => tokio::select! {
+                accepted = self.listener.accept() => {
+                    let (stream, addr) = accepted.context("accept peer")?;
+                    (Transport::Tcp(stream), addr)
+                }
+                accepted = utp.accept() => {
+                    let stream = accepted.context("accept utp peer")?;
+                    let addr = stream.peer_addr();
+                    (Transport::Utp(stream), addr)
+                }
+            },
+            None => {
+                let (stream, addr) = self.listener.accept().await.context("accept peer")?;
+                (Transport::Tcp(stream), addr)
+            }
+        };
+        let SocketAddr::V4(addr) = addr else {
+            anyhow::bail!("peer connected over IPv6");
+        };
+        Ok((stream, addr))
+    }
+
+    /// Runs the handshakes on a connection from `accept`.
+    pub(crate) async fn handshake(
+        &self,
+        mut stream: Transport,
+        addr: SocketAddrV4,
+    ) -> anyhow::Result<IncomingPeer> {
+        let mut prefix = [0u8; 20];
+        stream
+            .read_exact(&mut prefix)
+            .await
+            .context("read handshake")?;
+
+        let info_hashes = self.info_hashes.lock().unwrap().clone();
+        let (mut stream, expected) = if &prefix == PROTOCOL_PREFIX {
+            anyhow::ensure!(
+                self.encryption != EncryptionPolicy::Forced,
+                "plaintext handshake refused by encryption policy"
+            );
+            (MseStream::plaintext(stream, prefix.to_vec()), None)
+        } else {
+            anyhow::ensure!(
+                self.encryption != EncryptionPolicy::Disabled,
+                "encrypted handshake refused by encryption policy"
+            );
+            let (stream, info_hash) =
+                mse::respond(stream, &prefix, &info_hashes, self.encryption).await?;
+            (stream, Some(info_hash))
+        };
+
+        let mut theirs = Handshake::new([0; 20], [0; 20]);
+        stream
+            .read_exact(theirs.as_bytes_mut())
+            .await
+            .context("read handshake")?;
+        anyhow::ensure!(theirs.length == 19);
+        anyhow::ensure!(&theirs.bittorrent == b"BitTorrent protocol");
+        let info_hash = theirs.info_hash;
+        anyhow::ensure!(
+            info_hashes.contains(&info_hash),
+            "peer asked for a torrent we do not serve"
+        );
+        anyhow::ensure!(
+            expected.is_none_or(|expected| expected == info_hash),
+            "handshake does not match the mse torrent"
+        );
+
+        let mut ours = Handshake::ours(info_hash, self.peer_id);
+        stream
+            .write_all(ours.as_bytes_mut())
+            .await
+            .context("write handshake")?;
+        stream.flush().await.context("write handshake")?;
+        Ok(IncomingPeer {
+            addr,
+            stream,
+            handshake: theirs,
+        })
+    }
+}
+
+#[tokio::test]
+async fn listener_applies_encryption_policy() {
+    use crate::peers::{connect, ConnectConfig};
+
+    let info_hash = [5u8; 20];
+    for (listening, connecting, encrypted) in [
+        (
+            EncryptionPolicy::Forced,
+            EncryptionPolicy::Enabled,
+            Some(true),
+        ),
+        (
+            EncryptionPolicy::Enabled,
+            EncryptionPolicy::Disabled,
+            Some(false),
+        ),
+        (
+            EncryptionPolicy::Disabled,
+            EncryptionPolicy::Enabled,
+            Some(false),
+        ),
+        (EncryptionPolicy::Forced, EncryptionPolicy::Disabled, None),
+    ] {
+        let listener = Listener::bind("127.0.0.1:0".parse().unwrap(), listening)
+            .await
+            .unwrap();
+        listener.add_torrent(info_hash);
+        let addr = listener.local_addr().unwrap();
+        let accepting = tokio::spawn(async move {
+            // the fallback to plaintext needs a second connection
+            loop {
+                let (stream, addr) = listener.accept().await.unwrap();
+                if let Ok(incoming) = listener.handshake(stream, addr).await {
+                    return incoming.stream.is_encrypted();
+                }
+            }
+        });
+
+        let config = ConnectConfig {
+            encryption: connecting,
+            ..Default::default()
+        };
+        let connected = connect(addr, info_hash, &config).await;
+        match encrypted {
+            Some(encrypted) => {
+                let (stream, handshake) = connected.unwrap();
+                assert_eq!(handshake.info_hash, info_hash);
+                assert_eq!(stream.is_encrypted(), encrypted);
+                assert_eq!(accepting.await.unwrap(), encrypted);
+            }
+            None => {
+                assert!(connected.is_err());
+                accepting.abort();
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn listener_accepts_utp() {
+    use crate::peers::{connect, ConnectConfig};
+    use crate::transport::{TransportKind, TransportPolicy};
+
+    let info_hash = [6u8; 20];
+    let mut listener = Listener::bind("127.0.0.1:0".parse().unwrap(), EncryptionPolicy::Enabled)
+        .await
+        .unwrap();
+    let addr = listener.local_addr().unwrap();
+    let utp = UtpSocket::bind(SocketAddr::V4(addr)).await.unwrap();
+    listener.accept_utp(Arc::new(utp));
+    listener.add_torrent(info_hash);
+    let accepting = tokio::spawn(async move {
+        let (stream, addr) = listener.accept().await.unwrap();
+        listener.handshake(stream, addr).await.unwrap()
+    });
+
+    let config = ConnectConfig {
+        transport: TransportPolicy::UtpOnly,
+        utp: Some(Arc::new(
+            UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+                .await
+                .unwrap(),
+        )),
+        ..Default::default()
+    };
+    let (stream, handshake) = connect(addr, info_hash, &config).await.unwrap();
+    assert_eq!(handshake.info_hash, info_hash);
+    assert!(stream.is_encrypted());
+    let incoming = accepting.await.unwrap();
+    assert_eq!(incoming.stream.get_ref().kind(), TransportKind::Utp);
+}
@@ -0,0 +1,275 @@
+use anyhow::Context;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::time::{self, Duration};
+
+/// Multicast group and port of BEP 14 announces.
+pub const LSD_GROUP: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 192, 152, 143), 6771);
+
+/// Delay between two announces of the same torrent.
+pub const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, Clone)]
+pub struct LsdConfig {
+    pub group: SocketAddrV4,
+    /// Local interface used to join the group and send announces.
+    pub interface: Ipv4Addr,
+    /// Port our peer listener accepts connections on.
+    pub port: u16,
+}
+
+impl Default for LsdConfig {
+    fn default() -> Self {
+        Self {
+            group: LSD_GROUP,
+            interface: Ipv4Addr::UNSPECIFIED,
+            port: 6881,
+        }
+    }
+}
+
+/// A `BT-SEARCH` announce received from another client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LsdAnnounce {
+    pub port: u16,
+    pub info_hashes: Vec<[u8; 20]>,
+    pub cookie: Option<String>,
+}
+
+impl LsdAnnounce {
+    pub fn to_bytes(&self, group: SocketAddrV4) -> Vec<u8> {
+        let mut message = format!(
+            "BT-SEARCH * HTTP/1.1\r\nHost: {}\r\nPort: {}\r\n",
+            group, self.port
+        );
+        for info_hash in &self.info_hashes {
+            message.push_str(&format!("Infohash: {}\r\n", hex::encode(info_hash)));
+        }
+        if let Some(cookie) = &self.cookie {
+            message.push_str(&format!("cookie: {}\r\n", cookie));
+        }
+        message.push_str("\r\n\r\n");
+        message.into_bytes()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        let text = std::str::from_utf8(bytes).context("announce is not text")?;
+        let mut lines = text.split("\r\n");
+        anyhow::ensure!(
+            lines.next() == Some("BT-SEARCH * HTTP/1.1"),
+            "not a BT-SEARCH announce"
+        );
+        let mut port = None;
+        let mut info_hashes = Vec::new();
+        let mut cookie = None;
+        for line in lines {
+            let Some((name, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "port" => port = Some(value.parse::<u16>().context("invalid port")?),
+                "infohash" => {
+                    let mut info_hash = [0u8; 20];
+                    hex::decode_to_slice(value, &mut info_hash).context("invalid infohash")?;
+                    info_hashes.push(info_hash);
+                }
+                "cookie" => cookie = Some(String::from(value)),
+                _ => {}
+            }
+        }
+        let port = port.context("announce without port")?;
+        anyhow::ensure!(port != 0, "announce with port 0");
+        anyhow::ensure!(!info_hashes.is_empty(), "announce without infohash");
+        Ok(Self {
+            port,
+            info_hashes,
+            cookie,
+        })
+    }
+}
+
+type Subscribers = Arc<Mutex<HashMap<[u8; 20], mpsc::UnboundedSender<SocketAddrV4>>>>;
+
+/// Local Service Discovery (BEP 14): finds peers of our torrents on the LAN
+/// through multicast announces.
+pub struct Lsd {
+    socket: Arc<UdpSocket>,
+    config: LsdConfig,
+    cookie: String,
+    subscribers: Subscribers,
+    tasks: Vec<tokio::task::JoinHandle<()>>,
+}
+
+impl Drop for Lsd {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+impl Lsd {
+    pub async fn start(config: LsdConfig) -> anyhow::Result<Self> {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_reuse_address(true)?;
+        #[cfg(unix)]
+        socket.set_reuse_port(true)?;
+        socket.set_nonblocking(true)?;
+        socket
+            .bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, config.group.port()).into())
+            .context("bind lsd socket")?;
+        socket
+            .join_multicast_v4(config.group.ip(), &config.interface)
+            .context("join lsd multicast group")?;
+        socket.set_multicast_if_v4(&config.interface)?;
+        socket.set_multicast_loop_v4(true)?;
+        let socket = Arc::new(UdpSocket::from_std(socket.into())?);
+
+        let cookie = hex::encode(rand::random::<[u8; 8]>());
+        let subscribers: Subscribers = Arc::default();
+        let receiver = tokio::spawn(receive(
+            Arc::clone(&socket),
+            cookie.clone(),
+            Arc::clone(&subscribers),
+        ));
+        let announcer = tokio::spawn(announce_periodically(
+            Arc::clone(&socket),
+            config.clone(),
+            cookie.clone(),
+            Arc::clone(&subscribers),
+        ));
+
+        Ok(Self {
+            socket,
+            config,
+            cookie,
+            subscribers,
+            tasks: vec![receiver, announcer],
+        })
+    }
+
+    /// Starts announcing `info_hash` on the LAN and returns the addresses of
+    /// the LAN peers announcing it too.
+    pub async fn join(
+        &self,
+        info_hash: [u8; 20],
+    ) -> anyhow::Result<mpsc::UnboundedReceiver<SocketAddrV4>> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.subscribers.lock().unwrap().insert(info_hash, sender);
+        self.announce(&[info_hash]).await?;
+        Ok(receiver)
+    }
+
+    /// Stops announcing `info_hash`.
+    pub fn leave(&self, info_hash: &[u8; 20]) {
+        self.subscribers.lock().unwrap().remove(info_hash);
+    }
+
+    async fn announce(&self, info_hashes: &[[u8; 20]]) -> anyhow::Result<()> {
+        send_announce(&self.socket, &self.config, &self.cookie, info_hashes).await
+    }
+}
+
+async fn send_announce(
+    socket: &UdpSocket,
+    config: &LsdConfig,
+    cookie: &str,
+    info_hashes: &[[u8; 20]],
+) -> anyhow::Result<()> {
+    let announce = LsdAnnounce {
+        port: config.port,
+        info_hashes: info_hashes.to_vec(),
+        cookie: Some(String::from(cookie)),
+    };
+    socket
+        .send_to(&announce.to_bytes(config.group), config.group)
+        .await
+        .context("send lsd announce")?;
+    Ok(())
+}
+
+async fn announce_periodically(
+    socket: Arc<UdpSocket>,
+    config: LsdConfig,
+    cookie: String,
+    subscribers: Subscribers,
+) {
+    let mut interval = time::interval(ANNOUNCE_INTERVAL);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let info_hashes: Vec<_> = subscribers.lock().unwrap().keys().copied().collect();
+        if info_hashes.is_empty() {
+            continue;
+        }
+        // joining reported the failures to send, the next interval may do
+        let _ = send_announce(&socket, &config, &cookie, &info_hashes).await;
+    }
+}
+
+async fn receive(socket: Arc<UdpSocket>, cookie: String, subscribers: Subscribers) {
+    let mut buf = vec![0u8; 1500];
+    loop {
+        let Ok((n, SocketAddr::V4(from))) = socket.recv_from(&mut buf).await else {
+            continue;
+        };
+        let Ok(announce) = LsdAnnounce::from_bytes(&buf[..n]) else {
+            continue;
+        };
+        if announce.cookie.as_deref() == Some(cookie.as_str()) {
+            // our own announce looped back
+            continue;
+        }
+        let peer = SocketAddrV4::new(*from.ip(), announce.port);
+        let mut subscribers = subscribers.lock().unwrap();
+        for info_hash in &announce.info_hashes {
+            if let Some(sender) = subscribers.get(info_hash) {
+                if sender.send(peer).is_err() {
+                    subscribers.remove(info_hash);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn lsd_announce_round_trip() {
+    let announce = LsdAnnounce {
+        port: 6881,
+        info_hashes: vec![[0xab; 20], [0x01; 20]],
+        cookie: Some(String::from("c00k1e")),
+    };
+    let bytes = announce.to_bytes(LSD_GROUP);
+    assert!(
+        bytes.starts_with(b"BT-SEARCH * HTTP/1.1\r\nHost: 239.192.152.143:6771\r\nPort: 6881\r\n")
+    );
+    assert_eq!(LsdAnnounce::from_bytes(&bytes).unwrap(), announce);
+    assert!(LsdAnnounce::from_bytes(b"M-SEARCH * HTTP/1.1\r\n\r\n").is_err());
+}
+
+#[tokio::test]
+async fn lsd_loopback_discovery() {
+    let group = SocketAddrV4::new(*LSD_GROUP.ip(), 46000 + (std::process::id() % 1000) as u16);
+    let config = |port| LsdConfig {
+        group,
+        interface: Ipv4Addr::LOCALHOST,
+        port,
+    };
+    let a = Lsd::start(config(7001)).await.unwrap();
+    let b = Lsd::start(config(7002)).await.unwrap();
+
+    let info_hash = [0x42; 20];
+    let mut found_by_a = a.join(info_hash).await.unwrap();
+    let _found_by_b = b.join(info_hash).await.unwrap();
+
+    let peer = time::timeout(Duration::from_secs(2), found_by_a.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(peer, "127.0.0.1:7002".parse().unwrap());
+}
@@ -0,0 +1,117 @@
+use crate::parsing::{InfoHash, MetaInfo};
+use anyhow::Context;
+use std::fmt;
+
+/// A magnet link (BEP 9): enough to join the swarm of a torrent and fetch
+/// its metadata from the peers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MagnetLink {
+    pub info_hash: InfoHash,
+    /// `dn`, the name to show until the metadata arrives.
+    pub name: Option<String>,
+    /// `tr`
+    pub trackers: Vec<String>,
+    /// `ws`, web seeds (BEP 19).
+    pub web_seeds: Vec<String>,
+}
+
+impl MagnetLink {
+    pub fn from_meta_info(meta_info: &MetaInfo) -> Self {
+        Self {
+            info_hash: meta_info.info_hash,
+            name: Some(meta_info.info.name.clone()),
+            trackers: meta_info.announce.iter().cloned().collect(),
+            web_seeds: meta_info.url_list.clone(),
+        }
+    }
+
+    pub fn parse(link: &str) -> anyhow::Result<Self> {
+        let query = link.strip_prefix("magnet:?").context("not a magnet link")?;
+        let mut info_hash = None;
+        let mut magnet = Self {
+            info_hash: InfoHash::default(),
+            name: None,
+            trackers: Vec::new(),
+            web_seeds: Vec::new(),
+        };
+        for (key, value) in serde_urlencoded::from_str::<Vec<(String, String)>>(query)? {
+            match key.as_str() {
+                "xt" => {
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        info_hash = Some(parse_btih(hash)?);
+                    }
+                }
+                "dn" => magnet.name = Some(value),
+                "tr" => magnet.trackers.push(value),
+                "ws" => magnet.web_seeds.push(value),
+                _ => {}
+            }
+        }
+        magnet.info_hash = info_hash.context("magnet link has no urn:btih info hash")?;
+        Ok(magnet)
+    }
+}
+
+/// The info hash of `xt=urn:btih:`, in hex or base32.
+fn parse_btih(hash: &str) -> anyhow::Result<InfoHash> {
+    let bytes = match hash.len() {
+        40 => hex::decode(hash).context("info hash is not hex")?,
+        32 => base32_decode(hash).context("info hash is not base32")?,
+        len => anyhow::bail!("info hash has {len} characters"),
+    };
+    Ok(InfoHash(
+        bytes.try_into().expect("both encodings give 20 bytes"),
+    ))
+}
+
+fn base32_decode(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let (mut buffer, mut bits) = (0u64, 0);
+    for c in text.bytes() {
+        let value = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+        buffer = buffer << 5 | value as u64;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+impl fmt::Display for MagnetLink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "magnet:?xt=urn:btih:{}", self.info_hash)?;
+        let mut params = Vec::new();
+        if let Some(name) = &self.name {
+            params.push(("dn", name));
+        }
+        params.extend(self.trackers.iter().map(|url| ("tr", url)));
+        params.extend(self.web_seeds.iter().map(|url| ("ws", url)));
+        if !params.is_empty() {
+            let query = serde_urlencoded::to_string(&params).map_err(|_| fmt::Error)?;
+            write!(f, "&{query}")?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn magnet_link_round_trips() {
+    let magnet = MagnetLink {
+        info_hash: InfoHash([0xab; 20]),
+        name: Some(String::from("a b&c")),
+        trackers: vec![String::from("http://t.example/announce?x=1")],
+        web_seeds: Vec::new(),
+    };
+    let link = magnet.to_string();
+    assert!(link.starts_with("magnet:?xt=urn:btih:abababab"), "{link}");
+    assert_eq!(MagnetLink::parse(&link).unwrap(), magnet);
+
+    let base32 = MagnetLink::parse("magnet:?xt=urn:btih:VOV2XK5LVOV2XK5LVOV2XK5LVOV2XK5L").unwrap();
+    assert_eq!(base32.info_hash, magnet.info_hash);
+}
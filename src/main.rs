@@ -1,330 +1,645 @@
-#![allow(warnings)]
-
-mod bdecoder;
-mod download;
-mod parsing;
-mod peers;
-mod piece;
-mod tracker;
-
-use bdecoder::decode_bencoded_string;
-use bdecoder::read_content;
-use clap::{command, Arg, ArgAction, ArgMatches};
-use futures_util::{SinkExt, StreamExt};
-use peers::handshake_peer;
-use peers::Handshake;
-use peers::Message;
-use peers::MessageFrame;
-use peers::MessageTag;
-use peers::Piece;
-use peers::Request;
+use anyhow::Context;
+use clap::{command, Arg, ArgAction, ArgMatches, Command};
+use rustorrent::magnet::MagnetLink;
+use rustorrent::parsing::MetaInfo;
+use rustorrent::{
+    create, format, output, rate, select, tracker, validate, DhtConfig, DownloadConfig, FailAs,
+    Failed, Failure, LogLevel, LsdConfig, PieceOrder, RateConfig, RateLimits, Session,
+    SessionConfig, TorrentHandle, TorrentState,
+};
 use std::fs;
-use std::net::Ipv4Addr;
-use std::net::SocketAddrV4;
 use std::path::PathBuf;
 
-use sha1::{Digest, Sha1};
+fn torrents_arg() -> Arg {
+    Arg::new("torrent")
+        .required(true)
+        .help("Torrent file(s)")
+        .action(ArgAction::Append)
+}
 
-use std::str::FromStr;
+fn port_arg() -> Arg {
+    Arg::new("port")
+        .short('p')
+        .long("port")
+        .help("Port we accept peer connections on")
+        .value_parser(clap::value_parser!(u16))
+        .default_value("6881")
+}
 
-use anyhow::Context;
-use serde::{Deserialize, Serialize};
-use tokio::time;
-use tokio::time::Duration;
-use tracker::compute_length;
-use tracker::dump_peers;
-use tracker::TrackerRequest;
-use tracker::TrackerResponse;
+fn dht_arg() -> Arg {
+    Arg::new("dht")
+        .long("dht")
+        .value_name("STATE_FILE")
+        .help("Find peers through the DHT too, keeping its node id and routing table in STATE_FILE")
+        .value_parser(clap::value_parser!(PathBuf))
+        .num_args(0..=1)
+}
 
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+fn dht_config(matches: &ArgMatches) -> Option<DhtConfig> {
+    matches.contains_id("dht").then(|| DhtConfig {
+        state_file: matches.get_one::<PathBuf>("dht").cloned(),
+        ..Default::default()
+    })
+}
 
-use std::io::{self, Read};
+fn lsd_arg() -> Arg {
+    Arg::new("lsd")
+        .long("lsd")
+        .help("Find peers on the local network too, and announce the torrents there")
+        .action(ArgAction::SetTrue)
+}
 
-use crate::{bdecoder::encode_info_field, tracker::send_request};
+fn ban_file_arg() -> Arg {
+    Arg::new("ban-file")
+        .long("ban-file")
+        .help("File the peers banned for corrupt data or protocol violations are kept in")
+        .value_parser(clap::value_parser!(PathBuf))
+}
 
-pub const BLOCK_MAX: usize = 1 << 14;
+/// Options for the download and upload limits of each kind of peers, and
+/// the peers they limit.
+const RATE_LIMITS: [(&str, &str, &str); 5] = [
+    ("download-limit", "upload-limit", "all the peers"),
+    (
+        "torrent-download-limit",
+        "torrent-upload-limit",
+        "the peers of each torrent",
+    ),
+    ("peer-download-limit", "peer-upload-limit", "each peer"),
+    (
+        "lan-download-limit",
+        "lan-upload-limit",
+        "the peers on the local network, instead of the other limits",
+    ),
+    (
+        "alt-download-limit",
+        "alt-upload-limit",
+        "all the peers during --alt-schedule",
+    ),
+];
+
+fn rate_args() -> Vec<Arg> {
+    let mut args = Vec::new();
+    for (download, upload, peers) in RATE_LIMITS {
+        for (name, way) in [(download, "download from"), (upload, "upload to")] {
+            let arg = Arg::new(name)
+                .long(name)
+                .value_name("RATE")
+                .help(format!(
+                    "Bytes a second to {way} {peers}, with a K, M or G suffix"
+                ))
+                .value_parser(rate::parse_rate);
+            args.push(if name.starts_with("alt-") {
+                arg.requires("alt-schedule")
+            } else {
+                arg
+            });
+        }
+    }
+    args.push(
+        Arg::new("alt-schedule")
+            .long("alt-schedule")
+            .value_name("HH:MM-HH:MM")
+            .help("Daily UTC window when the --alt-* limits replace the others")
+            .value_parser(rate::parse_schedule),
+    );
+    args
+}
 
-#[tokio::main]
-async fn main() {
-    let matches: ArgMatches = command!()
+fn rate_config(matches: &ArgMatches) -> RateConfig {
+    let limits: Vec<Option<RateLimits>> = RATE_LIMITS
+        .iter()
+        .map(|&(download, upload, _)| {
+            let limit = |name: &str| matches.get_one::<u64>(name).copied();
+            match (limit(download), limit(upload)) {
+                (None, None) => None,
+                (download, upload) => Some(RateLimits {
+                    download: download.unwrap_or(0),
+                    upload: upload.unwrap_or(0),
+                }),
+            }
+        })
+        .collect();
+    RateConfig {
+        global: limits[0].unwrap_or_default(),
+        per_torrent: limits[1].unwrap_or_default(),
+        per_peer: limits[2].unwrap_or_default(),
+        lan: limits[3],
+        alternative: matches
+            .get_one::<(u16, u16)>("alt-schedule")
+            .map(|&(from, to)| rate::AlternativeRates {
+                limits: limits[4].unwrap_or_default(),
+                from,
+                to,
+            }),
+    }
+}
+
+fn cli() -> Command {
+    command!()
         .about("Rustorrent is a leeching and peering torrents tool built in Rust")
+        .subcommand_required(true)
+        .arg_required_else_help(true)
         .arg(
-            Arg::new("torrent file(s)")
-                .short('t')
-                .long("torrent")
-                .required(true)
-                .help("Torrent file(s)")
-                .action(ArgAction::Append),
-        )
-        .arg(
-            Arg::new("Pretty print file")
-                .short('p')
-                .long("pretty-print-file")
-                .required(false)
-                .help("Pretty print file(s) in JSON format")
-                .action(ArgAction::Count),
-        )
-        .arg(
-            Arg::new("Dump peer(s)")
-                .short('d')
-                .long("dump-peers")
-                .required(false)
-                .help("Display peers ip and port returned by the tracker")
-                .action(ArgAction::Count),
+            Arg::new("log-level")
+                .long("log-level")
+                .global(true)
+                .help("How much to print")
+                .value_parser(LogLevel::NAMES)
+                .default_value("info"),
         )
         .arg(
-            Arg::new("Verbose")
+            Arg::new("verbose")
                 .short('v')
                 .long("verbose")
-                .required(false)
-                .help("Display all the network communications with the peers")
+                .global(true)
+                .help("Print more, once per level: -v for debug, -vv for trace")
                 .action(ArgAction::Count),
         )
-        .get_matches();
+        .subcommand(
+            Command::new("info")
+                .about("Print the contents of torrent file(s)")
+                .arg(torrents_arg())
+                .arg(
+                    Arg::new("format")
+                        .long("format")
+                        .value_parser(format::Format::NAMES)
+                        .default_value("json"),
+                ),
+        )
+        .subcommand(
+            Command::new("download")
+                .about("Download the content of torrent file(s) or magnet link(s)")
+                .arg(torrents_arg().help("Torrent file(s) or magnet link(s)"))
+                .arg(
+                    Arg::new("output-dir")
+                        .short('o')
+                        .long("output-dir")
+                        .help("Directory the downloads are written to")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .default_value("."),
+                )
+                .arg(
+                    Arg::new("incomplete-dir")
+                        .long("incomplete-dir")
+                        .help("Directory downloads are written to until they are complete")
+                        .value_parser(clap::value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("existing")
+                        .long("existing")
+                        .help("What to do when the download already exists in the output directory")
+                        .value_parser(output::ExistingPolicy::NAMES)
+                        .default_value("rename"),
+                )
+                .arg(
+                    Arg::new("only")
+                        .long("only")
+                        .value_name("GLOB")
+                        .help("Download only the files matching GLOB, a name or a path in the torrent")
+                        .action(ArgAction::Append),
+                )
+                .arg(
+                    Arg::new("exclude")
+                        .long("exclude")
+                        .value_name("GLOB")
+                        .help("Skip the files matching GLOB, a name or a path in the torrent")
+                        .action(ArgAction::Append),
+                )
+                .arg(port_arg())
+                .args(rate_args())
+                .arg(ban_file_arg())
+                .arg(dht_arg())
+                .arg(lsd_arg())
+                .arg(
+                    Arg::new("max-peers")
+                        .long("max-peers")
+                        .help("Number of peers to download each torrent from at once")
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("5"),
+                )
+                .arg(
+                    Arg::new("max-connections")
+                        .long("max-connections")
+                        .help("Number of peers shared by the torrents downloading at once")
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("50"),
+                )
+                .arg(
+                    Arg::new("sequential")
+                        .long("sequential")
+                        .help("Download the pieces in order, to use the files before they are complete")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("seed")
+                        .long("seed")
+                        .help("Keep uploading the torrents once downloaded, until ctrl-c")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("seed")
+                .about("Upload the content of a torrent to the peers")
+                .arg(Arg::new("torrent").required(true).help("Torrent file"))
+                .arg(
+                    Arg::new("data-dir")
+                        .short('d')
+                        .long("data-dir")
+                        .help("Directory holding the content, named after the torrent")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .default_value("."),
+                )
+                .arg(port_arg())
+                .args(rate_args())
+                .arg(ban_file_arg())
+                .arg(dht_arg())
+                .arg(lsd_arg()),
+        )
+        .subcommand(
+            Command::new("create")
+                .about("Create a .torrent file from a file or directory")
+                .arg(
+                    Arg::new("path")
+                        .required(true)
+                        .help("File or directory to share"),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .help("Torrent file to write, <name>.torrent by default"),
+                )
+                .arg(
+                    Arg::new("announce")
+                        .short('a')
+                        .long("announce")
+                        .help("Tracker URL"),
+                )
+                .arg(
+                    Arg::new("announce-list")
+                        .long("announce-list")
+                        .help("Tier of comma separated tracker URLs, may be repeated")
+                        .action(ArgAction::Append),
+                )
+                .arg(Arg::new("comment").short('c').long("comment"))
+                .arg(
+                    Arg::new("created-by")
+                        .long("created-by")
+                        .default_value(concat!("rustorrent/", env!("CARGO_PKG_VERSION"))),
+                )
+                .arg(
+                    Arg::new("private")
+                        .long("private")
+                        .help("Only use the trackers to find peers")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("url-list")
+                        .long("web-seed")
+                        .help("Web seed URL, may be repeated")
+                        .action(ArgAction::Append),
+                )
+                .arg(
+                    Arg::new("piece-length")
+                        .long("piece-length")
+                        .help("Piece length in bytes, chosen from the content size by default")
+                        .value_parser(clap::value_parser!(usize)),
+                ),
+        )
+        .subcommand(
+            Command::new("check")
+                .about("Check torrent file(s) and report every problem found")
+                .arg(torrents_arg()),
+        )
+        .subcommand(
+            Command::new("scrape")
+                .about("Ask the tracker how many peers share torrent file(s)")
+                .arg(torrents_arg()),
+        )
+        .subcommand(
+            Command::new("magnet")
+                .about("Print the magnet link of torrent file(s)")
+                .arg(torrents_arg()),
+        )
+}
 
-    let torrents = matches
-        .get_many::<String>("torrent file(s)")
+#[tokio::main]
+async fn main() {
+    let matches = cli().get_matches();
+    let log = matches
+        .get_one::<String>("log-level")
+        .and_then(|name| LogLevel::from_name(name))
+        .unwrap_or_default()
+        .raised(matches.get_count("verbose"));
+
+    let result = match matches.subcommand() {
+        Some(("info", matches)) => info_command(matches),
+        Some(("download", matches)) => download_command(matches, log).await,
+        Some(("seed", matches)) => seed_command(matches, log).await,
+        Some(("create", matches)) => create_command(matches).fail_as(Failure::Disk),
+        Some(("check", matches)) => check_command(matches),
+        Some(("scrape", matches)) => scrape_command(matches).await,
+        Some(("magnet", matches)) => magnet_command(matches),
+        _ => unreachable!("clap requires one of the subcommands"),
+    };
+    if let Err(Failed(failure, e)) = result {
+        eprintln!("Error: {e:#}");
+        std::process::exit(failure as i32);
+    }
+}
+
+fn torrent_files(matches: &ArgMatches) -> impl Iterator<Item = &str> {
+    matches
+        .get_many::<String>("torrent")
         .unwrap_or_default()
         .map(|v| v.as_str())
-        .collect::<Vec<_>>();
+}
 
-    let ppf = matches.get_count("Pretty print file");
-    let dp = matches.get_count("Dump peer(s)");
-    let log = matches.get_count("Verbose");
+/// Reads and parses a torrent file, v2 part included.
+fn load_torrent(torrent_file: &str) -> Result<MetaInfo, Failed> {
+    let contents = fs::read(torrent_file)
+        .with_context(|| format!("read {torrent_file}"))
+        .fail_as(Failure::Torrent)?;
+    MetaInfo::from_bytes(&contents)
+        .with_context(|| torrent_file.to_string())
+        .fail_as(Failure::Torrent)
+}
 
-    for torrent_file in torrents {
-        let mut info_string = String::from("");
-        match encode_info_field(torrent_file) {
-            Ok(string) => {
-                info_string = string;
+fn info_command(matches: &ArgMatches) -> Result<(), Failed> {
+    let format = matches
+        .get_one::<String>("format")
+        .and_then(|name| format::Format::from_name(name))
+        .unwrap_or_default();
+    for torrent_file in torrent_files(matches) {
+        let meta_info = load_torrent(torrent_file)?;
+        let rendered = format::render(&meta_info, format).fail_as(Failure::Torrent)?;
+        println!("{rendered}");
+    }
+    Ok(())
+}
+
+async fn download_command(matches: &ArgMatches, log: LogLevel) -> Result<(), Failed> {
+    let output = output::OutputConfig {
+        dir: matches
+            .get_one::<PathBuf>("output-dir")
+            .expect("has a default")
+            .clone(),
+        incomplete_dir: matches.get_one::<PathBuf>("incomplete-dir").cloned(),
+        existing: matches
+            .get_one::<String>("existing")
+            .and_then(|name| output::ExistingPolicy::from_name(name))
+            .expect("has a default"),
+    };
+    let config = SessionConfig {
+        download: DownloadConfig {
+            max_peers: *matches
+                .get_one::<usize>("max-peers")
+                .expect("has a default"),
+            port: *matches.get_one::<u16>("port").expect("has a default"),
+            order: if matches.get_flag("sequential") {
+                PieceOrder::Sequential
+            } else {
+                PieceOrder::Available
+            },
+            log,
+            ..Default::default()
+        },
+        output: output.clone(),
+        max_connections: *matches
+            .get_one::<usize>("max-connections")
+            .expect("has a default"),
+        rates: rate_config(matches),
+        ban_file: matches.get_one::<PathBuf>("ban-file").cloned(),
+        dht: dht_config(matches),
+        lsd: matches.get_flag("lsd").then(LsdConfig::default),
+    };
+    let session = Session::start(config).await.fail_as(Failure::Network)?;
+
+    let globs = |name: &str| -> Vec<String> {
+        matches
+            .get_many::<String>(name)
+            .unwrap_or_default()
+            .cloned()
+            .collect()
+    };
+    let (only, exclude) = (globs("only"), globs("exclude"));
+    let mut added = Vec::new();
+    for torrent_file in torrent_files(matches) {
+        if torrent_file.starts_with("magnet:") {
+            if !only.is_empty() || !exclude.is_empty() {
+                return Err(Failed(
+                    Failure::Torrent,
+                    anyhow::anyhow!("--only and --exclude need a torrent file, not a magnet link"),
+                ));
             }
-            Err(e) => {
-                println!("Failed to extract info field");
-                std::process::exit(1);
+            let torrent = session.add_magnet(torrent_file).fail_as(Failure::Torrent)?;
+            added.push((torrent_file, torrent));
+            continue;
+        }
+        let meta_info = load_torrent(torrent_file)?;
+        if let Some(path) = output.skips(&meta_info.info.name) {
+            if log >= LogLevel::Info {
+                println!("{torrent_file}: {} already exists, skipped", path.display());
             }
+            continue;
         }
+        if let (Some(announce), true) = (&meta_info.announce, log >= LogLevel::Debug) {
+            println!(
+                "{}: tracker: requesting peers to {announce}",
+                &info_hash_to_string(&meta_info.swarm_info_hash())[..6],
+            );
+        }
+        let priorities = select::file_priorities(&meta_info.info, &only, &exclude);
+        let torrent = session
+            .add_torrent_with_priorities(meta_info, priorities)
+            .fail_as(Failure::Torrent)?;
+        added.push((torrent_file, torrent));
+    }
 
-        let contents = read_content(torrent_file).unwrap();
-
-        match decode_bencoded_string(contents) {
-            Ok(map) => {
-                // Use the map here
-                let meta_info = parsing::parse_metainfo(map.clone());
-                if ppf == 1 {
-                    println!("{{\n{}\n}}\n", meta_info);
-                } else {
-                    let mut hasher = Sha1::new();
-                    hasher.update(info_string.as_bytes());
-                    let info_hash: [u8; 20] = hasher
-                        .finalize()
-                        .try_into()
-                        .expect("GenericArray<, 20> == [; 20]");
-
-                    let info_hash_6_bytes = info_hash_to_string(&info_hash)[..6].to_string();
-
-                    
-                    if log == 1 {
-                        println!(
-                            "{}: tracker: requesting peers to {}",
-                            info_hash_6_bytes, meta_info.announce
-                        );
-                    }
-                    let tracker_reponse = send_request(map.clone(), info_hash).await;
-                    if dp == 1 {
-                        dump_peers(tracker_reponse.clone());
-                    }
-
-                    let peers = tracker_reponse.clone().peers.0;
-
-                    for (i, peer_ip) in peers.iter().enumerate() {
-                        let handshake_result = time::timeout(
-                            Duration::from_secs(5),
-                            handshake_peer(*peer_ip, info_hash),
-                        )
-                        .await;
-
-                        match handshake_result {
-                            Ok(peer) => {
-                                if log == 1 {
-                                    println!(
-                                        "{}: peers: connect: {}: handshake",
-                                        info_hash_6_bytes, peer_ip
-                                    );
-                                    println!(
-                                        "{}: msg: send: {}: handshake",
-                                        info_hash_6_bytes, peer_ip
-                                    );
-                                    println!(
-                                        "{}: msg: recv: {}: handshake",
-                                        info_hash_6_bytes, peer_ip
-                                    );
-                                }
-
-                                let mut peer = tokio_util::codec::Framed::new(peer, MessageFrame);
-                                let bitfield = peer
-                                    .next()
-                                    .await
-                                    .expect("peer always sends a bitfields")
-                                    .context("peer message was invalid")
-                                    .unwrap();
-
-                                if log == 1 {
-                                    println!(
-                                        "{}: msg: recv: {}: bitfield {:?}",
-                                        info_hash_6_bytes, peer_ip, bitfield.payload
-                                    );
-                                }
-
-                                peer.send(Message {
-                                    tag: MessageTag::Interested,
-                                    payload: Vec::new(),
-                                })
-                                .await
-                                .context("send interested message")
-                                .unwrap();
-                                if log == 1 {
-                                    println!(
-                                        "{}: msg: send: {}: interested",
-                                        info_hash_6_bytes, peer_ip
-                                    );
-                                }
-
-                                let unchoke = peer
-                                    .next()
-                                    .await
-                                    .expect("peer always sends an unchoke")
-                                    .context("peer message was invalid")
-                                    .unwrap();
-
-                                if log == 1 {
-                                    println!(
-                                        "{}: msg: recv: {}: unchoke",
-                                        info_hash_6_bytes, peer_ip
-                                    );
-                                }
-
-                                let piece_i = 0;
-
-                                println!("{}", meta_info.info.pieces.len());
-
-                                let piece_hash = meta_info.info.pieces[piece_i];
-                                let torrent_length = compute_length(&meta_info.info);
-                                let piece_size = if piece_i == meta_info.info.pieces.len() - 1 {
-                                    let md = torrent_length % meta_info.info.piece_length;
-                                    if md == 0 {
-                                        meta_info.info.piece_length
-                                    } else {
-                                        md
-                                    }
-                                } else {
-                                    meta_info.info.piece_length
-                                };
-
-                                let nblocks = (piece_size + (BLOCK_MAX - 1)) / BLOCK_MAX;
-                                println!("{nblocks}");
-                                let mut all_blocks = Vec::with_capacity(piece_size);
-                                for block in 0..nblocks {
-                                    let block_size = if block == nblocks - 1 {
-                                        let md = piece_size % BLOCK_MAX;
-                                        if md == 0 {
-                                            BLOCK_MAX
-                                        } else {
-                                            md
-                                        }
-                                    } else {
-                                        BLOCK_MAX
-                                    };
-                                    let mut request = Request::new(
-                                        piece_i as u32,
-                                        (block * BLOCK_MAX) as u32,
-                                        block_size as u32,
-                                    );
-                                    let request_bytes = Vec::from(request.as_bytes_mut());
-                                    peer.send(Message {
-                                        tag: MessageTag::Request,
-                                        payload: request_bytes,
-                                    })
-                                    .await
-                                    .with_context(|| format!("send request for block {block}"));
-
-                                    if log == 1 {
-                                        println!(
-                                            "{}: msg: send: {}: request {} {} {}",
-                                            info_hash_6_bytes,
-                                            peer_ip,
-                                            u32::from_ne_bytes(request.index),
-                                            u32::from_ne_bytes(request.begin),
-                                            u32::from_ne_bytes(request.length)
-                                        );
-                                    }
-
-                                    let piece = peer
-                                        .next()
-                                        .await
-                                        .expect("peer always sends a piece")
-                                        .context("peer message was invalid")
-                                        .unwrap();
-                                    if log == 1 {
-                                        println!(
-                                            "{}: msg: recv: {}: piece",
-                                            info_hash_6_bytes, peer_ip
-                                        );
-                                    }
-
-                                    let piece = Piece::ref_from_bytes(&piece.payload[..])
-                                        .expect("always get all Piece response fields from peer");
-                                    assert_eq!(piece.index() as usize, piece_i);
-                                    assert_eq!(piece.begin() as usize, block * BLOCK_MAX);
-                                    assert_eq!(piece.block().len(), block_size);
-                                    all_blocks.extend(piece.block());
-                                }
-                                assert_eq!(all_blocks.len(), piece_size);
-
-                                if log == 1 {
-                                    println!(
-                                        "{}: peers: disconnect: {}",
-                                        info_hash_6_bytes, peer_ip
-                                    );
-                                }
+    let failures = settle(added, log).await;
+    if failures.is_empty() && matches.get_flag("seed") {
+        if log >= LogLevel::Info {
+            println!("seeding on port {}, ctrl-c to stop", session.port());
+        }
+        tokio::signal::ctrl_c().await.fail_as(Failure::Network)?;
+    }
+    session.shutdown().await.fail_as(Failure::Disk)?;
+    match failures.first() {
+        Some(&failure) => Err(Failed(
+            failure,
+            anyhow::anyhow!("{} torrent(s) failed", failures.len()),
+        )),
+        None => Ok(()),
+    }
+}
 
-                                let output = PathBuf::from(&meta_info.info.name);
-                                tokio::fs::write(&output, all_blocks)
-                                    .await
-                                    .context("write out downloaded piece")
-                                    .unwrap();
-                                println!("Piece {piece_i} downloaded to {}.", output.display());
-                            }
-                            Err(_) => {
-                                //println!("Handshake timed out with peer: {}, trying next...", peer);
-                            }
-                        }
+/// Waits for each torrent to be complete or to fail, and returns how the
+/// failed ones did.
+async fn settle(torrents: Vec<(&str, TorrentHandle)>, log: LogLevel) -> Vec<Failure> {
+    let mut failures = Vec::new();
+    for (torrent_file, torrent) in torrents {
+        let settled = torrent
+            .wait()
+            .await
+            .unwrap_or_else(|e| TorrentState::Error(Failure::Network, format!("{e:#}")));
+        match settled {
+            TorrentState::Error(failure, message) => {
+                if log >= LogLevel::Error {
+                    eprintln!("Error: {torrent_file}: {message}");
+                }
+                failures.push(failure);
+            }
+            _ => {
+                if log >= LogLevel::Info {
+                    if let Some(path) = torrent.status().ok().and_then(|status| status.location) {
+                        println!("{torrent_file}: complete in {}", path.display());
                     }
-                    
-                    //download::all(map, info_hash).await;
                 }
             }
+        }
+    }
+    failures
+}
+
+async fn seed_command(matches: &ArgMatches, log: LogLevel) -> Result<(), Failed> {
+    let torrent_file = matches.get_one::<String>("torrent").expect("required");
+    let config = SessionConfig {
+        download: DownloadConfig {
+            port: *matches.get_one::<u16>("port").expect("has a default"),
+            log,
+            ..Default::default()
+        },
+        output: output::OutputConfig {
+            dir: matches
+                .get_one::<PathBuf>("data-dir")
+                .expect("has a default")
+                .clone(),
+            ..Default::default()
+        },
+        rates: rate_config(matches),
+        ban_file: matches.get_one::<PathBuf>("ban-file").cloned(),
+        dht: dht_config(matches),
+        lsd: matches.get_flag("lsd").then(LsdConfig::default),
+        ..Default::default()
+    };
+    let meta_info = load_torrent(torrent_file)?;
+    let session = Session::start(config).await.fail_as(Failure::Network)?;
+    let torrent = session.add_torrent(meta_info).fail_as(Failure::Torrent)?;
+
+    let failures = settle(vec![(torrent_file.as_str(), torrent)], log).await;
+    if let Some(&failure) = failures.first() {
+        return Err(Failed(
+            failure,
+            anyhow::anyhow!("{torrent_file} could not be seeded"),
+        ));
+    }
+    if log >= LogLevel::Info {
+        println!(
+            "{torrent_file}: seeding on port {}, ctrl-c to stop",
+            session.port()
+        );
+    }
+    tokio::signal::ctrl_c().await.fail_as(Failure::Network)?;
+    session.shutdown().await.fail_as(Failure::Disk)
+}
 
+/// Prints the validation report of each torrent.
+fn check_command(matches: &ArgMatches) -> Result<(), Failed> {
+    let mut invalid = 0;
+    for torrent_file in torrent_files(matches) {
+        let report = match fs::read(torrent_file) {
+            Ok(contents) => validate::validate(&contents),
             Err(e) => {
-                println!("Failed to decode: {}", e);
-                std::process::exit(1);
+                println!("{torrent_file}: {e}");
+                invalid += 1;
+                continue;
+            }
+        };
+        if report.is_valid() {
+            println!("{torrent_file}: valid");
+        } else {
+            invalid += 1;
+            for problem in &report.problems {
+                println!("{torrent_file}: {}: {}", problem.field, problem.message);
             }
         }
     }
+    if invalid > 0 {
+        return Err(Failed(
+            Failure::Torrent,
+            anyhow::anyhow!("{invalid} torrent file(s) are invalid"),
+        ));
+    }
+    Ok(())
 }
 
-fn urlencode(t: &[u8; 20]) -> String {
-    let mut encoded = String::with_capacity(3 * t.len());
-    for &byte in t {
-        encoded.push('%');
-        encoded.push_str(&hex::encode(&[byte]));
+async fn scrape_command(matches: &ArgMatches) -> Result<(), Failed> {
+    for torrent_file in torrent_files(matches) {
+        let meta_info = load_torrent(torrent_file)?;
+        let announce = meta_info
+            .announce
+            .as_deref()
+            .with_context(|| format!("{torrent_file} has no tracker"))
+            .fail_as(Failure::Torrent)?;
+        let stats = tracker::scrape(announce, meta_info.swarm_info_hash())
+            .await
+            .fail_as(Failure::Network)?;
+        println!(
+            "{torrent_file}: {} seeders, {} leechers, {} downloads",
+            stats.complete, stats.incomplete, stats.downloaded
+        );
     }
-    encoded
+    Ok(())
 }
 
-fn info_hash_to_string(t: &[u8; 20]) -> String {
-    let mut encoded = String::with_capacity(2 * t.len());
-    for &byte in t {
-        encoded.push_str(&hex::encode(&[byte]));
+fn magnet_command(matches: &ArgMatches) -> Result<(), Failed> {
+    for torrent_file in torrent_files(matches) {
+        let meta_info = load_torrent(torrent_file)?;
+        println!("{}", MagnetLink::from_meta_info(&meta_info));
     }
-    encoded
+    Ok(())
+}
+
+fn create_command(matches: &ArgMatches) -> anyhow::Result<()> {
+    let path = PathBuf::from(matches.get_one::<String>("path").expect("required"));
+    let strings = |id| {
+        matches
+            .get_many::<String>(id)
+            .unwrap_or_default()
+            .cloned()
+            .collect::<Vec<_>>()
+    };
+    let options = create::CreateOptions {
+        announce: matches.get_one::<String>("announce").cloned(),
+        announce_list: strings("announce-list")
+            .iter()
+            .map(|tier| tier.split(',').map(String::from).collect())
+            .collect(),
+        comment: matches.get_one::<String>("comment").cloned(),
+        created_by: matches.get_one::<String>("created-by").cloned(),
+        creation_date: None,
+        private: matches.get_flag("private"),
+        url_list: strings("url-list"),
+        piece_length: matches.get_one::<usize>("piece-length").copied(),
+    };
+    let created = create::create_torrent(&path, &options)?;
+    let output = match matches.get_one::<String>("output") {
+        Some(output) => PathBuf::from(output),
+        None => {
+            let name = path.file_name().context("path has no file name")?;
+            PathBuf::from(format!("{}.torrent", name.to_string_lossy()))
+        }
+    };
+    fs::write(&output, &created.bytes).with_context(|| format!("write {}", output.display()))?;
+    println!(
+        "{}: created {}",
+        info_hash_to_string(&created.info_hash),
+        output.display()
+    );
+    Ok(())
+}
+
+fn info_hash_to_string(t: &[u8; 20]) -> String {
+    hex::encode(t)
 }
@@ -0,0 +1,268 @@
+use crate::bdecoder::{self, Decoder};
+use crate::extension::{self, ExtendedHandshake, UT_METADATA, UT_METADATA_ID};
+use crate::magnet::MagnetLink;
+use crate::peers::{self, ConnectConfig, Message, MessageFrame, MessageTag};
+use anyhow::Context;
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use std::net::SocketAddrV4;
+use std::time::Duration;
+use tokio_util::codec::Framed;
+
+/// Size of the pieces the info dictionary is exchanged in.
+pub(crate) const PIECE_SIZE: usize = 1 << 14;
+
+/// Largest info dictionary we accept from a peer.
+const MAX_SIZE: usize = 1 << 24;
+
+/// Time a peer has to send the whole info dictionary.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+const REQUEST: i64 = 0;
+const DATA: i64 = 1;
+const REJECT: i64 = 2;
+
+/// A `ut_metadata` message, without the piece data that follows it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct MetadataMessage {
+    pub msg_type: i64,
+    pub piece: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub total_size: Option<i64>,
+}
+
+impl MetadataMessage {
+    pub(crate) fn request(piece: usize) -> Self {
+        Self {
+            msg_type: REQUEST,
+            piece: piece as i64,
+            total_size: None,
+        }
+    }
+
+    pub(crate) fn reject(piece: i64) -> Self {
+        Self {
+            msg_type: REJECT,
+            piece,
+            total_size: None,
+        }
+    }
+
+    pub(crate) fn is_request(&self) -> bool {
+        self.msg_type == REQUEST
+    }
+
+    /// Body of a message, followed by `data` for data messages.
+    pub(crate) fn to_bytes(&self, data: &[u8]) -> Vec<u8> {
+        let mut bytes = bdecoder::to_bytes(self).expect("the message encodes");
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    /// Splits a message body into the message and the data after it.
+    pub(crate) fn from_bytes(body: &[u8]) -> anyhow::Result<(Self, &[u8])> {
+        let (value, length) = Decoder::new(body)
+            .decode_prefix()
+            .context("decode ut_metadata message")?;
+        let message = Self::deserialize(&value).context("decode ut_metadata message")?;
+        Ok((message, &body[length..]))
+    }
+}
+
+/// The data message answering a request for `piece` of `metadata`, or a
+/// reject when there is no such piece.
+pub(crate) fn answer(metadata: &[u8], piece: i64) -> Vec<u8> {
+    let start = usize::try_from(piece).map_or(usize::MAX, |piece| piece * PIECE_SIZE);
+    if start >= metadata.len() {
+        return MetadataMessage::reject(piece).to_bytes(&[]);
+    }
+    let data = &metadata[start..metadata.len().min(start + PIECE_SIZE)];
+    let message = MetadataMessage {
+        msg_type: DATA,
+        piece,
+        total_size: Some(metadata.len() as i64),
+    };
+    message.to_bytes(data)
+}
+
+/// The extended handshake of a connection used for `ut_metadata`.
+pub(crate) fn handshake(metadata_size: Option<usize>) -> ExtendedHandshake {
+    let mut handshake = ExtendedHandshake::ours(None);
+    handshake.m.clear();
+    handshake
+        .m
+        .insert(String::from(UT_METADATA), UT_METADATA_ID as i64);
+    handshake.metadata_size = metadata_size.map(|size| size as i64);
+    handshake
+}
+
+/// Downloads the info dictionary of `info_hash` from the first of `peers`
+/// that serves it intact.
+pub(crate) async fn fetch(
+    info_hash: [u8; 20],
+    peers: &[SocketAddrV4],
+    connect: &ConnectConfig,
+) -> anyhow::Result<Vec<u8>> {
+    let mut last_error = anyhow::anyhow!("no peers to fetch the metadata from");
+    for &addr in peers {
+        let fetched = tokio::time::timeout(FETCH_TIMEOUT, fetch_from(addr, info_hash, connect))
+            .await
+            .unwrap_or_else(|_| Err(anyhow::anyhow!("timed out")));
+        match fetched {
+            Ok(info) => return Ok(info),
+            Err(e) => last_error = e.context(format!("fetch metadata from {addr}")),
+        }
+    }
+    Err(last_error)
+}
+
+async fn fetch_from(
+    addr: SocketAddrV4,
+    info_hash: [u8; 20],
+    connect: &ConnectConfig,
+) -> anyhow::Result<Vec<u8>> {
+    let (stream, theirs) = peers::connect(addr, info_hash, connect).await?;
+    anyhow::ensure!(
+        extension::supports_extensions(&theirs.reserved),
+        "peer does not support extensions"
+    );
+    let mut peer = Framed::new(connect.throttle.wrap(addr.ip(), stream), MessageFrame);
+    let ours = handshake(None).to_bytes()?;
+    peer.send(Message {
+        tag: MessageTag::Extended,
+        payload: extension::extended_payload(extension::HANDSHAKE_ID, &ours),
+    })
+    .await
+    .context("send extended handshake")?;
+
+    let mut metadata = Vec::new();
+    let mut missing = 0;
+    while let Some(message) = peer.next().await {
+        let message = message.context("peer message was invalid")?;
+        if message.tag != MessageTag::Extended {
+            continue;
+        }
+        let (&id, body) = message
+            .payload
+            .split_first()
+            .context("empty extended message")?;
+        match id {
+            extension::HANDSHAKE_ID => {
+                let theirs = ExtendedHandshake::from_bytes(body)?;
+                let remote_id = theirs
+                    .id_of(UT_METADATA)
+                    .context("peer does not serve metadata")?;
+                let size = theirs
+                    .metadata_size
+                    .and_then(|size| usize::try_from(size).ok())
+                    .filter(|size| (1..=MAX_SIZE).contains(size))
+                    .context("peer sent no valid metadata size")?;
+                metadata = vec![0; size];
+                missing = size.div_ceil(PIECE_SIZE);
+                for piece in 0..missing {
+                    let request = MetadataMessage::request(piece).to_bytes(&[]);
+                    peer.send(Message {
+                        tag: MessageTag::Extended,
+                        payload: extension::extended_payload(remote_id, &request),
+                    })
+                    .await
+                    .context("request metadata")?;
+                }
+            }
+            UT_METADATA_ID => {
+                let (message, data) = MetadataMessage::from_bytes(body)?;
+                anyhow::ensure!(message.msg_type != REJECT, "peer rejected the request");
+                if message.msg_type != DATA {
+                    continue;
+                }
+                let start = usize::try_from(message.piece)
+                    .ok()
+                    .map(|piece| piece * PIECE_SIZE)
+                    .filter(|&start| start < metadata.len())
+                    .context("peer sent a piece we did not request")?;
+                let end = metadata.len().min(start + PIECE_SIZE);
+                anyhow::ensure!(data.len() == end - start, "metadata piece has a bad length");
+                metadata[start..end].copy_from_slice(data);
+                missing -= 1;
+                if missing == 0 {
+                    anyhow::ensure!(
+                        Sha1::digest(&metadata)[..] == info_hash[..],
+                        "metadata does not match the info hash"
+                    );
+                    return Ok(metadata);
+                }
+            }
+            _ => {
+                // extension we never advertised
+            }
+        }
+    }
+    anyhow::bail!("peer left before sending the metadata")
+}
+
+/// A `.torrent` file made of the info dictionary fetched for `magnet`.
+pub(crate) fn torrent_bytes(magnet: &MagnetLink, info: &[u8]) -> Vec<u8> {
+    fn string(out: &mut Vec<u8>, s: &str) {
+        out.extend(format!("{}:", s.len()).as_bytes());
+        out.extend(s.as_bytes());
+    }
+    fn list(out: &mut Vec<u8>, items: &[String]) {
+        out.push(b'l');
+        for item in items {
+            string(out, item);
+        }
+        out.push(b'e');
+    }
+
+    // keys in sorted order
+    let mut torrent = b"d".to_vec();
+    string(&mut torrent, "announce");
+    string(
+        &mut torrent,
+        magnet.trackers.first().map_or("", String::as_str),
+    );
+    if magnet.trackers.len() > 1 {
+        string(&mut torrent, "announce-list");
+        torrent.push(b'l');
+        for tracker in &magnet.trackers {
+            list(&mut torrent, std::slice::from_ref(tracker));
+        }
+        torrent.push(b'e');
+    }
+    string(&mut torrent, "info");
+    torrent.extend_from_slice(info);
+    if !magnet.web_seeds.is_empty() {
+        string(&mut torrent, "url-list");
+        list(&mut torrent, &magnet.web_seeds);
+    }
+    torrent.push(b'e');
+    torrent
+}
+
+#[test]
+fn metadata_messages_carry_their_data() {
+    let metadata: Vec<u8> = (0..PIECE_SIZE + 10).map(|i| i as u8).collect();
+    let second = answer(&metadata, 1);
+    let (message, data) = MetadataMessage::from_bytes(&second).unwrap();
+    assert_eq!(message.total_size, Some(metadata.len() as i64));
+    assert_eq!(data, &metadata[PIECE_SIZE..]);
+    let past_end = answer(&metadata, 2);
+    let (message, data) = MetadataMessage::from_bytes(&past_end).unwrap();
+    assert_eq!((message, data), (MetadataMessage::reject(2), &[][..]));
+
+    let info = b"d6:lengthi3e4:name1:a12:piece lengthi4e6:pieces20:bbbbbbbbbbbbbbbbbbbbe";
+    let magnet = MagnetLink {
+        info_hash: crate::parsing::InfoHash(Sha1::digest(info).into()),
+        name: None,
+        trackers: vec![
+            String::from("http://a/announce"),
+            String::from("http://b/announce"),
+        ],
+        web_seeds: vec![String::from("http://seed/")],
+    };
+    let meta_info = crate::parsing::parse_metainfo(&torrent_bytes(&magnet, info)).unwrap();
+    assert_eq!(meta_info.info_hash, magnet.info_hash);
+    assert_eq!(meta_info.info_bytes(), info);
+    assert_eq!(meta_info.url_list, magnet.web_seeds);
+}
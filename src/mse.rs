@@ -0,0 +1,488 @@
+use anyhow::Context;
+use num_bigint::BigUint;
+use sha1::{Digest, Sha1};
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+/// 768-bit safe prime of the MSE Diffie-Hellman exchange.
+const PRIME: &[u8] = b"FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F14374FE1356D6D51C245E485B576625E7EC6F44C42E9A63A36210000000000090563";
+const GENERATOR: u32 = 2;
+const KEY_LEN: usize = 96;
+const MAX_PAD: usize = 512;
+const VC: [u8; 8] = [0; 8];
+
+pub const CRYPTO_PLAINTEXT: u32 = 0x01;
+pub const CRYPTO_RC4: u32 = 0x02;
+
+/// Whether connections use Message Stream Encryption.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EncryptionPolicy {
+    /// Plaintext BitTorrent handshakes only.
+    Disabled,
+    /// Try MSE first and fall back to plaintext; accept both.
+    #[default]
+    Enabled,
+    /// MSE with RC4 only.
+    Forced,
+}
+
+impl EncryptionPolicy {
+    /// `crypto_provide` bits sent when we initiate a connection.
+    fn provide(self) -> u32 {
+        match self {
+            EncryptionPolicy::Forced => CRYPTO_RC4,
+            _ => CRYPTO_RC4 | CRYPTO_PLAINTEXT,
+        }
+    }
+
+    /// Method picked among those `provide`d by the initiator.
+    fn select(self, provide: u32) -> Option<u32> {
+        if provide & CRYPTO_RC4 != 0 && self != EncryptionPolicy::Disabled {
+            Some(CRYPTO_RC4)
+        } else if provide & CRYPTO_PLAINTEXT != 0 && self != EncryptionPolicy::Forced {
+            Some(CRYPTO_PLAINTEXT)
+        } else {
+            None
+        }
+    }
+}
+
+/// RC4 keystream, with the first 1024 bytes discarded as MSE requires.
+#[derive(Clone)]
+pub struct Rc4 {
+    s: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    pub fn new(key: &[u8]) -> Self {
+        let mut s = [0u8; 256];
+        for (i, x) in s.iter_mut().enumerate() {
+            *x = i as u8;
+        }
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(s[i]).wrapping_add(key[i % key.len()]);
+            s.swap(i, j as usize);
+        }
+        let mut rc4 = Self { s, i: 0, j: 0 };
+        rc4.apply(&mut [0u8; 1024]);
+        rc4
+    }
+
+    pub fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.s[self.i as usize]);
+            self.s.swap(self.i as usize, self.j as usize);
+            let k =
+                self.s[(self.s[self.i as usize].wrapping_add(self.s[self.j as usize])) as usize];
+            *byte ^= k;
+        }
+    }
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+fn xor(a: [u8; 20], b: [u8; 20]) -> [u8; 20] {
+    let mut out = [0u8; 20];
+    for i in 0..20 {
+        out[i] = a[i] ^ b[i];
+    }
+    out
+}
+
+fn to_key(n: &BigUint) -> [u8; KEY_LEN] {
+    let bytes = n.to_bytes_be();
+    let mut key = [0u8; KEY_LEN];
+    key[KEY_LEN - bytes.len()..].copy_from_slice(&bytes);
+    key
+}
+
+/// Our DH private key and the public key sent to the other side.
+fn keypair() -> (BigUint, [u8; KEY_LEN]) {
+    let prime = BigUint::parse_bytes(PRIME, 16).expect("valid prime");
+    let private = BigUint::from_bytes_be(&rand::random::<[u8; 20]>());
+    let public = BigUint::from(GENERATOR).modpow(&private, &prime);
+    (private, to_key(&public))
+}
+
+fn shared_secret(private: &BigUint, other: &[u8]) -> [u8; KEY_LEN] {
+    let prime = BigUint::parse_bytes(PRIME, 16).expect("valid prime");
+    to_key(&BigUint::from_bytes_be(other).modpow(private, &prime))
+}
+
+fn random_pad() -> Vec<u8> {
+    let len = rand::random::<usize>() % (MAX_PAD + 1);
+    (0..len).map(|_| rand::random()).collect()
+}
+
+/// Reads until the stream produces `marker`, giving up after `limit` bytes.
+async fn synchronize<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    marker: &[u8],
+    limit: usize,
+) -> anyhow::Result<()> {
+    let mut window = Vec::with_capacity(limit);
+    while !window.ends_with(marker) {
+        anyhow::ensure!(window.len() < limit, "mse synchronization marker not found");
+        window.push(stream.read_u8().await.context("read mse padding")?);
+    }
+    Ok(())
+}
+
+/// Performs the initiating side of the MSE handshake for the torrent
+/// `info_hash`.
+pub async fn initiate<S>(
+    mut stream: S,
+    info_hash: [u8; 20],
+    policy: EncryptionPolicy,
+) -> anyhow::Result<MseStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (private, ya) = keypair();
+    stream.write_all(&ya).await.context("send mse public key")?;
+    stream
+        .write_all(&random_pad())
+        .await
+        .context("send mse padding")?;
+
+    let mut yb = [0u8; KEY_LEN];
+    stream
+        .read_exact(&mut yb)
+        .await
+        .context("read mse public key")?;
+    let secret = shared_secret(&private, &yb);
+    let mut encrypt = Rc4::new(&hash(&[b"keyA", &secret, &info_hash]));
+    let mut decrypt = Rc4::new(&hash(&[b"keyB", &secret, &info_hash]));
+
+    let mut message = Vec::new();
+    message.extend_from_slice(&hash(&[b"req1", &secret]));
+    message.extend_from_slice(&xor(
+        hash(&[b"req2", &info_hash]),
+        hash(&[b"req3", &secret]),
+    ));
+    let mut encrypted = Vec::new();
+    encrypted.extend_from_slice(&VC);
+    encrypted.extend_from_slice(&policy.provide().to_be_bytes());
+    encrypted.extend_from_slice(&0u16.to_be_bytes()); // len(PadC)
+    encrypted.extend_from_slice(&0u16.to_be_bytes()); // len(IA)
+    encrypt.apply(&mut encrypted);
+    message.extend_from_slice(&encrypted);
+    stream
+        .write_all(&message)
+        .await
+        .context("send mse crypto provide")?;
+
+    let mut vc = VC;
+    decrypt.apply(&mut vc);
+    synchronize(&mut stream, &vc, MAX_PAD + VC.len()).await?;
+
+    let mut select = [0u8; 6];
+    stream
+        .read_exact(&mut select)
+        .await
+        .context("read mse crypto select")?;
+    decrypt.apply(&mut select);
+    let crypto = u32::from_be_bytes(select[..4].try_into().expect("4 bytes"));
+    let mut pad = vec![0u8; u16::from_be_bytes([select[4], select[5]]) as usize];
+    anyhow::ensure!(pad.len() <= MAX_PAD, "mse padding too long");
+    stream
+        .read_exact(&mut pad)
+        .await
+        .context("read mse padding")?;
+    decrypt.apply(&mut pad);
+
+    match crypto {
+        CRYPTO_RC4 => Ok(MseStream::encrypted(stream, encrypt, decrypt, Vec::new())),
+        CRYPTO_PLAINTEXT if policy != EncryptionPolicy::Forced => {
+            Ok(MseStream::plaintext(stream, Vec::new()))
+        }
+        _ => anyhow::bail!("peer selected unsupported crypto method {crypto:#x}"),
+    }
+}
+
+/// Performs the receiving side of the MSE handshake. `prefix` holds bytes
+/// already read from the stream while detecting the protocol. Returns the
+/// stream and the info hash, among `info_hashes`, the initiator asked for.
+pub async fn respond<S>(
+    mut stream: S,
+    prefix: &[u8],
+    info_hashes: &[[u8; 20]],
+    policy: EncryptionPolicy,
+) -> anyhow::Result<(MseStream<S>, [u8; 20])>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    anyhow::ensure!(prefix.len() <= KEY_LEN, "protocol detection read too much");
+    let mut ya = [0u8; KEY_LEN];
+    ya[..prefix.len()].copy_from_slice(prefix);
+    stream
+        .read_exact(&mut ya[prefix.len()..])
+        .await
+        .context("read mse public key")?;
+
+    let (private, yb) = keypair();
+    stream.write_all(&yb).await.context("send mse public key")?;
+    stream
+        .write_all(&random_pad())
+        .await
+        .context("send mse padding")?;
+    let secret = shared_secret(&private, &ya);
+
+    synchronize(&mut stream, &hash(&[b"req1", &secret]), MAX_PAD + 20).await?;
+    let mut skey_hash = [0u8; 20];
+    stream
+        .read_exact(&mut skey_hash)
+        .await
+        .context("read mse skey hash")?;
+    let skey_hash = xor(skey_hash, hash(&[b"req3", &secret]));
+    let info_hash = *info_hashes
+        .iter()
+        .find(|info_hash| hash(&[b"req2", &info_hash[..]]) == skey_hash)
+        .context("mse handshake for an unknown torrent")?;
+
+    let mut decrypt = Rc4::new(&hash(&[b"keyA", &secret, &info_hash]));
+    let mut encrypt = Rc4::new(&hash(&[b"keyB", &secret, &info_hash]));
+
+    let mut provide = [0u8; 14];
+    stream
+        .read_exact(&mut provide)
+        .await
+        .context("read mse crypto provide")?;
+    decrypt.apply(&mut provide);
+    anyhow::ensure!(provide[..8] == VC, "invalid mse verification constant");
+    let crypto_provide = u32::from_be_bytes(provide[8..12].try_into().expect("4 bytes"));
+    let mut pad = vec![0u8; u16::from_be_bytes([provide[12], provide[13]]) as usize];
+    anyhow::ensure!(pad.len() <= MAX_PAD, "mse padding too long");
+    stream
+        .read_exact(&mut pad)
+        .await
+        .context("read mse padding")?;
+    decrypt.apply(&mut pad);
+    let mut ia_len = [0u8; 2];
+    stream
+        .read_exact(&mut ia_len)
+        .await
+        .context("read mse initial payload")?;
+    decrypt.apply(&mut ia_len);
+    let mut initial_payload = vec![0u8; u16::from_be_bytes(ia_len) as usize];
+    stream
+        .read_exact(&mut initial_payload)
+        .await
+        .context("read mse initial payload")?;
+    decrypt.apply(&mut initial_payload);
+
+    let crypto = policy
+        .select(crypto_provide)
+        .with_context(|| format!("no acceptable crypto method in {crypto_provide:#x}"))?;
+    let mut select = Vec::new();
+    select.extend_from_slice(&VC);
+    select.extend_from_slice(&crypto.to_be_bytes());
+    select.extend_from_slice(&0u16.to_be_bytes()); // len(PadD)
+    encrypt.apply(&mut select);
+    stream
+        .write_all(&select)
+        .await
+        .context("send mse crypto select")?;
+
+    let stream = if crypto == CRYPTO_RC4 {
+        MseStream::encrypted(stream, encrypt, decrypt, initial_payload)
+    } else {
+        MseStream::plaintext(stream, initial_payload)
+    };
+    Ok((stream, info_hash))
+}
+
+/// A peer connection, RC4 obfuscated when MSE negotiated it.
+pub struct MseStream<S> {
+    inner: S,
+    encrypt: Option<Rc4>,
+    decrypt: Option<Rc4>,
+    /// Plaintext already received during the handshake.
+    read_ahead: Vec<u8>,
+    /// Ciphertext not yet written to `inner`.
+    write_pending: Vec<u8>,
+}
+
+impl<S> std::fmt::Debug for MseStream<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MseStream")
+            .field("encrypted", &self.encrypt.is_some())
+            .finish()
+    }
+}
+
+impl<S> MseStream<S> {
+    pub fn plaintext(inner: S, read_ahead: Vec<u8>) -> Self {
+        Self {
+            inner,
+            encrypt: None,
+            decrypt: None,
+            read_ahead,
+            write_pending: Vec::new(),
+        }
+    }
+
+    fn encrypted(inner: S, encrypt: Rc4, decrypt: Rc4, read_ahead: Vec<u8>) -> Self {
+        Self {
+            inner,
+            encrypt: Some(encrypt),
+            decrypt: Some(decrypt),
+            read_ahead,
+            write_pending: Vec::new(),
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.encrypt.is_some()
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S: AsyncWrite + Unpin> MseStream<S> {
+    fn poll_write_pending(&mut self, cx: &mut std::task::Context<'_>) -> Poll<io::Result<()>> {
+        while !self.write_pending.is_empty() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.write_pending))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write_pending.drain(..n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for MseStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.read_ahead.is_empty() {
+            let n = this.read_ahead.len().min(buf.remaining());
+            buf.put_slice(&this.read_ahead[..n]);
+            this.read_ahead.drain(..n);
+            return Poll::Ready(Ok(()));
+        }
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        if let Some(decrypt) = &mut this.decrypt {
+            decrypt.apply(&mut buf.filled_mut()[before..]);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for MseStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.encrypt.is_none() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+        // The keystream has to advance exactly once per byte, so encrypted
+        // bytes are kept until the inner stream accepted them.
+        ready!(this.poll_write_pending(cx))?;
+        let mut encrypted = buf.to_vec();
+        if let Some(encrypt) = &mut this.encrypt {
+            encrypt.apply(&mut encrypted);
+        }
+        this.write_pending = encrypted;
+        let _ = this.poll_write_pending(cx)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_pending(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_pending(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[test]
+fn rc4_matches_reference() {
+    // RC4("Key", "Plaintext") without the MSE discard, from the RC4 test vectors.
+    let mut rc4 = Rc4 {
+        s: std::array::from_fn(|i| i as u8),
+        i: 0,
+        j: 0,
+    };
+    let key = b"Key";
+    let mut j = 0u8;
+    for i in 0..256 {
+        j = j.wrapping_add(rc4.s[i]).wrapping_add(key[i % key.len()]);
+        rc4.s.swap(i, j as usize);
+    }
+    let mut data = *b"Plaintext";
+    rc4.apply(&mut data);
+    assert_eq!(hex::encode(data), "bbf316e8d940af0ad3");
+}
+
+#[tokio::test]
+async fn mse_handshake_negotiates_policies() {
+    let info_hash = [9u8; 20];
+    for (ours, theirs, encrypted) in [
+        (EncryptionPolicy::Enabled, EncryptionPolicy::Enabled, true),
+        (EncryptionPolicy::Forced, EncryptionPolicy::Enabled, true),
+        (EncryptionPolicy::Enabled, EncryptionPolicy::Disabled, false),
+    ] {
+        let (a, b) = tokio::io::duplex(4096);
+        let responder = tokio::spawn(async move {
+            let (mut stream, skey) = respond(b, &[], &[[1u8; 20], info_hash], theirs)
+                .await
+                .unwrap();
+            assert_eq!(skey, info_hash);
+            let mut ping = [0u8; 4];
+            stream.read_exact(&mut ping).await.unwrap();
+            stream.write_all(b"pong").await.unwrap();
+            stream.flush().await.unwrap();
+            ping
+        });
+        let mut stream = initiate(a, info_hash, ours).await.unwrap();
+        assert_eq!(stream.is_encrypted(), encrypted);
+        stream.write_all(b"ping").await.unwrap();
+        stream.flush().await.unwrap();
+        let mut pong = [0u8; 4];
+        stream.read_exact(&mut pong).await.unwrap();
+        assert_eq!(&pong, b"pong");
+        assert_eq!(&responder.await.unwrap(), b"ping");
+    }
+
+    let (a, b) = tokio::io::duplex(4096);
+    let responder =
+        tokio::spawn(
+            async move { respond(b, &[], &[info_hash], EncryptionPolicy::Disabled).await },
+        );
+    let _ = initiate(a, info_hash, EncryptionPolicy::Forced).await;
+    assert!(
+        responder.await.unwrap().is_err(),
+        "forced rc4 against disabled encryption"
+    );
+}
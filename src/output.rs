@@ -0,0 +1,301 @@
+use crate::parts;
+use anyhow::Context;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Longest file name most filesystems accept, in bytes.
+const MAX_NAME: usize = 255;
+
+/// Where downloads are written until complete without an incomplete directory.
+const INCOMPLETE_DIR: &str = ".incomplete";
+
+/// What to do when a download would land on something that exists.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExistingPolicy {
+    /// Leave the existing file or directory and drop the download.
+    Skip,
+    /// Replace the existing file or directory.
+    Overwrite,
+    /// Save the download next to it as `name (1)`, `name (2)`...
+    #[default]
+    Rename,
+}
+
+impl ExistingPolicy {
+    pub const NAMES: [&'static str; 3] = ["skip", "overwrite", "rename"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "skip" => Some(Self::Skip),
+            "overwrite" => Some(Self::Overwrite),
+            "rename" => Some(Self::Rename),
+            _ => None,
+        }
+    }
+}
+
+/// Where downloads go.
+#[derive(Debug, Clone)]
+pub struct OutputConfig {
+    pub dir: PathBuf,
+    /// Downloads are written here first and moved to `dir` once complete,
+    /// rather than to a hidden directory in `dir`.
+    pub incomplete_dir: Option<PathBuf>,
+    pub existing: ExistingPolicy,
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        Self {
+            dir: PathBuf::from("."),
+            incomplete_dir: None,
+            existing: ExistingPolicy::default(),
+        }
+    }
+}
+
+impl OutputConfig {
+    /// Where the download of torrent `name` would be skipped, if it would.
+    pub fn skips(&self, name: &str) -> Option<PathBuf> {
+        let destination = self.dir.join(sanitize_component(name));
+        (self.existing == ExistingPolicy::Skip && destination.exists()).then_some(destination)
+    }
+}
+
+/// Where a download ended up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Saved {
+    Written(PathBuf),
+    /// Something already existed there and the policy said to keep it.
+    Skipped(PathBuf),
+}
+
+/// Turns a name from a torrent into one this system can create: no
+/// separators, reserved characters or device names, and no trailing dots
+/// or spaces, which Windows drops. The same rules apply on every system so
+/// a download gets the same names wherever it is saved.
+pub fn sanitize_component(name: &str) -> String {
+    let mut name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_control() || r#"<>:"/\|?*"#.contains(c) {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect();
+    while name.ends_with(['.', ' ']) {
+        name.pop();
+    }
+    if name.is_empty() {
+        return String::from("_");
+    }
+    let stem = name
+        .split('.')
+        .next()
+        .unwrap_or_default()
+        .to_ascii_uppercase();
+    let reserved = matches!(stem.as_str(), "CON" | "PRN" | "AUX" | "NUL")
+        || (stem.len() == 4
+            && (stem.starts_with("COM") || stem.starts_with("LPT"))
+            && stem.as_bytes()[3].is_ascii_digit());
+    if reserved {
+        name.insert(0, '_');
+    }
+    truncate_name(&name)
+}
+
+/// Shortens `name` to `MAX_NAME` bytes, keeping its extension.
+fn truncate_name(name: &str) -> String {
+    if name.len() <= MAX_NAME {
+        return name.to_string();
+    }
+    let extension = match name.rfind('.') {
+        Some(dot) if name.len() - dot <= 16 => &name[dot..],
+        _ => "",
+    };
+    let mut end = MAX_NAME - extension.len();
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{extension}", &name[..end])
+}
+
+/// The local path of a `/` separated path from a torrent.
+pub fn local_path(torrent_path: &str) -> PathBuf {
+    torrent_path.split('/').map(sanitize_component).collect()
+}
+
+/// Where the download of torrent `name` is written until it is complete:
+/// the incomplete directory, or a hidden one in the output directory.
+pub fn staging(config: &OutputConfig, name: &str) -> PathBuf {
+    let dir = match &config.incomplete_dir {
+        Some(dir) => dir.clone(),
+        None => config.dir.join(INCOMPLETE_DIR),
+    };
+    dir.join(sanitize_component(name))
+}
+
+/// Moves the complete download of torrent `name` from where it was staged
+/// into the output directory, with the parts file of the pieces it shares
+/// with skipped files.
+pub fn finish(config: &OutputConfig, name: &str) -> anyhow::Result<Saved> {
+    let root = sanitize_component(name);
+    let staging = staging(config, name);
+    let destination = config.dir.join(&root);
+    if staging == destination {
+        return Ok(Saved::Written(destination));
+    }
+    let destination = match (exists(&destination)?, config.existing) {
+        (false, _) => destination,
+        (true, ExistingPolicy::Skip) => return Ok(Saved::Skipped(destination)),
+        (true, ExistingPolicy::Overwrite) => {
+            remove(&destination)?;
+            destination
+        }
+        (true, ExistingPolicy::Rename) => free_name(&config.dir, &root)?,
+    };
+
+    fs::create_dir_all(&config.dir).with_context(|| format!("create {}", config.dir.display()))?;
+    if exists(&staging)? {
+        move_path(&staging, &destination)?;
+    }
+    let (parts, kept) = (parts::path(&staging), parts::path(&destination));
+    if exists(&kept)? {
+        // left by what was overwritten
+        remove(&kept)?;
+    }
+    if exists(&parts)? {
+        move_path(&parts, &kept)?;
+    }
+    if config.incomplete_dir.is_none() {
+        // removed once no other download is staged in it
+        let _ = fs::remove_dir(config.dir.join(INCOMPLETE_DIR));
+    }
+    Ok(Saved::Written(destination))
+}
+
+fn exists(path: &Path) -> anyhow::Result<bool> {
+    path.try_exists()
+        .with_context(|| format!("look for {}", path.display()))
+}
+
+fn remove(path: &Path) -> anyhow::Result<()> {
+    let removed = if path.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    };
+    removed.with_context(|| format!("remove {}", path.display()))
+}
+
+/// The first of `name (1)`, `name (2)`... that does not exist in `dir`.
+fn free_name(dir: &Path, name: &str) -> anyhow::Result<PathBuf> {
+    let (stem, extension) = match name.rfind('.') {
+        Some(dot) if dot > 0 => name.split_at(dot),
+        _ => (name, ""),
+    };
+    for n in 1.. {
+        let candidate = dir.join(format!("{stem} ({n}){extension}"));
+        if !exists(&candidate)? {
+            return Ok(candidate);
+        }
+    }
+    unreachable!("some name is free")
+}
+
+/// Renames `from` to `to`, copying when they are on different filesystems.
+fn move_path(from: &Path, to: &Path) -> anyhow::Result<()> {
+    match fs::rename(from, to) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            copy_recursive(from, to)?;
+            remove(from)
+        }
+        Err(e) => Err(e).with_context(|| format!("move {} to {}", from.display(), to.display())),
+    }
+}
+
+fn copy_recursive(from: &Path, to: &Path) -> anyhow::Result<()> {
+    if from.is_dir() {
+        fs::create_dir_all(to).with_context(|| format!("create {}", to.display()))?;
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            copy_recursive(&entry.path(), &to.join(entry.file_name()))?;
+        }
+    } else {
+        fs::copy(from, to)
+            .with_context(|| format!("copy {} to {}", from.display(), to.display()))?;
+    }
+    Ok(())
+}
+
+#[test]
+fn names_from_torrents_are_sanitized() {
+    assert_eq!(sanitize_component("a:b?c"), "a_b_c");
+    assert_eq!(sanitize_component(".."), "_");
+    assert_eq!(sanitize_component("name. "), "name");
+    assert_eq!(sanitize_component("con.txt"), "_con.txt");
+    assert_eq!(sanitize_component("COM1"), "_COM1");
+    assert_eq!(sanitize_component("COMPUTER"), "COMPUTER");
+    let long = format!("{}.mkv", "é".repeat(200));
+    let short = sanitize_component(&long);
+    assert!(short.len() <= MAX_NAME && short.ends_with("é.mkv"));
+    assert_eq!(local_path("x/../y"), PathBuf::from("x/_/y"));
+}
+
+#[test]
+fn existing_downloads_follow_the_policy() {
+    let dir = std::env::temp_dir().join(format!("rustorrent-output-{}", std::process::id()));
+    let config = OutputConfig {
+        dir: dir.join("done"),
+        incomplete_dir: Some(dir.join("incomplete")),
+        existing: ExistingPolicy::Rename,
+    };
+    let stage = |config: &OutputConfig, files: &[(&str, &[u8])]| {
+        let staging = staging(config, "t");
+        for (path, bytes) in files {
+            let path = staging.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, bytes).unwrap();
+        }
+    };
+    let files: [(&str, &[u8]); 2] = [("a", b"1"), ("sub/b", b"2")];
+
+    stage(&config, &files);
+    fs::write(parts::path(&staging(&config, "t")), b"").unwrap();
+    let first = finish(&config, "t").unwrap();
+    assert_eq!(first, Saved::Written(dir.join("done/t")));
+    assert_eq!(fs::read(dir.join("done/t/sub/b")).unwrap(), b"2");
+    assert!(dir.join("done/.t.parts").exists());
+    assert!(!dir.join("incomplete/t").exists());
+
+    stage(&config, &files);
+    let second = finish(&config, "t").unwrap();
+    assert_eq!(second, Saved::Written(dir.join("done/t (1)")));
+
+    let skip = OutputConfig {
+        existing: ExistingPolicy::Skip,
+        ..config.clone()
+    };
+    assert_eq!(
+        finish(&skip, "t").unwrap(),
+        Saved::Skipped(dir.join("done/t"))
+    );
+
+    let overwrite = OutputConfig {
+        existing: ExistingPolicy::Overwrite,
+        incomplete_dir: None,
+        ..config
+    };
+    fs::create_dir_all(dir.join("done/.incomplete")).unwrap();
+    fs::write(staging(&overwrite, "t"), b"3").unwrap();
+    finish(&overwrite, "t").unwrap();
+    assert_eq!(fs::read(dir.join("done/t")).unwrap(), b"3");
+    assert!(!dir.join("done/.t.parts").exists());
+    assert!(!dir.join("done/.incomplete").exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
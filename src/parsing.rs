@@ -1,291 +1,542 @@
-#![allow(warnings)]
-
-use crate::bdecoder::OwnedValue;
-use bendy::{encoding::AsString, value::Value};
-use clap::{command, Arg, ArgAction, ArgMatches};
+use crate::bdecoder::{self, info_span, DecodeError, Dict, Value};
+use crate::v2::InfoV2;
+use crate::validate::{validate_lenient, Report};
+use anyhow::Context;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use sha1::{Digest, Sha1};
 use std::collections::BTreeMap;
 use std::fmt;
-use std::{borrow::Cow, ops::Add};
 
-#[derive(Default, Debug, Clone)]
+/// Keys we do not model, mapped to their bencoded values so that
+/// re-encoding a torrent gives back the same bytes.
+pub type Extra = BTreeMap<Vec<u8>, Vec<u8>>;
+
+const FILE_KEYS: &[&str] = &["attr", "length", "md5sum", "path", "sha1", "symlink path"];
+const INFO_KEYS: &[&str] = &[
+    "files",
+    "length",
+    "name",
+    "piece length",
+    "pieces",
+    "private",
+    "source",
+];
+const META_KEYS: &[&str] = &[
+    "announce",
+    "comment",
+    "created by",
+    "creation date",
+    "encoding",
+    "httpseeds",
+    "info",
+    "nodes",
+    "url-list",
+];
+
+#[derive(Default, Debug, Clone, Deserialize, Serialize)]
 pub struct File {
     pub length: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub md5sum: Option<String>,
+    #[serde(deserialize_with = "join_path")]
     pub path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attr: Option<FileAttributes>,
+    #[serde(
+        default,
+        deserialize_with = "sha1_hash",
+        serialize_with = "hex_hash",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub sha1: Option<[u8; 20]>,
+    #[serde(
+        rename = "symlink path",
+        default,
+        deserialize_with = "join_symlink_path",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub symlink_path: Option<String>,
+    #[serde(skip)]
+    pub extra: Extra,
+}
+
+impl File {
+    /// Padding files (BEP 47) only align the next file to a piece
+    /// boundary; they are never written to disk.
+    pub fn is_padding(&self) -> bool {
+        self.attr.as_ref().is_some_and(FileAttributes::padding)
+    }
 }
-#[derive(Default, Debug, Clone)]
+
+/// The `attr` string of a file (BEP 47), one character per attribute.
+#[derive(Default, Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct FileAttributes(pub String);
+
+impl FileAttributes {
+    pub fn padding(&self) -> bool {
+        self.0.contains('p')
+    }
+
+    pub fn executable(&self) -> bool {
+        self.0.contains('x')
+    }
+
+    pub fn hidden(&self) -> bool {
+        self.0.contains('h')
+    }
+
+    pub fn symlink(&self) -> bool {
+        self.0.contains('l')
+    }
+}
+
+#[derive(Default, Debug, Clone, Deserialize, Serialize)]
 pub struct Info {
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub files: Option<Vec<File>>,
+    #[serde(default, skip_serializing_if = "is_zero")]
     pub length: usize,
     pub name: String,
+    #[serde(rename = "piece length")]
     pub piece_length: usize,
+    #[serde(
+        default,
+        deserialize_with = "split_pieces",
+        serialize_with = "hex_pieces"
+    )]
     pub pieces: Vec<[u8; 20]>,
+    /// BEP 27: peers come from the trackers only, never DHT, PEX or LSD.
+    #[serde(
+        default,
+        deserialize_with = "flag",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub private: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    #[serde(skip)]
+    pub extra: Extra,
+}
+
+impl Info {
+    pub fn is_private(&self) -> bool {
+        self.private == Some(true)
+    }
+}
+/// SHA1 of the `info` dictionary, taken over its bytes in the torrent file.
+/// It serializes as hex.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct InfoHash(pub [u8; 20]);
+
+impl InfoHash {
+    pub fn from_torrent(contents: &[u8]) -> Result<Self, DecodeError> {
+        let span = info_span(contents)?;
+        Ok(Self(Sha1::digest(&contents[span]).into()))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 20] {
+        &self.0
+    }
+}
+
+impl fmt::Display for InfoHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
 }
-#[derive(Default, Debug, Clone)]
+
+impl Serialize for InfoHash {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Deserialized from the torrent file; serialized for display, with
+/// hashes as hex and missing fields left out.
+#[derive(Default, Debug, Clone, Deserialize, Serialize)]
 pub struct MetaInfo {
     pub info: Info,
-    pub announce: String,
+    #[serde(rename = "info hash", skip_deserializing)]
+    pub info_hash: InfoHash,
+    /// Missing from trackerless torrents, which have `nodes` or only an
+    /// `announce-list`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub announce: Option<String>,
+    #[serde(rename = "creation date", skip_serializing_if = "Option::is_none")]
     pub creation_date: Option<i64>,
+    #[serde(
+        default,
+        deserialize_with = "lossy_string",
+        skip_serializing_if = "Option::is_none"
+    )]
     pub comment: Option<String>,
+    #[serde(
+        rename = "created by",
+        default,
+        deserialize_with = "lossy_string",
+        skip_serializing_if = "Option::is_none"
+    )]
     pub created_by: Option<String>,
+    #[serde(
+        rename = "url-list",
+        default,
+        deserialize_with = "url_list",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub url_list: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub httpseeds: Vec<String>,
+    /// DHT nodes to bootstrap from, as host and port.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub nodes: Vec<(String, u16)>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<String>,
+    #[serde(skip)]
+    pub extra: Extra,
+    /// The v2 part of v2 and hybrid torrents, parsed from the raw file.
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub v2: Option<InfoV2>,
+    /// The `info` dictionary exactly as the torrent stores it, which
+    /// `info_hash` is taken over. Empty unless parsed from a file.
+    #[serde(skip)]
+    pub raw_info: Vec<u8>,
+}
+
+/// `path` is a list of components, joined here with `/`.
+fn join_path<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    let components = Vec::<String>::deserialize(deserializer)?;
+    Ok(components.join("/"))
+}
+
+fn join_symlink_path<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<String>, D::Error> {
+    join_path(deserializer).map(Some)
+}
+
+fn sha1_hash<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<[u8; 20]>, D::Error> {
+    let bytes: &[u8] = serde_bytes::deserialize(deserializer)?;
+    bytes
+        .try_into()
+        .map(Some)
+        .map_err(|_| de::Error::invalid_length(bytes.len(), &"20 bytes"))
+}
+
+/// Flags such as `private` are stored as integers.
+fn flag<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<bool>, D::Error> {
+    Ok(Some(i64::deserialize(deserializer)? != 0))
+}
+
+fn is_zero(num: &usize) -> bool {
+    *num == 0
 }
 
-fn format_char(c: char) -> String {
-    if (c as u32) < 0x20 || (c as u32) > 0x7E {
-        // Format non-printable or non-ASCII characters
-        format!("\\u{:04x}", c as u32)
-    } else {
-        // Return printable ASCII characters as they are
-        c.to_string()
+fn hex_hash<S: Serializer>(hash: &Option<[u8; 20]>, serializer: S) -> Result<S::Ok, S::Error> {
+    match hash {
+        Some(hash) => serializer.serialize_str(&hex::encode(hash)),
+        None => serializer.serialize_none(),
     }
 }
 
-impl fmt::Display for File {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // Displaying the mandatory fields
-        write!(
-            f,
-            "\t\t\"length\": \"{}\"\n\t\t\"path\": \"{}\"",
-            self.length,
-            self.path.chars().map(format_char).collect::<String>()
-        )?;
-
-        // For the optional 'md5sum' field
-        if let Some(md5sum) = &self.md5sum {
-            write!(f, "\n\t\t\"md5sum\": \"{}\"", md5sum)?;
-        }
+fn hex_pieces<S: Serializer>(pieces: &[[u8; 20]], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(pieces.iter().map(hex::encode))
+}
 
-        Ok(())
+/// `pieces` holds the SHA1 of every piece end to end.
+fn split_pieces<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<[u8; 20]>, D::Error> {
+    let bytes: &[u8] = serde_bytes::deserialize(deserializer)?;
+    if !bytes.len().is_multiple_of(20) {
+        return Err(de::Error::invalid_length(bytes.len(), &"a multiple of 20"));
     }
+    Ok(bytes
+        .chunks_exact(20)
+        .map(|chunk| chunk.try_into().expect("chunks of 20 bytes"))
+        .collect())
 }
 
-impl fmt::Display for Info {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // Displaying the mandatory fields
-        write!(
-            f,
-            "\t\"length\": \"{}\"\n\t\"name\": \"{}\"\n\t\"piece-length\": \"{}\"",
-            self.length,
-            self.name.chars().map(format_char).collect::<String>(),
-            self.piece_length
-        );
-
-        write!(f, "\n\t\"pieces\": [")?;
-        for (i, piece) in self.pieces.iter().enumerate() {
-            if i > 0 {
-                write!(f, ", ")?;
-            }
-            for byte in piece {
-                write!(f, "{:02x}", byte)?;
-            }
-        }
-        write!(f, "]");
-
-        // For the optional 'files' field
-        if let Some(files) = &self.files {
-            // Assuming each file in the vector also implements Display
-            // You can format it as per your requirement
-            write!(f, "\n\t\"files\":\n")?;
-            for (i, file) in files.iter().enumerate() {
-                if i > 0 {
-                    write!(f, ", ")?;
+/// Text fields of old torrents are not always UTF-8; they are kept as
+/// well as they can be rather than failing the whole torrent.
+fn lossy_string<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    let bytes: &[u8] = serde_bytes::deserialize(deserializer)?;
+    Ok(Some(String::from_utf8_lossy(bytes).into_owned()))
+}
+
+/// `url-list` is either a single URL or a list of them (BEP 19).
+fn url_list<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum UrlList {
+        One(String),
+        Many(Vec<String>),
+    }
+    let urls = match UrlList::deserialize(deserializer)? {
+        UrlList::One(url) => vec![url],
+        UrlList::Many(urls) => urls,
+    };
+    Ok(urls.into_iter().filter(|url| !url.is_empty()).collect())
+}
+
+/// Parses a `.torrent` file, hashing its info dictionary as stored.
+/// Torrents failing validation are refused with every problem found.
+pub fn parse_metainfo(contents: &[u8]) -> Result<MetaInfo, Report> {
+    let report = validate_lenient(contents);
+    if !report.is_valid() {
+        return Err(report);
+    }
+    let mut meta_info: MetaInfo = bdecoder::from_bytes(contents)?;
+    meta_info.info_hash = InfoHash::from_torrent(contents).map_err(bdecoder::Error::from)?;
+    let span = info_span(contents).map_err(bdecoder::Error::from)?;
+    meta_info.raw_info = contents[span].to_vec();
+
+    let torrent = bdecoder::decode_bencoded_string(contents).map_err(bdecoder::Error::from)?;
+    meta_info.extra = unknown_keys(&torrent, META_KEYS);
+    if let Some(info) = torrent.get(&b"info"[..]).and_then(Value::as_dict) {
+        meta_info.info.extra = unknown_keys(info, INFO_KEYS);
+        let raw_files = info.get(&b"files"[..]).and_then(Value::as_list);
+        if let (Some(files), Some(raw_files)) = (&mut meta_info.info.files, raw_files) {
+            for (file, raw) in files.iter_mut().zip(raw_files) {
+                if let Some(raw) = raw.as_dict() {
+                    file.extra = unknown_keys(raw, FILE_KEYS);
                 }
-                write!(f, "{}", file)?;
             }
         }
-
-        Ok(())
     }
+    Ok(meta_info)
 }
 
-impl fmt::Display for MetaInfo {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // Start by displaying the mandatory fields
-        write!(
-            f,
-            "{}\n\t\"announce\": \"{}\"",
-            self.info,
-            self.announce.chars().map(format_char).collect::<String>()
-        )?;
-
-        // For optional fields, you can use a pattern like this:
-        if let Some(creation_date) = self.creation_date {
-            write!(f, "\n\t\"creation-date\": \"{}\"", creation_date)?;
+fn unknown_keys(dict: &Dict<'_>, known: &[&str]) -> Extra {
+    dict.iter()
+        .filter(|(key, _)| !known.iter().any(|known| known.as_bytes() == **key))
+        .map(|(key, value)| (key.to_vec(), value.encode()))
+        .collect()
+}
+
+impl MetaInfo {
+    /// Parses a `.torrent` file, v2 part included.
+    pub fn from_bytes(contents: &[u8]) -> anyhow::Result<Self> {
+        let v2 = InfoV2::from_torrent(contents).context("invalid v2 metadata")?;
+        let mut meta_info = parse_metainfo(contents).context("invalid torrent")?;
+        meta_info.v2 = v2;
+        Ok(meta_info)
+    }
+
+    /// The hash peers and trackers know the torrent by: v2-only swarms are
+    /// found with the truncated v2 hash.
+    pub fn swarm_info_hash(&self) -> [u8; 20] {
+        match self.v2.as_ref().filter(|v2| !v2.hybrid) {
+            Some(v2) => v2.truncated_info_hash(),
+            None => self.info_hash.0,
         }
+    }
 
-        if let Some(comment) = &self.comment {
-            write!(
-                f,
-                "\n\t\"comment: \"{}\"",
-                comment.chars().map(format_char).collect::<String>()
-            )?;
+    /// Whether the torrent has no v1 `pieces`, only the v2 file tree.
+    pub fn is_v2_only(&self) -> bool {
+        self.v2.as_ref().is_some_and(|v2| !v2.hybrid)
+    }
+
+    /// The bencoded info dictionary, as the peers exchange it (BEP 9).
+    pub fn info_bytes(&self) -> Vec<u8> {
+        if !self.raw_info.is_empty() {
+            return self.raw_info.clone();
         }
+        let torrent = self.to_bytes();
+        let span = info_span(&torrent).expect("to_bytes encodes an info dictionary");
+        torrent[span].to_vec()
+    }
 
+    /// Encodes the torrent again, unknown keys included and the `info`
+    /// dictionary as stored, or as parsed for torrents not read from a file.
+    /// Hashing it gives back `info_hash`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let pieces = self.info.pieces.concat();
+        let mut torrent = with_extra(&self.extra);
+        if self.raw_info.is_empty() {
+            torrent.insert(&b"info"[..], Value::Dict(self.info_value(&pieces)));
+        }
+        if let Some(announce) = &self.announce {
+            torrent.insert(&b"announce"[..], bytes(announce));
+        }
+        if let Some(creation_date) = self.creation_date {
+            torrent.insert(&b"creation date"[..], Value::Integer(creation_date));
+        }
+        if let Some(comment) = &self.comment {
+            torrent.insert(&b"comment"[..], bytes(comment));
+        }
         if let Some(created_by) = &self.created_by {
-            write!(
-                f,
-                "\n\t\"created-by: \"{}\"",
-                created_by.chars().map(format_char).collect::<String>()
-            )?;
+            torrent.insert(&b"created by"[..], bytes(created_by));
+        }
+        if let Some(encoding) = &self.encoding {
+            torrent.insert(&b"encoding"[..], bytes(encoding));
         }
+        match self.url_list.as_slice() {
+            [] => {}
+            [url] => {
+                torrent.insert(&b"url-list"[..], bytes(url));
+            }
+            urls => {
+                let urls = urls.iter().map(|url| bytes(url)).collect();
+                torrent.insert(&b"url-list"[..], Value::List(urls));
+            }
+        }
+        if !self.httpseeds.is_empty() {
+            let seeds = self.httpseeds.iter().map(|url| bytes(url)).collect();
+            torrent.insert(&b"httpseeds"[..], Value::List(seeds));
+        }
+        if !self.nodes.is_empty() {
+            let nodes = self
+                .nodes
+                .iter()
+                .map(|(host, port)| Value::List(vec![bytes(host), Value::Integer(*port as i64)]))
+                .collect();
+            torrent.insert(&b"nodes"[..], Value::List(nodes));
+        }
+        if self.raw_info.is_empty() {
+            return Value::Dict(torrent).encode();
+        }
+        // re-encoding would sort the keys of a non-canonical info dictionary
+        let (before, after): (Dict, Dict) = torrent
+            .into_iter()
+            .partition(|&(key, _)| key < &b"info"[..]);
+        let mut out = Value::Dict(before).encode();
+        out.pop();
+        out.extend_from_slice(b"4:info");
+        out.extend_from_slice(&self.raw_info);
+        out.extend_from_slice(&Value::Dict(after).encode()[1..]);
+        out
+    }
 
-        Ok(())
+    /// `info` encoded from its fields, for torrents not parsed from a file.
+    fn info_value<'a>(&'a self, pieces: &'a [u8]) -> Dict<'a> {
+        let mut info = with_extra(&self.info.extra);
+        info.insert(&b"name"[..], bytes(&self.info.name));
+        info.insert(&b"piece length"[..], integer(self.info.piece_length));
+        info.insert(&b"pieces"[..], Value::Bytes(pieces));
+        if let Some(private) = self.info.private {
+            info.insert(&b"private"[..], Value::Integer(private as i64));
+        }
+        if let Some(source) = &self.info.source {
+            info.insert(&b"source"[..], bytes(source));
+        }
+        match &self.info.files {
+            Some(files) => {
+                let files = files.iter().map(File::to_value).collect();
+                info.insert(&b"files"[..], Value::List(files));
+            }
+            None => {
+                info.insert(&b"length"[..], integer(self.info.length));
+            }
+        }
+        info
     }
 }
 
-fn parse_info(d: &BTreeMap<String, OwnedValue>) -> Info {
-    /* Retrieve fields */
-    let length: usize = d
-        .get("length")
-        .and_then(|x| extract_integer(x))
-        .unwrap_or_default() as usize;
-    let name = d.get("name").unwrap();
-    let piece_length = d
-        .get("piece length")
-        .and_then(|x| extract_integer(x))
-        .unwrap_or_default() as usize;
-    let pieces = d
-        .get("pieces")
-        .and_then(|x| extract_groups_bytes(x))
-        .unwrap_or_default();
-
-    /* Retrive 'files' field */
-    let list_files = extract_list_files(length, d);
-
-    /* Initialize Info struct */
-    let info_data = Info {
-        files: Some(list_files),
-        length,
-        name: String::from_utf8(extract_bytes(name).unwrap()).unwrap(),
-        piece_length,
-        pieces,
-    };
-    info_data
+impl File {
+    fn to_value(&self) -> Value<'_> {
+        let mut file = with_extra(&self.extra);
+        file.insert(&b"length"[..], integer(self.length));
+        file.insert(&b"path"[..], path_value(&self.path));
+        if let Some(md5sum) = &self.md5sum {
+            file.insert(&b"md5sum"[..], bytes(md5sum));
+        }
+        if let Some(attr) = &self.attr {
+            file.insert(&b"attr"[..], bytes(&attr.0));
+        }
+        if let Some(sha1) = &self.sha1 {
+            file.insert(&b"sha1"[..], Value::Bytes(sha1));
+        }
+        if let Some(symlink_path) = &self.symlink_path {
+            file.insert(&b"symlink path"[..], path_value(symlink_path));
+        }
+        Value::Dict(file)
+    }
 }
 
-fn extract_list_files(length: usize, d: &BTreeMap<String, OwnedValue>) -> Vec<File> {
-    let mut list_files: Vec<File> = Vec::new();
-    if length == 0 {
-        let files = d.get("files").and_then(|x| extract_list(x)).unwrap();
-        for file in files {
-            if let OwnedValue::Dict(fdict) = file {
-                let flength: usize = fdict
-                    .get("length")
-                    .and_then(|x| extract_integer(x))
-                    .unwrap_or_default() as usize;
-                let md5sum = Some(convert_option_bytes_to_string(
-                    fdict.get("md5sum").and_then(|x| extract_bytes(x)),
-                ));
-                let fpath = fdict.get("path");
-                let mut full_path = String::new();
-                if let Some(OwnedValue::List(path)) = fpath {
-                    for p in path {
-                        full_path.push_str(&convert_option_bytes_to_string(extract_bytes(p)));
-                        full_path.push_str("/");
-                    }
-                    full_path.pop(); 
-                }
+fn with_extra(extra: &Extra) -> Dict<'_> {
+    extra
+        .iter()
+        .map(|(key, value)| {
+            let value = bdecoder::decode(value).expect("extra values were encoded by us");
+            (&key[..], value)
+        })
+        .collect()
+}
 
-                let new_file = File {
-                    length: flength,
-                    md5sum,
-                    path: full_path,
-                };
-                list_files.push(new_file);
-            } else {
-                panic!("parse_metainfo: error when extracting info[files]");
-            }
-        }
-    }
-    list_files
+fn bytes(s: &str) -> Value<'_> {
+    Value::Bytes(s.as_bytes())
 }
 
-fn extract_integer(value: &OwnedValue) -> Option<i64> {
-    if let OwnedValue::Integer(num) = value {
-        Some(*num)
-    } else {
-        None
-    }
+fn integer(num: usize) -> Value<'static> {
+    Value::Integer(num as i64)
 }
 
-fn extract_bytes(value: &OwnedValue) -> Option<Vec<u8>> {
-    if let OwnedValue::Str(bytes) = value {
-        Some(bytes.clone().as_bytes().to_vec())
-    } else {
-        None
-    }
+fn path_value(path: &str) -> Value<'_> {
+    Value::List(path.split('/').map(bytes).collect())
 }
 
-fn extract_groups_bytes(value: &OwnedValue) -> Option<Vec<[u8; 20]>> {
-    if let OwnedValue::Str(bytes) = value {
-        let byte_vec = bytes.clone().as_bytes().to_vec();
-        if byte_vec.len() % 20 == 0 {
-            let mut chunks = Vec::new();
-            for chunk in byte_vec.chunks_exact(20) {
-                let mut array = [0u8; 20];
-                array.copy_from_slice(chunk);
-                chunks.push(array);
-            }
-            Some(chunks)
-        } else {
-            // Handle the case where the bytes are not a multiple of 20
-            None
-        }
-    } else {
-        None
-    }
+#[test]
+fn re_encoding_keeps_every_key() {
+    let torrent = b"d8:announce3:url8:encoding5:UTF-89:httpseedsl4:seede\
+        4:infod5:filesld4:attr1:p6:lengthi1e4:pathl4:.pad1:0ee\
+        d6:lengthi3e4:pathl1:ae4:sha120:aaaaaaaaaaaaaaaaaaaa7:unknowni1ee\
+        d4:attr1:l6:lengthi0e4:pathl1:be12:symlink pathl1:aeee\
+        4:name1:x12:piece lengthi4e6:pieces20:bbbbbbbbbbbbbbbbbbbb\
+        7:privatei1e6:source3:src5:x-keyi7ee\
+        5:nodesll4:hosti6881eee9:x-unknown4:keepe";
+    let meta_info = parse_metainfo(torrent).unwrap();
+
+    assert!(meta_info.info.is_private());
+    assert_eq!(meta_info.info.source.as_deref(), Some("src"));
+    assert_eq!(meta_info.httpseeds, ["seed"]);
+    assert_eq!(meta_info.nodes, [(String::from("host"), 6881)]);
+    let files = meta_info.info.files.as_ref().unwrap();
+    assert!(files[0].is_padding());
+    assert_eq!(files[1].sha1, Some([b'a'; 20]));
+    assert!(files[2].attr.as_ref().unwrap().symlink());
+    assert_eq!(files[2].symlink_path.as_deref(), Some("a"));
+    assert_eq!(meta_info.to_bytes(), torrent);
 }
 
-fn extract_list(value: &OwnedValue) -> Option<&Vec<OwnedValue>> {
-    if let OwnedValue::List(ref list) = value {
-        Some(list)
-    } else {
-        None
-    }
+#[test]
+fn info_bytes_are_served_as_stored() {
+    let torrent = b"d8:announce3:url4:infod6:lengthi3e4:name1:x12:piece lengthi4e\
+        6:pieces20:bbbbbbbbbbbbbbbbbbbb7:privatei2eee";
+    let meta_info = parse_metainfo(torrent).unwrap();
+
+    assert!(meta_info.info.is_private());
+    let span = info_span(torrent).unwrap();
+    assert_eq!(meta_info.info_bytes(), torrent[span]);
+    assert_eq!(meta_info.to_bytes(), torrent);
+    let sha1: [u8; 20] = Sha1::digest(meta_info.info_bytes()).into();
+    assert_eq!(sha1, meta_info.info_hash.0);
 }
 
-fn convert_option_bytes_to_string(option: Option<Vec<u8>>) -> String {
-    option.map_or(String::from(""), |bytes| {
-        String::from_utf8(bytes).unwrap_or_default()
-    })
+#[test]
+fn non_canonical_torrents_load() {
+    let torrent = b"d8:announce3:url4:infod4:name1:x6:lengthi03e12:piece lengthi4e\
+        6:pieces20:bbbbbbbbbbbbbbbbbbbbe7:comment1:ce";
+    let meta_info = parse_metainfo(torrent).unwrap();
+
+    assert_eq!(meta_info.info.length, 3);
+    assert_eq!(meta_info.comment.as_deref(), Some("c"));
+    let span = info_span(torrent).unwrap();
+    assert_eq!(meta_info.info_bytes(), torrent[span.clone()]);
+    let reloaded = parse_metainfo(&meta_info.to_bytes()).unwrap();
+    assert_eq!(reloaded.info_bytes(), torrent[span]);
 }
 
-pub fn parse_metainfo(dict: BTreeMap<String, OwnedValue>) -> MetaInfo {
-    /* Retrieve fields */
-    let info = dict.get("info").expect("Required field missing: info");
-    let announce = dict
-        .get("announce")
-        .expect("Required field missing: announce");
-    let creation_date = dict.get("creation-date");
-    let comment = dict.get("comment");
-    let created_by = dict.get("created-by");
-
-    if let OwnedValue::Dict(d) = info {
-        let info_data = parse_info(d);
-
-        /* Initialize MetaInfo struct */
-        let meta_info = MetaInfo {
-            info: info_data,
-            announce: if let OwnedValue::Str(s) = announce {
-                String::from_utf8(s.clone().as_bytes().to_vec()).unwrap()
-            } else {
-                String::from("")
-            },
-            creation_date: Some(
-                creation_date
-                    .and_then(|x| extract_integer(x))
-                    .unwrap_or_default(),
-            ),
-            comment: Some(convert_option_bytes_to_string(
-                comment.and_then(|x| extract_bytes(x)),
-            )),
-            created_by: Some(convert_option_bytes_to_string(
-                created_by.and_then(|x| extract_bytes(x)),
-            )),
-        };
-
-        meta_info
-    } else {
-        panic!("Wrong type for field info");
-    }
+#[test]
+fn info_hash_covers_the_stored_info() {
+    let torrent = b"d8:announce3:url4:infod6:lengthi3e4:name1:x6:pieces20:bbbbbbbbbbbbbbbbbbbb\
+        12:piece lengthi4e7:privatei1eee";
+    let meta_info = parse_metainfo(torrent).unwrap();
+
+    let span = info_span(torrent).unwrap();
+    let info = bdecoder::decode(&torrent[span.clone()]).unwrap();
+    assert_ne!(info.encode(), torrent[span.clone()]);
+    let sha1: [u8; 20] = Sha1::digest(&torrent[span]).into();
+    assert_eq!(meta_info.info_hash.0, sha1);
+    assert_eq!(
+        meta_info.info_hash,
+        InfoHash::from_torrent(torrent).unwrap()
+    );
 }
@@ -0,0 +1,76 @@
+use anyhow::Context;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The parts file of the download at `root`: a hidden file next to it that
+/// holds the pieces shared between wanted and skipped files, so skipped
+/// files are never created.
+pub(crate) fn path(root: &Path) -> PathBuf {
+    let name = root.file_name().unwrap_or_default().to_string_lossy();
+    root.with_file_name(format!(".{name}.parts"))
+}
+
+/// Writes `pieces` as a sequence of piece index, length and data, each
+/// number 4 bytes big endian. No pieces removes the file.
+pub(crate) fn write<'a>(
+    path: &Path,
+    pieces: impl IntoIterator<Item = (usize, &'a [u8])>,
+) -> anyhow::Result<()> {
+    let mut contents = Vec::new();
+    for (index, data) in pieces {
+        contents.extend((index as u32).to_be_bytes());
+        contents.extend((data.len() as u32).to_be_bytes());
+        contents.extend_from_slice(data);
+    }
+    if contents.is_empty() {
+        return match fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                Err(e).with_context(|| format!("remove {}", path.display()))
+            }
+            _ => Ok(()),
+        };
+    }
+    fs::write(path, contents).with_context(|| format!("write {}", path.display()))
+}
+
+/// The pieces in the parts file at `path`, none if there is no such file.
+pub(crate) fn read(path: &Path) -> anyhow::Result<BTreeMap<usize, Vec<u8>>> {
+    let contents = match fs::read(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(e) => return Err(e).with_context(|| format!("read {}", path.display())),
+    };
+    let mut pieces = BTreeMap::new();
+    let mut rest = &contents[..];
+    while !rest.is_empty() {
+        let number = |bytes: &[u8]| u32::from_be_bytes(bytes.try_into().unwrap()) as usize;
+        anyhow::ensure!(rest.len() >= 8, "{} is truncated", path.display());
+        let (index, length) = (number(&rest[..4]), number(&rest[4..8]));
+        let data = rest
+            .get(8..8 + length)
+            .with_context(|| format!("{} is truncated", path.display()))?;
+        pieces.insert(index, data.to_vec());
+        rest = &rest[8 + length..];
+    }
+    Ok(pieces)
+}
+
+#[test]
+fn parts_files_hold_pieces() {
+    let dir = std::env::temp_dir().join(format!("rustorrent-parts-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = path(&dir.join("t"));
+    assert_eq!(path, dir.join(".t.parts"));
+    assert!(read(&path).unwrap().is_empty());
+
+    write(&path, [(0, &b"abcd"[..]), (7, &b"xy"[..])]).unwrap();
+    let pieces = read(&path).unwrap();
+    assert_eq!(pieces[&0], b"abcd");
+    assert_eq!(pieces[&7], b"xy");
+
+    write(&path, []).unwrap();
+    assert!(!path.exists());
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -1,10 +1,11 @@
 use bytes::{Buf, BufMut, BytesMut};
 use serde::de::{self, Deserialize, Deserializer, Visitor};
 use serde::ser::{Serialize, Serializer};
+use std::collections::HashSet;
 use std::fmt;
 use std::net::{Ipv4Addr, SocketAddrV4};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio_util::codec::Decoder;
 use tokio_util::codec::Encoder;
 use tokio_util::codec::Framed;
@@ -13,45 +14,312 @@ use anyhow::Context;
 
 use futures_util::{SinkExt, StreamExt};
 
+use crate::extension::{self, ExtendedHandshake};
+use crate::fast;
+use crate::mse::{self, EncryptionPolicy, MseStream};
+use crate::pex::{PexState, FLAG_ENCRYPTION, FLAG_OUTGOING, FLAG_SEED, FLAG_UTP};
+use crate::rate::{Throttle, Throttled};
+use crate::transport::{self, Transport, TransportKind, TransportPolicy};
+use crate::utp::UtpSocket;
 use crate::BLOCK_MAX;
 
+/// Connection to a peer over TCP or uTP, RC4 obfuscated if MSE negotiated it.
+pub(crate) type PeerStream = MseStream<Transport>;
+
+/// Suggestions remembered from a peer; later ones are ignored.
+const MAX_SUGGESTED: usize = 64;
+
+/// The peer id we use unless a session picked its own.
+pub(crate) const PEER_ID: [u8; 20] = *b"-MB2025-100101070501";
+
+/// A peer id with our client prefix and random digits, so that the peers
+/// can tell our sessions apart.
+pub(crate) fn random_peer_id() -> [u8; 20] {
+    let mut peer_id = PEER_ID;
+    for byte in &mut peer_id[8..] {
+        *byte = b'0' + rand::random::<u8>() % 10;
+    }
+    peer_id
+}
+
+/// How we reach peers.
+#[derive(Debug, Clone)]
+pub struct ConnectConfig {
+    pub peer_id: [u8; 20],
+    pub encryption: EncryptionPolicy,
+    pub transport: TransportPolicy,
+    /// Socket outgoing uTP connections are opened on; without it only TCP is used.
+    pub utp: Option<Arc<UtpSocket>>,
+    /// The torrent is private (BEP 27): PEX is neither offered nor accepted.
+    pub private: bool,
+    /// Bandwidth limits of the connections, set by the session.
+    pub throttle: Throttle,
+    /// Port we accept peers on, told to them in the extended handshake.
+    pub listen_port: Option<u16>,
+}
+
+impl Default for ConnectConfig {
+    fn default() -> Self {
+        Self {
+            peer_id: PEER_ID,
+            encryption: EncryptionPolicy::default(),
+            transport: TransportPolicy::default(),
+            utp: None,
+            private: false,
+            throttle: Throttle::default(),
+            listen_port: None,
+        }
+    }
+}
+
+/// A peer breaking the protocol, which gets it banned.
+#[derive(Debug)]
+pub(crate) struct Violation(pub String);
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "protocol violation: {}", self.0)
+    }
+}
+
+impl std::error::Error for Violation {}
+
+pub(crate) fn violation(what: impl Into<String>) -> anyhow::Error {
+    Violation(what.into()).into()
+}
+
 #[derive(Debug)]
 pub(crate) struct Peer {
     pub addr: SocketAddrV4,
-    stream: Framed<TcpStream, MessageFrame>,
+    stream: Framed<Throttled<PeerStream>, MessageFrame>,
     bitfield: Bitfield,
+    num_pieces: usize,
     choked: bool,
+    extended: Option<ExtendedHandshake>,
+    pex: PexState,
+    private: bool,
+    fast: bool,
+    allowed_fast: HashSet<u32>,
+    suggested: Vec<u32>,
 }
 
 impl Peer {
-    pub async fn new(peer_addr: SocketAddrV4, info_hash: [u8; 20]) -> anyhow::Result<Self> {
-        let mut peer = tokio::net::TcpStream::connect(peer_addr).await.unwrap();
-        let mut handshake = Handshake::new(info_hash, *b"-MB2025-100101070501");
-        {
-            let handshake_bytes = handshake.as_bytes_mut();
-            peer.write_all(handshake_bytes)
-                .await
-                .context("write handshake")?;
-            peer.read_exact(handshake_bytes)
-                .await
-                .context("read handshake")?;
-        }
-        anyhow::ensure!(handshake.length == 19);
-        anyhow::ensure!(&handshake.bittorrent == b"BitTorrent protocol");
+    pub async fn new(
+        peer_addr: SocketAddrV4,
+        info_hash: [u8; 20],
+        num_pieces: usize,
+        config: &ConnectConfig,
+    ) -> anyhow::Result<Self> {
+        let (peer, handshake) = connect(peer_addr, info_hash, config).await?;
+        let supports_extensions = extension::supports_extensions(&handshake.reserved);
+        let supports_fast = fast::supports_fast(&handshake.reserved);
+        let peer = config.throttle.wrap(peer_addr.ip(), peer);
         let mut peer = tokio_util::codec::Framed::new(peer, MessageFrame);
-        let bitfield = peer
+        let first = peer
             .next()
             .await
-            .expect("peer always sends a bitfields")
+            .context("peer closed the connection before its first message")?
             .context("peer message was invalid")?;
-        anyhow::ensure!(bitfield.tag == MessageTag::Bitfield);
+        // a peer without any piece may leave out its bitfield
+        let (bitfield, first) = match first.tag {
+            MessageTag::Bitfield => (Bitfield::from_payload(first.payload), None),
+            MessageTag::HaveAll if supports_fast => (Bitfield::full(num_pieces), None),
+            MessageTag::HaveNone if supports_fast => (Bitfield::empty(num_pieces), None),
+            _ => (Bitfield::empty(num_pieces), Some(first)),
+        };
+
+        if supports_extensions {
+            let mut ours = ExtendedHandshake::ours(config.listen_port);
+            if config.private {
+                ours.m.remove(extension::UT_PEX);
+            }
+            let ours = ours.to_bytes()?;
+            peer.send(Message {
+                tag: MessageTag::Extended,
+                payload: extension::extended_payload(extension::HANDSHAKE_ID, &ours),
+            })
+            .await
+            .context("send extended handshake")?;
+        }
 
-        Ok(Self {
+        let mut peer = Self {
             addr: peer_addr,
             stream: peer,
-            bitfield: Bitfield::from_payload(bitfield.payload),
+            bitfield,
+            num_pieces,
             choked: true,
-        })
+            extended: None,
+            pex: PexState::default(),
+            private: config.private,
+            fast: supports_fast,
+            allowed_fast: HashSet::new(),
+            suggested: Vec::new(),
+        };
+        if let Some(first) = first {
+            peer.on_message(first).await?;
+        }
+        Ok(peer)
+    }
+
+    /// Handles a message that is not an answer to our requests.
+    async fn on_message(&mut self, msg: Message) -> anyhow::Result<()> {
+        match msg.tag {
+            MessageTag::Have => {
+                let index = self.piece_of(&msg.payload)?;
+                self.bitfield.set_piece(index as usize);
+            }
+            MessageTag::Request => {
+                // not allowing requests for now
+                self.reject(msg.payload).await?;
+            }
+            MessageTag::Interested | MessageTag::NotInterested | MessageTag::Cancel => {
+                // not allowing requests for now
+            }
+            MessageTag::Piece | MessageTag::RejectRequest => {
+                // piece that we no longer need/are responsible for
+            }
+            MessageTag::Choke => {
+                self.choked = true;
+            }
+            MessageTag::Unchoke => {
+                self.choked = false;
+            }
+            MessageTag::Bitfield | MessageTag::HaveAll | MessageTag::HaveNone => {
+                return Err(violation(format!("{:?} after the first message", msg.tag)));
+            }
+            MessageTag::SuggestPiece => {
+                let index = self.piece_of(&msg.payload)?;
+                self.suggest(index);
+            }
+            MessageTag::AllowedFast => {
+                let index = self.piece_of(&msg.payload)?;
+                self.allowed_fast.insert(index);
+            }
+            MessageTag::Extended => {
+                self.on_extended(&msg.payload)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Refuses a request from the peer. With the fast extension the peer
+    /// must be told; without it the request is silently dropped.
+    async fn reject(&mut self, payload: Vec<u8>) -> anyhow::Result<()> {
+        if !self.fast {
+            return Ok(());
+        }
+        self.stream
+            .send(Message {
+                tag: MessageTag::RejectRequest,
+                payload,
+            })
+            .await
+            .context("send reject request")
+    }
+
+    /// The piece index carried by `payload`, which must be one of ours.
+    fn piece_of(&self, payload: &[u8]) -> anyhow::Result<u32> {
+        let index = piece_index(payload)?;
+        if index as usize >= self.num_pieces {
+            return Err(violation(format!(
+                "piece {index} of {} pieces",
+                self.num_pieces
+            )));
+        }
+        Ok(index)
+    }
+
+    fn suggest(&mut self, index: u32) {
+        if self.suggested.len() < MAX_SUGGESTED && !self.suggested.contains(&index) {
+            self.suggested.push(index);
+        }
+    }
+
+    /// Pieces the peer suggested we download from it.
+    pub(crate) fn suggested_pieces(&self) -> &[u32] {
+        &self.suggested
+    }
+
+    /// Pieces we may request from the peer only because they are allowed
+    /// fast: empty unless the peer is choking us.
+    pub(crate) fn fast_pieces(&self) -> impl Iterator<Item = usize> + '_ {
+        self.allowed_fast
+            .iter()
+            .filter(|_| self.choked)
+            .map(|&index| index as usize)
+    }
+
+    /// Whether we may request `piece_i` from the peer right now.
+    fn can_request(&self, piece_i: usize) -> bool {
+        !self.choked || self.allowed_fast.contains(&(piece_i as u32))
+    }
+
+    /// Handles a BEP 10 extended message.
+    fn on_extended(&mut self, payload: &[u8]) -> anyhow::Result<()> {
+        let (&id, body) = payload.split_first().context("empty extended message")?;
+        match id {
+            extension::HANDSHAKE_ID => {
+                let handshake = ExtendedHandshake::from_bytes(body)?;
+                if !self.private {
+                    self.pex.set_remote_id(handshake.id_of(extension::UT_PEX));
+                }
+                self.extended = Some(handshake);
+            }
+            extension::UT_PEX_ID if !self.private => {
+                self.pex.receive(body, self.addr)?;
+            }
+            _ => {
+                // extension we never advertised
+            }
+        }
+        Ok(())
+    }
+
+    /// PEX flags describing our connection to this peer.
+    pub(crate) fn pex_flags(&self) -> u8 {
+        let stream = self.stream.get_ref().get_ref();
+        let mut flags = FLAG_OUTGOING;
+        if stream.get_ref().kind() == TransportKind::Utp {
+            flags |= FLAG_UTP;
+        }
+        if stream.is_encrypted() {
+            flags |= FLAG_ENCRYPTION;
+        }
+        if self.bitfield.is_complete(self.num_pieces) {
+            flags |= FLAG_SEED;
+        }
+        flags
+    }
+
+    /// Tells the peer about the swarm we are connected to, if the peer
+    /// supports `ut_pex` and enough time passed since the last update.
+    pub(crate) async fn send_pex(
+        &mut self,
+        connected: &[(SocketAddrV4, u8)],
+    ) -> anyhow::Result<()> {
+        let Some(remote_id) = self.pex.remote_id() else {
+            return Ok(());
+        };
+        let connected: Vec<_> = connected
+            .iter()
+            .filter(|&&(addr, _)| addr != self.addr)
+            .copied()
+            .collect();
+        let Some(message) = self.pex.build(&connected) else {
+            return Ok(());
+        };
+        self.stream
+            .send(Message {
+                tag: MessageTag::Extended,
+                payload: extension::extended_payload(remote_id, &message.to_bytes()?),
+            })
+            .await
+            .context("send ut_pex message")
+    }
+
+    /// Peer addresses this peer told us about through PEX.
+    pub(crate) fn take_pex_peers(&mut self) -> Vec<SocketAddrV4> {
+        self.pex.take_learned()
     }
 
     pub(crate) fn has_piece(&self, piece_i: usize) -> bool {
@@ -65,7 +333,7 @@ impl Peer {
         nblocks: usize,
         submit: kanal::AsyncSender<usize>,
         tasks: kanal::AsyncReceiver<usize>,
-        finish: tokio::sync::mpsc::Sender<Message>,
+        finish: tokio::sync::mpsc::Sender<(SocketAddrV4, Message)>,
     ) -> anyhow::Result<()> {
         anyhow::ensure!(self.bitfield.has_piece(piece_i));
 
@@ -79,39 +347,14 @@ impl Peer {
 
         // TODO: timeout, error, and return block to submit if .next() timed out
         'task: loop {
-            while self.choked {
-                let unchoke = self
+            while !self.can_request(piece_i) {
+                let msg = self
                     .stream
                     .next()
                     .await
-                    .expect("peer always sends an unchoke")
+                    .context("peer closed the connection")?
                     .context("peer message was invalid")?;
-                match unchoke.tag {
-                    MessageTag::Unchoke => {
-                        self.choked = false;
-                        assert!(unchoke.payload.is_empty());
-                        break;
-                    }
-                    MessageTag::Have => {
-                        // TODO: update bitfield
-                        // TODO: add to list of peers for relevant piece
-                    }
-                    MessageTag::Interested
-                    | MessageTag::NotInterested
-                    | MessageTag::Request
-                    | MessageTag::Cancel => {
-                        // not allowing requests for now
-                    }
-                    MessageTag::Piece => {
-                        // piece that we no longer need/are responsible for
-                    }
-                    MessageTag::Choke => {
-                        anyhow::bail!("peer sent unchoke while unchoked");
-                    }
-                    MessageTag::Bitfield => {
-                        anyhow::bail!("peer sent bitfield after handshake has been completed");
-                    }
-                }
+                self.on_message(msg).await?;
             }
             let Ok(block) = tasks.recv().await else {
                 break;
@@ -137,7 +380,7 @@ impl Peer {
             self.stream
                 .send(Message {
                     tag: MessageTag::Request,
-                    payload: request_bytes,
+                    payload: request_bytes.clone(),
                 })
                 .await
                 .with_context(|| format!("send request for block {block}"))?;
@@ -148,56 +391,53 @@ impl Peer {
                     .stream
                     .next()
                     .await
-                    .expect("peer always sends a piece")
+                    .context("peer closed the connection")?
                     .context("peer message was invalid")?;
 
                 match msg.tag {
                     MessageTag::Choke => {
-                        assert!(msg.payload.is_empty());
                         self.choked = true;
-                        submit.send(block).await.expect("we still have a receiver");
-                        continue 'task;
+                        if !self.fast {
+                            // choking implicitly rejects our pending request
+                            submit.send(block).await.expect("we still have a receiver");
+                            continue 'task;
+                        }
+                    }
+                    MessageTag::RejectRequest => {
+                        if msg.payload == request_bytes {
+                            submit.send(block).await.expect("we still have a receiver");
+                            continue 'task;
+                        }
                     }
                     MessageTag::Piece => {
                         let piece = Piece::ref_from_bytes(&msg.payload[..])
-                            .expect("always get all Piece response fields from peer");
+                            .ok_or_else(|| violation("piece message too short"))?;
 
                         if piece.index() as usize != piece_i
                             || piece.begin() as usize != block * BLOCK_MAX
                         {
                             // piece that we no longer need/are responsible for
+                        } else if piece.block().len() != block_size {
+                            return Err(violation(format!(
+                                "block of {} bytes instead of {block_size}",
+                                piece.block().len()
+                            )));
                         } else {
-                            assert_eq!(piece.block().len(), block_size);
                             break;
                         }
                     }
-                    MessageTag::Have => {
-                        // TODO: update bitfield
-                        // TODO: add to list of peers for relevant piece
-                    }
-                    MessageTag::Interested
-                    | MessageTag::NotInterested
-                    | MessageTag::Request
-                    | MessageTag::Cancel => {
-                        // not allowing requests for now
-                    }
-                    MessageTag::Unchoke => {
-                        //anyhow::bail!("peer sent unchoke while unchoked");
-                    }
-                    MessageTag::Bitfield => {
-                        //anyhow::bail!("peer sent bitfield after handshake has been completed");
-                    }
+                    _ => self.on_message(msg).await?,
                 }
             }
 
-            finish.send(msg).await.expect("receiver should not go away while there are active peers (us) and missing blocks (this one)");
+            finish.send((self.addr, msg)).await.expect("receiver should not go away while there are active peers (us) and missing blocks (this one)");
         }
 
         Ok(())
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct Peers(pub Vec<SocketAddrV4>);
 struct PeersVisitor;
 
@@ -212,7 +452,7 @@ impl<'de> Visitor<'de> for PeersVisitor {
     where
         E: de::Error,
     {
-        if bytes.len() % 6 != 0 {
+        if !bytes.len().is_multiple_of(6) {
             return Err(E::custom(format!("length is {}", bytes.len())));
         }
 
@@ -278,9 +518,52 @@ impl Bitfield {
         })
     }
 
+    /// Whether the peer has every one of the `num_pieces`, i.e. is a seed.
+    pub(crate) fn is_complete(&self, num_pieces: usize) -> bool {
+        (0..num_pieces).all(|piece_i| self.has_piece(piece_i))
+    }
+
+    pub(crate) fn set_piece(&mut self, piece_i: usize) {
+        let byte_i = piece_i / (u8::BITS as usize);
+        let bit_i = (piece_i % (u8::BITS as usize)) as u32;
+        if self.payload.len() <= byte_i {
+            self.payload.resize(byte_i + 1, 0);
+        }
+        self.payload[byte_i] |= 1u8.rotate_right(bit_i + 1);
+    }
+
     fn from_payload(payload: Vec<u8>) -> Bitfield {
         Self { payload }
     }
+
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        &self.payload
+    }
+
+    /// Bitfield of a peer that sent `HaveAll`.
+    pub(crate) fn full(num_pieces: usize) -> Bitfield {
+        let mut bitfield = Self::empty(num_pieces);
+        for piece_i in 0..num_pieces {
+            bitfield.set_piece(piece_i);
+        }
+        bitfield
+    }
+
+    /// Bitfield of a peer that sent `HaveNone`.
+    pub(crate) fn empty(num_pieces: usize) -> Bitfield {
+        Self {
+            payload: vec![0; num_pieces.div_ceil(u8::BITS as usize)],
+        }
+    }
+}
+
+/// Piece index carried by `Have`, `SuggestPiece` and `AllowedFast`.
+fn piece_index(payload: &[u8]) -> anyhow::Result<u32> {
+    let index: [u8; 4] = payload
+        .get(..4)
+        .and_then(|index| index.try_into().ok())
+        .context("message too short for a piece index")?;
+    Ok(u32::from_be_bytes(index))
 }
 
 #[test]
@@ -312,6 +595,68 @@ fn bitfield_iter() {
     assert_eq!(pieces.next(), None);
 }
 
+#[test]
+fn bitfield_have_all_none() {
+    let mut bf = Bitfield::empty(10);
+    assert_eq!(bf.pieces().count(), 0);
+    bf.set_piece(9);
+    assert!(bf.has_piece(9));
+    assert_eq!(
+        Bitfield::full(10).pieces().collect::<Vec<_>>(),
+        (0..10).collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn bitfield_complete() {
+    let mut bf = Bitfield::empty(10);
+    for piece_i in 0..9 {
+        bf.set_piece(piece_i);
+    }
+    assert!(!bf.is_complete(10));
+    bf.set_piece(9);
+    assert!(bf.is_complete(10));
+    assert!(Bitfield::full(10).is_complete(10));
+}
+
+#[tokio::test]
+async fn missing_bitfield_is_empty() {
+    use crate::listener::Listener;
+
+    let info_hash = [7u8; 20];
+    let listener = Listener::bind("127.0.0.1:0".parse().unwrap(), EncryptionPolicy::Disabled)
+        .await
+        .unwrap();
+    listener.add_torrent(info_hash);
+    let addr = listener.local_addr().unwrap();
+    let seeding = tokio::spawn(async move {
+        let (stream, addr) = listener.accept().await.unwrap();
+        let incoming = listener.handshake(stream, addr).await.unwrap();
+        let mut peer = Framed::new(incoming.stream, MessageFrame);
+        for tag in [MessageTag::Have, MessageTag::Bitfield] {
+            peer.send(Message {
+                tag,
+                payload: 2u32.to_be_bytes().to_vec(),
+            })
+            .await
+            .unwrap();
+        }
+        peer
+    });
+
+    let config = ConnectConfig {
+        encryption: EncryptionPolicy::Disabled,
+        ..Default::default()
+    };
+    let mut peer = Peer::new(addr, info_hash, 4, &config).await.unwrap();
+    assert!(peer.has_piece(2) && !peer.has_piece(0));
+    let _seeder = seeding.await.unwrap();
+    let (submit, tasks) = kanal::bounded_async(1);
+    let (finish, _done) = tokio::sync::mpsc::channel(1);
+    let late = peer.participate(2, 4, 1, submit, tasks, finish).await;
+    assert!(late.unwrap_err().is::<Violation>());
+}
+
 #[repr(C)]
 #[repr(packed)]
 pub struct Handshake {
@@ -333,6 +678,14 @@ impl Handshake {
         }
     }
 
+    /// Our handshake, advertising the extensions we support.
+    pub fn ours(info_hash: [u8; 20], peer_id: [u8; 20]) -> Self {
+        let mut handshake = Self::new(info_hash, peer_id);
+        handshake.reserved[extension::EXTENSION_BIT.0] |= extension::EXTENSION_BIT.1;
+        handshake.reserved[fast::FAST_BIT.0] |= fast::FAST_BIT.1;
+        handshake
+    }
+
     pub fn as_bytes_mut(&mut self) -> &mut [u8] {
         let bytes = self as *mut Self as *mut [u8; std::mem::size_of::<Self>()];
         let bytes: &mut [u8; std::mem::size_of::<Self>()] = unsafe { &mut *bytes };
@@ -340,15 +693,75 @@ impl Handshake {
     }
 }
 
-pub async fn handshake_peer(peer_ip: SocketAddrV4, info_hash: [u8; 20]) -> TcpStream {
-    let mut peer = tokio::net::TcpStream::connect(peer_ip).await.unwrap();
-    let mut handshake = Handshake::new(info_hash, *b"-MB2025-100101070501");
+/// Opens a connection to `peer_addr` and exchanges handshakes for
+/// `info_hash`, over the transports `config` allows. With `Enabled`
+/// encryption, a failed MSE handshake is retried in plaintext.
+pub(crate) async fn connect(
+    peer_addr: SocketAddrV4,
+    info_hash: [u8; 20],
+    config: &ConnectConfig,
+) -> anyhow::Result<(PeerStream, Handshake)> {
+    match config.encryption {
+        EncryptionPolicy::Disabled => connect_plaintext(peer_addr, info_hash, config).await,
+        EncryptionPolicy::Forced => connect_encrypted(peer_addr, info_hash, config).await,
+        EncryptionPolicy::Enabled => match connect_encrypted(peer_addr, info_hash, config).await {
+            Ok(connected) => Ok(connected),
+            Err(_) => connect_plaintext(peer_addr, info_hash, config).await,
+        },
+    }
+}
+
+async fn connect_plaintext(
+    peer_addr: SocketAddrV4,
+    info_hash: [u8; 20],
+    config: &ConnectConfig,
+) -> anyhow::Result<(PeerStream, Handshake)> {
+    let peer = transport::open(peer_addr, config.transport, config.utp.as_deref()).await?;
+    let mut peer = MseStream::plaintext(peer, Vec::new());
+    let handshake = exchange_handshake(&mut peer, info_hash, config.peer_id).await?;
+    Ok((peer, handshake))
+}
+
+async fn connect_encrypted(
+    peer_addr: SocketAddrV4,
+    info_hash: [u8; 20],
+    config: &ConnectConfig,
+) -> anyhow::Result<(PeerStream, Handshake)> {
+    let peer = transport::open(peer_addr, config.transport, config.utp.as_deref()).await?;
+    let mut peer = mse::initiate(peer, info_hash, config.encryption).await?;
+    let handshake = exchange_handshake(&mut peer, info_hash, config.peer_id).await?;
+    Ok((peer, handshake))
+}
+
+/// Sends our handshake and reads the peer's one.
+async fn exchange_handshake<S>(
+    stream: &mut S,
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+) -> anyhow::Result<Handshake>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut handshake = Handshake::ours(info_hash, peer_id);
     {
         let handshake_bytes = handshake.as_bytes_mut();
-        peer.write_all(handshake_bytes).await.unwrap();
-        peer.read_exact(handshake_bytes).await.unwrap();
+        stream
+            .write_all(handshake_bytes)
+            .await
+            .context("write handshake")?;
+        stream.flush().await.context("write handshake")?;
+        stream
+            .read_exact(handshake_bytes)
+            .await
+            .context("read handshake")?;
     }
-    peer
+    anyhow::ensure!(handshake.length == 19);
+    anyhow::ensure!(&handshake.bittorrent == b"BitTorrent protocol");
+    anyhow::ensure!(
+        handshake.info_hash == info_hash,
+        "peer serves another torrent"
+    );
+    Ok(handshake)
 }
 
 #[repr(C)]
@@ -368,18 +781,6 @@ impl Request {
         }
     }
 
-    pub fn index(&self) -> u32 {
-        u32::from_be_bytes(self.index)
-    }
-
-    pub fn begin(&self) -> u32 {
-        u32::from_be_bytes(self.begin)
-    }
-
-    pub fn length(&self) -> u32 {
-        u32::from_be_bytes(self.length)
-    }
-
     pub fn as_bytes_mut(&mut self) -> &mut [u8] {
         let bytes = self as *mut Self as *mut [u8; std::mem::size_of::<Self>()];
         let bytes: &mut [u8; std::mem::size_of::<Self>()] = unsafe { &mut *bytes };
@@ -430,6 +831,12 @@ pub enum MessageTag {
     Request = 6,
     Piece = 7,
     Cancel = 8,
+    SuggestPiece = 13,
+    HaveAll = 14,
+    HaveNone = 15,
+    RejectRequest = 16,
+    AllowedFast = 17,
+    Extended = 20,
 }
 
 #[derive(Debug, Clone)]
@@ -501,6 +908,12 @@ impl Decoder for MessageFrame {
             6 => MessageTag::Request,
             7 => MessageTag::Piece,
             8 => MessageTag::Cancel,
+            13 => MessageTag::SuggestPiece,
+            14 => MessageTag::HaveAll,
+            15 => MessageTag::HaveNone,
+            16 => MessageTag::RejectRequest,
+            17 => MessageTag::AllowedFast,
+            20 => MessageTag::Extended,
             tag => {
                 return Err(std::io::Error::new(
                     std::io::ErrorKind::InvalidData,
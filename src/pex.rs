@@ -0,0 +1,190 @@
+use crate::bdecoder;
+use crate::peers::Peers;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::net::SocketAddrV4;
+use tokio::time::{Duration, Instant};
+
+/// Minimum delay between two PEX messages sent to, or accepted from, a peer.
+pub const PEX_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Most peers announced in the added or dropped set of one message.
+pub const PEX_MAX_PEERS: usize = 50;
+
+pub const FLAG_ENCRYPTION: u8 = 0x01;
+pub const FLAG_SEED: u8 = 0x02;
+pub const FLAG_UTP: u8 = 0x04;
+pub const FLAG_OUTGOING: u8 = 0x10;
+
+/// Bencoded body of a `ut_pex` extended message. IPv6 sets are ignored.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PexMessage {
+    #[serde(default)]
+    pub added: Peers,
+    #[serde(rename = "added.f", default, with = "serde_bytes")]
+    pub added_f: Vec<u8>,
+    #[serde(default)]
+    pub dropped: Peers,
+}
+
+impl PexMessage {
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        bdecoder::from_bytes(bytes).context("decode ut_pex message")
+    }
+
+    pub fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        bdecoder::to_bytes(self).context("encode ut_pex message")
+    }
+
+    /// Added peers along with their flags (0 when the peer sent none).
+    pub fn added_with_flags(&self) -> impl Iterator<Item = (SocketAddrV4, u8)> + '_ {
+        self.added
+            .0
+            .iter()
+            .enumerate()
+            .map(|(i, &addr)| (addr, self.added_f.get(i).copied().unwrap_or(0)))
+    }
+}
+
+/// Per-peer PEX bookkeeping: what we told the peer last time and when.
+#[derive(Debug, Default)]
+pub(crate) struct PexState {
+    remote_id: Option<u8>,
+    advertised: HashSet<SocketAddrV4>,
+    last_sent: Option<Instant>,
+    last_received: Option<Instant>,
+    learned: Vec<SocketAddrV4>,
+}
+
+impl PexState {
+    pub(crate) fn set_remote_id(&mut self, id: Option<u8>) {
+        self.remote_id = id;
+    }
+
+    pub(crate) fn remote_id(&self) -> Option<u8> {
+        self.remote_id
+    }
+
+    /// Builds the next message to send given the peers we are currently
+    /// connected to, or None if it is too early or nothing changed.
+    pub(crate) fn build(&mut self, connected: &[(SocketAddrV4, u8)]) -> Option<PexMessage> {
+        self.remote_id?;
+        if let Some(last) = self.last_sent {
+            if last.elapsed() < PEX_INTERVAL {
+                return None;
+            }
+        }
+
+        let mut message = PexMessage::default();
+        for &(addr, flags) in connected {
+            if message.added.0.len() >= PEX_MAX_PEERS {
+                break;
+            }
+            if self.advertised.insert(addr) {
+                message.added.0.push(addr);
+                message.added_f.push(flags);
+            }
+        }
+        let still_connected: HashSet<_> = connected.iter().map(|&(addr, _)| addr).collect();
+        let gone: Vec<_> = self
+            .advertised
+            .iter()
+            .filter(|addr| !still_connected.contains(addr))
+            .take(PEX_MAX_PEERS)
+            .copied()
+            .collect();
+        for addr in gone {
+            self.advertised.remove(&addr);
+            message.dropped.0.push(addr);
+        }
+
+        if message.added.0.is_empty() && message.dropped.0.is_empty() {
+            return None;
+        }
+        self.last_sent = Some(Instant::now());
+        Some(message)
+    }
+
+    /// Handles a `ut_pex` message from `from`. Messages arriving faster than
+    /// the protocol allows are dropped, as are implausible addresses.
+    pub(crate) fn receive(&mut self, body: &[u8], from: SocketAddrV4) -> anyhow::Result<()> {
+        if let Some(last) = self.last_received {
+            if last.elapsed() < PEX_INTERVAL / 2 {
+                return Ok(());
+            }
+        }
+        self.last_received = Some(Instant::now());
+
+        let message = PexMessage::from_bytes(body)?;
+        self.learned.extend(
+            message
+                .added_with_flags()
+                .map(|(addr, _)| addr)
+                .filter(|&addr| addr != from && is_plausible(addr))
+                .take(PEX_MAX_PEERS),
+        );
+        Ok(())
+    }
+
+    /// Takes the addresses learned since the last call.
+    pub(crate) fn take_learned(&mut self) -> Vec<SocketAddrV4> {
+        std::mem::take(&mut self.learned)
+    }
+}
+
+/// Rejects addresses no peer could be listening on.
+pub fn is_plausible(addr: SocketAddrV4) -> bool {
+    let ip = addr.ip();
+    addr.port() != 0 && !ip.is_unspecified() && !ip.is_broadcast() && !ip.is_multicast()
+}
+
+#[test]
+fn pex_message_round_trip() {
+    let a = "10.0.0.1:6881".parse().unwrap();
+    let b = "10.0.0.2:51413".parse().unwrap();
+    let message = PexMessage {
+        added: Peers(vec![a, b]),
+        added_f: vec![FLAG_OUTGOING, FLAG_SEED],
+        dropped: Peers(vec![]),
+    };
+    let bytes = message.to_bytes().unwrap();
+    let decoded = PexMessage::from_bytes(&bytes).unwrap();
+    assert_eq!(
+        decoded.added_with_flags().collect::<Vec<_>>(),
+        vec![(a, FLAG_OUTGOING), (b, FLAG_SEED)]
+    );
+    assert!(decoded.dropped.0.is_empty());
+}
+
+#[test]
+fn pex_state_diffs_and_filters() {
+    let a = "10.0.0.1:6881".parse().unwrap();
+    let b = "10.0.0.2:6881".parse().unwrap();
+    let mut state = PexState::default();
+    assert!(state.build(&[(a, 0)]).is_none(), "peer has no ut_pex id");
+
+    state.set_remote_id(Some(3));
+    let first = state.build(&[(a, 0), (b, 0)]).unwrap();
+    assert_eq!(first.added.0, vec![a, b]);
+    assert!(state.build(&[(a, 0)]).is_none(), "rate limited");
+
+    state.last_sent = None;
+    let second = state.build(&[(a, 0)]).unwrap();
+    assert!(second.added.0.is_empty());
+    assert_eq!(second.dropped.0, vec![b]);
+
+    let from = "10.0.0.9:6881".parse().unwrap();
+    let incoming = PexMessage {
+        added: Peers(vec![
+            a,
+            from,
+            "0.0.0.0:6881".parse().unwrap(),
+            "10.0.0.3:0".parse().unwrap(),
+        ]),
+        ..Default::default()
+    };
+    state.receive(&incoming.to_bytes().unwrap(), from).unwrap();
+    state.receive(&incoming.to_bytes().unwrap(), from).unwrap();
+    assert_eq!(state.take_learned(), vec![a]);
+}
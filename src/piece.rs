@@ -1,9 +1,12 @@
+use crate::parsing::Info;
+use crate::session::FilePriority;
 use crate::tracker::compute_length;
 use crate::{parsing::MetaInfo, peers::Peer};
 use std::collections::HashSet;
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct PieceFile {
+    priority: FilePriority,
     peers: HashSet<usize>,
     piece_i: usize,
     length: usize,
@@ -12,9 +15,9 @@ pub struct PieceFile {
 
 impl Ord for PieceFile {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.peers
-            .len()
-            .cmp(&other.peers.len())
+        self.priority
+            .cmp(&other.priority)
+            .then(self.peers.len().cmp(&other.peers.len()))
             .then(self.peers.iter().cmp(other.peers.iter()))
             .then(self.hash.cmp(&other.hash))
             .then(self.length.cmp(&other.length))
@@ -29,7 +32,12 @@ impl PartialOrd for PieceFile {
 }
 
 impl PieceFile {
-    pub(crate) fn new(piece_i: usize, meta_info: &MetaInfo, peers: &[Peer]) -> Self {
+    pub(crate) fn new(
+        piece_i: usize,
+        meta_info: &MetaInfo,
+        peers: &[Peer],
+        priority: FilePriority,
+    ) -> Self {
         let piece_hash = meta_info.info.pieces[piece_i];
         let piece_size = if piece_i == meta_info.info.pieces.len() - 1 {
             let torrent_length = compute_length(&meta_info.info);
@@ -50,6 +58,7 @@ impl PieceFile {
             .collect();
 
         Self {
+            priority,
             peers,
             piece_i,
             length: piece_size,
@@ -61,6 +70,10 @@ impl PieceFile {
         &self.peers
     }
 
+    pub(crate) fn priority(&self) -> FilePriority {
+        self.priority
+    }
+
     pub(crate) fn index(&self) -> usize {
         self.piece_i
     }
@@ -73,3 +86,76 @@ impl PieceFile {
         self.length
     }
 }
+
+/// How much each piece is wanted: as much as the most wanted file it holds
+/// data of, so `Skip` only when every such file is skipped. Files without
+/// a priority are normal, and padding files never count.
+pub(crate) fn piece_priorities(info: &Info, file_priorities: &[FilePriority]) -> Vec<FilePriority> {
+    spans(info, file_priorities).0
+}
+
+/// The wanted pieces that also hold data of a skipped file. Writing them
+/// to their files would create the skipped ones.
+pub(crate) fn boundary_pieces(info: &Info, file_priorities: &[FilePriority]) -> Vec<usize> {
+    let (priorities, skipped) = spans(info, file_priorities);
+    (0..priorities.len())
+        .filter(|&piece_i| priorities[piece_i] != FilePriority::Skip && skipped[piece_i])
+        .collect()
+}
+
+/// For each piece, the highest priority of its files and whether one of
+/// them is skipped.
+fn spans(info: &Info, file_priorities: &[FilePriority]) -> (Vec<FilePriority>, Vec<bool>) {
+    let mut priorities = vec![FilePriority::Skip; info.pieces.len()];
+    let mut skipped = vec![false; info.pieces.len()];
+    let files = match &info.files {
+        Some(files) => files
+            .iter()
+            .map(|file| (file.length, file.is_padding()))
+            .collect(),
+        None => vec![(info.length, false)],
+    };
+    let mut offset = 0;
+    for (file_i, (length, padding)) in files.into_iter().enumerate() {
+        let start = offset;
+        offset += length;
+        if padding || length == 0 {
+            continue;
+        }
+        let priority = file_priorities.get(file_i).copied().unwrap_or_default();
+        for piece_i in start / info.piece_length..=(offset - 1) / info.piece_length {
+            priorities[piece_i] = priorities[piece_i].max(priority);
+            skipped[piece_i] |= priority == FilePriority::Skip;
+        }
+    }
+    (priorities, skipped)
+}
+
+#[test]
+fn file_priorities_make_piece_priorities() {
+    use crate::parsing::File;
+
+    let file = |length| File {
+        length,
+        ..Default::default()
+    };
+    // pieces of 4: [a a a b] [b b b b] [b c c c]
+    let info = Info {
+        files: Some(vec![file(3), file(6), file(3)]),
+        piece_length: 4,
+        pieces: vec![[0; 20]; 3],
+        ..Default::default()
+    };
+    use FilePriority::*;
+    assert_eq!(piece_priorities(&info, &[]), [Normal; 3]);
+    assert_eq!(
+        piece_priorities(&info, &[High, Skip, Low]),
+        [High, Skip, Low]
+    );
+    assert_eq!(boundary_pieces(&info, &[High, Skip, Low]), [0, 2]);
+    assert_eq!(
+        piece_priorities(&info, &[Skip, Skip, Low]),
+        [Skip, Skip, Low]
+    );
+    assert_eq!(boundary_pieces(&info, &[Skip, Normal, Skip]), [0, 2]);
+}
@@ -0,0 +1,402 @@
+use anyhow::Context;
+use std::future::Future;
+use std::io;
+use std::net::Ipv4Addr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Poll};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Sleep;
+
+/// Download and upload rates, in bytes a second, 0 for unlimited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RateLimits {
+    pub download: u64,
+    pub upload: u64,
+}
+
+/// Limits used instead of the global ones every day between two times,
+/// given in minutes after midnight UTC. `from` after `to` spans midnight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AlternativeRates {
+    pub limits: RateLimits,
+    pub from: u16,
+    pub to: u16,
+}
+
+impl AlternativeRates {
+    fn active_at(&self, minute: u16) -> bool {
+        if self.from <= self.to {
+            (self.from..self.to).contains(&minute)
+        } else {
+            minute >= self.from || minute < self.to
+        }
+    }
+
+    /// Whether the alternative limits apply now.
+    pub fn active(&self) -> bool {
+        let seconds = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.active_at((seconds % 86_400 / 60) as u16)
+    }
+}
+
+/// The bandwidth of a session.
+#[derive(Debug, Clone, Default)]
+pub struct RateConfig {
+    /// Shared by every connection of the session.
+    pub global: RateLimits,
+    /// Shared by the connections of each torrent.
+    pub per_torrent: RateLimits,
+    pub per_peer: RateLimits,
+    /// Shared by the peers on the local network, which then skip the
+    /// global and torrent limits. Without it they count as the others.
+    pub lan: Option<RateLimits>,
+    pub alternative: Option<AlternativeRates>,
+}
+
+/// A token bucket: `rate` bytes a second, with bursts of up to a second
+/// of them.
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    rate: AtomicU64,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Negative after a transfer larger than what was left.
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(rate: u64) -> Self {
+        Self {
+            rate: AtomicU64::new(rate),
+            bucket: Mutex::new(Bucket {
+                tokens: rate as f64,
+                last: Instant::now(),
+            }),
+        }
+    }
+
+    pub(crate) fn rate(&self) -> u64 {
+        self.rate.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_rate(&self, rate: u64) {
+        self.rate.store(rate, Ordering::Relaxed);
+    }
+
+    /// Bytes that may go through now, or how long until some may.
+    fn available(&self) -> Result<usize, Duration> {
+        let rate = self.rate();
+        if rate == 0 {
+            return Ok(usize::MAX);
+        }
+        let mut bucket = self.bucket.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.last).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate as f64).min(rate as f64);
+        bucket.last = now;
+        if bucket.tokens >= 1.0 {
+            Ok(bucket.tokens as usize)
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / rate as f64))
+        }
+    }
+
+    fn consume(&self, bytes: usize) {
+        if self.rate() != 0 {
+            self.bucket.lock().unwrap().tokens -= bytes as f64;
+        }
+    }
+}
+
+/// The buckets a connection draws from, each way.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Limiters {
+    download: Vec<Arc<RateLimiter>>,
+    upload: Vec<Arc<RateLimiter>>,
+}
+
+impl Limiters {
+    fn new(limits: RateLimits) -> Self {
+        Self {
+            download: vec![Arc::new(RateLimiter::new(limits.download))],
+            upload: vec![Arc::new(RateLimiter::new(limits.upload))],
+        }
+    }
+
+    pub(crate) fn set_limits(&self, limits: RateLimits) {
+        for limiter in &self.download {
+            limiter.set_rate(limits.download);
+        }
+        for limiter in &self.upload {
+            limiter.set_rate(limits.upload);
+        }
+    }
+
+    fn and(&self, other: &Limiters) -> Limiters {
+        Limiters {
+            download: [&self.download[..], &other.download[..]].concat(),
+            upload: [&self.upload[..], &other.upload[..]].concat(),
+        }
+    }
+}
+
+/// The limits of the connections of a torrent. No limits by default.
+#[derive(Debug, Clone, Default)]
+pub struct Throttle {
+    remote: Limiters,
+    lan: Option<Limiters>,
+    per_peer: RateLimits,
+}
+
+impl Throttle {
+    /// Wraps a connection to a peer at `ip` in its limits.
+    pub(crate) fn wrap<S>(&self, ip: &Ipv4Addr, stream: S) -> Throttled<S> {
+        let lan = ip.is_private() || ip.is_loopback() || ip.is_link_local();
+        let shared = match (&self.lan, lan) {
+            (Some(lan), true) => lan,
+            _ => &self.remote,
+        };
+        Throttled {
+            inner: stream,
+            limiters: shared.and(&Limiters::new(self.per_peer)),
+            read_wait: None,
+            write_wait: None,
+        }
+    }
+}
+
+/// The shared buckets of a session.
+#[derive(Debug)]
+pub(crate) struct SessionRates {
+    config: RateConfig,
+    global: Limiters,
+    lan: Option<Limiters>,
+}
+
+impl SessionRates {
+    pub(crate) fn new(config: RateConfig) -> Self {
+        Self {
+            global: Limiters::new(config.global),
+            lan: config.lan.map(Limiters::new),
+            config,
+        }
+    }
+
+    pub(crate) fn global(&self) -> &Limiters {
+        &self.global
+    }
+
+    /// The throttle of a new torrent, and the buckets only it draws from.
+    pub(crate) fn torrent(&self) -> (Throttle, Limiters) {
+        let torrent = Limiters::new(self.config.per_torrent);
+        let throttle = Throttle {
+            remote: self.global.and(&torrent),
+            lan: self.lan.clone(),
+            per_peer: self.config.per_peer,
+        };
+        (throttle, torrent)
+    }
+
+    /// Switches the global limits to the alternative ones and back as
+    /// their schedule says, checking every minute.
+    pub(crate) async fn follow_schedule(&self) {
+        let Some(alternative) = self.config.alternative else {
+            return std::future::pending().await;
+        };
+        loop {
+            self.global.set_limits(if alternative.active() {
+                alternative.limits
+            } else {
+                self.config.global
+            });
+            tokio::time::sleep(Duration::from_secs(60)).await;
+        }
+    }
+}
+
+/// A stream whose reads and writes wait for the tokens of its limiters.
+#[derive(Debug)]
+pub(crate) struct Throttled<S> {
+    inner: S,
+    limiters: Limiters,
+    read_wait: Option<Pin<Box<Sleep>>>,
+    write_wait: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> Throttled<S> {
+    pub(crate) fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+/// Waits until every limiter has tokens, and returns the fewest any has.
+fn poll_allowance(
+    limiters: &[Arc<RateLimiter>],
+    wait: &mut Option<Pin<Box<Sleep>>>,
+    cx: &mut std::task::Context<'_>,
+) -> Poll<usize> {
+    loop {
+        if let Some(sleep) = wait {
+            ready!(sleep.as_mut().poll(cx));
+            *wait = None;
+        }
+        let mut allowed = usize::MAX;
+        let mut longest = Duration::ZERO;
+        for limiter in limiters {
+            match limiter.available() {
+                Ok(available) => allowed = allowed.min(available),
+                Err(until) => longest = longest.max(until),
+            }
+        }
+        if longest.is_zero() {
+            return Poll::Ready(allowed);
+        }
+        *wait = Some(Box::pin(tokio::time::sleep(longest)));
+    }
+}
+
+fn consume(limiters: &[Arc<RateLimiter>], bytes: usize) {
+    for limiter in limiters {
+        limiter.consume(bytes);
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Throttled<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let allowed = ready!(poll_allowance(
+            &this.limiters.download,
+            &mut this.read_wait,
+            cx
+        ));
+        let mut limited = ReadBuf::new(buf.initialize_unfilled_to(allowed.min(buf.remaining())));
+        ready!(Pin::new(&mut this.inner).poll_read(cx, &mut limited))?;
+        let n = limited.filled().len();
+        buf.advance(n);
+        consume(&this.limiters.download, n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Throttled<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let allowed = ready!(poll_allowance(
+            &this.limiters.upload,
+            &mut this.write_wait,
+            cx
+        ));
+        let n = ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..allowed.min(buf.len())]))?;
+        consume(&this.limiters.upload, n);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Parses a rate in bytes a second, with an optional `K`, `M` or `G`
+/// suffix for powers of 1024: `500K`, `2M`. 0 is unlimited.
+pub fn parse_rate(rate: &str) -> anyhow::Result<u64> {
+    let (number, unit) = match rate.char_indices().last() {
+        Some((at, suffix)) if suffix.is_ascii_alphabetic() => (&rate[..at], suffix),
+        _ => (rate, 'B'),
+    };
+    let scale: u64 = match unit.to_ascii_uppercase() {
+        'B' => 1,
+        'K' => 1 << 10,
+        'M' => 1 << 20,
+        'G' => 1 << 30,
+        _ => anyhow::bail!("unknown unit {unit} in {rate}"),
+    };
+    let number: u64 = number
+        .parse()
+        .with_context(|| format!("invalid rate {rate}"))?;
+    number
+        .checked_mul(scale)
+        .with_context(|| format!("rate {rate} is too large"))
+}
+
+/// Parses a daily window `HH:MM-HH:MM` into minutes after midnight.
+pub fn parse_schedule(schedule: &str) -> anyhow::Result<(u16, u16)> {
+    let minutes = |time: &str| -> anyhow::Result<u16> {
+        let (hours, minutes) = time
+            .split_once(':')
+            .with_context(|| format!("{time} is not HH:MM"))?;
+        let (hours, minutes): (u16, u16) = (hours.parse()?, minutes.parse()?);
+        anyhow::ensure!(hours < 24 && minutes < 60, "{time} is not a time of day");
+        Ok(hours * 60 + minutes)
+    };
+    let (from, to) = schedule
+        .split_once('-')
+        .with_context(|| format!("{schedule} is not HH:MM-HH:MM"))?;
+    Ok((minutes(from)?, minutes(to)?))
+}
+
+#[test]
+fn rates_and_schedules_parse() {
+    assert_eq!(parse_rate("500").unwrap(), 500);
+    assert_eq!(parse_rate("64K").unwrap(), 64 << 10);
+    assert_eq!(parse_rate("2m").unwrap(), 2 << 20);
+    assert!(parse_rate("2X").is_err());
+    assert_eq!(parse_schedule("22:30-06:00").unwrap(), (1350, 360));
+    assert!(parse_schedule("25:00-06:00").is_err());
+
+    let night = AlternativeRates {
+        limits: RateLimits::default(),
+        from: 1350,
+        to: 360,
+    };
+    assert!(night.active_at(0) && night.active_at(1400) && !night.active_at(720));
+}
+
+#[tokio::test]
+async fn throttled_streams_keep_to_their_rate() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let (near, mut far) = tokio::io::duplex(1 << 16);
+    let throttle = Throttle {
+        remote: Limiters::new(RateLimits {
+            download: 0,
+            upload: 200_000,
+        }),
+        ..Default::default()
+    };
+    let mut near = throttle.wrap(&Ipv4Addr::new(1, 2, 3, 4), near);
+    let data: Vec<u8> = (0..300_000).map(|i| i as u8).collect();
+    let start = Instant::now();
+    let sent = data.clone();
+    let writer = tokio::spawn(async move { near.write_all(&sent).await });
+    let mut received = vec![0; data.len()];
+    far.read_exact(&mut received).await.unwrap();
+    writer.await.unwrap().unwrap();
+    assert_eq!(received, data);
+    // a second of burst, then 100 000 bytes at 200 000 a second
+    assert!(start.elapsed() >= Duration::from_millis(450));
+}
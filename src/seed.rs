@@ -0,0 +1,531 @@
+use crate::extension::{self, ExtendedHandshake, UT_METADATA, UT_METADATA_ID};
+use crate::fast;
+use crate::listener::IncomingPeer;
+use crate::metadata::{self, MetadataMessage};
+use crate::output::sanitize_component;
+use crate::parsing::{Info, MetaInfo};
+use crate::parts;
+use crate::peers::{Bitfield, Message, MessageFrame, MessageTag};
+use crate::piece::{boundary_pieces, piece_priorities};
+use crate::rate::Throttle;
+use crate::session::FilePriority;
+use crate::tracker::compute_length;
+use crate::webseed::file_ranges;
+use anyhow::Context;
+use futures_util::{SinkExt, StreamExt};
+use sha1::{Digest, Sha1};
+use std::collections::{BTreeMap, HashSet};
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio_util::codec::Framed;
+
+/// Largest block a peer may request from us.
+const MAX_REQUEST: usize = 1 << 17;
+
+/// The data of a torrent on disk, read back for the peers: all of it, or
+/// the pieces of the files that were not skipped. Downloads write their
+/// pieces to it as they are verified.
+#[derive(Debug)]
+pub(crate) struct Storage {
+    info: Info,
+    root: PathBuf,
+    have: Bitfield,
+    /// Paths in the torrent of the skipped files, which are never written.
+    skipped: HashSet<String>,
+    /// Wanted pieces shared with skipped files.
+    boundary: Vec<usize>,
+    /// Pieces shared with skipped files, kept in the parts file.
+    parts: Mutex<BTreeMap<usize, Vec<u8>>>,
+    /// The info dictionary, served to peers that joined from a magnet link.
+    metadata: Option<Vec<u8>>,
+}
+
+impl Storage {
+    /// The torrent stored under `data_dir`, as `create` or a download
+    /// left it: a file or a directory named after the torrent, with the
+    /// names sanitized the way downloads save them.
+    pub(crate) fn new(meta_info: &MetaInfo, data_dir: &Path) -> Self {
+        Self::at(
+            meta_info,
+            data_dir.join(sanitize_component(&meta_info.info.name)),
+        )
+    }
+
+    /// The torrent stored at `root`, wherever a download saved it.
+    pub(crate) fn at(meta_info: &MetaInfo, root: PathBuf) -> Self {
+        let metadata = meta_info.info_bytes();
+        Self {
+            info: meta_info.info.clone(),
+            root,
+            have: Bitfield::full(meta_info.info.pieces.len()),
+            skipped: HashSet::new(),
+            boundary: Vec::new(),
+            parts: Mutex::default(),
+            // peers check it against the info hash
+            metadata: (Sha1::digest(&metadata)[..] == meta_info.info_hash.0[..])
+                .then_some(metadata),
+        }
+    }
+
+    /// Keeps only the pieces of the files that are not skipped, reading
+    /// those shared with skipped files from the parts file.
+    pub(crate) fn only(mut self, file_priorities: &[FilePriority]) -> Self {
+        let priorities = piece_priorities(&self.info, file_priorities);
+        self.have = Bitfield::empty(priorities.len());
+        for (index, priority) in priorities.into_iter().enumerate() {
+            if priority != FilePriority::Skip {
+                self.have.set_piece(index);
+            }
+        }
+        if let Some(files) = &self.info.files {
+            self.skipped = files
+                .iter()
+                .zip(file_priorities)
+                .filter(|(_, &priority)| priority == FilePriority::Skip)
+                .map(|(file, _)| file.path.clone())
+                .collect();
+        }
+        // a parts file that cannot be read fails the check of its pieces
+        let mut parts = parts::read(&parts::path(&self.root)).unwrap_or_default();
+        self.boundary = boundary_pieces(&self.info, file_priorities);
+        parts.retain(|index, _| self.boundary.contains(index));
+        self.parts = Mutex::new(parts);
+        self
+    }
+
+    pub(crate) fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub(crate) fn num_pieces(&self) -> usize {
+        self.info.pieces.len()
+    }
+
+    /// Number of pieces we have and serve.
+    pub(crate) fn num_have(&self) -> usize {
+        self.have
+            .pieces()
+            .filter(|&index| index < self.num_pieces())
+            .count()
+    }
+
+    fn piece_length(&self, index: usize) -> usize {
+        let offset = index * self.info.piece_length;
+        self.info
+            .piece_length
+            .min(compute_length(&self.info) - offset)
+    }
+
+    /// Where the file at `path` in the torrent is stored.
+    fn file_path(&self, path: &[&str]) -> PathBuf {
+        path.iter().fold(self.root.clone(), |dir, part| {
+            dir.join(sanitize_component(part))
+        })
+    }
+
+    /// Reads `length` bytes at `offset` of the torrent, across files.
+    pub(crate) async fn read(&self, offset: usize, length: usize) -> anyhow::Result<Vec<u8>> {
+        let mut bytes = Vec::with_capacity(length);
+        for (path, range, padding) in file_ranges(&self.info, offset, length) {
+            if padding {
+                bytes.resize(bytes.len() + range.len(), 0);
+                continue;
+            }
+            let path = self.file_path(&path);
+            let mut file = File::open(&path)
+                .await
+                .with_context(|| format!("open {}", path.display()))?;
+            file.seek(SeekFrom::Start(range.start as u64)).await?;
+            let start = bytes.len();
+            bytes.resize(start + range.len(), 0);
+            file.read_exact(&mut bytes[start..])
+                .await
+                .with_context(|| format!("read {}", path.display()))?;
+        }
+        Ok(bytes)
+    }
+
+    /// Reads `length` bytes at `begin` of piece `index`, from the parts
+    /// file if the piece is there.
+    async fn read_block(
+        &self,
+        index: usize,
+        begin: usize,
+        length: usize,
+    ) -> anyhow::Result<Vec<u8>> {
+        let part = self.parts.lock().unwrap().get(&index).map(|piece| {
+            piece
+                .get(begin..begin + length)
+                .map(<[u8]>::to_vec)
+                .context("piece in the parts file is too short")
+        });
+        match part {
+            Some(block) => block,
+            None => {
+                self.read(index * self.info.piece_length + begin, length)
+                    .await
+            }
+        }
+    }
+
+    /// Creates the files that are not skipped, keeping what they hold, so
+    /// that even those without a byte to download are there. Skipped files
+    /// an earlier attempt wanted are removed.
+    pub(crate) async fn create_files(&self) -> anyhow::Result<()> {
+        for path in &self.skipped {
+            let path = self.file_path(&path.split('/').collect::<Vec<_>>());
+            match tokio::fs::remove_file(&path).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    return Err(e).with_context(|| format!("remove {}", path.display()));
+                }
+                _ => {}
+            }
+        }
+        let files = match &self.info.files {
+            Some(files) => files
+                .iter()
+                .filter(|file| !file.is_padding() && !self.skipped.contains(&file.path))
+                .map(|file| file.path.split('/').collect())
+                .collect(),
+            None => vec![Vec::new()],
+        };
+        for path in files {
+            self.open(&self.file_path(&path)).await?;
+        }
+        Ok(())
+    }
+
+    /// Opens the file at `path` for writing, creating it and its directory.
+    async fn open(&self, path: &Path) -> anyhow::Result<File> {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .with_context(|| format!("create {}", parent.display()))?;
+        }
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .await
+            .with_context(|| format!("open {}", path.display()))
+    }
+
+    /// Writes verified piece `index` to the files that are not skipped, and
+    /// to the parts file when it is shared with a skipped one.
+    pub(crate) async fn write_piece(&self, index: usize, bytes: &[u8]) -> anyhow::Result<()> {
+        let mut rest = bytes;
+        for (path, range, padding) in
+            file_ranges(&self.info, index * self.info.piece_length, bytes.len())
+        {
+            let (data, after) = rest.split_at(range.len());
+            rest = after;
+            if padding || self.skipped.contains(&path.join("/")) {
+                continue;
+            }
+            let path = self.file_path(&path);
+            let mut file = self.open(&path).await?;
+            file.seek(SeekFrom::Start(range.start as u64)).await?;
+            file.write_all(data)
+                .await
+                .with_context(|| format!("write {}", path.display()))?;
+            file.flush().await?;
+        }
+        if self.boundary.contains(&index) {
+            let mut parts = self.parts.lock().unwrap();
+            parts.insert(index, bytes.to_vec());
+            let pieces = parts.iter().map(|(&index, piece)| (index, &piece[..]));
+            parts::write(&parts::path(&self.root), pieces)?;
+        }
+        Ok(())
+    }
+
+    /// Piece `index`, if we have it.
+    pub(crate) async fn read_piece(&self, index: usize) -> Option<anyhow::Result<Vec<u8>>> {
+        if !self.have.has_piece(index) {
+            return None;
+        }
+        Some(self.read_block(index, 0, self.piece_length(index)).await)
+    }
+
+    /// Indices of the pieces we have whose data is there and matches their
+    /// hash, as an unfinished download left them.
+    pub(crate) async fn verified(&self) -> Vec<usize> {
+        let mut good = Vec::new();
+        for (index, hash) in self.info.pieces.iter().enumerate() {
+            if !self.have.has_piece(index) {
+                continue;
+            }
+            let piece = self.read_block(index, 0, self.piece_length(index)).await;
+            if matches!(piece, Ok(piece) if Sha1::digest(&piece)[..] == hash[..]) {
+                good.push(index);
+            }
+        }
+        good
+    }
+
+    /// Indices of the pieces we have whose data does not match their hash.
+    pub(crate) async fn verify(&self) -> anyhow::Result<Vec<usize>> {
+        let mut bad = Vec::new();
+        for (index, hash) in self.info.pieces.iter().enumerate() {
+            if !self.have.has_piece(index) {
+                continue;
+            }
+            let piece = self.read_block(index, 0, self.piece_length(index)).await?;
+            if Sha1::digest(&piece)[..] != hash[..] {
+                bad.push(index);
+            }
+        }
+        Ok(bad)
+    }
+}
+
+/// Uploads to a peer that connected to us: we have our pieces, unchoke it
+/// as soon as it is interested and answer its requests until it leaves.
+/// Peers supporting extensions may also ask for the info dictionary, and
+/// those supporting fast get their allowed fast set.
+pub(crate) async fn serve(
+    storage: &Storage,
+    incoming: IncomingPeer,
+    throttle: &Throttle,
+) -> anyhow::Result<()> {
+    let extensions = extension::supports_extensions(&incoming.handshake.reserved);
+    let allowed_fast = if fast::supports_fast(&incoming.handshake.reserved) {
+        fast::allowed_fast_set(
+            *incoming.addr.ip(),
+            incoming.handshake.info_hash,
+            storage.num_pieces(),
+            fast::ALLOWED_FAST_COUNT,
+        )
+    } else {
+        Vec::new()
+    };
+    let stream = throttle.wrap(incoming.addr.ip(), incoming.stream);
+    let mut peer = Framed::new(stream, MessageFrame);
+    peer.send(Message {
+        tag: MessageTag::Bitfield,
+        payload: storage.have.as_bytes().to_vec(),
+    })
+    .await
+    .context("send bitfield")?;
+    for index in allowed_fast {
+        peer.send(Message {
+            tag: MessageTag::AllowedFast,
+            payload: index.to_be_bytes().to_vec(),
+        })
+        .await
+        .context("send allowed fast")?;
+    }
+    if let (true, Some(metadata)) = (extensions, &storage.metadata) {
+        let ours = metadata::handshake(Some(metadata.len())).to_bytes()?;
+        peer.send(Message {
+            tag: MessageTag::Extended,
+            payload: extension::extended_payload(extension::HANDSHAKE_ID, &ours),
+        })
+        .await
+        .context("send extended handshake")?;
+    }
+    let mut metadata_id = None;
+
+    while let Some(message) = peer.next().await {
+        let message = message.context("peer message was invalid")?;
+        match message.tag {
+            MessageTag::Interested => {
+                peer.send(Message {
+                    tag: MessageTag::Unchoke,
+                    payload: Vec::new(),
+                })
+                .await
+                .context("send unchoke")?;
+            }
+            MessageTag::Request => {
+                let field = |i: usize| -> anyhow::Result<usize> {
+                    let bytes = message
+                        .payload
+                        .get(4 * i..4 * i + 4)
+                        .context("request is too short")?;
+                    Ok(u32::from_be_bytes(bytes.try_into()?) as usize)
+                };
+                let (index, begin, length) = (field(0)?, field(1)?, field(2)?);
+                anyhow::ensure!(
+                    index < storage.num_pieces()
+                        && storage.have.has_piece(index)
+                        && length <= MAX_REQUEST
+                        && begin + length <= storage.piece_length(index),
+                    "peer requested an invalid block"
+                );
+                let mut payload = message.payload[..8].to_vec();
+                payload.extend(storage.read_block(index, begin, length).await?);
+                peer.send(Message {
+                    tag: MessageTag::Piece,
+                    payload,
+                })
+                .await
+                .context("send piece")?;
+            }
+            MessageTag::Extended => {
+                let Some((&id, body)) = message.payload.split_first() else {
+                    continue;
+                };
+                match (id, &storage.metadata) {
+                    (extension::HANDSHAKE_ID, _) => {
+                        metadata_id = ExtendedHandshake::from_bytes(body)?.id_of(UT_METADATA);
+                    }
+                    (UT_METADATA_ID, Some(info)) => {
+                        let (request, _) = MetadataMessage::from_bytes(body)?;
+                        let Some(remote_id) = metadata_id.filter(|_| request.is_request()) else {
+                            continue;
+                        };
+                        peer.send(Message {
+                            tag: MessageTag::Extended,
+                            payload: extension::extended_payload(
+                                remote_id,
+                                &metadata::answer(info, request.piece),
+                            ),
+                        })
+                        .await
+                        .context("send metadata")?;
+                    }
+                    _ => {
+                        // extension we never advertised
+                    }
+                }
+            }
+            _ => {
+                // nothing to do for a seed
+            }
+        }
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn seed_answers_requests() {
+    use crate::listener::Listener;
+    use crate::mse::EncryptionPolicy;
+    use crate::peers::{connect, ConnectConfig, Piece};
+
+    let dir = std::env::temp_dir().join(format!("rustorrent-seed-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("t")).unwrap();
+    std::fs::write(dir.join("t/a"), b"hello ").unwrap();
+    std::fs::write(dir.join("t/b"), b"world").unwrap();
+    let created = crate::create::create_torrent(
+        &dir.join("t"),
+        &crate::create::CreateOptions {
+            announce: Some(String::from("http://tracker.invalid/announce")),
+            piece_length: Some(1 << 14),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let meta_info = crate::parsing::parse_metainfo(&created.bytes).unwrap();
+    let storage = Storage::new(&meta_info, &dir);
+    assert!(storage.verify().await.unwrap().is_empty());
+    let num_pieces = storage.num_pieces();
+
+    let listener = Listener::bind("127.0.0.1:0".parse().unwrap(), EncryptionPolicy::Disabled)
+        .await
+        .unwrap();
+    listener.add_torrent(created.info_hash);
+    let addr = listener.local_addr().unwrap();
+    let seeding = tokio::spawn(async move {
+        let (stream, addr) = listener.accept().await.unwrap();
+        let incoming = listener.handshake(stream, addr).await.unwrap();
+        serve(&storage, incoming, &Throttle::default()).await
+    });
+
+    let config = ConnectConfig {
+        encryption: EncryptionPolicy::Disabled,
+        ..Default::default()
+    };
+    let (stream, _) = connect(addr, created.info_hash, &config).await.unwrap();
+    let mut peer = Framed::new(stream, MessageFrame);
+    assert_eq!(
+        peer.next().await.unwrap().unwrap().tag,
+        MessageTag::Bitfield
+    );
+    // and with fast, so we may get some pieces while choked
+    let allowed_fast = crate::fast::allowed_fast_set(
+        std::net::Ipv4Addr::LOCALHOST,
+        created.info_hash,
+        num_pieces,
+        crate::fast::ALLOWED_FAST_COUNT,
+    );
+    for index in allowed_fast {
+        let message = peer.next().await.unwrap().unwrap();
+        assert_eq!(message.tag, MessageTag::AllowedFast);
+        assert_eq!(message.payload, index.to_be_bytes());
+    }
+    // we connect with extensions, so the info dictionary is offered too
+    assert_eq!(
+        peer.next().await.unwrap().unwrap().tag,
+        MessageTag::Extended
+    );
+    for tag in [MessageTag::Interested, MessageTag::Request] {
+        let payload = match tag {
+            MessageTag::Request => [0u32, 3, 5].iter().flat_map(|n| n.to_be_bytes()).collect(),
+            _ => Vec::new(),
+        };
+        peer.send(Message { tag, payload }).await.unwrap();
+    }
+    assert_eq!(peer.next().await.unwrap().unwrap().tag, MessageTag::Unchoke);
+    let piece = peer.next().await.unwrap().unwrap();
+    assert_eq!(piece.tag, MessageTag::Piece);
+    assert_eq!(
+        Piece::ref_from_bytes(&piece.payload).unwrap().block(),
+        b"lo wo"
+    );
+
+    drop(peer);
+    seeding.await.unwrap().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn skipped_files_are_never_written() {
+    let dir = std::env::temp_dir().join(format!("rustorrent-skip-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("t")).unwrap();
+    std::fs::write(dir.join("t/a"), vec![1; 20_000]).unwrap();
+    std::fs::write(dir.join("t/b"), vec![2; 20_000]).unwrap();
+    std::fs::write(dir.join("t/c"), vec![3; 9_000]).unwrap();
+    let created = crate::create::create_torrent(
+        &dir.join("t"),
+        &crate::create::CreateOptions {
+            announce: Some(String::from("http://tracker.invalid/announce")),
+            piece_length: Some(1 << 14),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let meta_info = crate::parsing::parse_metainfo(&created.bytes).unwrap();
+    let seeded = Storage::new(&meta_info, &dir);
+    let priorities = [
+        FilePriority::Normal,
+        FilePriority::Skip,
+        FilePriority::Normal,
+    ];
+
+    // b was wanted by an earlier attempt
+    let root = dir.join("staging");
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::write(root.join("b"), b"stale").unwrap();
+    let staging = Storage::at(&meta_info, root.clone()).only(&priorities);
+    staging.create_files().await.unwrap();
+    assert!(!root.join("b").exists());
+    for index in 0..staging.num_pieces() {
+        let piece = seeded.read_piece(index).await.unwrap().unwrap();
+        staging.write_piece(index, &piece).await.unwrap();
+    }
+
+    assert!(!root.join("b").exists());
+    assert_eq!(std::fs::read(root.join("a")).unwrap(), vec![1; 20_000]);
+    assert_eq!(std::fs::read(root.join("c")).unwrap(), vec![3; 9_000]);
+    let parts = parts::read(&parts::path(&root)).unwrap();
+    assert_eq!(parts.keys().copied().collect::<Vec<_>>(), [1, 2]);
+    let saved = Storage::at(&meta_info, root).only(&priorities);
+    assert!(saved.verify().await.unwrap().is_empty());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
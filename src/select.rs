@@ -0,0 +1,84 @@
+use crate::parsing::Info;
+use crate::session::FilePriority;
+
+/// Priorities of the files of a torrent that match one of the `only`
+/// globs, if there are any, and none of the `exclude` ones: normal for
+/// these, skip for the others.
+pub fn file_priorities(info: &Info, only: &[String], exclude: &[String]) -> Vec<FilePriority> {
+    let paths: Vec<&str> = match &info.files {
+        Some(files) => files.iter().map(|file| file.path.as_str()).collect(),
+        None => vec![info.name.as_str()],
+    };
+    paths
+        .into_iter()
+        .map(|path| {
+            let matches = |pattern: &String| matches_path(pattern, path);
+            let wanted =
+                (only.is_empty() || only.iter().any(matches)) && !exclude.iter().any(matches);
+            if wanted {
+                FilePriority::Normal
+            } else {
+                FilePriority::Skip
+            }
+        })
+        .collect()
+}
+
+/// Whether `pattern` matches the `/` separated `path` of a file in its
+/// torrent. A pattern without `/` is matched against the file name only.
+/// `*` stands for any characters but `/`, `**` for any characters and `?`
+/// for one character but `/`.
+pub fn matches_path(pattern: &str, path: &str) -> bool {
+    let path = if pattern.contains('/') {
+        path
+    } else {
+        path.rsplit('/').next().unwrap_or(path)
+    };
+    glob(pattern.as_bytes(), path.as_bytes())
+}
+
+fn glob(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        [b'*', b'*', rest @ ..] => (0..=text.len()).any(|skip| glob(rest, &text[skip..])),
+        [b'*', rest @ ..] => {
+            let segment = text.iter().position(|&c| c == b'/').unwrap_or(text.len());
+            (0..=segment).any(|skip| glob(rest, &text[skip..]))
+        }
+        [b'?', rest @ ..] => matches!(text, [c, tail @ ..] if *c != b'/' && glob(rest, tail)),
+        [c, rest @ ..] => matches!(text, [t, tail @ ..] if t == c && glob(rest, tail)),
+    }
+}
+
+#[test]
+fn globs_select_files() {
+    assert!(matches_path("*.mkv", "season 1/e01.mkv"));
+    assert!(!matches_path("*.mkv", "e01.mkv.part"));
+    assert!(matches_path("season ?/*", "season 1/e01.mkv"));
+    assert!(!matches_path("*/e01.mkv", "a/b/e01.mkv"));
+    assert!(matches_path("**/e01.mkv", "a/b/e01.mkv"));
+
+    let file = |path: &str| crate::parsing::File {
+        path: path.to_string(),
+        ..Default::default()
+    };
+    let info = Info {
+        files: Some(vec![file("a.mkv"), file("a.srt"), file("extras/b.mkv")]),
+        ..Default::default()
+    };
+    let globs = |globs: &[&str]| {
+        globs
+            .iter()
+            .map(|glob| glob.to_string())
+            .collect::<Vec<_>>()
+    };
+    use FilePriority::*;
+    assert_eq!(
+        file_priorities(&info, &globs(&["*.mkv"]), &globs(&["extras/*"])),
+        [Normal, Skip, Skip]
+    );
+    assert_eq!(
+        file_priorities(&info, &[], &globs(&["*.srt"])),
+        [Normal, Skip, Normal]
+    );
+}
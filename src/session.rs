@@ -0,0 +1,1109 @@
+use crate::ban::BanList;
+use crate::dht::{Dht, DhtConfig};
+use crate::download::{self, DownloadConfig};
+use crate::event::{self, Event, Events};
+use crate::listener::Listener;
+use crate::lsd::{Lsd, LsdConfig};
+use crate::magnet::MagnetLink;
+use crate::metadata;
+use crate::output::{self, OutputConfig, Saved};
+use crate::parsing::MetaInfo;
+use crate::peers::{random_peer_id, ConnectConfig};
+use crate::rate::{Limiters, RateConfig, RateLimits, SessionRates, Throttle};
+use crate::seed::{self, Storage};
+use crate::stream::{FileReader, Progress};
+use crate::tracker;
+use crate::transport::{Transport, TransportPolicy};
+use crate::utp::UtpSocket;
+use crate::{FailAs, Failed, Failure, LogLevel};
+use anyhow::Context;
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, watch};
+use tokio::task::{JoinHandle, JoinSet};
+
+/// Connections shared by the torrents of a session by default.
+const MAX_CONNECTIONS: usize = 50;
+
+/// Bytes left we tell trackers about for a magnet link, whose size is
+/// unknown until the metadata arrives.
+const UNKNOWN_LEFT: usize = metadata::PIECE_SIZE;
+
+/// How long a peer connecting to us has to complete the handshakes.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The connections of a session, split evenly between the torrents that
+/// are downloading.
+#[derive(Debug)]
+pub struct ConnectionSlots {
+    total: usize,
+    active: AtomicUsize,
+}
+
+impl ConnectionSlots {
+    pub fn new(total: usize) -> Self {
+        Self {
+            total,
+            active: AtomicUsize::new(0),
+        }
+    }
+
+    /// Connections each downloading torrent may have open.
+    pub fn share(&self) -> usize {
+        let active = self.active.load(Ordering::Relaxed).max(1);
+        (self.total / active).max(1)
+    }
+
+    /// Counts a torrent in the split until the returned guard is dropped.
+    fn join(self: &Arc<Self>) -> ActiveTorrent {
+        self.active.fetch_add(1, Ordering::Relaxed);
+        ActiveTorrent(Arc::clone(self))
+    }
+}
+
+struct ActiveTorrent(Arc<ConnectionSlots>);
+
+impl Drop for ActiveTorrent {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TorrentState {
+    /// Fetching the info dictionary of a magnet link from the peers.
+    Metadata,
+    /// Hashing the data already on disk.
+    Checking,
+    Downloading,
+    /// Complete, and uploading to the peers that connect.
+    Seeding,
+    Paused,
+    Error(Failure, String),
+}
+
+impl TorrentState {
+    /// The torrent stays in this state until it is paused or resumed.
+    pub fn is_settled(&self) -> bool {
+        matches!(self, Self::Seeding | Self::Paused | Self::Error(..))
+    }
+}
+
+/// How much a file of a torrent is wanted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum FilePriority {
+    /// Not downloaded at all.
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+/// A snapshot of a torrent of a session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TorrentStatus {
+    pub info_hash: [u8; 20],
+    /// From the magnet link, if any, until the metadata arrives.
+    pub name: Option<String>,
+    pub state: TorrentState,
+    /// Total length of the files, once the metadata is known.
+    pub length: Option<usize>,
+    pub num_pieces: Option<usize>,
+    pub pieces_done: usize,
+    /// Where the data is, once seeding.
+    pub location: Option<PathBuf>,
+    /// One per file, once the metadata is known.
+    pub file_priorities: Vec<FilePriority>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+    pub download: DownloadConfig,
+    pub output: OutputConfig,
+    /// Connections shared by all the torrents downloading at once.
+    pub max_connections: usize,
+    /// Starts a DHT node for the public torrents.
+    pub dht: Option<DhtConfig>,
+    /// Finds peers of the public torrents on the LAN (BEP 14), announcing
+    /// the port of the session rather than the one set here.
+    pub lsd: Option<LsdConfig>,
+    pub rates: RateConfig,
+    /// Where the banned peers are loaded from and saved to.
+    pub ban_file: Option<PathBuf>,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            download: DownloadConfig::default(),
+            output: OutputConfig::default(),
+            max_connections: MAX_CONNECTIONS,
+            dht: None,
+            lsd: None,
+            rates: RateConfig::default(),
+            ban_file: None,
+        }
+    }
+}
+
+struct Torrent {
+    /// Unknown for a magnet link until the peers send it.
+    meta_info: Option<Arc<MetaInfo>>,
+    magnet: Option<MagnetLink>,
+    state: Arc<watch::Sender<TorrentState>>,
+    /// Where the complete data is, once seeding.
+    storage: Option<Arc<Storage>>,
+    task: Option<JoinHandle<()>>,
+    file_priorities: Vec<FilePriority>,
+    pieces_done: Arc<AtomicUsize>,
+    progress: Arc<Progress>,
+    throttle: Throttle,
+    /// The buckets of the torrent alone, within `throttle`.
+    limits: Limiters,
+}
+
+struct Shared {
+    config: SessionConfig,
+    listener: Listener,
+    dht: Option<Dht>,
+    /// Set once the DHT node joined the network, or failed to.
+    dht_joined: tokio::sync::OnceCell<()>,
+    lsd: Option<Lsd>,
+    rates: SessionRates,
+    events: broadcast::Sender<Event>,
+    torrents: Mutex<HashMap<[u8; 20], Torrent>>,
+}
+
+impl Shared {
+    fn emit(&self, event: Event) {
+        // nobody listening is fine
+        let _ = self.events.send(event);
+    }
+
+    /// The DHT node, once its first caller had it join the network.
+    async fn dht(&self) -> Option<&Dht> {
+        let dht = self.dht.as_ref()?;
+        self.dht_joined
+            .get_or_init(|| async {
+                if let Err(e) = dht.bootstrap().await {
+                    // the nodes of the torrents may still let it in
+                    if self.config.download.log >= LogLevel::Warn {
+                        eprintln!("Warning: dht: {e:#}");
+                    }
+                }
+            })
+            .await;
+        Some(dht)
+    }
+}
+
+/// Runs many torrents at once with one peer id, one listener and one DHT
+/// node. Each torrent is checked against the data on disk, downloaded if
+/// incomplete, then seeded.
+pub struct Session {
+    shared: Arc<Shared>,
+    /// Accepting peers, following the rate schedule and joining the DHT.
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Session {
+    /// Binds the listener on `config.download.port`, 0 picking any free
+    /// port, and starts the DHT node if there is one, which joins the
+    /// network in the background.
+    pub async fn start(mut config: SessionConfig) -> anyhow::Result<Self> {
+        let peer_id = random_peer_id();
+        config.download.connect.peer_id = peer_id;
+        config.download.slots = Some(Arc::new(ConnectionSlots::new(config.max_connections)));
+        if let Some(path) = &config.ban_file {
+            config.download.bans = Arc::new(BanList::load(path)?);
+        }
+
+        let mut listener = Listener::bind(
+            SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, config.download.port),
+            config.download.connect.encryption,
+        )
+        .await?;
+        listener.set_peer_id(peer_id);
+        config.download.port = listener.local_addr()?.port();
+        if config.download.connect.transport != TransportPolicy::TcpOnly {
+            // uTP peers reach us on the port we announce for TCP
+            let addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, config.download.port);
+            match UtpSocket::bind(addr.into()).await {
+                Ok(utp) => {
+                    let utp = Arc::new(utp);
+                    listener.accept_utp(Arc::clone(&utp));
+                    config.download.connect.utp = Some(utp);
+                }
+                Err(e) => {
+                    if config.download.log >= LogLevel::Warn {
+                        eprintln!("Warning: {e:#}, only TCP is used");
+                    }
+                }
+            }
+        }
+
+        let dht = match &config.dht {
+            Some(dht) => Some(Dht::start(dht.clone()).await?),
+            None => None,
+        };
+        if let (Some(dht), true) = (&dht, config.download.log >= LogLevel::Debug) {
+            println!(
+                "dht: node {} on {}",
+                hex::encode(dht.id()),
+                dht.local_addr()?
+            );
+        }
+        let lsd = match &config.lsd {
+            Some(lsd) => Some(
+                Lsd::start(LsdConfig {
+                    port: config.download.port,
+                    ..lsd.clone()
+                })
+                .await?,
+            ),
+            None => None,
+        };
+        let rates = SessionRates::new(config.rates.clone());
+        let shared = Arc::new(Shared {
+            config,
+            listener,
+            dht,
+            dht_joined: tokio::sync::OnceCell::new(),
+            lsd,
+            rates,
+            events: broadcast::channel(event::CAPACITY).0,
+            torrents: Mutex::default(),
+        });
+        let tasks = vec![
+            tokio::spawn(accept_peers(Arc::clone(&shared))),
+            tokio::spawn({
+                let shared = Arc::clone(&shared);
+                async move { shared.rates.follow_schedule().await }
+            }),
+            tokio::spawn({
+                let shared = Arc::clone(&shared);
+                async move {
+                    shared.dht().await;
+                }
+            }),
+        ];
+        Ok(Self { shared, tasks })
+    }
+
+    /// Port the peers can connect to us on.
+    pub fn port(&self) -> u16 {
+        self.shared.config.download.port
+    }
+
+    /// The peers no torrent of the session connects to or accepts: those
+    /// that broke the protocol or sent corrupt data, and those banned here.
+    pub fn bans(&self) -> &BanList {
+        &self.shared.config.download.bans
+    }
+
+    /// Changes the limits shared by every connection. An alternative
+    /// schedule puts back its own limits, or these, at its next check.
+    pub fn set_rate_limits(&self, limits: RateLimits) {
+        self.shared.rates.global().set_limits(limits);
+    }
+
+    /// The events of every torrent from now on. A subscriber that falls
+    /// too far behind misses the oldest ones.
+    pub fn events(&self) -> broadcast::Receiver<Event> {
+        self.shared.events.subscribe()
+    }
+
+    /// Starts checking, downloading and seeding a torrent.
+    pub fn add_torrent(&self, meta_info: MetaInfo) -> anyhow::Result<TorrentHandle> {
+        let file_priorities = vec![FilePriority::default(); num_files(&meta_info)];
+        self.add_torrent_with_priorities(meta_info, file_priorities)
+    }
+
+    /// Starts a torrent downloading its files as much as `file_priorities`,
+    /// one per file, wants them.
+    pub fn add_torrent_with_priorities(
+        &self,
+        meta_info: MetaInfo,
+        file_priorities: Vec<FilePriority>,
+    ) -> anyhow::Result<TorrentHandle> {
+        check_supported(&meta_info)?;
+        check_priorities(&meta_info, &file_priorities)?;
+        let info_hash = meta_info.swarm_info_hash();
+        self.insert(info_hash, Some(meta_info), None, file_priorities)
+    }
+
+    pub fn add_torrent_bytes(&self, contents: &[u8]) -> anyhow::Result<TorrentHandle> {
+        self.add_torrent(MetaInfo::from_bytes(contents)?)
+    }
+
+    pub fn add_torrent_file(&self, path: impl AsRef<Path>) -> anyhow::Result<TorrentHandle> {
+        let path = path.as_ref();
+        let contents = std::fs::read(path).with_context(|| format!("read {}", path.display()))?;
+        self.add_torrent_bytes(&contents)
+            .with_context(|| format!("{}", path.display()))
+    }
+
+    /// Starts a torrent from a magnet link, fetching its metadata from the
+    /// peers that the trackers of the link, or the DHT, know.
+    pub fn add_magnet(&self, link: &str) -> anyhow::Result<TorrentHandle> {
+        let magnet = MagnetLink::parse(link)?;
+        self.insert(magnet.info_hash.0, None, Some(magnet), Vec::new())
+    }
+
+    fn insert(
+        &self,
+        info_hash: [u8; 20],
+        meta_info: Option<MetaInfo>,
+        magnet: Option<MagnetLink>,
+        file_priorities: Vec<FilePriority>,
+    ) -> anyhow::Result<TorrentHandle> {
+        let mut torrents = self.shared.torrents.lock().unwrap();
+        anyhow::ensure!(
+            !torrents.contains_key(&info_hash),
+            "{} is already in the session",
+            hex::encode(info_hash)
+        );
+        let (throttle, limits) = self.shared.rates.torrent();
+        let mut torrent = Torrent {
+            meta_info: meta_info.map(Arc::new),
+            magnet,
+            state: Arc::new(watch::channel(TorrentState::Checking).0),
+            storage: None,
+            task: None,
+            file_priorities,
+            pieces_done: Arc::default(),
+            progress: Arc::new(Progress::new()),
+            throttle,
+            limits,
+        };
+        torrent.task = Some(spawn(&self.shared, &torrent, info_hash));
+        torrents.insert(info_hash, torrent);
+        self.shared.listener.add_torrent(info_hash);
+        Ok(self.handle(info_hash))
+    }
+
+    fn handle(&self, info_hash: [u8; 20]) -> TorrentHandle {
+        TorrentHandle {
+            shared: Arc::clone(&self.shared),
+            info_hash,
+        }
+    }
+
+    pub fn torrent(&self, info_hash: &[u8; 20]) -> Option<TorrentHandle> {
+        let known = self.shared.torrents.lock().unwrap().contains_key(info_hash);
+        known.then(|| self.handle(*info_hash))
+    }
+
+    pub fn torrents(&self) -> Vec<TorrentHandle> {
+        let info_hashes: Vec<_> = self
+            .shared
+            .torrents
+            .lock()
+            .unwrap()
+            .keys()
+            .copied()
+            .collect();
+        info_hashes
+            .into_iter()
+            .map(|info_hash| self.handle(info_hash))
+            .collect()
+    }
+
+    /// Stops every torrent and upload, and saves the DHT routing table and
+    /// the bans.
+    pub async fn shutdown(self) -> anyhow::Result<()> {
+        for task in &self.tasks {
+            task.abort();
+        }
+        let tasks: Vec<_> = {
+            let mut torrents = self.shared.torrents.lock().unwrap();
+            torrents
+                .values_mut()
+                .filter_map(|torrent| torrent.task.take())
+                .collect()
+        };
+        for task in tasks {
+            task.abort();
+            // a cancelled task is the expected outcome
+            let _ = task.await;
+        }
+        if let Some(dht) = &self.shared.dht {
+            dht.save().await?;
+        }
+        if let Some(path) = &self.shared.config.ban_file {
+            self.bans().save(path)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+        for torrent in self.shared.torrents.lock().unwrap().values() {
+            if let Some(task) = &torrent.task {
+                task.abort();
+            }
+        }
+    }
+}
+
+/// Files of a torrent, a single file torrent counting as one.
+fn num_files(meta_info: &MetaInfo) -> usize {
+    meta_info.info.files.as_ref().map_or(1, Vec::len)
+}
+
+/// Where file `file` starts in its torrent, and its length.
+fn file_span(meta_info: &MetaInfo, file: usize) -> anyhow::Result<(usize, usize)> {
+    let info = &meta_info.info;
+    let Some(files) = &info.files else {
+        anyhow::ensure!(file == 0, "torrent has a single file");
+        return Ok((0, info.length));
+    };
+    anyhow::ensure!(file < files.len(), "torrent has {} files", files.len());
+    let offset = files[..file].iter().map(|file| file.length).sum();
+    Ok((offset, files[file].length))
+}
+
+fn check_priorities(meta_info: &MetaInfo, priorities: &[FilePriority]) -> anyhow::Result<()> {
+    let files = num_files(meta_info);
+    anyhow::ensure!(
+        priorities.len() == files,
+        "torrent has {files} files, not {}",
+        priorities.len()
+    );
+    Ok(())
+}
+
+fn spawn(shared: &Arc<Shared>, torrent: &Torrent, info_hash: [u8; 20]) -> JoinHandle<()> {
+    tokio::spawn(run(
+        Arc::clone(shared),
+        info_hash,
+        Arc::clone(&torrent.state),
+    ))
+}
+
+/// Follows and controls a torrent of a session. It stays valid, though
+/// every call fails, once the torrent is removed.
+#[derive(Clone)]
+pub struct TorrentHandle {
+    shared: Arc<Shared>,
+    info_hash: [u8; 20],
+}
+
+impl TorrentHandle {
+    pub fn info_hash(&self) -> [u8; 20] {
+        self.info_hash
+    }
+
+    fn with_torrent<T>(&self, f: impl FnOnce(&mut Torrent) -> T) -> anyhow::Result<T> {
+        let mut torrents = self.shared.torrents.lock().unwrap();
+        let torrent = torrents
+            .get_mut(&self.info_hash)
+            .context("torrent is not in the session")?;
+        Ok(f(torrent))
+    }
+
+    pub fn status(&self) -> anyhow::Result<TorrentStatus> {
+        self.with_torrent(|torrent| {
+            let meta_info = torrent.meta_info.as_deref();
+            let name = match meta_info {
+                Some(meta_info) => Some(meta_info.info.name.clone()),
+                None => torrent
+                    .magnet
+                    .as_ref()
+                    .and_then(|magnet| magnet.name.clone()),
+            };
+            let state = torrent.state.borrow().clone();
+            TorrentStatus {
+                info_hash: self.info_hash,
+                name,
+                state,
+                length: meta_info.map(|meta_info| tracker::compute_length(&meta_info.info)),
+                num_pieces: meta_info.map(|meta_info| meta_info.info.pieces.len()),
+                pieces_done: torrent.pieces_done.load(Ordering::Relaxed),
+                location: torrent
+                    .storage
+                    .as_ref()
+                    .map(|storage| storage.root().to_path_buf()),
+                file_priorities: torrent.file_priorities.clone(),
+            }
+        })
+    }
+
+    pub fn subscribe(&self) -> anyhow::Result<watch::Receiver<TorrentState>> {
+        self.with_torrent(|torrent| torrent.state.subscribe())
+    }
+
+    /// Waits until the torrent is seeding, paused or failed.
+    pub async fn wait(&self) -> anyhow::Result<TorrentState> {
+        let mut state = self.subscribe()?;
+        let settled = state
+            .wait_for(TorrentState::is_settled)
+            .await
+            .context("torrent was removed")?
+            .clone();
+        Ok(settled)
+    }
+
+    /// Stops downloading or seeding the torrent. What was downloaded of it
+    /// so far is dropped.
+    pub fn pause(&self) -> anyhow::Result<()> {
+        self.with_torrent(|torrent| {
+            if let Some(task) = torrent.task.take() {
+                task.abort();
+            }
+            if let Some(lsd) = &self.shared.lsd {
+                lsd.leave(&self.info_hash);
+            }
+            torrent.storage = None;
+            torrent.state.send_replace(TorrentState::Paused);
+        })
+    }
+
+    /// Starts the paused torrent again, from the check of its data.
+    pub fn resume(&self) -> anyhow::Result<()> {
+        self.with_torrent(|torrent| {
+            anyhow::ensure!(
+                *torrent.state.borrow() == TorrentState::Paused,
+                "torrent is not paused"
+            );
+            torrent.state.send_replace(TorrentState::Checking);
+            torrent.task = Some(spawn(&self.shared, torrent, self.info_hash));
+            Ok(())
+        })?
+    }
+
+    pub fn remove(&self) -> anyhow::Result<()> {
+        let torrent = self
+            .shared
+            .torrents
+            .lock()
+            .unwrap()
+            .remove(&self.info_hash)
+            .context("torrent is not in the session")?;
+        if let Some(task) = torrent.task {
+            task.abort();
+        }
+        if let Some(lsd) = &self.shared.lsd {
+            lsd.leave(&self.info_hash);
+        }
+        self.shared.listener.remove_torrent(&self.info_hash);
+        Ok(())
+    }
+
+    /// Opens file `file` of the torrent, in the order of the torrent, to
+    /// read it while it downloads.
+    pub fn open_file(&self, file: usize) -> anyhow::Result<FileReader> {
+        let (meta_info, priority) = self.with_torrent(|torrent| {
+            let priority = torrent.file_priorities.get(file).copied();
+            (torrent.meta_info.clone(), priority)
+        })?;
+        let meta_info = meta_info.context("the metadata of the torrent is not known yet")?;
+        let (offset, length) = file_span(&meta_info, file)?;
+        anyhow::ensure!(
+            priority != Some(FilePriority::Skip),
+            "file {file} is skipped"
+        );
+        Ok(FileReader::new(
+            self.clone(),
+            offset as u64,
+            length as u64,
+            meta_info.info.piece_length as u64,
+        ))
+    }
+
+    /// Asks for the bytes of file `file` in `range` within `deadline`: the
+    /// pieces holding them are downloaded before the others, earliest
+    /// deadline first.
+    pub fn set_deadline(
+        &self,
+        file: usize,
+        range: Range<u64>,
+        deadline: Duration,
+    ) -> anyhow::Result<()> {
+        let meta_info = self.with_torrent(|torrent| torrent.meta_info.clone())?;
+        let meta_info = meta_info.context("the metadata of the torrent is not known yet")?;
+        let (offset, length) = file_span(&meta_info, file)?;
+        let end = range.end.min(length as u64);
+        if range.start >= end {
+            return Ok(());
+        }
+        let piece_length = meta_info.info.piece_length as u64;
+        let first = (offset as u64 + range.start) / piece_length;
+        let last = (offset as u64 + end - 1) / piece_length;
+        let at = Instant::now() + deadline;
+        for index in first..=last {
+            self.set_piece_deadline(index as usize, at);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn set_piece_deadline(&self, index: usize, at: Instant) {
+        // a removed torrent downloads nothing anyway
+        let _ = self.with_torrent(|torrent| torrent.progress.set_deadline(index, at));
+    }
+
+    /// Piece `index` once it is verified, from the download or the files.
+    pub(crate) async fn piece(&self, index: usize) -> anyhow::Result<Vec<u8>> {
+        loop {
+            let (progress, storage, meta_info, mut state) = self.with_torrent(|torrent| {
+                (
+                    Arc::clone(&torrent.progress),
+                    torrent.storage.clone(),
+                    torrent.meta_info.clone(),
+                    torrent.state.subscribe(),
+                )
+            })?;
+            let mut finished = progress.subscribe();
+            if let Some(storage) = &storage {
+                if let Some(piece) = storage.read_piece(index).await {
+                    return piece;
+                }
+            }
+            match &*state.borrow_and_update() {
+                TorrentState::Error(_, message) => anyhow::bail!("{message}"),
+                TorrentState::Paused => anyhow::bail!("torrent is paused"),
+                TorrentState::Seeding if storage.is_some() => {
+                    anyhow::bail!("piece {index} is not downloaded")
+                }
+                _ => {}
+            }
+            if let Some(meta_info) = &meta_info {
+                let num_pieces = meta_info.info.pieces.len();
+                anyhow::ensure!(index < num_pieces, "torrent has no piece {index}");
+                if let Some(piece) = progress.read(index).await {
+                    return piece;
+                }
+            }
+            progress.set_deadline(index, Instant::now());
+            tokio::select! {
+                _ = finished.changed() => {}
+                _ = state.changed() => {}
+            }
+        }
+    }
+
+    /// Sets how much each file is wanted, in the order of the torrent. A
+    /// running torrent picks them up when it is paused and resumed.
+    pub fn set_file_priorities(&self, priorities: Vec<FilePriority>) -> anyhow::Result<()> {
+        self.with_torrent(|torrent| {
+            let meta_info = torrent
+                .meta_info
+                .as_ref()
+                .context("the metadata of the torrent is not known yet")?;
+            check_priorities(meta_info, &priorities)?;
+            torrent.file_priorities = priorities;
+            Ok(())
+        })?
+    }
+
+    /// Changes the limits shared by the connections of the torrent.
+    pub fn set_rate_limits(&self, limits: RateLimits) -> anyhow::Result<()> {
+        self.with_torrent(|torrent| torrent.limits.set_limits(limits))
+    }
+}
+
+/// Takes a torrent from checking to seeding, or to its error.
+async fn run(shared: Arc<Shared>, info_hash: [u8; 20], state: Arc<watch::Sender<TorrentState>>) {
+    let log = shared.config.download.log;
+    match complete(&shared, info_hash, &state).await {
+        Ok((meta_info, storage)) => {
+            if let Some(torrent) = shared.torrents.lock().unwrap().get_mut(&info_hash) {
+                torrent.storage = Some(Arc::new(storage));
+            }
+            state.send_replace(TorrentState::Seeding);
+            shared.emit(Event::TorrentComplete { info_hash });
+            if let Some(announce) = &meta_info.announce {
+                let config = &shared.config.download;
+                let announced = tracker::send_request(
+                    announce,
+                    info_hash,
+                    config.connect.peer_id,
+                    config.port,
+                    0,
+                )
+                .await;
+                if let Err(e) = announced {
+                    // peers may still find us through other trackers or the ones we know
+                    shared.emit(Event::TrackerError {
+                        info_hash,
+                        message: format!("{e:#}"),
+                    });
+                    if log >= LogLevel::Warn {
+                        eprintln!("Warning: {}: {e:#}", meta_info.info.name);
+                    }
+                }
+            }
+            let Some(lsd) = shared.lsd.as_ref().filter(|_| !meta_info.info.is_private()) else {
+                return;
+            };
+            // announced on the LAN while the task seeds, until paused or removed
+            match lsd.join(info_hash).await {
+                Ok(mut lan_peers) => while lan_peers.recv().await.is_some() {},
+                Err(e) => {
+                    if log >= LogLevel::Warn {
+                        eprintln!("Warning: {}: {e:#}", meta_info.info.name);
+                    }
+                }
+            }
+        }
+        Err(Failed(failure, e)) => {
+            state.send_replace(TorrentState::Error(failure, format!("{e:#}")));
+        }
+    }
+}
+
+/// The storage of the complete torrent, or of its wanted files: the data
+/// already in the output directory if it checks, else a download, which
+/// goes on from the pieces an earlier attempt wrote.
+async fn complete(
+    shared: &Shared,
+    info_hash: [u8; 20],
+    state: &watch::Sender<TorrentState>,
+) -> Result<(Arc<MetaInfo>, Storage), Failed> {
+    let config = &shared.config;
+    let (meta_info, magnet, file_priorities, pieces_done, progress, throttle) = {
+        let torrents = shared.torrents.lock().unwrap();
+        let torrent = torrents
+            .get(&info_hash)
+            .context("torrent was removed")
+            .fail_as(Failure::Torrent)?;
+        (
+            torrent.meta_info.clone(),
+            torrent.magnet.clone(),
+            torrent.file_priorities.clone(),
+            Arc::clone(&torrent.pieces_done),
+            Arc::clone(&torrent.progress),
+            torrent.throttle.clone(),
+        )
+    };
+    let (meta_info, file_priorities) = match (meta_info, magnet) {
+        (Some(meta_info), _) => (meta_info, file_priorities),
+        (None, Some(magnet)) => {
+            state.send_replace(TorrentState::Metadata);
+            let meta_info = Arc::new(fetch_metadata(shared, &magnet, &throttle).await?);
+            let file_priorities = vec![FilePriority::default(); num_files(&meta_info)];
+            if let Some(torrent) = shared.torrents.lock().unwrap().get_mut(&info_hash) {
+                torrent.meta_info = Some(Arc::clone(&meta_info));
+                torrent.file_priorities = file_priorities.clone();
+            }
+            (meta_info, file_priorities)
+        }
+        (None, None) => unreachable!("torrents are added with metadata or a magnet link"),
+    };
+
+    if !meta_info.info.is_private() && !meta_info.nodes.is_empty() {
+        if let Some(dht) = shared.dht().await {
+            dht.add_nodes(&meta_info.nodes).await;
+        }
+    }
+
+    state.send_replace(TorrentState::Checking);
+    let existing = Storage::new(&meta_info, &config.output.dir).only(&file_priorities);
+    if matches!(existing.verify().await, Ok(bad) if bad.is_empty()) {
+        pieces_done.store(existing.num_have(), Ordering::Relaxed);
+        return Ok((meta_info, existing));
+    }
+
+    // pieces written by an earlier attempt are kept
+    let staging = output::staging(&config.output, &meta_info.info.name);
+    let staging = Storage::at(&meta_info, staging).only(&file_priorities);
+    let have = staging.verified().await;
+    staging
+        .create_files()
+        .await
+        .with_context(|| format!("create the files of {}", meta_info.info.name))
+        .fail_as(Failure::Disk)?;
+    progress.start(Arc::new(staging), &have);
+
+    state.send_replace(TorrentState::Downloading);
+    pieces_done.store(have.len(), Ordering::Relaxed);
+    let slots = config.download.slots.as_ref().expect("set by start");
+    let download = DownloadConfig {
+        events: Some(Events::new(shared.events.clone(), pieces_done)),
+        file_priorities: file_priorities.clone(),
+        connect: ConnectConfig {
+            throttle,
+            ..config.download.connect.clone()
+        },
+        ..config.download.clone()
+    };
+    let dht = shared.dht().await;
+    {
+        let _active = slots.join();
+        download::all(
+            &meta_info,
+            info_hash,
+            dht,
+            shared.lsd.as_ref(),
+            &progress,
+            &download,
+        )
+        .await
+        .with_context(|| format!("download {}", meta_info.info.name))
+        .fail_as(Failure::Network)?;
+    }
+    progress.stop();
+    let saved = output::finish(&config.output, &meta_info.info.name)
+        .with_context(|| format!("save {}", meta_info.info.name))
+        .fail_as(Failure::Disk)?;
+    match saved {
+        Saved::Written(root) => {
+            let storage = Storage::at(&meta_info, root).only(&file_priorities);
+            Ok((meta_info, storage))
+        }
+        Saved::Skipped(path) => Err(Failed(
+            Failure::Disk,
+            anyhow::anyhow!("{} already exists", path.display()),
+        )),
+    }
+}
+
+/// Asks the trackers of the link and the DHT for peers, and fetches the
+/// info dictionary from them.
+async fn fetch_metadata(
+    shared: &Shared,
+    magnet: &MagnetLink,
+    throttle: &Throttle,
+) -> Result<MetaInfo, Failed> {
+    let config = &shared.config.download;
+    let info_hash = magnet.info_hash.0;
+    let mut peers = Vec::new();
+    for announce in &magnet.trackers {
+        let response = tracker::send_request(
+            announce,
+            info_hash,
+            config.connect.peer_id,
+            config.port,
+            UNKNOWN_LEFT,
+        )
+        .await;
+        match response {
+            Ok(response) => peers.extend(response.peers.0),
+            Err(e) => shared.emit(Event::TrackerError {
+                info_hash,
+                message: format!("{e:#}"),
+            }),
+        }
+    }
+    if let Some(dht) = shared.dht().await {
+        peers.extend(dht.lookup_peers(info_hash).await);
+    }
+    let connect = ConnectConfig {
+        throttle: throttle.clone(),
+        ..config.connect.clone()
+    };
+    let info = metadata::fetch(info_hash, &peers, &connect)
+        .await
+        .fail_as(Failure::Network)?;
+    let meta_info =
+        MetaInfo::from_bytes(&metadata::torrent_bytes(magnet, &info)).fail_as(Failure::Torrent)?;
+    check_supported(&meta_info).fail_as(Failure::Torrent)?;
+    Ok(meta_info)
+}
+
+/// Pieces are laid out as in v1 torrents, across the files, which v2-only
+/// ones are not: each of their files starts a piece.
+fn check_supported(meta_info: &MetaInfo) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        !meta_info.is_v2_only(),
+        "{} is a v2-only torrent, which cannot be downloaded or seeded: \
+        only v1 and hybrid torrents can",
+        meta_info.info.name
+    );
+    Ok(())
+}
+
+/// Hands the peers that connect to the torrents being seeded.
+async fn accept_peers(shared: Arc<Shared>) {
+    let log = shared.config.download.log;
+    let mut uploads = JoinSet::new();
+    loop {
+        let accepted = tokio::select! {
+            accepted = shared.listener.accept() => accepted,
+            Some(served) = uploads.join_next(), if !uploads.is_empty() => {
+                if log >= LogLevel::Debug {
+                    if let Ok((addr, served)) = served {
+                        println!("peers: disconnect: {addr}: {served:?}");
+                    }
+                }
+                continue;
+            }
+        };
+        let (stream, addr) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                if log >= LogLevel::Debug {
+                    println!("peers: refused: {e:?}");
+                }
+                continue;
+            }
+        };
+        if shared.config.download.bans.is_banned(addr.ip()) {
+            continue;
+        }
+        // the handshakes run beside the loop, so that a slow peer holds
+        // up nobody
+        let shared = Arc::clone(&shared);
+        uploads.spawn(async move { (addr, upload(&shared, stream, addr).await) });
+    }
+}
+
+/// Runs the handshakes with a peer that connected to us, and serves it
+/// the torrent it asks for if it is complete.
+async fn upload(shared: &Shared, stream: Transport, addr: SocketAddrV4) -> anyhow::Result<()> {
+    let incoming = tokio::time::timeout(HANDSHAKE_TIMEOUT, shared.listener.handshake(stream, addr))
+        .await
+        .context("handshake timed out")??;
+    let info_hash = incoming.handshake.info_hash;
+    let seeding = shared
+        .torrents
+        .lock()
+        .unwrap()
+        .get(&info_hash)
+        .and_then(|torrent| Some((torrent.storage.clone()?, torrent.throttle.clone())));
+    // we only upload complete torrents
+    let (storage, throttle) = seeding.context("torrent is not complete")?;
+    if shared.config.download.log >= LogLevel::Debug {
+        println!("peers: connect: {addr}");
+    }
+    seed::serve(&storage, incoming, &throttle).await
+}
+
+#[tokio::test]
+async fn session_downloads_torrents_at_once() {
+    use std::io::SeekFrom;
+    use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+    let dir = std::env::temp_dir().join(format!("rustorrent-session-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("seed/b")).unwrap();
+    std::fs::write(dir.join("seed/a"), vec![7u8; 40_000]).unwrap();
+    std::fs::write(dir.join("seed/b/x"), b"hello ").unwrap();
+    std::fs::write(dir.join("seed/b/y"), b"world").unwrap();
+
+    let config = |dir: PathBuf| SessionConfig {
+        download: DownloadConfig {
+            port: 0,
+            ..Default::default()
+        },
+        output: OutputConfig {
+            dir,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let seeder = Session::start(config(dir.join("seed"))).await.unwrap();
+
+    // a tracker that sends everyone to the seeder
+    let tracker = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let announce = format!("http://{}/announce", tracker.local_addr().unwrap());
+    let seeder_port = seeder.port();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = tracker.accept().await.unwrap();
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request).await;
+            let mut body = b"d8:intervali60e5:peers6:\x7f\0\0\x01".to_vec();
+            body.extend(seeder_port.to_be_bytes());
+            body.push(b'e');
+            let head = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            );
+            let _ = stream.write_all(&[head.as_bytes(), &body].concat()).await;
+        }
+    });
+
+    let mut torrents = Vec::new();
+    for name in ["a", "b"] {
+        let created = crate::create::create_torrent(
+            &dir.join("seed").join(name),
+            &crate::create::CreateOptions {
+                announce: Some(announce.clone()),
+                piece_length: Some(1 << 14),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let torrent = seeder.add_torrent_bytes(&created.bytes).unwrap();
+        assert_eq!(torrent.wait().await.unwrap(), TorrentState::Seeding);
+        torrents.push(MetaInfo::from_bytes(&created.bytes).unwrap());
+    }
+
+    // one from its torrent file, the other from a magnet link
+    let leecher = Session::start(config(dir.join("leech"))).await.unwrap();
+    let mut events = leecher.events();
+    let a = leecher.add_torrent(torrents[0].clone()).unwrap();
+    let mut reader = a.open_file(0).unwrap();
+    reader.seek(SeekFrom::Start(20_000)).await.unwrap();
+    let mut streamed = Vec::new();
+    reader.read_to_end(&mut streamed).await.unwrap();
+    assert_eq!(streamed, vec![7u8; 20_000]);
+    let magnet = MagnetLink::from_meta_info(&torrents[1]).to_string();
+    let b = leecher.add_magnet(&magnet).unwrap();
+    for torrent in [&a, &b] {
+        assert_eq!(torrent.wait().await.unwrap(), TorrentState::Seeding);
+    }
+    for file in ["a", "b/x", "b/y"] {
+        assert_eq!(
+            std::fs::read(dir.join("leech").join(file)).unwrap(),
+            std::fs::read(dir.join("seed").join(file)).unwrap()
+        );
+    }
+    let status = a.status().unwrap();
+    assert_eq!((status.pieces_done, status.num_pieces), (3, Some(3)));
+    assert_eq!(b.status().unwrap().file_priorities.len(), 2);
+    let mut complete = 0;
+    while let Ok(event) = events.try_recv() {
+        complete += matches!(event, Event::TorrentComplete { .. }) as usize;
+    }
+    assert_eq!(complete, 2);
+
+    a.pause().unwrap();
+    assert_eq!(a.status().unwrap().state, TorrentState::Paused);
+    assert_eq!(a.status().unwrap().location, None);
+    a.resume().unwrap();
+    assert_eq!(a.wait().await.unwrap(), TorrentState::Seeding);
+    assert_eq!(a.status().unwrap().location, Some(dir.join("leech/a")));
+    a.remove().unwrap();
+    assert_eq!(leecher.torrents().len(), 1);
+
+    leecher.shutdown().await.unwrap();
+    seeder.shutdown().await.unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn v2_only_torrents_are_refused() {
+    let mut torrent =
+        b"d8:announce3:url4:infod9:file treed1:ad0:d6:lengthi5e11:pieces root32:".to_vec();
+    torrent.extend([9; 32]);
+    torrent.extend(b"eee12:meta versioni2e4:name2:v212:piece lengthi16384eee");
+    let meta_info = MetaInfo::from_bytes(&torrent).unwrap();
+    assert!(meta_info.is_v2_only());
+
+    let session = Session::start(SessionConfig {
+        download: DownloadConfig {
+            port: 0,
+            ..Default::default()
+        },
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+    let Err(refused) = session.add_torrent_bytes(&torrent) else {
+        panic!("a v2-only torrent was added");
+    };
+    assert!(refused.to_string().contains("v2-only"), "{refused:#}");
+    assert!(session.torrents().is_empty());
+    session.shutdown().await.unwrap();
+}
@@ -0,0 +1,69 @@
+use crate::BLOCK_MAX;
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::net::SocketAddrV4;
+
+/// Who sent each block of the pieces that failed their hash check, to find
+/// out who sent the corrupt ones once the piece is intact. Until then the
+/// piece is downloaded from a single peer, so that a failure is its own.
+#[derive(Debug, Default)]
+pub(crate) struct SmartBan {
+    /// Block index, sender and hash of the block, of every failed attempt.
+    failed: HashMap<usize, Vec<(usize, SocketAddrV4, [u8; 20])>>,
+}
+
+impl SmartBan {
+    /// Records an attempt at `piece` that failed, whose block `i` came from
+    /// `senders[i]`.
+    pub(crate) fn failed(&mut self, piece: usize, data: &[u8], senders: &[SocketAddrV4]) {
+        let blocks = data.chunks(BLOCK_MAX).zip(senders).enumerate();
+        self.failed.entry(piece).or_default().extend(
+            blocks.map(|(block, (bytes, &sender))| (block, sender, Sha1::digest(bytes).into())),
+        );
+    }
+
+    /// The peers that sent blocks of a failed attempt at `piece`, if any.
+    pub(crate) fn suspects(&self, piece: usize) -> Option<Vec<SocketAddrV4>> {
+        let blocks = self.failed.get(&piece)?;
+        let mut suspects: Vec<_> = blocks.iter().map(|&(_, sender, _)| sender).collect();
+        suspects.sort();
+        suspects.dedup();
+        Some(suspects)
+    }
+
+    /// `piece` is now intact: the peers that sent one of its blocks with
+    /// other data.
+    pub(crate) fn passed(&mut self, piece: usize, data: &[u8]) -> Vec<SocketAddrV4> {
+        let Some(blocks) = self.failed.remove(&piece) else {
+            return Vec::new();
+        };
+        let intact: Vec<[u8; 20]> = data
+            .chunks(BLOCK_MAX)
+            .map(|bytes| Sha1::digest(bytes).into())
+            .collect();
+        let mut culprits: Vec<_> = blocks
+            .into_iter()
+            .filter(|(block, _, hash)| intact.get(*block) != Some(hash))
+            .map(|(_, sender, _)| sender)
+            .collect();
+        culprits.sort();
+        culprits.dedup();
+        culprits
+    }
+}
+
+#[test]
+fn intact_pieces_point_at_the_corrupt_blocks() {
+    let peer = |port| SocketAddrV4::new(std::net::Ipv4Addr::LOCALHOST, port);
+    let intact = vec![1u8; 3 * BLOCK_MAX];
+    let mut corrupt = intact.clone();
+    corrupt[BLOCK_MAX + 5] = 0;
+
+    let mut smart_ban = SmartBan::default();
+    assert_eq!(smart_ban.suspects(0), None);
+    smart_ban.failed(0, &corrupt, &[peer(1), peer(2), peer(1)]);
+    assert_eq!(smart_ban.suspects(0), Some(vec![peer(1), peer(2)]));
+    assert_eq!(smart_ban.passed(0, &intact), [peer(2)]);
+    assert_eq!(smart_ban.suspects(0), None);
+    assert!(smart_ban.passed(0, &intact).is_empty());
+}
@@ -0,0 +1,267 @@
+use crate::seed::Storage;
+use crate::session::TorrentHandle;
+use anyhow::Context as _;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::io::{self, SeekFrom};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
+use tokio::sync::watch;
+
+/// Pieces after the one being read that a reader asks for in advance.
+const READAHEAD: usize = 4;
+
+/// Time given to each piece read ahead, after the previous one.
+const READAHEAD_STEP: Duration = Duration::from_secs(2);
+
+/// The pieces of a download as they are verified and written, shared with
+/// the readers of its files, and the pieces they are waiting for.
+#[derive(Debug)]
+pub struct Progress {
+    pieces: Mutex<Pieces>,
+    /// Counts the pieces finished, to wake the readers.
+    finished: watch::Sender<usize>,
+    deadlines: Mutex<BTreeMap<usize, Instant>>,
+}
+
+#[derive(Debug, Default)]
+struct Pieces {
+    /// Where the pieces are written until the download is complete.
+    storage: Option<Arc<Storage>>,
+    have: Vec<bool>,
+}
+
+impl Progress {
+    pub(crate) fn new() -> Self {
+        Self {
+            pieces: Mutex::default(),
+            finished: watch::channel(0).0,
+            deadlines: Mutex::default(),
+        }
+    }
+
+    /// Starts over writing to `storage`, which already holds the pieces
+    /// in `have`.
+    pub(crate) fn start(&self, storage: Arc<Storage>, have: &[usize]) {
+        let mut pieces = Pieces {
+            have: vec![false; storage.num_pieces()],
+            storage: Some(storage),
+        };
+        for &index in have {
+            pieces.have[index] = true;
+        }
+        *self.pieces.lock().unwrap() = pieces;
+    }
+
+    /// Whether piece `index` is already written.
+    pub(crate) fn has(&self, index: usize) -> bool {
+        let pieces = self.pieces.lock().unwrap();
+        pieces.have.get(index).copied().unwrap_or(false)
+    }
+
+    /// Writes verified piece `index` and wakes the readers waiting for it.
+    pub(crate) async fn finish(&self, index: usize, bytes: &[u8]) -> anyhow::Result<()> {
+        let storage = self.pieces.lock().unwrap().storage.clone();
+        storage
+            .context("the download was not started")?
+            .write_piece(index, bytes)
+            .await
+            .with_context(|| format!("write piece {index}"))?;
+        if let Some(have) = self.pieces.lock().unwrap().have.get_mut(index) {
+            *have = true;
+        }
+        self.deadlines.lock().unwrap().remove(&index);
+        self.finished.send_modify(|finished| *finished += 1);
+        Ok(())
+    }
+
+    /// Forgets the pieces once the download is complete, before its files
+    /// move. Readers then go to the files where they end up.
+    pub(crate) fn stop(&self) {
+        *self.pieces.lock().unwrap() = Pieces::default();
+    }
+
+    /// Piece `index`, if it is verified and the download still holds it.
+    pub(crate) async fn read(&self, index: usize) -> Option<anyhow::Result<Vec<u8>>> {
+        let storage = {
+            let pieces = self.pieces.lock().unwrap();
+            let have = pieces.have.get(index).copied().unwrap_or(false);
+            pieces.storage.clone().filter(|_| have)?
+        };
+        storage.read_piece(index).await
+    }
+
+    pub(crate) fn subscribe(&self) -> watch::Receiver<usize> {
+        self.finished.subscribe()
+    }
+
+    /// Asks for piece `index` by `at`, keeping an earlier deadline.
+    pub(crate) fn set_deadline(&self, index: usize, at: Instant) {
+        let mut deadlines = self.deadlines.lock().unwrap();
+        let deadline = deadlines.entry(index).or_insert(at);
+        *deadline = (*deadline).min(at);
+    }
+
+    /// Of the `pieces` still to download, the one with the earliest deadline.
+    pub(crate) fn most_urgent(&self, pieces: impl Iterator<Item = usize>) -> Option<usize> {
+        let deadlines = self.deadlines.lock().unwrap();
+        pieces
+            .filter_map(|index| Some((*deadlines.get(&index)?, index)))
+            .min()
+            .map(|(_, index)| index)
+    }
+}
+
+type PieceFuture = Pin<Box<dyn Future<Output = anyhow::Result<Vec<u8>>> + Send>>;
+
+/// A file of a torrent of a session, readable before the download is
+/// complete: reads wait for the pieces they need, which the download then
+/// fetches first, and seeks are free.
+pub struct FileReader {
+    torrent: TorrentHandle,
+    /// Where the file starts in the torrent.
+    offset: u64,
+    length: u64,
+    piece_length: u64,
+    position: u64,
+    piece: Option<(usize, Vec<u8>)>,
+    pending: Option<(usize, PieceFuture)>,
+}
+
+impl FileReader {
+    pub(crate) fn new(torrent: TorrentHandle, offset: u64, length: u64, piece_length: u64) -> Self {
+        Self {
+            torrent,
+            offset,
+            length,
+            piece_length,
+            position: 0,
+            piece: None,
+            pending: None,
+        }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    /// Asks the download for the pieces after `index`, sooner for nearer ones.
+    fn read_ahead(&self, index: usize) {
+        let end = (self.offset + self.length).div_ceil(self.piece_length) as usize;
+        let now = Instant::now();
+        for (step, next) in (index + 1..end.min(index + 1 + READAHEAD)).enumerate() {
+            self.torrent
+                .set_piece_deadline(next, now + READAHEAD_STEP * (step as u32 + 1));
+        }
+    }
+}
+
+impl AsyncRead for FileReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.position >= this.length || buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        let at = this.offset + this.position;
+        let index = (at / this.piece_length) as usize;
+        loop {
+            if let Some((piece, bytes)) = &this.piece {
+                if *piece == index {
+                    let start = (at - index as u64 * this.piece_length) as usize;
+                    let left_in_file = (this.length - this.position) as usize;
+                    let n = buf.remaining().min(bytes.len() - start).min(left_in_file);
+                    buf.put_slice(&bytes[start..start + n]);
+                    this.position += n as u64;
+                    return Poll::Ready(Ok(()));
+                }
+            }
+            if !matches!(&this.pending, Some((piece, _)) if *piece == index) {
+                let torrent = this.torrent.clone();
+                this.pending = Some((index, Box::pin(async move { torrent.piece(index).await })));
+                this.read_ahead(index);
+            }
+            let (_, pending) = this.pending.as_mut().expect("set above");
+            match pending.as_mut().poll(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(piece) => {
+                    this.pending = None;
+                    let bytes = piece.map_err(io::Error::other)?;
+                    this.piece = Some((index, bytes));
+                }
+            }
+        }
+    }
+}
+
+impl AsyncSeek for FileReader {
+    fn start_seek(mut self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        let position = match position {
+            SeekFrom::Start(position) => Some(position),
+            SeekFrom::End(delta) => self.length.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.position.checked_add_signed(delta),
+        };
+        self.position = position
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before the start"))?;
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(Ok(self.position))
+    }
+}
+
+#[test]
+fn deadlines_pick_the_most_urgent_piece() {
+    let progress = Progress::new();
+    let now = Instant::now();
+    progress.set_deadline(7, now + Duration::from_secs(2));
+    progress.set_deadline(3, now + Duration::from_secs(5));
+    progress.set_deadline(3, now + Duration::from_secs(1));
+    assert_eq!(progress.most_urgent([2, 3, 7].into_iter()), Some(3));
+    assert_eq!(progress.most_urgent([2, 7].into_iter()), Some(7));
+}
+
+#[tokio::test]
+async fn finished_pieces_are_read_back_from_disk() {
+    let dir = std::env::temp_dir().join(format!("rustorrent-progress-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("t")).unwrap();
+    std::fs::write(dir.join("t/a"), vec![1; 20_000]).unwrap();
+    std::fs::write(dir.join("t/b"), vec![2; 20_000]).unwrap();
+    let created = crate::create::create_torrent(
+        &dir.join("t"),
+        &crate::create::CreateOptions {
+            announce: Some(String::from("http://tracker.invalid/announce")),
+            piece_length: Some(1 << 14),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let meta_info = crate::parsing::parse_metainfo(&created.bytes).unwrap();
+    let piece = Storage::new(&meta_info, &dir).read_piece(1).await.unwrap();
+    let piece = piece.unwrap();
+
+    let staging = Arc::new(Storage::at(&meta_info, dir.join("staging")).only(&[]));
+    let progress = Progress::new();
+    progress.start(Arc::clone(&staging), &[]);
+    assert!(progress.read(1).await.is_none());
+    progress.finish(1, &piece).await.unwrap();
+    assert_eq!(progress.read(1).await.unwrap().unwrap(), piece);
+    assert!(progress.read(0).await.is_none());
+    // what a later attempt goes on from
+    assert_eq!(staging.verified().await, [1]);
+
+    progress.stop();
+    assert!(progress.read(1).await.is_none());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -1,21 +1,7 @@
-#![allow(warnings)]
-
-use crate::bdecoder::OwnedValue;
-use crate::parsing::File;
+use crate::bdecoder;
 use crate::parsing::Info;
 use std::collections::BTreeMap;
-use std::io::Bytes;
-use std::net::SocketAddrV4;
-
-use crate::parsing::parse_metainfo;
-
-use bendy::decoding::Error;
-use curl::easy::Easy;
-use std::io::{stdout, Write};
-
-use crate::bdecoder::decode_bencoded_string;
-
-use sha1::{Digest, Sha1};
+use std::fmt::Write;
 
 use anyhow::Context;
 use serde::{Deserialize, Serialize};
@@ -38,15 +24,6 @@ pub struct TrackerResponse {
     pub peers: Peers,
 }
 
-pub fn extract_info_field(dict: BTreeMap<String, OwnedValue>) -> BTreeMap<String, OwnedValue> {
-    let info = dict.get("info").expect("Required field missing: info");
-    if let OwnedValue::Dict(d) = info {
-        d.clone()
-    } else {
-        panic!("Wrong type for field info");
-    }
-}
-
 pub fn compute_length(info: &Info) -> usize {
     if info.length > 0 {
         info.length
@@ -61,38 +38,86 @@ pub fn compute_length(info: &Info) -> usize {
     }
 }
 
+/// Announces us to the tracker at `announce` as `peer_id` listening on
+/// `port` with `left` bytes still to download, and returns the peers it
+/// knows.
 pub async fn send_request(
-    dict: BTreeMap<String, OwnedValue>,
+    announce: &str,
     info_hash: [u8; 20],
-) -> TrackerResponse {
-    let meta_info = parse_metainfo(dict);
-
+    peer_id: [u8; 20],
+    port: u16,
+    left: usize,
+) -> anyhow::Result<TrackerResponse> {
     let request = TrackerRequest {
-        peer_id: String::from("-MB2025-100101070501"),
-        port: 6881,
+        peer_id: String::from_utf8_lossy(&peer_id).into_owned(),
+        port,
         uploaded: 0,
         downloaded: 0,
-        left: compute_length(&meta_info.info),
+        left,
         compact: 1,
     };
 
-    let url_params = serde_urlencoded::to_string(&request).unwrap();
+    let url_params = serde_urlencoded::to_string(&request)?;
     let tracker_url = format!(
         "{}?{}&info_hash={}",
-        meta_info.announce,
+        announce,
         url_params,
         &urlencode(&info_hash)
     );
 
-    let response = reqwest::get(&tracker_url).await.unwrap();
-    let response = response.bytes().await.unwrap();
+    let response = reqwest::get(&tracker_url)
+        .await
+        .with_context(|| format!("announce to {announce}"))?;
+    let response = response.bytes().await.context("read tracker response")?;
+
+    bdecoder::from_bytes(&response).context("decode tracker response")
+}
+
+/// Swarm counts a tracker reports for a torrent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct ScrapeStats {
+    /// Peers with the whole torrent.
+    pub complete: i64,
+    /// Times the torrent was downloaded to the end.
+    pub downloaded: i64,
+    /// Peers still downloading.
+    pub incomplete: i64,
+}
 
-    let tracker_response: TrackerResponse = serde_bencode::from_bytes(&response).unwrap();
-    tracker_response
+#[derive(Debug, Deserialize)]
+struct ScrapeResponse {
+    files: BTreeMap<serde_bytes::ByteBuf, ScrapeStats>,
 }
 
-pub fn dump_peers(tracker_reponse: TrackerResponse) -> () {
-    for peer in &tracker_reponse.peers.0 {
+/// The scrape URL of a tracker: the last path segment of its announce URL
+/// must start with `announce`, which is replaced by `scrape`.
+pub fn scrape_url(announce: &str) -> Option<String> {
+    let (base, last) = announce.rsplit_once('/')?;
+    let rest = last.strip_prefix("announce")?;
+    Some(format!("{base}/scrape{rest}"))
+}
+
+pub async fn scrape(announce: &str, info_hash: [u8; 20]) -> anyhow::Result<ScrapeStats> {
+    let url = scrape_url(announce)
+        .with_context(|| format!("tracker {announce} does not support scraping"))?;
+    let separator = if url.contains('?') { '&' } else { '?' };
+    let url = format!("{url}{separator}info_hash={}", urlencode(&info_hash));
+
+    let response = reqwest::get(&url)
+        .await
+        .with_context(|| format!("scrape {announce}"))?;
+    let response = response.bytes().await.context("read scrape response")?;
+    let response: ScrapeResponse =
+        bdecoder::from_bytes(&response).context("decode scrape response")?;
+    response
+        .files
+        .get(serde_bytes::Bytes::new(&info_hash))
+        .copied()
+        .context("tracker does not know the torrent")
+}
+
+pub fn dump_peers(tracker_response: &TrackerResponse) {
+    for peer in &tracker_response.peers.0 {
         println!("{}:{}", peer.ip(), peer.port());
     }
 }
@@ -100,8 +125,20 @@ pub fn dump_peers(tracker_reponse: TrackerResponse) -> () {
 fn urlencode(t: &[u8]) -> String {
     let mut encoded = String::with_capacity(3 * t.len());
     for &byte in t {
-        encoded.push('%');
-        encoded.push_str(&hex::encode(&[byte]));
+        write!(encoded, "%{byte:02x}").expect("writing to a String cannot fail");
     }
     encoded
 }
+
+#[test]
+fn scrape_url_replaces_announce() {
+    assert_eq!(
+        scrape_url("http://example.com/announce").as_deref(),
+        Some("http://example.com/scrape")
+    );
+    assert_eq!(
+        scrape_url("http://example.com/x/announce.php?passkey=1").as_deref(),
+        Some("http://example.com/x/scrape.php?passkey=1")
+    );
+    assert_eq!(scrape_url("http://example.com/a"), None);
+}
@@ -0,0 +1,146 @@
+use crate::utp::{UtpSocket, UtpStream};
+use anyhow::Context;
+use std::io;
+use std::net::SocketAddrV4;
+use std::pin::Pin;
+use std::task::Poll;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+
+/// Which transports we try when connecting to a peer, and in which order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TransportPolicy {
+    TcpOnly,
+    UtpOnly,
+    PreferTcp,
+    /// uTP first, as it backs off in front of other traffic, then TCP.
+    #[default]
+    PreferUtp,
+}
+
+impl TransportPolicy {
+    fn order(self) -> &'static [TransportKind] {
+        match self {
+            TransportPolicy::TcpOnly => &[TransportKind::Tcp],
+            TransportPolicy::UtpOnly => &[TransportKind::Utp],
+            TransportPolicy::PreferTcp => &[TransportKind::Tcp, TransportKind::Utp],
+            TransportPolicy::PreferUtp => &[TransportKind::Utp, TransportKind::Tcp],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportKind {
+    Tcp,
+    Utp,
+}
+
+/// Byte stream to a peer, over TCP or uTP.
+#[derive(Debug)]
+pub(crate) enum Transport {
+    Tcp(TcpStream),
+    Utp(UtpStream),
+}
+
+impl Transport {
+    pub(crate) fn kind(&self) -> TransportKind {
+        match self {
+            Transport::Tcp(_) => TransportKind::Tcp,
+            Transport::Utp(_) => TransportKind::Utp,
+        }
+    }
+}
+
+/// Connects to `addr` with the transports allowed by `policy`, falling back
+/// to the next one when a connection fails. uTP is skipped without a socket.
+pub(crate) async fn open(
+    addr: SocketAddrV4,
+    policy: TransportPolicy,
+    utp: Option<&UtpSocket>,
+) -> anyhow::Result<Transport> {
+    let mut last_error = None;
+    for kind in policy.order() {
+        let connected = match (kind, utp) {
+            (TransportKind::Tcp, _) => TcpStream::connect(addr)
+                .await
+                .map(Transport::Tcp)
+                .context("connect to peer over tcp"),
+            (TransportKind::Utp, Some(utp)) => utp
+                .connect(addr.into())
+                .await
+                .map(Transport::Utp)
+                .context("connect to peer over utp"),
+            (TransportKind::Utp, None) => continue,
+        };
+        match connected {
+            Ok(transport) => return Ok(transport),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| anyhow::anyhow!("no transport allowed to reach {addr}")))
+}
+
+impl AsyncRead for Transport {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Transport::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Transport::Utp(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Transport {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Transport::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Transport::Utp(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Transport::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Transport::Utp(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Transport::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Transport::Utp(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+#[tokio::test]
+async fn transport_falls_back_to_tcp() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let std::net::SocketAddr::V4(addr) = listener.local_addr().unwrap() else {
+        unreachable!()
+    };
+    let mut utp = UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    utp.connect_timeout = tokio::time::Duration::from_millis(300);
+
+    // nothing speaks uTP on the TCP listener's port
+    let transport = open(addr, TransportPolicy::PreferUtp, Some(&utp))
+        .await
+        .unwrap();
+    assert_eq!(transport.kind(), TransportKind::Tcp);
+    assert!(open(addr, TransportPolicy::UtpOnly, Some(&utp))
+        .await
+        .is_err());
+    assert!(open(addr, TransportPolicy::UtpOnly, None).await.is_err());
+}
@@ -0,0 +1,731 @@
+use anyhow::Context;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::Poll;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::time::{self, Duration, Instant};
+
+const HEADER_LEN: usize = 20;
+const VERSION: u8 = 1;
+
+/// Largest payload of a data packet, keeping datagrams under common MTUs.
+const PAYLOAD_MAX: usize = 1400 - HEADER_LEN;
+
+/// Bytes we buffer for the application; also the window we advertise.
+const RECV_WINDOW: usize = 1 << 20;
+
+/// LEDBAT one-way queuing delay target.
+const TARGET_DELAY: u32 = 100_000;
+
+/// LEDBAT window growth per round trip when the delay is far below target.
+const MAX_CWND_INCREASE_PER_RTT: f64 = 3000.0;
+
+const MIN_WINDOW: usize = PAYLOAD_MAX;
+const INITIAL_WINDOW: usize = 4 * PAYLOAD_MAX;
+const MAX_WINDOW: usize = RECV_WINDOW;
+
+/// How long the base delay is remembered before it is measured again, so
+/// route changes do not leave us with a stale minimum.
+const BASE_DELAY_TTL: Duration = Duration::from_secs(120);
+
+const MIN_RTO: Duration = Duration::from_millis(500);
+const MAX_RTO: Duration = Duration::from_secs(8);
+
+/// Retransmissions of one packet before the connection is given up.
+const MAX_TRANSMISSIONS: u32 = 6;
+
+/// How long we wait for the other side's FIN once ours has been acked.
+const LINGER: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+enum PacketType {
+    Data = 0,
+    Fin = 1,
+    State = 2,
+    Reset = 3,
+    Syn = 4,
+}
+
+#[derive(Debug, Clone)]
+struct Packet {
+    kind: PacketType,
+    connection_id: u16,
+    timestamp: u32,
+    timestamp_difference: u32,
+    wnd_size: u32,
+    seq_nr: u16,
+    ack_nr: u16,
+    payload: Vec<u8>,
+}
+
+impl Packet {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(HEADER_LEN + self.payload.len());
+        out.push((self.kind as u8) << 4 | VERSION);
+        out.push(0); // no extension
+        out.extend_from_slice(&self.connection_id.to_be_bytes());
+        out.extend_from_slice(&self.timestamp.to_be_bytes());
+        out.extend_from_slice(&self.timestamp_difference.to_be_bytes());
+        out.extend_from_slice(&self.wnd_size.to_be_bytes());
+        out.extend_from_slice(&self.seq_nr.to_be_bytes());
+        out.extend_from_slice(&self.ack_nr.to_be_bytes());
+        out.extend_from_slice(&self.payload);
+        out
+    }
+
+    fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        anyhow::ensure!(bytes.len() >= HEADER_LEN, "utp packet too short");
+        anyhow::ensure!(bytes[0] & 0x0f == VERSION, "unknown utp version");
+        let kind = match bytes[0] >> 4 {
+            0 => PacketType::Data,
+            1 => PacketType::Fin,
+            2 => PacketType::State,
+            3 => PacketType::Reset,
+            4 => PacketType::Syn,
+            kind => anyhow::bail!("unknown utp packet type {kind}"),
+        };
+        // skip the extension chain (selective acks are not used)
+        let mut extension = bytes[1];
+        let mut offset = HEADER_LEN;
+        while extension != 0 {
+            anyhow::ensure!(bytes.len() >= offset + 2, "truncated utp extension");
+            extension = bytes[offset];
+            offset += 2 + bytes[offset + 1] as usize;
+            anyhow::ensure!(bytes.len() >= offset, "truncated utp extension");
+        }
+        let u16_at = |i: usize| u16::from_be_bytes([bytes[i], bytes[i + 1]]);
+        let u32_at = |i: usize| u32::from_be_bytes(bytes[i..i + 4].try_into().expect("4 bytes"));
+        Ok(Self {
+            kind,
+            connection_id: u16_at(2),
+            timestamp: u32_at(4),
+            timestamp_difference: u32_at(8),
+            wnd_size: u32_at(12),
+            seq_nr: u16_at(16),
+            ack_nr: u16_at(18),
+            payload: bytes[offset..].to_vec(),
+        })
+    }
+}
+
+fn now_micros() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as u32)
+        .unwrap_or(0)
+}
+
+/// Whether sequence number `a` comes before `b`, accounting for wrap-around.
+fn seq_less(a: u16, b: u16) -> bool {
+    (b.wrapping_sub(a) as i16) > 0
+}
+
+type Connections = Arc<Mutex<HashMap<(SocketAddr, u16), mpsc::UnboundedSender<Packet>>>>;
+
+/// A UDP socket carrying uTP (BEP 29) connections, both the ones we open
+/// and the ones peers open to us.
+pub struct UtpSocket {
+    socket: Arc<UdpSocket>,
+    connections: Connections,
+    incoming: tokio::sync::Mutex<mpsc::Receiver<UtpStream>>,
+    task: tokio::task::JoinHandle<()>,
+    /// How long `connect` waits for the other side to answer.
+    pub connect_timeout: Duration,
+}
+
+impl Drop for UtpSocket {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl std::fmt::Debug for UtpSocket {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UtpSocket")
+            .field("local_addr", &self.socket.local_addr())
+            .finish()
+    }
+}
+
+impl UtpSocket {
+    pub async fn bind(addr: SocketAddr) -> anyhow::Result<Self> {
+        let socket = Arc::new(UdpSocket::bind(addr).await.context("bind utp socket")?);
+        let connections: Connections = Arc::default();
+        let (accept, incoming) = mpsc::channel(16);
+        let task = tokio::spawn(receive(
+            Arc::clone(&socket),
+            Arc::clone(&connections),
+            accept,
+        ));
+        Ok(Self {
+            socket,
+            connections,
+            incoming: tokio::sync::Mutex::new(incoming),
+            task,
+            connect_timeout: Duration::from_secs(3),
+        })
+    }
+
+    pub fn local_addr(&self) -> anyhow::Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    /// Opens a uTP connection to `addr`.
+    pub async fn connect(&self, addr: SocketAddr) -> anyhow::Result<UtpStream> {
+        let (sender, mut packets) = mpsc::unbounded_channel();
+        let recv_id = loop {
+            let id = rand::random::<u16>();
+            let mut connections = self.connections.lock().unwrap();
+            if let std::collections::hash_map::Entry::Vacant(entry) = connections.entry((addr, id))
+            {
+                entry.insert(sender);
+                break id;
+            }
+        };
+
+        let syn = Packet {
+            kind: PacketType::Syn,
+            connection_id: recv_id,
+            timestamp: 0,
+            timestamp_difference: 0,
+            wnd_size: RECV_WINDOW as u32,
+            seq_nr: 1,
+            ack_nr: 0,
+            payload: Vec::new(),
+        };
+        let deadline = Instant::now() + self.connect_timeout;
+        let state = loop {
+            let mut syn = syn.clone();
+            syn.timestamp = now_micros();
+            self.socket
+                .send_to(&syn.to_bytes(), addr)
+                .await
+                .context("send utp syn")?;
+            let retry = (Instant::now() + Duration::from_secs(1)).min(deadline);
+            match time::timeout_at(retry, packets.recv()).await {
+                Ok(Some(packet)) if packet.kind == PacketType::State => break packet,
+                Ok(Some(packet)) if packet.kind == PacketType::Reset => {
+                    self.connections.lock().unwrap().remove(&(addr, recv_id));
+                    anyhow::bail!("utp connection refused by {addr}");
+                }
+                Ok(_) => {}
+                Err(_) if Instant::now() >= deadline => {
+                    self.connections.lock().unwrap().remove(&(addr, recv_id));
+                    anyhow::bail!("utp connection to {addr} timed out");
+                }
+                Err(_) => {}
+            }
+        };
+
+        let connection = Connection::new(
+            Arc::clone(&self.socket),
+            Arc::clone(&self.connections),
+            addr,
+            recv_id,
+            recv_id.wrapping_add(1),
+            2,
+            state.seq_nr.wrapping_sub(1),
+        );
+        Ok(connection.spawn(packets))
+    }
+
+    /// Waits for a peer to open a uTP connection to us.
+    pub async fn accept(&self) -> anyhow::Result<UtpStream> {
+        self.incoming
+            .lock()
+            .await
+            .recv()
+            .await
+            .context("utp socket closed")
+    }
+}
+
+async fn receive(
+    socket: Arc<UdpSocket>,
+    connections: Connections,
+    accept: mpsc::Sender<UtpStream>,
+) {
+    let mut buf = vec![0u8; 65536];
+    loop {
+        let Ok((n, from)) = socket.recv_from(&mut buf).await else {
+            continue;
+        };
+        let Ok(packet) = Packet::from_bytes(&buf[..n]) else {
+            continue;
+        };
+        let key = if packet.kind == PacketType::Syn {
+            (from, packet.connection_id.wrapping_add(1))
+        } else {
+            (from, packet.connection_id)
+        };
+
+        let known = connections.lock().unwrap().get(&key).cloned();
+        if let Some(sender) = known {
+            if sender.send(packet).is_err() {
+                connections.lock().unwrap().remove(&key);
+            }
+            continue;
+        }
+
+        match packet.kind {
+            PacketType::Syn if accept.capacity() > 0 => {
+                let (sender, packets) = mpsc::unbounded_channel();
+                connections.lock().unwrap().insert(key, sender.clone());
+                let seq_nr = rand::random::<u16>();
+                let connection = Connection::new(
+                    Arc::clone(&socket),
+                    Arc::clone(&connections),
+                    from,
+                    key.1,
+                    packet.connection_id,
+                    seq_nr,
+                    packet.seq_nr,
+                );
+                // the connection answers the syn itself, like a retransmitted one
+                let _ = sender.send(packet);
+                let _ = accept.try_send(connection.spawn(packets));
+            }
+            PacketType::Reset => {}
+            _ => {
+                let reset = Packet {
+                    kind: PacketType::Reset,
+                    connection_id: packet.connection_id,
+                    timestamp: now_micros(),
+                    timestamp_difference: 0,
+                    wnd_size: 0,
+                    seq_nr: 0,
+                    ack_nr: packet.seq_nr,
+                    payload: Vec::new(),
+                };
+                let _ = socket.send_to(&reset.to_bytes(), from).await;
+            }
+        }
+    }
+}
+
+/// One end of a uTP connection. Reads and writes go through an in-memory
+/// pipe to the task running the protocol.
+#[derive(Debug)]
+pub struct UtpStream {
+    pipe: DuplexStream,
+    peer_addr: SocketAddr,
+}
+
+impl UtpStream {
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+}
+
+impl AsyncRead for UtpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().pipe).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for UtpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().pipe).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().pipe).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().pipe).poll_shutdown(cx)
+    }
+}
+
+#[derive(Debug)]
+struct Sent {
+    seq_nr: u16,
+    kind: PacketType,
+    payload: Vec<u8>,
+    sent_at: Instant,
+    transmissions: u32,
+}
+
+/// Protocol state of one connection.
+struct Connection {
+    socket: Arc<UdpSocket>,
+    connections: Connections,
+    addr: SocketAddr,
+    recv_id: u16,
+    send_id: u16,
+    /// Sequence number of the next packet we send.
+    seq_nr: u16,
+    /// Last sequence number received in order.
+    ack_nr: u16,
+    in_flight: VecDeque<Sent>,
+    cur_window: usize,
+    max_window: usize,
+    peer_window: usize,
+    out_of_order: HashMap<u16, Packet>,
+    deliver: Vec<u8>,
+    rtt: Duration,
+    rtt_var: Duration,
+    rto: Duration,
+    base_delay: u32,
+    base_delay_since: Instant,
+    reply_micro: u32,
+    duplicate_acks: u32,
+    fin_sent: bool,
+    fin_received: bool,
+    fin_acked_at: Option<Instant>,
+}
+
+impl Connection {
+    fn new(
+        socket: Arc<UdpSocket>,
+        connections: Connections,
+        addr: SocketAddr,
+        recv_id: u16,
+        send_id: u16,
+        seq_nr: u16,
+        ack_nr: u16,
+    ) -> Self {
+        Self {
+            socket,
+            connections,
+            addr,
+            recv_id,
+            send_id,
+            seq_nr,
+            ack_nr,
+            in_flight: VecDeque::new(),
+            cur_window: 0,
+            max_window: INITIAL_WINDOW,
+            peer_window: RECV_WINDOW,
+            out_of_order: HashMap::new(),
+            deliver: Vec::new(),
+            rtt: Duration::ZERO,
+            rtt_var: Duration::ZERO,
+            rto: Duration::from_secs(1),
+            base_delay: u32::MAX,
+            base_delay_since: Instant::now(),
+            reply_micro: 0,
+            duplicate_acks: 0,
+            fin_sent: false,
+            fin_received: false,
+            fin_acked_at: None,
+        }
+    }
+
+    fn spawn(self, packets: mpsc::UnboundedReceiver<Packet>) -> UtpStream {
+        let (pipe, ours) = tokio::io::duplex(RECV_WINDOW);
+        let peer_addr = self.addr;
+        tokio::spawn(self.run(ours, packets));
+        UtpStream { pipe, peer_addr }
+    }
+
+    fn advertised_window(&self) -> u32 {
+        let buffered: usize = self.deliver.len()
+            + self
+                .out_of_order
+                .values()
+                .map(|packet| packet.payload.len())
+                .sum::<usize>();
+        RECV_WINDOW.saturating_sub(buffered) as u32
+    }
+
+    async fn send(&self, kind: PacketType, seq_nr: u16, payload: &[u8]) -> io::Result<()> {
+        let packet = Packet {
+            kind,
+            connection_id: self.send_id,
+            timestamp: now_micros(),
+            timestamp_difference: self.reply_micro,
+            wnd_size: self.advertised_window(),
+            seq_nr,
+            ack_nr: self.ack_nr,
+            payload: payload.to_vec(),
+        };
+        self.socket.send_to(&packet.to_bytes(), self.addr).await?;
+        Ok(())
+    }
+
+    /// Sends a packet that has to be acknowledged (data or fin).
+    async fn send_reliable(&mut self, kind: PacketType, payload: Vec<u8>) -> io::Result<()> {
+        let seq_nr = self.seq_nr;
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+        self.send(kind, seq_nr, &payload).await?;
+        self.cur_window += payload.len();
+        self.in_flight.push_back(Sent {
+            seq_nr,
+            kind,
+            payload,
+            sent_at: Instant::now(),
+            transmissions: 1,
+        });
+        Ok(())
+    }
+
+    async fn resend_oldest(&mut self) -> io::Result<()> {
+        let Some(sent) = self.in_flight.front() else {
+            return Ok(());
+        };
+        let (kind, seq_nr, payload) = (sent.kind, sent.seq_nr, sent.payload.clone());
+        self.send(kind, seq_nr, &payload).await?;
+        let sent = self.in_flight.front_mut().expect("checked above");
+        sent.sent_at = Instant::now();
+        sent.transmissions += 1;
+        Ok(())
+    }
+
+    fn can_send(&self) -> bool {
+        let window = self.max_window.min(self.peer_window);
+        self.in_flight.is_empty() || self.cur_window + PAYLOAD_MAX <= window
+    }
+
+    fn update_rtt(&mut self, sample: Duration) {
+        if self.rtt.is_zero() {
+            self.rtt = sample;
+            self.rtt_var = sample / 2;
+        } else {
+            let delta = self.rtt.abs_diff(sample);
+            self.rtt_var = (self.rtt_var * 3 + delta) / 4;
+            self.rtt = (self.rtt * 7 + sample) / 8;
+        }
+        self.rto = (self.rtt + self.rtt_var * 4).clamp(MIN_RTO, MAX_RTO);
+    }
+
+    /// LEDBAT: grows the window while the queuing delay our packets see is
+    /// below target and shrinks it above.
+    fn update_window(&mut self, delay: u32, bytes_acked: usize) {
+        if delay == 0 || bytes_acked == 0 {
+            return;
+        }
+        if self.base_delay_since.elapsed() > BASE_DELAY_TTL {
+            self.base_delay = u32::MAX;
+            self.base_delay_since = Instant::now();
+        }
+        self.base_delay = self.base_delay.min(delay);
+        let our_delay = delay.wrapping_sub(self.base_delay).min(u32::MAX / 2);
+        let off_target = (TARGET_DELAY as f64 - our_delay as f64) / TARGET_DELAY as f64;
+        let window_factor = bytes_acked as f64 / self.max_window as f64;
+        let gain = MAX_CWND_INCREASE_PER_RTT * off_target * window_factor.min(1.0);
+        self.max_window = ((self.max_window as f64 + gain) as usize).clamp(MIN_WINDOW, MAX_WINDOW);
+    }
+
+    async fn on_packet(&mut self, packet: Packet) -> anyhow::Result<()> {
+        self.reply_micro = now_micros().wrapping_sub(packet.timestamp);
+        match packet.kind {
+            PacketType::Reset => anyhow::bail!("utp connection reset by peer"),
+            PacketType::Syn => {
+                // (re)acknowledge the connection, without consuming a sequence number
+                self.send(PacketType::State, self.seq_nr, &[]).await?;
+                return Ok(());
+            }
+            _ => {}
+        }
+        self.peer_window = packet.wnd_size as usize;
+
+        let mut bytes_acked = 0;
+        while let Some(sent) = self.in_flight.front() {
+            if seq_less(packet.ack_nr, sent.seq_nr) {
+                break;
+            }
+            let sent = self.in_flight.pop_front().expect("checked above");
+            bytes_acked += sent.payload.len();
+            self.cur_window -= sent.payload.len();
+            if sent.transmissions == 1 {
+                self.update_rtt(sent.sent_at.elapsed());
+            }
+        }
+        if bytes_acked > 0 || self.in_flight.is_empty() {
+            self.duplicate_acks = 0;
+        } else if packet.kind == PacketType::State {
+            self.duplicate_acks += 1;
+            if self.duplicate_acks == 3 {
+                self.resend_oldest().await?;
+            }
+        }
+        self.update_window(packet.timestamp_difference, bytes_acked);
+        if self.fin_sent && self.in_flight.is_empty() && self.fin_acked_at.is_none() {
+            self.fin_acked_at = Some(Instant::now());
+        }
+
+        if matches!(packet.kind, PacketType::Data | PacketType::Fin) {
+            if seq_less(self.ack_nr, packet.seq_nr) && self.out_of_order.len() < 1024 {
+                self.out_of_order.insert(packet.seq_nr, packet);
+            }
+            while let Some(next) = self.out_of_order.remove(&self.ack_nr.wrapping_add(1)) {
+                self.ack_nr = next.seq_nr;
+                self.deliver.extend_from_slice(&next.payload);
+                if next.kind == PacketType::Fin {
+                    self.fin_received = true;
+                    self.out_of_order.clear();
+                }
+            }
+            self.send(PacketType::State, self.seq_nr, &[]).await?;
+        }
+        Ok(())
+    }
+
+    async fn run(mut self, pipe: DuplexStream, mut packets: mpsc::UnboundedReceiver<Packet>) {
+        if self.drive(pipe, &mut packets).await.is_err() {
+            // the stream reads the end of the connection, the peer the reset
+            let _ = self.send(PacketType::Reset, self.seq_nr, &[]).await;
+        }
+        self.connections
+            .lock()
+            .unwrap()
+            .remove(&(self.addr, self.recv_id));
+    }
+
+    async fn drive(
+        &mut self,
+        pipe: DuplexStream,
+        packets: &mut mpsc::UnboundedReceiver<Packet>,
+    ) -> anyhow::Result<()> {
+        let (mut from_app, mut to_app) = tokio::io::split(pipe);
+        let mut app_gone = false;
+        let mut app_closed = false;
+        let mut buf = vec![0u8; PAYLOAD_MAX];
+        loop {
+            if self.fin_received && self.deliver.is_empty() && !app_closed {
+                let _ = to_app.shutdown().await;
+                app_closed = true;
+            }
+            if let Some(acked_at) = self.fin_acked_at {
+                if app_closed || app_gone || acked_at.elapsed() > LINGER {
+                    return Ok(());
+                }
+            }
+
+            let timeout = self
+                .in_flight
+                .front()
+                .map(|sent| sent.sent_at + self.rto)
+                .or(self.fin_acked_at.map(|acked_at| acked_at + LINGER))
+                .unwrap_or_else(|| Instant::now() + Duration::from_secs(3600));
+            let can_send = !self.fin_sent && self.can_send();
+            let can_deliver = !self.deliver.is_empty() && !app_gone;
+
+            tokio::select! {
+                packet = packets.recv() => {
+                    let Some(packet) = packet else {
+                        anyhow::bail!("utp socket closed");
+                    };
+                    self.on_packet(packet).await?;
+                }
+                read = from_app.read(&mut buf), if can_send => {
+                    match read {
+                        Ok(0) | Err(_) => {
+                            self.fin_sent = true;
+                            self.send_reliable(PacketType::Fin, Vec::new()).await?;
+                        }
+                        Ok(n) => self.send_reliable(PacketType::Data, buf[..n].to_vec()).await?,
+                    }
+                }
+                written = to_app.write(&self.deliver), if can_deliver => {
+                    match written {
+                        Ok(n) => {
+                            self.deliver.drain(..n);
+                        }
+                        Err(_) => {
+                            app_gone = true;
+                            self.deliver.clear();
+                        }
+                    }
+                }
+                _ = time::sleep_until(timeout) => {
+                    if let Some(sent) = self.in_flight.front() {
+                        anyhow::ensure!(
+                            sent.transmissions < MAX_TRANSMISSIONS,
+                            "utp connection to {} timed out",
+                            self.addr
+                        );
+                        self.max_window = MIN_WINDOW;
+                        self.rto = (self.rto * 2).min(MAX_RTO);
+                        self.resend_oldest().await?;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn utp_packet_round_trip() {
+    let packet = Packet {
+        kind: PacketType::Data,
+        connection_id: 0xbeef,
+        timestamp: 1,
+        timestamp_difference: 2,
+        wnd_size: 3,
+        seq_nr: 65535,
+        ack_nr: 7,
+        payload: b"hello".to_vec(),
+    };
+    let bytes = packet.to_bytes();
+    assert_eq!(bytes[0], 0x01);
+    let decoded = Packet::from_bytes(&bytes).unwrap();
+    assert_eq!(decoded.connection_id, 0xbeef);
+    assert_eq!(decoded.seq_nr, 65535);
+    assert_eq!(decoded.payload, b"hello");
+    assert!(seq_less(65535, 0));
+    assert!(!seq_less(0, 65535));
+}
+
+#[tokio::test]
+async fn utp_loopback_transfer() {
+    let server = UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let client = UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let server_addr = server.local_addr().unwrap();
+
+    let data: Vec<u8> = (0..1_000_000u32).map(|i| (i % 251) as u8).collect();
+    let expected = data.clone();
+    let echo = tokio::spawn(async move {
+        let mut stream = server.accept().await.unwrap();
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).await.unwrap();
+        stream.write_all(&received[..1000]).await.unwrap();
+        stream.shutdown().await.unwrap();
+        received
+    });
+
+    let mut stream = client.connect(server_addr).await.unwrap();
+    stream.write_all(&data).await.unwrap();
+    stream.shutdown().await.unwrap();
+    let mut reply = Vec::new();
+    stream.read_to_end(&mut reply).await.unwrap();
+    assert_eq!(reply, &expected[..1000]);
+    assert!(echo.await.unwrap() == expected);
+}
+
+#[tokio::test]
+async fn utp_connect_without_listener_fails() {
+    let mut client = UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    client.connect_timeout = Duration::from_millis(300);
+    let nobody = UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let addr = nobody.local_addr().unwrap();
+    drop(nobody);
+    assert!(client.connect(addr).await.is_err());
+}
@@ -0,0 +1,341 @@
+use crate::bdecoder::{self, Dict, Value};
+use anyhow::Context;
+use serde::{Serialize, Serializer};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+/// Size of the blocks hashed into the leaves of the merkle trees.
+pub const MERKLE_BLOCK: usize = 1 << 14;
+
+/// A file of a v2 torrent (BEP 52).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FileV2 {
+    pub path: Vec<String>,
+    pub length: usize,
+    /// Root of the merkle tree of the file; empty files have none.
+    #[serde(
+        rename = "pieces root",
+        serialize_with = "hex_root",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub pieces_root: Option<[u8; 32]>,
+}
+
+/// The v2 part of a torrent: its file tree, piece layers and SHA-256 info hash.
+#[derive(Debug, Clone, Serialize)]
+pub struct InfoV2 {
+    pub name: String,
+    #[serde(rename = "piece length")]
+    pub piece_length: usize,
+    pub files: Vec<FileV2>,
+    /// Hashes of the pieces of each file larger than a piece, by pieces root.
+    #[serde(skip)]
+    pub piece_layers: HashMap<[u8; 32], Vec<[u8; 32]>>,
+    #[serde(rename = "info hash", serialize_with = "hex_hash")]
+    pub info_hash: [u8; 32],
+    /// Whether the torrent also carries the v1 `pieces` (a hybrid torrent).
+    pub hybrid: bool,
+}
+
+impl InfoV2 {
+    /// Parses the v2 part of a `.torrent` file, if it has one.
+    pub fn from_torrent(contents: &[u8]) -> anyhow::Result<Option<Self>> {
+        let torrent = bdecoder::decode(contents).context("invalid bencode")?;
+        let Value::Dict(torrent) = torrent else {
+            anyhow::bail!("torrent is not a dictionary");
+        };
+        let Some(Value::Dict(info)) = torrent.get(&b"info"[..]) else {
+            anyhow::bail!("torrent has no info dictionary");
+        };
+        match info.get(&b"meta version"[..]) {
+            None => return Ok(None),
+            Some(Value::Integer(2)) => {}
+            Some(version) => anyhow::bail!("unsupported meta version {version:?}"),
+        }
+
+        let name = string(info.get(&b"name"[..]).context("info has no name")?)?;
+        let piece_length = match info.get(&b"piece length"[..]) {
+            Some(Value::Integer(length)) => *length as usize,
+            _ => anyhow::bail!("info has no piece length"),
+        };
+        anyhow::ensure!(
+            piece_length >= MERKLE_BLOCK && piece_length.is_power_of_two(),
+            "v2 piece length must be a power of two of at least 16 KiB"
+        );
+        let Some(Value::Dict(tree)) = info.get(&b"file tree"[..]) else {
+            anyhow::bail!("v2 info has no file tree");
+        };
+        let mut files = Vec::new();
+        walk_file_tree(tree, &mut Vec::new(), &mut files)?;
+
+        let mut piece_layers = HashMap::new();
+        if let Some(Value::Dict(layers)) = torrent.get(&b"piece layers"[..]) {
+            for (root, layer) in layers {
+                let root: [u8; 32] = root[..]
+                    .try_into()
+                    .context("piece layer key is not a hash")?;
+                let Value::Bytes(layer) = layer else {
+                    anyhow::bail!("piece layer is not a byte string");
+                };
+                anyhow::ensure!(layer.len() % 32 == 0, "piece layer of odd length");
+                let hashes = layer
+                    .chunks_exact(32)
+                    .map(|hash| hash.try_into().expect("chunks of 32 bytes"))
+                    .collect();
+                piece_layers.insert(root, hashes);
+            }
+        }
+
+        let info_bytes = &contents[bdecoder::info_span(contents)?];
+        let info_v2 = Self {
+            name,
+            piece_length,
+            files,
+            piece_layers,
+            info_hash: Sha256::digest(info_bytes).into(),
+            hybrid: info.contains_key(&b"pieces"[..]),
+        };
+        info_v2.check_piece_layers()?;
+        Ok(Some(info_v2))
+    }
+
+    /// The info hash truncated to 20 bytes, as used by trackers and in the
+    /// peer handshake.
+    pub fn truncated_info_hash(&self) -> [u8; 20] {
+        self.info_hash[..20].try_into().expect("32 bytes")
+    }
+
+    /// Checks that every file larger than a piece has a piece layer, and
+    /// that the layer hashes up to the file's pieces root.
+    fn check_piece_layers(&self) -> anyhow::Result<()> {
+        for file in &self.files {
+            let Some(root) = file.pieces_root else {
+                continue;
+            };
+            if file.length <= self.piece_length {
+                continue;
+            }
+            let layer = self
+                .piece_layers
+                .get(&root)
+                .with_context(|| format!("no piece layer for {}", file.path.join("/")))?;
+            anyhow::ensure!(
+                layer.len() == file.length.div_ceil(self.piece_length),
+                "piece layer of {} has the wrong length",
+                file.path.join("/")
+            );
+            let pad = pad_hash(self.piece_length / MERKLE_BLOCK);
+            anyhow::ensure!(
+                merkle_root(layer.clone(), layer.len().next_power_of_two(), pad) == root,
+                "piece layer of {} does not match its root",
+                file.path.join("/")
+            );
+        }
+        Ok(())
+    }
+
+    /// Number of pieces, each file starting on a piece boundary.
+    pub fn num_pieces(&self) -> usize {
+        self.files
+            .iter()
+            .map(|file| file.length.div_ceil(self.piece_length))
+            .sum()
+    }
+
+    /// The file piece `index` belongs to, and the index of the piece in it.
+    pub fn piece_location(&self, mut index: usize) -> Option<(usize, usize)> {
+        for (file_index, file) in self.files.iter().enumerate() {
+            let pieces = file.length.div_ceil(self.piece_length);
+            if index < pieces {
+                return Some((file_index, index));
+            }
+            index -= pieces;
+        }
+        None
+    }
+
+    /// Checks the data of piece `index` against the merkle hashes.
+    pub fn verify_piece(&self, index: usize, data: &[u8]) -> bool {
+        let Some((file_index, piece)) = self.piece_location(index) else {
+            return false;
+        };
+        let file = &self.files[file_index];
+        let Some(root) = file.pieces_root else {
+            return false;
+        };
+        // in hybrid torrents the v1 piece goes on with the padding file
+        let length = self
+            .piece_length
+            .min(file.length - piece * self.piece_length);
+        let Some(data) = data.get(..length) else {
+            return false;
+        };
+        let leaves: Vec<[u8; 32]> = data
+            .chunks(MERKLE_BLOCK)
+            .map(|block| Sha256::digest(block).into())
+            .collect();
+        if file.length <= self.piece_length {
+            // the piece is the whole file, its tree is only as wide as the file
+            merkle_root(
+                leaves,
+                data.len().div_ceil(MERKLE_BLOCK).next_power_of_two(),
+                [0; 32],
+            ) == root
+        } else {
+            let Some(expected) = self
+                .piece_layers
+                .get(&root)
+                .and_then(|layer| layer.get(piece))
+            else {
+                return false;
+            };
+            merkle_root(leaves, self.piece_length / MERKLE_BLOCK, [0; 32]) == *expected
+        }
+    }
+}
+
+fn hex_hash<S: Serializer>(hash: &[u8; 32], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&hex::encode(hash))
+}
+
+fn hex_root<S: Serializer>(root: &Option<[u8; 32]>, serializer: S) -> Result<S::Ok, S::Error> {
+    match root {
+        Some(root) => serializer.serialize_str(&hex::encode(root)),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// Collects the files of a `file tree`, in the tree's (sorted) order.
+fn walk_file_tree(
+    tree: &Dict,
+    path: &mut Vec<String>,
+    files: &mut Vec<FileV2>,
+) -> anyhow::Result<()> {
+    for (name, node) in tree {
+        let Value::Dict(node) = node else {
+            anyhow::bail!("file tree node is not a dictionary");
+        };
+        if name.is_empty() {
+            let length = match node.get(&b"length"[..]) {
+                Some(Value::Integer(length)) if *length >= 0 => *length as usize,
+                _ => anyhow::bail!("file {} has no length", path.join("/")),
+            };
+            let pieces_root = match node.get(&b"pieces root"[..]) {
+                Some(Value::Bytes(root)) => {
+                    Some(root[..].try_into().context("pieces root is not a hash")?)
+                }
+                _ if length == 0 => None,
+                _ => anyhow::bail!("file {} has no pieces root", path.join("/")),
+            };
+            files.push(FileV2 {
+                path: path.clone(),
+                length,
+                pieces_root,
+            });
+        } else {
+            let name = std::str::from_utf8(name).context("file name is not UTF-8")?;
+            anyhow::ensure!(
+                name != "." && name != ".." && !name.contains('/'),
+                "invalid file name {name:?}"
+            );
+            path.push(String::from(name));
+            walk_file_tree(node, path, files)?;
+            path.pop();
+        }
+    }
+    Ok(())
+}
+
+fn string(value: &Value) -> anyhow::Result<String> {
+    match value {
+        Value::Bytes(bytes) => Ok(String::from_utf8(bytes.to_vec()).context("not UTF-8")?),
+        _ => anyhow::bail!("not a byte string"),
+    }
+}
+
+/// Root of a merkle tree `width` leaves wide (a power of two), the leaves
+/// missing after `leaves` being `pad`.
+fn merkle_root(mut layer: Vec<[u8; 32]>, width: usize, pad: [u8; 32]) -> [u8; 32] {
+    layer.resize(width.max(1), pad);
+    while layer.len() > 1 {
+        layer = layer
+            .chunks(2)
+            .map(|pair| hash_pair(pair[0], pair[1]))
+            .collect();
+    }
+    layer[0]
+}
+
+fn hash_pair(left: [u8; 32], right: [u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+/// Root of a subtree of `leaves` zero leaves: the hash padding a piece layer.
+fn pad_hash(leaves: usize) -> [u8; 32] {
+    merkle_root(Vec::new(), leaves, [0; 32])
+}
+
+#[test]
+fn v2_torrent_verifies_pieces() {
+    let piece_length = 2 * MERKLE_BLOCK;
+    let big: Vec<u8> = (0..5 * MERKLE_BLOCK + 100)
+        .map(|i| (i % 239) as u8)
+        .collect();
+    let small = b"small file".to_vec();
+
+    let leaves = |data: &[u8]| -> Vec<[u8; 32]> {
+        data.chunks(MERKLE_BLOCK)
+            .map(|block| Sha256::digest(block).into())
+            .collect()
+    };
+    let layer: Vec<[u8; 32]> = big
+        .chunks(piece_length)
+        .map(|piece| merkle_root(leaves(piece), piece_length / MERKLE_BLOCK, [0; 32]))
+        .collect();
+    let big_root = merkle_root(
+        leaves(&big),
+        leaves(&big).len().next_power_of_two(),
+        [0; 32],
+    );
+    let small_root = merkle_root(leaves(&small), 1, [0; 32]);
+
+    fn file(length: usize, root: &[u8]) -> Value<'_> {
+        let mut leaf = Dict::new();
+        leaf.insert(&b"length"[..], Value::Integer(length as i64));
+        leaf.insert(&b"pieces root"[..], Value::Bytes(root));
+        let mut node = Dict::new();
+        node.insert(&b""[..], Value::Dict(leaf));
+        Value::Dict(node)
+    }
+    let mut tree = Dict::new();
+    tree.insert(&b"big.bin"[..], file(big.len(), &big_root));
+    tree.insert(&b"small.txt"[..], file(small.len(), &small_root));
+    let mut info = Dict::new();
+    info.insert(&b"file tree"[..], Value::Dict(tree));
+    info.insert(&b"meta version"[..], Value::Integer(2));
+    info.insert(&b"name"[..], Value::Bytes(b"v2"));
+    info.insert(&b"piece length"[..], Value::Integer(piece_length as i64));
+    let layer = layer.concat();
+    let mut layers = Dict::new();
+    layers.insert(&big_root[..], Value::Bytes(&layer));
+    let mut torrent = Dict::new();
+    torrent.insert(&b"info"[..], Value::Dict(info.clone()));
+    torrent.insert(&b"piece layers"[..], Value::Dict(layers));
+    let contents = Value::Dict(torrent).encode();
+
+    let v2 = InfoV2::from_torrent(&contents).unwrap().unwrap();
+    assert!(!v2.hybrid);
+    let info_hash: [u8; 32] = Sha256::digest(Value::Dict(info).encode()).into();
+    assert_eq!(v2.info_hash, info_hash);
+    assert_eq!(v2.truncated_info_hash(), info_hash[..20]);
+    assert_eq!(v2.num_pieces(), 4);
+    for (i, piece) in big.chunks(piece_length).enumerate() {
+        assert!(v2.verify_piece(i, piece));
+    }
+    assert!(v2.verify_piece(3, &small));
+    assert!(!v2.verify_piece(0, &big[piece_length..2 * piece_length]));
+    assert!(!v2.verify_piece(3, b"other file"));
+}
@@ -0,0 +1,404 @@
+use crate::bdecoder::{self, Dict, Value};
+use std::collections::HashSet;
+use std::fmt;
+
+/// Something wrong with a torrent, and the field it was found in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    pub field: String,
+    pub message: String,
+}
+
+/// Every problem found in a torrent file; empty when it is valid.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Report {
+    pub problems: Vec<Problem>,
+}
+
+impl Report {
+    pub fn is_valid(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    fn push(&mut self, field: impl Into<String>, message: impl Into<String>) {
+        self.problems.push(Problem {
+            field: field.into(),
+            message: message.into(),
+        });
+    }
+
+    /// The value of `key` in `dict`, reporting it when missing.
+    fn required<'d, 'a>(
+        &mut self,
+        dict: &'d Dict<'a>,
+        field: &str,
+        key: &str,
+    ) -> Option<&'d Value<'a>> {
+        let value = dict.get(key.as_bytes());
+        if value.is_none() {
+            self.push(field, "required key is missing");
+        }
+        value
+    }
+
+    fn string(&mut self, value: &Value<'_>, field: &str) {
+        match value {
+            Value::Bytes(bytes) if std::str::from_utf8(bytes).is_err() => {
+                self.push(field, "is not valid UTF-8")
+            }
+            Value::Bytes(_) => {}
+            _ => self.push(field, "must be a string"),
+        }
+    }
+
+    fn integer(&mut self, value: &Value<'_>, field: &str) -> Option<i64> {
+        match value {
+            Value::Integer(num) if *num < 0 => {
+                self.push(field, "must not be negative");
+                None
+            }
+            Value::Integer(num) => Some(*num),
+            _ => {
+                self.push(field, "must be an integer");
+                None
+            }
+        }
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, problem) in self.problems.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}: {}", problem.field, problem.message)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Report {}
+
+impl From<bdecoder::Error> for Report {
+    fn from(e: bdecoder::Error) -> Self {
+        let mut report = Report::default();
+        report.push("torrent", e.to_string());
+        report
+    }
+}
+
+/// Checks a `.torrent` file against BEP 3, collecting every problem rather
+/// than stopping at the first one. Bencode not in canonical form is one.
+pub fn validate(contents: &[u8]) -> Report {
+    let mut report = Report::default();
+    if let Err(e) = bdecoder::decode_strict(contents) {
+        if e.kind.is_non_canonical() {
+            report.push("torrent", e.to_string());
+        }
+    }
+    check(contents, report)
+}
+
+/// Checks a `.torrent` file like `validate`, but accepts bencode that is
+/// not in canonical form, as clients loading torrents do.
+pub fn validate_lenient(contents: &[u8]) -> Report {
+    check(contents, Report::default())
+}
+
+fn check(contents: &[u8], mut report: Report) -> Report {
+    let torrent = match bdecoder::decode(contents) {
+        Ok(Value::Dict(torrent)) => torrent,
+        Ok(_) => {
+            report.push("torrent", "must be a dictionary");
+            return report;
+        }
+        Err(e) => {
+            report.push("torrent", e.to_string());
+            return report;
+        }
+    };
+
+    // trackerless torrents find their peers through the DHT
+    let trackerless =
+        torrent.contains_key(&b"nodes"[..]) || torrent.contains_key(&b"announce-list"[..]);
+    let announce = match trackerless {
+        true => torrent.get(&b"announce"[..]),
+        false => report.required(&torrent, "announce", "announce"),
+    };
+    if let Some(announce) = announce {
+        report.string(announce, "announce");
+    }
+    if let Some(tiers) = torrent.get(&b"announce-list"[..]) {
+        match tiers {
+            Value::List(tiers) => {
+                for (i, tier) in tiers.iter().enumerate() {
+                    let field = format!("announce-list[{i}]");
+                    match tier {
+                        Value::List(urls) => {
+                            for (j, url) in urls.iter().enumerate() {
+                                report.string(url, &format!("{field}[{j}]"));
+                            }
+                        }
+                        _ => report.push(field, "must be a list"),
+                    }
+                }
+            }
+            _ => report.push("announce-list", "must be a list"),
+        }
+    }
+    if let Some(date) = torrent.get(&b"creation date"[..]) {
+        report.integer(date, "creation date");
+    }
+    for key in ["comment", "created by", "encoding"] {
+        if let Some(value) = torrent.get(key.as_bytes()) {
+            report.string(value, key);
+        }
+    }
+    match torrent.get(&b"url-list"[..]) {
+        Some(Value::List(urls)) => {
+            for (i, url) in urls.iter().enumerate() {
+                report.string(url, &format!("url-list[{i}]"));
+            }
+        }
+        Some(url) => report.string(url, "url-list"),
+        None => {}
+    }
+    match torrent.get(&b"httpseeds"[..]) {
+        Some(Value::List(urls)) => {
+            for (i, url) in urls.iter().enumerate() {
+                report.string(url, &format!("httpseeds[{i}]"));
+            }
+        }
+        Some(_) => report.push("httpseeds", "must be a list"),
+        None => {}
+    }
+    match torrent.get(&b"nodes"[..]) {
+        Some(Value::List(nodes)) => {
+            for (i, node) in nodes.iter().enumerate() {
+                match node.as_list() {
+                    Some([host, Value::Integer(0..=65535)]) => {
+                        report.string(host, &format!("nodes[{i}]"))
+                    }
+                    _ => report.push(format!("nodes[{i}]"), "must be a host and a port"),
+                }
+            }
+        }
+        Some(_) => report.push("nodes", "must be a list"),
+        None => {}
+    }
+
+    match report.required(&torrent, "info", "info") {
+        Some(Value::Dict(info)) => validate_info(info, &mut report),
+        Some(_) => report.push("info", "must be a dictionary"),
+        None => {}
+    }
+    report
+}
+
+fn validate_info(info: &Dict<'_>, report: &mut Report) {
+    if let Some(name) = report.required(info, "info.name", "name") {
+        report.string(name, "info.name");
+        if let Some(name) = name.as_str() {
+            check_component(name, "info.name", report);
+        }
+    }
+
+    let piece_length = match report.required(info, "info.piece length", "piece length") {
+        Some(value) => match report.integer(value, "info.piece length") {
+            Some(0) => {
+                report.push("info.piece length", "must not be zero");
+                None
+            }
+            piece_length => piece_length,
+        },
+        None => None,
+    };
+
+    if let Some(private) = info.get(&b"private"[..]) {
+        report.integer(private, "info.private");
+    }
+    if let Some(source) = info.get(&b"source"[..]) {
+        report.string(source, "info.source");
+    }
+
+    // v2-only torrents describe their files in a `file tree` instead
+    if info.contains_key(&b"meta version"[..]) && !info.contains_key(&b"pieces"[..]) {
+        return;
+    }
+
+    let num_pieces = match report.required(info, "info.pieces", "pieces") {
+        Some(Value::Bytes(pieces)) if pieces.len() % 20 != 0 => {
+            report.push(
+                "info.pieces",
+                format!("length {} is not a multiple of 20", pieces.len()),
+            );
+            None
+        }
+        Some(Value::Bytes(pieces)) => Some(pieces.len() / 20),
+        Some(_) => {
+            report.push("info.pieces", "must be a string");
+            None
+        }
+        None => None,
+    };
+
+    let total_length = match (info.get(&b"length"[..]), info.get(&b"files"[..])) {
+        (Some(_), Some(_)) => {
+            report.push("info", "has both length and files");
+            None
+        }
+        (None, None) => {
+            report.push("info", "has neither length nor files");
+            None
+        }
+        (Some(length), None) => report.integer(length, "info.length"),
+        (None, Some(Value::List(files))) => validate_files(files, report),
+        (None, Some(_)) => {
+            report.push("info.files", "must be a list");
+            None
+        }
+    };
+
+    if let (Some(piece_length), Some(num_pieces), Some(total_length)) =
+        (piece_length, num_pieces, total_length)
+    {
+        let expected = (total_length as u64).div_ceil(piece_length as u64);
+        if expected != num_pieces as u64 {
+            report.push(
+                "info.pieces",
+                format!("has {num_pieces} hashes but {total_length} bytes make {expected} pieces"),
+            );
+        }
+    }
+}
+
+/// Checks the entries of `files` and returns their total length.
+fn validate_files(files: &[Value<'_>], report: &mut Report) -> Option<i64> {
+    if files.is_empty() {
+        report.push("info.files", "must not be empty");
+    }
+    let mut total_length = Some(0i64);
+    let mut paths = HashSet::new();
+    for (i, file) in files.iter().enumerate() {
+        let field = format!("info.files[{i}]");
+        let Value::Dict(file) = file else {
+            report.push(field, "must be a dictionary");
+            total_length = None;
+            continue;
+        };
+        let length = report
+            .required(file, &format!("{field}.length"), "length")
+            .and_then(|length| report.integer(length, &format!("{field}.length")));
+        total_length = total_length
+            .zip(length)
+            .and_then(|(total, length)| total.checked_add(length));
+
+        let path_field = format!("{field}.path");
+        let components = match report.required(file, &path_field, "path") {
+            Some(Value::List(components)) if components.is_empty() => {
+                report.push(&path_field, "must not be empty");
+                continue;
+            }
+            Some(Value::List(components)) => components,
+            Some(_) => {
+                report.push(&path_field, "must be a list");
+                continue;
+            }
+            None => continue,
+        };
+        if let Some(attr) = file.get(&b"attr"[..]) {
+            report.string(attr, &format!("{field}.attr"));
+        }
+        match file.get(&b"sha1"[..]) {
+            Some(Value::Bytes(sha1)) if sha1.len() != 20 => {
+                report.push(format!("{field}.sha1"), "must be 20 bytes")
+            }
+            Some(Value::Bytes(_)) | None => {}
+            Some(_) => report.push(format!("{field}.sha1"), "must be a string"),
+        }
+        match file.get(&b"symlink path"[..]) {
+            Some(Value::List(components)) => {
+                for (j, component) in components.iter().enumerate() {
+                    report.string(component, &format!("{field}.symlink path[{j}]"));
+                }
+            }
+            Some(_) => report.push(format!("{field}.symlink path"), "must be a list"),
+            None => {}
+        }
+
+        let mut path = Vec::new();
+        for (j, component) in components.iter().enumerate() {
+            let component_field = format!("{path_field}[{j}]");
+            report.string(component, &component_field);
+            if let Some(component) = component.as_str() {
+                check_component(component, &component_field, report);
+                path.push(component);
+            }
+        }
+        if !paths.insert(path.join("/")) {
+            report.push(
+                path_field,
+                format!("duplicates the path {}", path.join("/")),
+            );
+        }
+    }
+    total_length
+}
+
+/// A path component must stay inside the download directory.
+fn check_component(component: &str, field: &str, report: &mut Report) {
+    if component.is_empty() {
+        report.push(field, "must not be empty");
+    } else if component == "." || component == ".." {
+        report.push(
+            field,
+            format!("{component:?} escapes the download directory"),
+        );
+    } else if component.contains(['/', '\\']) || component.as_bytes().get(1) == Some(&b':') {
+        report.push(
+            field,
+            format!("{component:?} is not a single relative name"),
+        );
+    }
+}
+
+#[test]
+fn report_lists_every_problem() {
+    let valid = b"d8:announce3:url4:infod5:filesld6:lengthi3e4:pathl1:aeee\
+        4:name1:x12:piece lengthi2e6:pieces40:aaaaaaaaaaaaaaaaaaaabbbbbbbbbbbbbbbbbbbbee";
+    assert!(validate(valid).is_valid(), "{}", validate(valid));
+    let trackerless = b"d4:infod6:lengthi3e4:name1:x12:piece lengthi4e\
+        6:pieces20:aaaaaaaaaaaaaaaaaaaae5:nodesll4:hosti6881eeee";
+    assert!(
+        validate(trackerless).is_valid(),
+        "{}",
+        validate(trackerless)
+    );
+
+    let invalid = b"d4:infod5:filesld6:lengthi3e4:pathl2:..eed6:lengthi1e4:pathl2:..eee\
+        4:name2:/x12:piece lengthi2e6:pieces3:abcee";
+    let fields: Vec<_> = validate(invalid)
+        .problems
+        .into_iter()
+        .map(|problem| problem.field)
+        .collect();
+    assert_eq!(
+        fields,
+        [
+            "announce",
+            "info.name",
+            "info.pieces",
+            "info.files[0].path[0]",
+            "info.files[1].path[0]",
+            "info.files[1].path",
+        ]
+    );
+
+    let unsorted = b"d4:infod4:name1:x6:lengthi3e12:piece lengthi4e\
+        6:pieces20:aaaaaaaaaaaaaaaaaaaae8:announce3:urle";
+    assert!(validate_lenient(unsorted).is_valid());
+    let report = validate(unsorted);
+    assert_eq!(report.problems.len(), 1, "{report}");
+    assert_eq!(report.problems[0].field, "torrent");
+}
@@ -0,0 +1,257 @@
+use crate::parsing::Info;
+use crate::tracker::compute_length;
+use anyhow::Context;
+use reqwest::{header, StatusCode, Url};
+use sha1::{Digest, Sha1};
+use std::ops::Range;
+
+/// A web seed (BEP 19): an HTTP server holding the files of the torrent,
+/// from which pieces are fetched with range requests.
+#[derive(Debug)]
+pub(crate) struct WebSeed {
+    url: String,
+    client: reqwest::Client,
+    banned: bool,
+}
+
+impl WebSeed {
+    pub(crate) fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            client: reqwest::Client::new(),
+            banned: false,
+        }
+    }
+
+    pub(crate) fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Whether the seed served corrupt data and must not be used again.
+    pub(crate) fn is_banned(&self) -> bool {
+        self.banned
+    }
+
+    /// Downloads piece `index` and checks it against its hash. A seed
+    /// serving data that does not match is banned.
+    pub(crate) async fn fetch_piece(
+        &mut self,
+        info: &Info,
+        index: usize,
+    ) -> anyhow::Result<Vec<u8>> {
+        anyhow::ensure!(!self.banned, "web seed {} is banned", self.url);
+        let offset = index * info.piece_length;
+        let length = info.piece_length.min(compute_length(info) - offset);
+
+        let mut piece = Vec::with_capacity(length);
+        for (path, range, padding) in file_ranges(info, offset, length) {
+            if padding {
+                piece.resize(piece.len() + range.len(), 0);
+                continue;
+            }
+            let url = file_url(&self.url, info, &path)?;
+            let bytes = self.fetch_range(url, range.clone()).await?;
+            anyhow::ensure!(
+                bytes.len() == range.len(),
+                "web seed {} sent {} bytes instead of {}",
+                self.url,
+                bytes.len(),
+                range.len()
+            );
+            piece.extend_from_slice(&bytes);
+        }
+
+        let hash: [u8; 20] = Sha1::digest(&piece).into();
+        if hash != info.pieces[index] {
+            self.banned = true;
+            anyhow::bail!("web seed {} sent a corrupt piece {index}", self.url);
+        }
+        Ok(piece)
+    }
+
+    async fn fetch_range(&self, url: Url, range: Range<usize>) -> anyhow::Result<Vec<u8>> {
+        let response = self
+            .client
+            .get(url)
+            .header(
+                header::RANGE,
+                format!("bytes={}-{}", range.start, range.end - 1),
+            )
+            .send()
+            .await
+            .context("request web seed")?;
+        let status = response.status();
+        let bytes = response.bytes().await.context("read web seed response")?;
+        match status {
+            StatusCode::PARTIAL_CONTENT => Ok(bytes.to_vec()),
+            // the server ignored the range and sent the whole file
+            StatusCode::OK if bytes.len() >= range.end => Ok(bytes[range].to_vec()),
+            status => anyhow::bail!("web seed {} answered {status}", self.url),
+        }
+    }
+}
+
+/// The files overlapping `length` bytes at `offset` of the torrent, with the
+/// byte range of each file they cover. Single-file torrents have one file
+/// with an empty path. Padding files (BEP 47) are flagged: they hold zeros
+/// and are not on the seed.
+pub(crate) fn file_ranges(
+    info: &Info,
+    offset: usize,
+    length: usize,
+) -> Vec<(Vec<&str>, Range<usize>, bool)> {
+    let files = match &info.files {
+        Some(files) if !files.is_empty() => files
+            .iter()
+            .map(|file| {
+                (
+                    file.path.split('/').collect(),
+                    file.length,
+                    file.is_padding(),
+                )
+            })
+            .collect(),
+        _ => vec![(Vec::new(), info.length, false)],
+    };
+
+    let end = offset + length;
+    let mut ranges = Vec::new();
+    let mut file_start = 0;
+    for (path, file_length, padding) in files {
+        let file_end = file_start + file_length;
+        if file_end > offset && file_start < end {
+            let start = offset.max(file_start) - file_start;
+            let stop = end.min(file_end) - file_start;
+            ranges.push((path, start..stop, padding));
+        }
+        file_start = file_end;
+    }
+    ranges
+}
+
+/// URL of a file on the seed. A seed URL ending with `/` is a directory the
+/// torrent is stored in under its name; otherwise a single-file torrent
+/// lives at the URL itself.
+fn file_url(base: &str, info: &Info, path: &[&str]) -> anyhow::Result<Url> {
+    let mut url = Url::parse(base).context("invalid web seed url")?;
+    if !base.ends_with('/') && path.is_empty() {
+        return Ok(url);
+    }
+    url.path_segments_mut()
+        .map_err(|_| anyhow::anyhow!("web seed url cannot have a path"))?
+        .pop_if_empty()
+        .push(&info.name)
+        .extend(path);
+    Ok(url)
+}
+
+#[cfg(test)]
+async fn serve_files(files: Vec<(&'static str, Vec<u8>)>) -> std::net::SocketAddr {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let n = stream.read(&mut buf).await.unwrap();
+                if n == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buf[..n]);
+            }
+            let request = String::from_utf8(request).unwrap();
+            let path = request.split(' ').nth(1).unwrap_or_default();
+            let range = request.lines().find_map(|line| {
+                let line = line.to_ascii_lowercase();
+                let (start, end) = line.strip_prefix("range: bytes=")?.split_once('-')?;
+                Some(start.parse::<usize>().ok()?..end.parse::<usize>().ok()? + 1)
+            });
+            let response = match (files.iter().find(|(p, _)| *p == path), range) {
+                (Some((_, body)), Some(range)) => {
+                    let mut response = format!(
+                        "HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                        range.len()
+                    )
+                    .into_bytes();
+                    response.extend_from_slice(&body[range]);
+                    response
+                }
+                _ => b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                    .to_vec(),
+            };
+            stream.write_all(&response).await.unwrap();
+        }
+    });
+    addr
+}
+
+#[cfg(test)]
+fn two_file_info(data: &[u8], split: usize) -> Info {
+    use crate::parsing::File;
+
+    Info {
+        files: Some(vec![
+            File {
+                length: split,
+                md5sum: None,
+                path: String::from("a b.txt"),
+                ..Default::default()
+            },
+            File {
+                length: data.len() - split,
+                md5sum: None,
+                path: String::from("sub/c.bin"),
+                ..Default::default()
+            },
+        ]),
+        length: 0,
+        name: String::from("multi"),
+        piece_length: 16,
+        pieces: data
+            .chunks(16)
+            .map(|chunk| Sha1::digest(chunk).into())
+            .collect(),
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn web_seed_fetches_pieces_across_files() {
+    let data: Vec<u8> = (0..40u8).collect();
+    let info = two_file_info(&data, 20);
+    let addr = serve_files(vec![
+        ("/multi/a%20b.txt", data[..20].to_vec()),
+        ("/multi/sub/c.bin", data[20..].to_vec()),
+    ])
+    .await;
+
+    let mut seed = WebSeed::new(format!("http://{addr}/"));
+    for index in 0..info.pieces.len() {
+        let piece = seed.fetch_piece(&info, index).await.unwrap();
+        assert_eq!(piece, data[index * 16..][..piece.len()]);
+    }
+    assert!(!seed.is_banned());
+}
+
+#[tokio::test]
+async fn web_seed_with_corrupt_data_is_banned() {
+    let data: Vec<u8> = (0..40u8).collect();
+    let info = two_file_info(&data, 20);
+    let mut corrupt = data[20..].to_vec();
+    corrupt[5] ^= 0xff;
+    let addr = serve_files(vec![
+        ("/multi/a%20b.txt", data[..20].to_vec()),
+        ("/multi/sub/c.bin", corrupt),
+    ])
+    .await;
+
+    let mut seed = WebSeed::new(format!("http://{addr}/"));
+    assert!(seed.fetch_piece(&info, 0).await.is_ok());
+    assert!(seed.fetch_piece(&info, 1).await.is_err());
+    assert!(seed.is_banned());
+    assert!(seed.fetch_piece(&info, 0).await.is_err());
+}